@@ -24,6 +24,7 @@ use uefi::{
             AllocateType, BootServices, LocateSearchType, MemoryDescriptor, MemoryType,
             OPEN_PROTOCOL_BY_HANDLE_PROTOCOL,
         },
+        configuration_table::ACPI_20_TABLE_GUID,
         system_table::SystemTable,
     },
     types::{Char16, Handle, Status, Uint32, Uintn, Void},
@@ -55,12 +56,21 @@ fn main_impl(image_handle: Handle, system_table: &mut SystemTable) -> Result<()>
     let stdout = system_table.stdout();
     writeln!(stdout, "hello world")?;
 
-    let kernel_first_addr = load_kernel_file(image_handle, system_table)?;
+    let (kernel_first_addr, kernel_last_addr) = load_kernel_file(image_handle, system_table)?;
     let kernel_arg = calc_kernel_arg(image_handle, system_table)?;
 
     let stack_base = alloc_stack(system_table)?;
-
-    exit_boot_service(image_handle, system_table)?;
+    let acpi_rsdp = find_acpi_rsdp(system_table);
+
+    let memory_map = exit_boot_service(image_handle, system_table)?;
+    let kernel_arg = kernel_arg
+        .with_memory_map(memory_map)
+        .with_kernel_image(
+            kernel_first_addr,
+            (kernel_last_addr - kernel_first_addr) as usize,
+        )
+        .with_stack(stack_base, STACK_SIZE)
+        .with_acpi_rsdp(acpi_rsdp);
 
     let entry_addr = unsafe { *((kernel_first_addr + 24) as *const u64) } as *const ();
     let kernel_main: KernelMain = unsafe { mem::transmute(entry_addr) };
@@ -82,10 +92,12 @@ mod arch {
             FN.write(kernel_fn);
         }
 
+        // スタックは下位アドレスに向かって伸びるので、確保した領域の終端から使う
+        let stack_top = stack_base + STACK_SIZE as u64;
         unsafe {
             asm! {
                 "mov rsp, {x}",
-                x = in(reg) stack_base
+                x = in(reg) stack_top
             }
         }
 
@@ -111,6 +123,16 @@ fn alloc_stack(system_table: &SystemTable) -> Result<u64> {
     Ok(stack_base)
 }
 
+/// 見つからなければ0を返す
+fn find_acpi_rsdp(system_table: &SystemTable) -> u64 {
+    system_table
+        .configuration_tables()
+        .iter()
+        .find(|table| table.vendor_guid == ACPI_20_TABLE_GUID)
+        .map(|table| table.vendor_table as u64)
+        .unwrap_or(0)
+}
+
 fn calc_kernel_arg(image_handle: Handle, system_table: &SystemTable) -> Result<KernelArg> {
     let gop = open_gop(system_table.boot_services(), image_handle)?;
     let mode = gop.mode();
@@ -166,7 +188,8 @@ fn calc_kernel_arg(image_handle: Handle, system_table: &SystemTable) -> Result<K
     Ok(kernel_arg)
 }
 
-fn load_kernel_file(image_handle: Handle, system_table: &mut SystemTable) -> Result<u64> {
+/// カーネルを読み込み、配置した物理アドレスの範囲を返す
+fn load_kernel_file(image_handle: Handle, system_table: &mut SystemTable) -> Result<(u64, u64)> {
     info!("open root dir");
     let root_dir = open_root_dir(image_handle, system_table.boot_services())?;
 
@@ -251,15 +274,28 @@ fn load_kernel_file(image_handle: Handle, system_table: &mut SystemTable) -> Res
         .to_result()
         .map_err(|_| Error::Custom("failed to free pages"))?;
 
-    Ok(kernel_first_addr)
+    Ok((kernel_first_addr, kernel_last_addr as u64))
 }
 
-fn exit_boot_service(image_handle: Handle, system_table: &SystemTable) -> Result<()> {
+fn exit_boot_service(
+    image_handle: Handle,
+    system_table: &SystemTable,
+) -> Result<kernel::memory_map::MemoryMap> {
     const MEMORY_MAP_SIZE: usize = 4096 * 4;
-    let mut memorymap_buf = [0u8; MEMORY_MAP_SIZE];
+    // カーネルに渡すのでスタックではなくページを確保する
+    let mut memorymap_buf = 0;
+    (system_table.boot_services().allocate_pages)(
+        AllocateType::AllocateAnyPages,
+        MemoryType::EfiLoaderData,
+        MEMORY_MAP_SIZE / 0x1000,
+        &mut memorymap_buf,
+    )
+    .to_result()
+    .map_err(|_| Error::Custom("failed to allocate pages for memory map"))?;
+
     let mut memory_map = MemoryMap {
         buffer_size: MEMORY_MAP_SIZE,
-        buffer: &mut memorymap_buf as *mut [u8; MEMORY_MAP_SIZE] as *mut Void,
+        buffer: memorymap_buf as *mut Void,
         map_size: 0,
         map_key: 0,
         descriptor_size: 0,
@@ -282,7 +318,16 @@ fn exit_boot_service(image_handle: Handle, system_table: &SystemTable) -> Result
             .map_err(|_| Error::Custom("failed to exit boot services"))?;
     }
 
-    Ok(())
+    let memory_map = unsafe {
+        kernel::memory_map::MemoryMap::new(
+            memory_map.buffer as *const u8,
+            memory_map.map_size,
+            memory_map.descriptor_size,
+            memory_map.descriptor_version,
+        )
+    };
+
+    Ok(memory_map)
 }
 
 fn open_root_dir(image_handle: Handle, boot_services: &BootServices) -> Result<*mut FileProtocol> {
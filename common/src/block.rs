@@ -0,0 +1,23 @@
+//! ブロックデバイス
+//!
+//! 決まった大きさのブロック単位で読み書きするデバイス。ファイルシステムはこれを通してディスクを読む。
+
+pub trait BlockDevice {
+    type Error;
+
+    /// 1ブロックのバイト数
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /// `lba`から`buf`を埋めるだけ読む。`buf`の長さはブロックサイズの倍数でなければならない。
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// `lba`から`buf`を書く。`buf`の長さはブロックサイズの倍数でなければならない。
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), Self::Error>;
+
+    /// ディスク全体のバイト数
+    fn capacity(&self) -> u64 {
+        self.block_count() * self.block_size() as u64
+    }
+}
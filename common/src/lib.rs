@@ -1,7 +1,9 @@
 #![cfg_attr(not(test), no_std)]
 
+pub mod block;
 pub mod log;
 pub mod map;
+pub mod mutex;
 pub mod ring_buf;
 pub mod zeroed;
 
@@ -24,6 +24,22 @@ impl<K, V, const N: usize, H> FixedMap<K, V, N, H> {
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// 順番は挿入した順とは限らない
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.arr.iter().flatten().map(|(k, v)| (k, v))
+    }
+
+    /// `f`がfalseを返した要素を取り除く
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        for entry in self.arr.iter_mut() {
+            if let Some((k, v)) = entry {
+                if !f(k, v) {
+                    *entry = None;
+                }
+            }
+        }
+    }
 }
 
 impl<K, V, const N: usize> FixedMap<K, V, N, DefaultHashBuilder> {
@@ -165,4 +181,22 @@ mod tests {
         assert_eq!(map.insert(3, 1), Ok(None));
         assert_eq!(map.len(), 2);
     }
+
+    #[test]
+    fn test_iter_and_retain() {
+        let mut map: FixedMap<u32, i32, 4> = FixedMap::new();
+        for i in 0..4 {
+            map.insert(i, i as i32 * 10).unwrap();
+        }
+        assert_eq!(map.iter().map(|(_, v)| v).sum::<i32>(), 60);
+
+        map.retain(|k, v| {
+            *v += 1;
+            k % 2 == 0
+        });
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(&2), Some(&21));
+        assert_eq!(map.get(&1), None);
+        assert!(map.iter().all(|(k, _)| k % 2 == 0));
+    }
 }
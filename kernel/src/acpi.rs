@@ -0,0 +1,273 @@
+//! ACPIテーブルの読み取り
+//!
+//! RSDPからXSDTをたどって目的のテーブルを探す。物理アドレスはアイデンティティマップされている前提。
+
+use core::{mem, ptr, slice};
+
+use crate::error::{Error, Result};
+
+/// Root System Description Pointer (ACPI 2.0以降)
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+impl Rsdp {
+    const SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+    /// ACPI 1.0の範囲のバイト数
+    const V1_LENGTH: usize = 20;
+
+    pub fn is_valid(&self) -> bool {
+        if &self.signature != Self::SIGNATURE || self.revision < 2 {
+            return false;
+        }
+
+        let bytes =
+            unsafe { slice::from_raw_parts(self as *const _ as *const u8, self.length as usize) };
+        checksum(&bytes[..Self::V1_LENGTH]) && checksum(bytes)
+    }
+
+    pub fn xsdt(&self) -> Result<&'static Xsdt> {
+        let header = unsafe { &*(self.xsdt_address as *const DescriptionHeader) };
+        if !header.is_valid(Xsdt::SIGNATURE) {
+            return Err(Error::invalid_acpi_table());
+        }
+        Ok(unsafe { &*(header as *const _ as *const Xsdt) })
+    }
+}
+
+/// 各テーブル共通のヘッダ
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct DescriptionHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+impl DescriptionHeader {
+    pub fn signature(&self) -> [u8; 4] {
+        self.signature
+    }
+
+    pub fn length(&self) -> usize {
+        self.length as usize
+    }
+
+    pub fn is_valid(&self, signature: &[u8; 4]) -> bool {
+        if &self.signature != signature {
+            return false;
+        }
+
+        let bytes = unsafe { slice::from_raw_parts(self as *const _ as *const u8, self.length()) };
+        checksum(bytes)
+    }
+
+    /// ヘッダに続くデータ部分の先頭
+    fn body(&self) -> *const u8 {
+        unsafe { (self as *const Self as *const u8).add(mem::size_of::<Self>()) }
+    }
+
+    fn body_len(&self) -> usize {
+        self.length().saturating_sub(mem::size_of::<Self>())
+    }
+}
+
+/// Extended System Description Table
+#[repr(C, packed)]
+pub struct Xsdt {
+    header: DescriptionHeader,
+}
+
+impl Xsdt {
+    const SIGNATURE: &'static [u8; 4] = b"XSDT";
+
+    /// 各テーブルへの物理アドレス
+    pub fn entries(&self) -> impl Iterator<Item = u64> + '_ {
+        let body = self.header.body() as *const u64;
+        let count = self.header.body_len() / mem::size_of::<u64>();
+        // エントリは8バイト境界に揃っていない
+        (0..count).map(move |i| unsafe { ptr::read_unaligned(body.add(i)) })
+    }
+
+    pub fn find_table(&self, signature: &[u8; 4]) -> Result<&'static DescriptionHeader> {
+        self.entries()
+            .map(|addr| unsafe { &*(addr as *const DescriptionHeader) })
+            .find(|header| header.is_valid(signature))
+            .ok_or_else(Error::acpi_table_not_found)
+    }
+
+    pub fn mcfg(&self) -> Result<&'static Mcfg> {
+        let header = self.find_table(Mcfg::SIGNATURE)?;
+        Ok(unsafe { &*(header as *const _ as *const Mcfg) })
+    }
+}
+
+/// PCI Express memory mapped configuration space base address description table
+#[repr(C, packed)]
+pub struct Mcfg {
+    header: DescriptionHeader,
+    _reserved: u64,
+}
+
+impl Mcfg {
+    const SIGNATURE: &'static [u8; 4] = b"MCFG";
+
+    pub fn entries(&self) -> impl Iterator<Item = McfgEntry> + '_ {
+        let base = unsafe { (self as *const Self as *const u8).add(mem::size_of::<Self>()) }
+            as *const McfgEntry;
+        let count = self.header.length().saturating_sub(mem::size_of::<Self>())
+            / mem::size_of::<McfgEntry>();
+        (0..count).map(move |i| unsafe { ptr::read_unaligned(base.add(i)) })
+    }
+}
+
+/// PCIセグメントグループごとのECAM領域
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    base_address: u64,
+    segment_group: u16,
+    start_bus: u8,
+    end_bus: u8,
+    _reserved: u32,
+}
+
+impl McfgEntry {
+    pub fn base_address(&self) -> u64 {
+        self.base_address
+    }
+
+    pub fn segment_group(&self) -> u16 {
+        self.segment_group
+    }
+
+    pub fn start_bus(&self) -> u8 {
+        self.start_bus
+    }
+
+    pub fn end_bus(&self) -> u8 {
+        self.end_bus
+    }
+}
+
+/// `rsdp`からMCFGを探す
+///
+/// # Safety
+/// `rsdp`はファームウェアから渡されたRSDPの物理アドレスで、ACPIテーブルがマップされている必要がある
+pub unsafe fn find_mcfg(rsdp: u64) -> Result<&'static Mcfg> {
+    let rsdp = &*(rsdp as *const Rsdp);
+    if !rsdp.is_valid() {
+        return Err(Error::invalid_acpi_table());
+    }
+    rsdp.xsdt()?.mcfg()
+}
+
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    const HEADER_SIZE: usize = mem::size_of::<DescriptionHeader>();
+
+    fn fix_checksum(bytes: &mut [u8], checksum_offset: usize) {
+        bytes[checksum_offset] = 0;
+        let sum = bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        bytes[checksum_offset] = 0u8.wrapping_sub(sum);
+    }
+
+    fn table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(signature);
+        bytes.extend_from_slice(&((HEADER_SIZE + body.len()) as u32).to_le_bytes());
+        bytes.resize(HEADER_SIZE, 0);
+        bytes.extend_from_slice(body);
+        fix_checksum(&mut bytes, 9);
+        bytes
+    }
+
+    fn rsdp(xsdt: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(Rsdp::SIGNATURE);
+        bytes.resize(15, 0);
+        bytes.push(2); // revision
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&(mem::size_of::<Rsdp>() as u32).to_le_bytes());
+        bytes.extend_from_slice(&xsdt.to_le_bytes());
+        bytes.resize(mem::size_of::<Rsdp>(), 0);
+        fix_checksum(&mut bytes[..Rsdp::V1_LENGTH], 8);
+        fix_checksum(&mut bytes, 32);
+        bytes
+    }
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(mem::size_of::<Rsdp>(), 36);
+        assert_eq!(HEADER_SIZE, 36);
+        assert_eq!(mem::size_of::<Mcfg>(), 44);
+        assert_eq!(mem::size_of::<McfgEntry>(), 16);
+    }
+
+    #[test]
+    fn test_find_mcfg() {
+        let mut mcfg_body = std::vec![0u8; 8];
+        mcfg_body.extend_from_slice(&0xe000_0000u64.to_le_bytes());
+        mcfg_body.extend_from_slice(&0u16.to_le_bytes());
+        mcfg_body.extend_from_slice(&[0, 0xff]);
+        mcfg_body.extend_from_slice(&0u32.to_le_bytes());
+        let mcfg = table(b"MCFG", &mcfg_body);
+        let facp = table(b"FACP", &[0; 4]);
+
+        let mut xsdt_body = Vec::new();
+        xsdt_body.extend_from_slice(&(facp.as_ptr() as u64).to_le_bytes());
+        xsdt_body.extend_from_slice(&(mcfg.as_ptr() as u64).to_le_bytes());
+        let xsdt = table(b"XSDT", &xsdt_body);
+        let rsdp = rsdp(xsdt.as_ptr() as u64);
+
+        let mcfg = unsafe { find_mcfg(rsdp.as_ptr() as u64) }.unwrap();
+        let entries: Vec<_> = mcfg.entries().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].base_address(), 0xe000_0000);
+        assert_eq!(entries[0].segment_group(), 0);
+        assert_eq!(entries[0].start_bus(), 0);
+        assert_eq!(entries[0].end_bus(), 0xff);
+    }
+
+    #[test]
+    fn test_invalid_tables() {
+        let xsdt = table(b"XSDT", &[]);
+        let mut rsdp = rsdp(xsdt.as_ptr() as u64);
+        assert_eq!(
+            unsafe { find_mcfg(rsdp.as_ptr() as u64) }.err(),
+            Some(Error::acpi_table_not_found())
+        );
+
+        rsdp[16] ^= 1;
+        assert_eq!(
+            unsafe { find_mcfg(rsdp.as_ptr() as u64) }.err(),
+            Some(Error::invalid_acpi_table())
+        );
+    }
+}
@@ -1,44 +1,489 @@
 use core::{
-    alloc::GlobalAlloc,
-    ptr,
-    sync::atomic::{AtomicUsize, Ordering},
+    alloc::{GlobalAlloc, Layout},
+    mem, ptr,
 };
 
+use common::mutex::Mutex;
+
+use crate::memory_manager::{self, FRAME_SIZE};
+
+#[cfg(all(feature = "alloc", not(test)))]
 #[global_allocator]
-static HEAP: StaticAllocator<{ 4096 * 1000 }> = StaticAllocator::new();
+static HEAP: LockedHeap<MemoryManagerFrames> = LockedHeap::new(MemoryManagerFrames);
 
-pub struct StaticAllocator<const SIZE: usize> {
-    buf: [u8; SIZE],
-    head: AtomicUsize,
+/// ヒープが足りなくなったときにフレームを供給する
+pub trait FrameSource {
+    /// 連続した`num_frames`個のフレームを確保し、先頭アドレスを返す
+    fn allocate_frames(&mut self, num_frames: usize) -> Option<*mut u8>;
 }
 
-impl<const SIZE: usize> StaticAllocator<SIZE> {
+/// `memory_manager`からフレームを取る。物理アドレスはidentity mapされている前提。
+pub struct MemoryManagerFrames;
+
+impl FrameSource for MemoryManagerFrames {
+    fn allocate_frames(&mut self, num_frames: usize) -> Option<*mut u8> {
+        memory_manager::memory_manager()
+            .allocate(num_frames)
+            .ok()
+            .map(|frame| frame.as_ptr())
+    }
+}
+
+/// slabのブロックサイズ。これより大きいものは`LinkedListHeap`から取る。
+const SLAB_SIZES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// 大きい領域を拡張するときの最小フレーム数
+const MIN_GROW_FRAMES: usize = 16;
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+/// 空き領域をアドレス順に並べたリスト。解放時に隣接する領域と結合する。
+struct ListNode {
+    size: usize,
+    next: *mut ListNode,
+}
+
+const NODE_SIZE: usize = mem::size_of::<ListNode>();
+
+pub struct LinkedListHeap {
+    head: *mut ListNode,
+}
+
+impl LinkedListHeap {
     pub const fn new() -> Self {
         Self {
-            buf: [0; SIZE],
-            head: AtomicUsize::new(0),
-        }
-    }
-}
-
-unsafe impl<const SIZE: usize> GlobalAlloc for StaticAllocator<SIZE> {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let head = self.head.load(Ordering::SeqCst);
-        let align = layout.align();
-        let res = head % align;
-        let start = if res == 0 { head } else { head + (align - res) };
-        if start + align > self.buf.len() {
-            ptr::null_mut()
-        } else if self
-            .head
-            .compare_exchange(head, start + align, Ordering::SeqCst, Ordering::SeqCst)
-            .is_ok()
-        {
-            start as *mut u8
+            head: ptr::null_mut(),
+        }
+    }
+
+    /// # Safety
+    /// `addr`から`size`バイトは未使用で、`NODE_SIZE`の倍数かつ`NODE_SIZE`境界に揃っている必要がある。
+    pub unsafe fn add_region(&mut self, addr: usize, size: usize) {
+        debug_assert!(
+            addr.is_multiple_of(NODE_SIZE) && size.is_multiple_of(NODE_SIZE) && size != 0
+        );
+
+        let mut prev: *mut ListNode = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let node = addr as *mut ListNode;
+        node.write(ListNode { size, next });
+
+        // 後ろと結合
+        if !next.is_null() && addr + size == next as usize {
+            (*node).size += (*next).size;
+            (*node).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.head = node;
+        } else if prev as usize + (*prev).size == addr {
+            // 前と結合
+            (*prev).size += (*node).size;
+            (*prev).next = (*node).next;
         } else {
-            ptr::null_mut()
+            (*prev).next = node;
+        }
+    }
+
+    /// `size`と`align`は`NODE_SIZE`の倍数である必要がある
+    pub fn allocate(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let mut prev: *mut ListNode = ptr::null_mut();
+        let mut current = self.head;
+        while !current.is_null() {
+            let start = current as usize;
+            let (region_size, next) = unsafe { ((*current).size, (*current).next) };
+            let end = start + region_size;
+            let aligned = align_up(start, align);
+
+            if aligned + size <= end {
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    unsafe { (*prev).next = next };
+                }
+
+                unsafe {
+                    if start < aligned {
+                        self.add_region(start, aligned - start);
+                    }
+                    if aligned + size < end {
+                        self.add_region(aligned + size, end - aligned - size);
+                    }
+                }
+                return Some(aligned as *mut u8);
+            }
+
+            prev = current;
+            current = next;
+        }
+
+        None
+    }
+
+    /// 空き領域の合計バイト数
+    pub fn free_size(&self) -> usize {
+        let mut total = 0;
+        let mut current = self.head;
+        while !current.is_null() {
+            unsafe {
+                total += (*current).size;
+                current = (*current).next;
+            }
+        }
+        total
+    }
+}
+
+impl Default for LinkedListHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 小さいサイズはslabで、大きいサイズはリストで管理するヒープ。
+/// 足りなくなったら`FrameSource`からフレームを取ってくる。
+pub struct Heap<S> {
+    slabs: [*mut FreeBlock; SLAB_SIZES.len()],
+    list: LinkedListHeap,
+    source: S,
+}
+
+unsafe impl<S: Send> Send for Heap<S> {}
+
+impl<S: FrameSource> Heap<S> {
+    pub const fn new(source: S) -> Self {
+        Self {
+            slabs: [ptr::null_mut(); SLAB_SIZES.len()],
+            list: LinkedListHeap::new(),
+            source,
+        }
+    }
+
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match slab_class(layout) {
+            Some(class) => self.allocate_slab(class),
+            None => self.allocate_large(layout),
+        }
+    }
+
+    /// # Safety
+    /// `ptr`は同じ`layout`で`allocate`したものである必要がある
+    pub unsafe fn free(&mut self, ptr: *mut u8, layout: Layout) {
+        match slab_class(layout) {
+            Some(class) => {
+                let block = ptr as *mut FreeBlock;
+                block.write(FreeBlock {
+                    next: self.slabs[class],
+                });
+                self.slabs[class] = block;
+            }
+            None => {
+                let (size, _) = large_size_align(layout);
+                self.list.add_region(ptr as usize, size);
+            }
+        }
+    }
+
+    fn allocate_slab(&mut self, class: usize) -> *mut u8 {
+        if self.slabs[class].is_null() {
+            let Some(frame) = self.source.allocate_frames(1) else {
+                return ptr::null_mut();
+            };
+
+            // フレームはFRAME_SIZE境界にあるので、各ブロックはサイズ境界に揃う
+            let block_size = SLAB_SIZES[class];
+            for offset in (0..FRAME_SIZE).step_by(block_size).rev() {
+                unsafe {
+                    let block = frame.add(offset) as *mut FreeBlock;
+                    block.write(FreeBlock {
+                        next: self.slabs[class],
+                    });
+                    self.slabs[class] = block;
+                }
+            }
+        }
+
+        let block = self.slabs[class];
+        self.slabs[class] = unsafe { (*block).next };
+        block as *mut u8
+    }
+
+    fn allocate_large(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = large_size_align(layout);
+        if let Some(ptr) = self.list.allocate(size, align) {
+            return ptr;
+        }
+
+        let num_frames = (size + align).div_ceil(FRAME_SIZE).max(MIN_GROW_FRAMES);
+        let Some(region) = self.source.allocate_frames(num_frames) else {
+            return ptr::null_mut();
+        };
+        unsafe {
+            self.list
+                .add_region(region as usize, num_frames * FRAME_SIZE)
+        };
+
+        self.list.allocate(size, align).unwrap_or(ptr::null_mut())
+    }
+}
+
+pub struct LockedHeap<S>(Mutex<Heap<S>>);
+
+impl<S: FrameSource> LockedHeap<S> {
+    pub const fn new(source: S) -> Self {
+        Self(Mutex::new(Heap::new(source)))
+    }
+}
+
+unsafe impl<S: FrameSource + Send> GlobalAlloc for LockedHeap<S> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().free(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        // 同じslabに収まるならそのまま使う
+        if let (Some(old), Some(new)) = (slab_class(layout), slab_class(new_layout)) {
+            if old == new {
+                return ptr;
+            }
+        }
+
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+fn slab_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SLAB_SIZES.iter().position(|&s| size <= s)
+}
+
+fn large_size_align(layout: Layout) -> (usize, usize) {
+    (
+        align_up(layout.size(), NODE_SIZE),
+        layout.align().max(NODE_SIZE),
+    )
+}
+
+const fn align_up(v: usize, align: usize) -> usize {
+    (v + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{alloc::System, vec::Vec};
+
+    use super::*;
+
+    /// ホストのアロケータからフレームを取る。確保したものはdropで返す。
+    #[derive(Default)]
+    struct HostFrames {
+        allocated: Vec<(*mut u8, Layout)>,
+    }
+
+    impl FrameSource for HostFrames {
+        fn allocate_frames(&mut self, num_frames: usize) -> Option<*mut u8> {
+            let layout = Layout::from_size_align(num_frames * FRAME_SIZE, FRAME_SIZE).ok()?;
+            let ptr = unsafe { System.alloc(layout) };
+            self.allocated.push((ptr, layout));
+            Some(ptr)
+        }
+    }
+
+    impl Drop for HostFrames {
+        fn drop(&mut self) {
+            for &(ptr, layout) in &self.allocated {
+                unsafe { System.dealloc(ptr, layout) }
+            }
+        }
+    }
+
+    unsafe impl Send for HostFrames {}
+
+    /// 再現性のある疑似乱数
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 as usize
+        }
+    }
+
+    struct Live {
+        ptr: *mut u8,
+        layout: Layout,
+        fill: u8,
+    }
+
+    fn fill(live: &Live) {
+        unsafe { ptr::write_bytes(live.ptr, live.fill, live.layout.size()) }
+    }
+
+    /// 中身が書き換わっていれば他の割り当てと重なっている
+    fn check(live: &Live) {
+        let data = unsafe { core::slice::from_raw_parts(live.ptr, live.layout.size()) };
+        assert!(
+            data.iter().all(|&b| b == live.fill),
+            "overlapped: {:p}",
+            live.ptr
+        );
+    }
+
+    fn check_disjoint(lives: &[Live]) {
+        let mut ranges: Vec<_> = lives
+            .iter()
+            .map(|l| (l.ptr as usize, l.ptr as usize + l.layout.size()))
+            .collect();
+        ranges.sort();
+        for w in ranges.windows(2) {
+            assert!(w[0].1 <= w[1].0, "{:x?} overlaps {:x?}", w[0], w[1]);
+        }
+    }
+
+    #[test]
+    fn test_slab_reuse() {
+        let mut heap = Heap::new(HostFrames::default());
+        let layout = Layout::from_size_align(24, 8).unwrap();
+
+        let a = heap.allocate(layout);
+        let b = heap.allocate(layout);
+        assert_ne!(a, b);
+        assert_eq!(a as usize % 32, 0);
+
+        unsafe { heap.free(a, layout) };
+        assert_eq!(heap.allocate(layout), a);
+        assert_eq!(heap.source.allocated.len(), 1);
+    }
+
+    #[test]
+    fn test_large_alignment() {
+        let mut heap = Heap::new(HostFrames::default());
+        let layout = Layout::from_size_align(5000, 4096).unwrap();
+
+        let a = heap.allocate(layout);
+        let b = heap.allocate(layout);
+        assert_eq!(a as usize % 4096, 0);
+        assert_eq!(b as usize % 4096, 0);
+        assert_ne!(a, b);
+
+        unsafe {
+            heap.free(a, layout);
+            heap.free(b, layout);
         }
+        // 解放した領域は結合されて元の大きさに戻る
+        assert_eq!(heap.list.free_size(), MIN_GROW_FRAMES * FRAME_SIZE);
     }
 
-    unsafe fn dealloc(&self, _: *mut u8, _: core::alloc::Layout) {}
+    #[test]
+    fn test_grow_beyond_min_frames() {
+        let mut heap = Heap::new(HostFrames::default());
+        let layout = Layout::from_size_align(FRAME_SIZE * (MIN_GROW_FRAMES + 3), 8).unwrap();
+
+        let a = heap.allocate(layout);
+        assert!(!a.is_null());
+        unsafe { heap.free(a, layout) };
+    }
+
+    #[test]
+    fn test_linked_list_merge() {
+        #[repr(align(16))]
+        struct Buf([u8; 256]);
+        let mut buf = Buf([0; 256]);
+        let base = buf.0.as_mut_ptr() as usize;
+
+        let mut list = LinkedListHeap::new();
+        unsafe {
+            list.add_region(base + 128, 64);
+            list.add_region(base, 64);
+            list.add_region(base + 64, 64);
+        }
+        assert_eq!(list.free_size(), 192);
+        assert_eq!(list.allocate(192, 16), Some(base as *mut u8));
+        assert_eq!(list.allocate(16, 16), None);
+    }
+
+    #[test]
+    fn test_stress() {
+        let heap = LockedHeap::new(HostFrames::default());
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+        let mut lives: Vec<Live> = Vec::new();
+
+        for i in 0..20000 {
+            match rng.next() % 4 {
+                0 | 1 => {
+                    let size = match rng.next() % 8 {
+                        0 => rng.next() % 20000 + 1,
+                        _ => rng.next() % 600 + 1,
+                    };
+                    let align = 1 << (rng.next() % 7);
+                    let layout = Layout::from_size_align(size, align).unwrap();
+                    let ptr = unsafe { heap.alloc(layout) };
+                    assert!(!ptr.is_null());
+                    assert_eq!(ptr as usize % align, 0);
+
+                    let live = Live {
+                        ptr,
+                        layout,
+                        fill: i as u8,
+                    };
+                    fill(&live);
+                    lives.push(live);
+                }
+                2 if !lives.is_empty() => {
+                    let live = lives.swap_remove(rng.next() % lives.len());
+                    check(&live);
+                    unsafe { heap.dealloc(live.ptr, live.layout) };
+                }
+                3 if !lives.is_empty() => {
+                    let idx = rng.next() % lives.len();
+                    let live = &mut lives[idx];
+                    check(live);
+
+                    let new_size = rng.next() % 3000 + 1;
+                    let ptr = unsafe { heap.realloc(live.ptr, live.layout, new_size) };
+                    assert!(!ptr.is_null());
+                    let kept = live.layout.size().min(new_size);
+                    let data = unsafe { core::slice::from_raw_parts(ptr, kept) };
+                    assert!(data.iter().all(|&b| b == live.fill));
+
+                    live.ptr = ptr;
+                    live.layout = Layout::from_size_align(new_size, live.layout.align()).unwrap();
+                    fill(live);
+                }
+                _ => {}
+            }
+
+            if i % 1000 == 0 {
+                lives.iter().for_each(check);
+                check_disjoint(&lives);
+            }
+        }
+
+        lives.iter().for_each(check);
+        check_disjoint(&lives);
+        for live in lives {
+            unsafe { heap.dealloc(live.ptr, live.layout) };
+        }
+    }
 }
@@ -0,0 +1,44 @@
+//! Local APIC
+
+use core::ptr;
+
+use crate::{error::Result, paging};
+
+pub const LOCAL_APIC_BASE: u64 = 0xfee0_0000;
+
+const ID: u64 = 0x20;
+const EOI: u64 = 0xb0;
+const SPURIOUS_INTERRUPT_VECTOR: u64 = 0xf0;
+
+/// SVRのAPIC Software Enable
+const APIC_ENABLE: u32 = 1 << 8;
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+fn read(offset: u64) -> u32 {
+    unsafe { ptr::read_volatile((LOCAL_APIC_BASE + offset) as *const u32) }
+}
+
+fn write(offset: u64, value: u32) {
+    unsafe { ptr::write_volatile((LOCAL_APIC_BASE + offset) as *mut u32, value) }
+}
+
+/// Local APICのレジスタをUCでマップして有効にする。`paging::init`の後に呼ぶ。
+pub fn init() -> Result<()> {
+    paging::map_mmio(LOCAL_APIC_BASE, paging::PAGE_SIZE_4K)?;
+
+    let svr = read(SPURIOUS_INTERRUPT_VECTOR) & !0xff;
+    write(
+        SPURIOUS_INTERRUPT_VECTOR,
+        svr | APIC_ENABLE | SPURIOUS_VECTOR as u32,
+    );
+    Ok(())
+}
+
+pub fn local_apic_id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+/// 割り込みハンドラの最後に呼ぶ
+pub fn end_of_interrupt() {
+    write(EOI, 0)
+}
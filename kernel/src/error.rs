@@ -17,6 +17,46 @@ impl Error {
     pub fn out_of_range_bar() -> Error {
         Error(ErrorKind::OutOfRangeBar)
     }
+
+    pub fn no_enough_memory() -> Error {
+        Error(ErrorKind::NoEnoughMemory)
+    }
+
+    pub fn frame_out_of_range() -> Error {
+        Error(ErrorKind::FrameOutOfRange)
+    }
+
+    pub fn frame_not_allocated() -> Error {
+        Error(ErrorKind::FrameNotAllocated)
+    }
+
+    pub fn invalid_frame_count() -> Error {
+        Error(ErrorKind::InvalidFrameCount)
+    }
+
+    pub fn unaligned_addr() -> Error {
+        Error(ErrorKind::UnalignedAddr)
+    }
+
+    pub fn page_not_mapped() -> Error {
+        Error(ErrorKind::PageNotMapped)
+    }
+
+    pub fn capability_not_found() -> Error {
+        Error(ErrorKind::CapabilityNotFound)
+    }
+
+    pub fn invalid_capability_offset() -> Error {
+        Error(ErrorKind::InvalidCapabilityOffset)
+    }
+
+    pub fn invalid_acpi_table() -> Error {
+        Error(ErrorKind::InvalidAcpiTable)
+    }
+
+    pub fn acpi_table_not_found() -> Error {
+        Error(ErrorKind::AcpiTableNotFound)
+    }
 }
 
 impl From<GraphicError> for Error {
@@ -31,4 +71,14 @@ enum ErrorKind {
     TooManyDevices,
     Graphic(GraphicError),
     OutOfRangeBar,
+    NoEnoughMemory,
+    FrameOutOfRange,
+    FrameNotAllocated,
+    InvalidFrameCount,
+    UnalignedAddr,
+    PageNotMapped,
+    CapabilityNotFound,
+    InvalidCapabilityOffset,
+    InvalidAcpiTable,
+    AcpiTableNotFound,
 }
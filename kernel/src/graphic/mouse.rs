@@ -82,8 +82,12 @@ impl MouseCursor {
         Ok(())
     }
 
-    pub fn move_relative(&mut self, x: i8, y: i8) {
-        self.pos.move_relative(x as i32, y as i32);
+    pub fn move_relative(&mut self, x: i32, y: i32) {
+        self.pos.move_relative(x, y);
+    }
+
+    pub fn move_to(&mut self, pos: PixelPosition) {
+        self.pos = pos;
     }
 }
 
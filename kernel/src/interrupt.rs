@@ -0,0 +1,223 @@
+use core::{fmt, mem, ptr};
+
+use common::error;
+
+use crate::{
+    segment::{self, KERNEL_CS},
+    x86::{self, DescriptorTablePointer},
+};
+
+/// 例外のベクタ番号
+pub mod vector {
+    pub const DIVIDE_ERROR: u8 = 0;
+    pub const INVALID_OPCODE: u8 = 6;
+    pub const DOUBLE_FAULT: u8 = 8;
+    pub const GENERAL_PROTECTION: u8 = 13;
+    pub const PAGE_FAULT: u8 = 14;
+
+    pub const XHCI: u8 = 0x40;
+}
+
+pub type Handler = extern "x86-interrupt" fn(InterruptStackFrame);
+pub type HandlerWithErrorCode = extern "x86-interrupt" fn(InterruptStackFrame, u64);
+pub type DivergingHandlerWithErrorCode = extern "x86-interrupt" fn(InterruptStackFrame, u64) -> !;
+
+/// 割り込み時にCPUがスタックに積む値
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct InterruptStackFrame {
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl fmt::Debug for InterruptStackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InterruptStackFrame")
+            .field("rip", &format_args!("{:#x}", self.rip))
+            .field("cs", &format_args!("{:#x}", self.cs))
+            .field("rflags", &format_args!("{:#x}", self.rflags))
+            .field("rsp", &format_args!("{:#x}", self.rsp))
+            .field("ss", &format_args!("{:#x}", self.ss))
+            .finish()
+    }
+}
+
+/// 64bitの割り込みゲート
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IdtEntry {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    type_attr: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    _reserved: u32,
+}
+
+impl IdtEntry {
+    /// present, DPL0, 64bit interrupt gate
+    const INTERRUPT_GATE: u8 = 0x8e;
+
+    pub const fn missing() -> Self {
+        Self {
+            offset_low: 0,
+            selector: 0,
+            ist: 0,
+            type_attr: 0,
+            offset_mid: 0,
+            offset_high: 0,
+            _reserved: 0,
+        }
+    }
+
+    /// `ist`が0ならスタックを切り替えない
+    pub fn new(handler: u64, selector: u16, ist: u8) -> Self {
+        Self {
+            offset_low: handler as u16,
+            selector,
+            ist: ist & 0x7,
+            type_attr: Self::INTERRUPT_GATE,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            _reserved: 0,
+        }
+    }
+
+    pub fn handler_addr(&self) -> u64 {
+        self.offset_low as u64 | (self.offset_mid as u64) << 16 | (self.offset_high as u64) << 32
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.type_attr & 0x80 != 0
+    }
+}
+
+#[repr(C, align(16))]
+pub struct InterruptDescriptorTable {
+    entries: [IdtEntry; 256],
+}
+
+impl InterruptDescriptorTable {
+    pub const fn new() -> Self {
+        Self {
+            entries: [IdtEntry::missing(); 256],
+        }
+    }
+
+    pub fn set(&mut self, vector: u8, entry: IdtEntry) {
+        self.entries[vector as usize] = entry;
+    }
+
+    pub fn get(&self, vector: u8) -> &IdtEntry {
+        &self.entries[vector as usize]
+    }
+}
+
+impl Default for InterruptDescriptorTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
+
+/// 例外ハンドラを登録してIDTを読み込む。`segment::init`の後に呼ぶ。
+pub fn init() {
+    let idt = unsafe { &mut *ptr::addr_of_mut!(IDT) };
+
+    let entry = |handler: u64| IdtEntry::new(handler, KERNEL_CS, 0);
+    idt.set(
+        vector::DIVIDE_ERROR,
+        entry(divide_error as Handler as usize as u64),
+    );
+    idt.set(
+        vector::INVALID_OPCODE,
+        entry(invalid_opcode as Handler as usize as u64),
+    );
+    idt.set(
+        vector::GENERAL_PROTECTION,
+        entry(general_protection as HandlerWithErrorCode as usize as u64),
+    );
+    idt.set(
+        vector::PAGE_FAULT,
+        entry(page_fault as HandlerWithErrorCode as usize as u64),
+    );
+    // カーネルスタックが壊れていても処理できるように別のスタックを使う
+    idt.set(
+        vector::DOUBLE_FAULT,
+        IdtEntry::new(
+            double_fault as DivergingHandlerWithErrorCode as usize as u64,
+            KERNEL_CS,
+            segment::DOUBLE_FAULT_IST,
+        ),
+    );
+
+    unsafe {
+        x86::lidt(&DescriptorTablePointer {
+            limit: mem::size_of::<InterruptDescriptorTable>() as u16 - 1,
+            base: idt as *const _ as u64,
+        })
+    };
+}
+
+/// 割り込みハンドラを登録する。ハンドラの最後で`apic::end_of_interrupt`を呼ぶ必要がある。
+pub fn set_handler(vector: u8, handler: Handler) {
+    let entry = IdtEntry::new(handler as usize as u64, KERNEL_CS, 0);
+    x86::without_interrupts(|| unsafe { (*ptr::addr_of_mut!(IDT)).set(vector, entry) });
+}
+
+fn fault(name: &str, frame: &InterruptStackFrame, error_code: Option<u64>) -> ! {
+    match error_code {
+        Some(code) => error!(
+            "EXCEPTION: {name}, rip: {:#x}, error code: {code:#x}",
+            frame.rip
+        ),
+        None => error!("EXCEPTION: {name}, rip: {:#x}", frame.rip),
+    }
+    error!("{:#?}", frame);
+
+    loop {
+        x86::hlt();
+    }
+}
+
+extern "x86-interrupt" fn divide_error(frame: InterruptStackFrame) {
+    fault("#DE divide error", &frame, None)
+}
+
+extern "x86-interrupt" fn invalid_opcode(frame: InterruptStackFrame) {
+    fault("#UD invalid opcode", &frame, None)
+}
+
+extern "x86-interrupt" fn general_protection(frame: InterruptStackFrame, error_code: u64) {
+    fault("#GP general protection", &frame, Some(error_code))
+}
+
+extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, error_code: u64) {
+    error!("page fault at cr2: {:#x}", x86::read_cr2());
+    fault("#PF page fault", &frame, Some(error_code))
+}
+
+extern "x86-interrupt" fn double_fault(frame: InterruptStackFrame, error_code: u64) -> ! {
+    fault("#DF double fault", &frame, Some(error_code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idt_entry() {
+        assert_eq!(mem::size_of::<IdtEntry>(), 16);
+        assert_eq!(mem::size_of::<InterruptDescriptorTable>(), 4096);
+
+        let entry = IdtEntry::new(0xffff_8000_1234_5678, KERNEL_CS, 1);
+        assert_eq!(entry.handler_addr(), 0xffff_8000_1234_5678);
+        assert!(entry.is_present());
+        assert!(!IdtEntry::missing().is_present());
+    }
+}
@@ -0,0 +1,43 @@
+//! USBキーボードから届いたキーイベントのキュー
+
+use common::{mutex::Mutex, ring_buf::RingBuffer, Zeroed as _};
+use usb::usbd::keyboard::KeyEvent;
+
+const QUEUE_SIZE: usize = 64;
+
+static EVENTS: Mutex<Option<RingBuffer<KeyEvent, QUEUE_SIZE>>> = Mutex::new(None);
+
+/// キューがいっぱいなら新しいイベントを捨てる
+pub fn push(event: KeyEvent) {
+    let mut events = EVENTS.lock();
+    let _ = events.get_or_insert_with(RingBuffer::zeroed).push(event);
+}
+
+pub fn pop() -> Option<KeyEvent> {
+    EVENTS.lock().as_mut()?.pop()
+}
+
+#[cfg(test)]
+mod tests {
+    use usb::usbd::keyboard::{KeyEventKind, Modifiers};
+
+    use super::*;
+
+    #[test]
+    fn test_queue() {
+        let event = |usage| KeyEvent {
+            usage,
+            kind: KeyEventKind::Press,
+            modifiers: Modifiers::default(),
+            caps_lock: false,
+        };
+
+        for usage in 0..QUEUE_SIZE as u8 + 1 {
+            push(event(usage));
+        }
+        for usage in 0..QUEUE_SIZE as u8 {
+            assert_eq!(pop(), Some(event(usage)));
+        }
+        assert_eq!(pop(), None);
+    }
+}
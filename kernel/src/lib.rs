@@ -1,12 +1,27 @@
 #![no_std]
+#![feature(abi_x86_interrupt)]
 #![allow(dead_code)]
 
-#[cfg(feature = "alloc")]
+pub mod acpi;
 pub mod allocater;
+pub mod apic;
 pub mod error;
 pub mod graphic;
+pub mod interrupt;
+pub mod keyboard;
 pub mod logger;
+pub mod memory_manager;
+pub mod memory_map;
+pub mod paging;
 pub mod pci;
+pub mod pointer;
+pub mod segment;
+pub mod sound;
+pub mod x86;
+
+use core::ops::Range;
+
+use memory_map::MemoryMap;
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -17,6 +32,12 @@ pub struct KernelArg {
     horizontal_resolution: u32,
     vertical_resolution: u32,
     pixel_format: PixelFormat,
+    memory_map: MemoryMap,
+    kernel_image_base: u64,
+    kernel_image_size: usize,
+    stack_base: u64,
+    stack_size: usize,
+    acpi_rsdp: u64,
 }
 
 impl KernelArg {
@@ -37,8 +58,66 @@ impl KernelArg {
             horizontal_resolution,
             vertical_resolution,
             pixel_format,
+            memory_map: MemoryMap::empty(),
+            kernel_image_base: 0,
+            kernel_image_size: 0,
+            stack_base: 0,
+            stack_size: 0,
+            acpi_rsdp: 0,
+        }
+    }
+
+    /// ExitBootServicesの後で取得したメモリマップをセットする
+    pub fn with_memory_map(self, memory_map: MemoryMap) -> Self {
+        Self { memory_map, ..self }
+    }
+
+    /// カーネルが読み込まれた物理アドレスの範囲をセットする
+    pub fn with_kernel_image(self, base: u64, size: usize) -> Self {
+        Self {
+            kernel_image_base: base,
+            kernel_image_size: size,
+            ..self
+        }
+    }
+
+    /// ブートローダが確保したスタックの範囲をセットする
+    pub fn with_stack(self, base: u64, size: usize) -> Self {
+        Self {
+            stack_base: base,
+            stack_size: size,
+            ..self
+        }
+    }
+
+    /// UEFIのConfiguration Tableから見つけたRSDPのアドレスをセットする
+    pub fn with_acpi_rsdp(self, rsdp: u64) -> Self {
+        Self {
+            acpi_rsdp: rsdp,
+            ..self
         }
     }
+
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.memory_map
+    }
+
+    pub fn kernel_image(&self) -> Range<u64> {
+        self.kernel_image_base..self.kernel_image_base + self.kernel_image_size as u64
+    }
+
+    pub fn stack(&self) -> Range<u64> {
+        self.stack_base..self.stack_base + self.stack_size as u64
+    }
+
+    pub fn acpi_rsdp(&self) -> Option<u64> {
+        (self.acpi_rsdp != 0).then_some(self.acpi_rsdp)
+    }
+
+    pub fn frame_buffer(&self) -> Range<u64> {
+        let base = self.frame_buffer_base as u64;
+        base..base + self.frame_buffer_size as u64
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -1,24 +1,34 @@
 #![no_std]
 #![no_main]
+#![feature(abi_x86_interrupt)]
 
 #[cfg(feature = "alloc")]
 extern crate alloc;
 
-use core::{arch::asm, panic::PanicInfo, pin::pin};
+use core::{
+    arch::asm,
+    mem::MaybeUninit,
+    panic::PanicInfo,
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use common::{debug, info, Zeroed as _};
+use common::{block::BlockDevice, debug, error, info, mutex::Mutex, Zeroed as _};
 use kernel::{
+    apic,
     error::Error as LibError,
     graphic::{
         error::Error as GraphicError, mouse::MouseCursor, pixel::FrameBufferInfo, Color,
         PixelPosition, PixelWriter, RectWriter, StringWriter,
     },
-    logger,
-    pci::{Device, Pci, PciExtUsb as _},
-    println, KernelArg,
+    interrupt::{self, vector, InterruptStackFrame},
+    keyboard, logger, memory_manager, paging,
+    pci::{self, MsiDeliveryMode, MsiTriggerMode, Pci, PciExtUsb as _},
+    pointer, print, println, segment, sound, x86, KernelArg,
 };
 use usb::{
-    usbd::{driver::Driver, error::Error as UsbError},
+    usbd::{driver::Driver, error::Error as UsbError, pointer::Motion},
     xhci::{
         driver::{Context, Controller},
         error::Error as XhciError,
@@ -41,6 +51,8 @@ fn panic(info: &PanicInfo) -> ! {
 #[no_mangle]
 pub extern "sysv64" fn kernel_main(arg: &'static KernelArg) -> ! {
     logger::init_logger();
+    segment::init();
+    interrupt::init();
 
     if let Err(e) = kernel_main_impl(*arg) {
         println!("{:?}", e)
@@ -95,54 +107,196 @@ fn kernel_main_impl(arg: KernelArg) -> Result<()> {
         Color::new(45, 118, 237),
     )?;
 
-    let mut pci = Pci::new();
+    for desc in arg.memory_map() {
+        debug!(
+            "memory map: {:?}, {:#x} - {:#x}",
+            desc.kind(),
+            desc.physical_start(),
+            desc.physical_end()
+        );
+    }
+
+    memory_manager::init(
+        arg.memory_map(),
+        &[arg.kernel_image(), arg.stack(), arg.frame_buffer()],
+    );
+    info!(
+        "free frames: {}",
+        memory_manager::memory_manager().free_frame_count()
+    );
+    paging::init(arg.memory_map(), arg.frame_buffer())?;
+    info!("switch to kernel page table");
+    apic::init()?;
+
+    let config_access = pci::config::select(arg.acpi_rsdp());
+    info!(
+        "pci config space size: {:#x}",
+        config_access.config_space_size()
+    );
+    let mut pci = Pci::with_access(config_access);
 
     pci.scan_all_bus()?;
     info!("scan all bus");
+    pci.dump();
 
-    // for dev in pci.devices() {
-    //     debug!("{:?}", dev.class_code());
-    // }
-
-    let usb = pci
+    let xhc_dev = pci
         .find_usb()
         .ok_or(Error::custom("cannot find usb device"))?;
-    let bar = read_xhci_bar(usb)?;
-    info!("bar: {:p}", bar as *const u8);
+    let bar = xhc_dev.bar(0)?;
+    info!("bar: {:x?}", bar);
+    if !bar.is_memory() {
+        return Err(Error::custom("xhc bar0 is not memory space"));
+    }
+    paging::map_mmio(bar.addr(), bar.size())?;
 
-    if usb.read_vender_id()?.is_intel() {
-        pci.switch_ehci2xhci(usb)?;
+    if xhc_dev.read_vender_id()?.is_intel() {
+        pci.switch_ehci2xhci(xhc_dev)?;
     }
 
-    let cx = Context::zeroed();
-    let cx = pin!(cx);
-    let xhci: Controller<_> = unsafe { Controller::new(bar, cx) };
+    xhc_dev.configure_msi_fixed_destination(
+        apic::local_apic_id(),
+        MsiTriggerMode::Level,
+        MsiDeliveryMode::Fixed,
+        vector::XHCI,
+    )?;
+    interrupt::set_handler(vector::XHCI, xhci_interrupt_handler);
+
+    let cx = unsafe { (*ptr::addr_of_mut!(XHCI_CONTEXT)).write(Context::zeroed()) };
+    let cx = unsafe { Pin::new_unchecked(cx) };
+    let xhci: Controller<_> = unsafe { Controller::new(bar.addr(), cx) };
 
     info!("initialize usb...");
     let mut usb = Driver::new(xhci)?;
-
-    for i in 0..1000 {
-        usb.process()?;
-    }
-    let slot_id = loop {
-        if let Some(slot_id) = usb.configure_device()? {
-            break slot_id;
-        }
-    };
+    usb.set_keyboard_handler(keyboard::push);
+    usb.set_pointer_handler(pointer::push);
+    usb.set_audio_handler(sound::fill);
+    usb.set_detach_handler(|slot_id| info!("usb device detached: slot {}", slot_id));
+    *XHCI.lock() = Some(XhciDriver(usb));
+    x86::sti();
 
     let mut mouse = MouseCursor::new();
     loop {
-        let pos = usb.get_mouse(slot_id)?;
+        wait_for_xhci_event();
 
         {
+            let mut usb = XHCI.lock();
+            let usb = &mut usb.as_mut().unwrap().0;
+            process_events(usb);
+            // 起動時につながっていたデバイスも、ハブの先や差し直されたデバイスもここで設定する
+            // 1つのデバイスの失敗で、ほかのデバイスを止めない
+            loop {
+                let slot_id = match usb.configure_device() {
+                    Ok(Some(slot_id)) => slot_id,
+                    Ok(None) => break,
+                    // 取り外しはconfigure_deviceの中で処理済み
+                    Err(e) if e.is_device_not_found() => {
+                        info!("usb device detached while configuring: {:?}", e);
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("failed to configure usb device: {:?}", e);
+                        continue;
+                    }
+                };
+                if let Some(mut storage) = usb.storage(slot_id) {
+                    if let Err(e) = dump_first_block(&mut storage) {
+                        error!("slot {}: failed to read usb storage: {:?}", slot_id, e);
+                    }
+                }
+                if usb.is_audio(slot_id) {
+                    println!("playing 440Hz tone on slot {}", slot_id);
+                }
+            }
+        }
+        while let Some(event) = keyboard::pop() {
+            if let Some(c) = event.char() {
+                print!("{}", c);
+            }
+        }
+        while let Some(event) = pointer::pop() {
             let mut w = kernel::console_mut();
             let mut w = w.graphic_mut();
             mouse.erase(&mut w)?;
-
-            mouse.move_relative(pos[1] as i8, pos[2] as i8);
+            match event.motion {
+                Motion::Relative { dx, dy } => mouse.move_relative(dx, dy),
+                // タブレットの座標を画面の大きさに合わせる
+                Motion::Absolute { x, y, max_x, max_y } => {
+                    let width = w.info().horizontal_resolution() as i64;
+                    let height = w.info().vertical_resolution() as i64;
+                    let x = x as i64 * (width - 1) / (max_x as i64).max(1);
+                    let y = y as i64 * (height - 1) / (max_y as i64).max(1);
+                    mouse.move_to(PixelPosition::new(x as u32, y as u32));
+                }
+            }
             mouse.write(&mut w)?;
         }
-        // info!("{:?}", pos);
+    }
+}
+
+struct XhciDriver(Driver<'static>);
+
+// 割り込みハンドラとメインの処理の間では`XHCI`のロックを通してしか触らない
+unsafe impl Send for XhciDriver {}
+
+static mut XHCI_CONTEXT: MaybeUninit<Context> = MaybeUninit::uninit();
+static XHCI: Mutex<Option<XhciDriver>> = Mutex::new(None);
+/// xHCIの割り込みが来たらtrueになる
+static XHCI_EVENT: AtomicBool = AtomicBool::new(false);
+
+extern "x86-interrupt" fn xhci_interrupt_handler(_frame: InterruptStackFrame) {
+    // メインの処理がロックを持っている場合は、そちらでevent ringから取り出す
+    if let Some(mut usb) = XHCI.try_lock() {
+        if let Some(usb) = usb.as_mut() {
+            usb.0.drain_events();
+        }
+    }
+    XHCI_EVENT.store(true, Ordering::Release);
+    apic::end_of_interrupt();
+}
+
+/// xHCIの割り込みが来るまでhltで待つ
+fn wait_for_xhci_event() {
+    loop {
+        x86::cli();
+        if XHCI_EVENT.swap(false, Ordering::Acquire) {
+            x86::sti();
+            return;
+        }
+        x86::sti_hlt();
+    }
+}
+
+/// ブロック0の先頭とブートシグネチャを表示する
+fn dump_first_block(storage: &mut impl BlockDevice<Error = UsbError>) -> Result<()> {
+    println!(
+        "usb storage: {} blocks x {} bytes",
+        storage.block_count(),
+        storage.block_size()
+    );
+
+    let mut buf = [0u8; 4096];
+    let Some(block) = buf.get_mut(..storage.block_size()) else {
+        println!("unsupported block size");
+        return Ok(());
+    };
+    storage.read_blocks(0, block)?;
+    println!("{:02x?}", &block[..16]);
+    if block.get(510..512) == Some(&[0x55, 0xaa]) {
+        println!("boot signature found");
+    }
+    Ok(())
+}
+
+/// キューに溜まっているイベントをすべて処理する
+/// 1つのイベントの失敗で止めず、記録して残りのイベントを処理する
+fn process_events(usb: &mut Driver) {
+    loop {
+        if let Err(e) = usb.process() {
+            error!("failed to process usb event: {:?}", e);
+        }
+        if !usb.has_pending_events() {
+            break;
+        }
     }
 }
 
@@ -209,12 +363,3 @@ enum ErrorKind {
 }
 
 type Result<T> = core::result::Result<T, Error>;
-
-fn read_xhci_bar(dev: &Device) -> Result<u64> {
-    let bar0 = dev.read_bar(0)? as u64;
-    debug!("bar0: {}", bar0);
-    let bar1 = dev.read_bar(1)? as u64;
-    debug!("bar1: {}", bar1);
-
-    Ok((bar1 << 32) | (bar0 & !0xf))
-}
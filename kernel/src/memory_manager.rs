@@ -0,0 +1,309 @@
+use core::ops::Range;
+
+use common::mutex::{Mutex, MutexGuard};
+
+use crate::{
+    error::{Error, Result},
+    memory_map::MemoryMap,
+};
+
+/// 物理フレームのサイズ
+pub const FRAME_SIZE: usize = 4096;
+
+/// 管理できる物理メモリの最大値
+pub const MAX_PHYSICAL_MEMORY: usize = 16 * 1024 * 1024 * 1024;
+pub const MAX_FRAME_COUNT: usize = MAX_PHYSICAL_MEMORY / FRAME_SIZE;
+
+type MapLine = u64;
+const BITS_PER_MAP_LINE: usize = MapLine::BITS as usize;
+pub const DEFAULT_MAP_LINE_COUNT: usize = MAX_FRAME_COUNT / BITS_PER_MAP_LINE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FrameID(usize);
+
+impl FrameID {
+    pub const fn new(id: usize) -> Self {
+        Self(id)
+    }
+
+    /// `addr`を含むフレーム
+    pub const fn from_addr(addr: u64) -> Self {
+        Self(addr as usize / FRAME_SIZE)
+    }
+
+    /// `addr`以上で最初のフレーム
+    pub const fn from_addr_ceil(addr: u64) -> Self {
+        Self((addr as usize).div_ceil(FRAME_SIZE))
+    }
+
+    pub const fn id(self) -> usize {
+        self.0
+    }
+
+    pub const fn addr(self) -> u64 {
+        (self.0 * FRAME_SIZE) as u64
+    }
+
+    pub const fn as_ptr<T>(self) -> *mut T {
+        self.addr() as *mut T
+    }
+
+    pub const fn add(self, n: usize) -> Self {
+        Self(self.0 + n)
+    }
+}
+
+/// ビットマップで物理フレームを管理する。
+/// ビットが立っているフレームは使用中。
+pub struct BitmapMemoryManager<const LINES: usize = DEFAULT_MAP_LINE_COUNT> {
+    alloc_map: [MapLine; LINES],
+    range_begin: FrameID,
+    range_end: FrameID,
+}
+
+impl<const LINES: usize> BitmapMemoryManager<LINES> {
+    pub const FRAME_COUNT: usize = LINES * BITS_PER_MAP_LINE;
+
+    /// 割り当てられるフレームがない状態で作る。`init`を呼ぶ必要がある。
+    pub const fn new() -> Self {
+        Self {
+            alloc_map: [0; LINES],
+            range_begin: FrameID(1),
+            range_end: FrameID(1),
+        }
+    }
+
+    /// メモリマップの空き領域を割り当て可能にし、`reserved`の物理アドレス範囲は使用中にする。
+    /// フレーム0はnullと区別できないので割り当てない。
+    pub fn init(&mut self, memory_map: &MemoryMap, reserved: &[Range<u64>]) {
+        self.alloc_map.fill(MapLine::MAX);
+
+        let mut available_end = 0;
+        for desc in memory_map.available() {
+            let start = FrameID::from_addr_ceil(desc.physical_start());
+            let end = FrameID::from_addr(desc.physical_end());
+            self.mark(start, end, false);
+            available_end = available_end.max(end.id());
+        }
+
+        for range in reserved {
+            let start = FrameID::from_addr(range.start);
+            let end = FrameID::from_addr_ceil(range.end);
+            self.mark(start, end, true);
+        }
+        self.set_bit(FrameID(0), true);
+
+        self.range_begin = FrameID(1);
+        self.range_end = FrameID(available_end.clamp(1, Self::FRAME_COUNT));
+    }
+
+    /// 連続した`num_frames`個のフレームを確保する
+    pub fn allocate(&mut self, num_frames: usize) -> Result<FrameID> {
+        if num_frames == 0 {
+            return Err(Error::invalid_frame_count());
+        }
+
+        let mut start = self.range_begin.id();
+        'search: loop {
+            if self.range_end.id() < start + num_frames {
+                return Err(Error::no_enough_memory());
+            }
+
+            for i in 0..num_frames {
+                if self.get_bit(FrameID(start + i)) {
+                    start += i + 1;
+                    continue 'search;
+                }
+            }
+
+            let start = FrameID(start);
+            self.mark_allocated(start, num_frames);
+            return Ok(start);
+        }
+    }
+
+    /// `allocate`で確保したフレームを解放する
+    pub fn free(&mut self, start: FrameID, num_frames: usize) -> Result<()> {
+        let end = start.add(num_frames);
+        if Self::FRAME_COUNT < end.id() {
+            return Err(Error::frame_out_of_range());
+        }
+        if (start.id()..end.id()).any(|id| !self.get_bit(FrameID(id))) {
+            return Err(Error::frame_not_allocated());
+        }
+
+        self.mark(start, end, false);
+        Ok(())
+    }
+
+    pub fn mark_allocated(&mut self, start: FrameID, num_frames: usize) {
+        self.mark(start, start.add(num_frames), true)
+    }
+
+    pub fn is_allocated(&self, frame: FrameID) -> bool {
+        Self::FRAME_COUNT <= frame.id() || self.get_bit(frame)
+    }
+
+    /// 割り当て可能な範囲にある空きフレームの数
+    pub fn free_frame_count(&self) -> usize {
+        (self.range_begin.id()..self.range_end.id())
+            .filter(|&id| !self.get_bit(FrameID(id)))
+            .count()
+    }
+
+    /// 割り当て可能なフレームの範囲
+    pub fn range(&self) -> Range<FrameID> {
+        self.range_begin..self.range_end
+    }
+
+    fn mark(&mut self, start: FrameID, end: FrameID, allocated: bool) {
+        let end = end.id().min(Self::FRAME_COUNT);
+        for id in start.id()..end {
+            self.set_bit(FrameID(id), allocated);
+        }
+    }
+
+    fn get_bit(&self, frame: FrameID) -> bool {
+        let line = frame.id() / BITS_PER_MAP_LINE;
+        let bit = frame.id() % BITS_PER_MAP_LINE;
+        self.alloc_map[line] & (1 << bit) != 0
+    }
+
+    fn set_bit(&mut self, frame: FrameID, allocated: bool) {
+        let line = frame.id() / BITS_PER_MAP_LINE;
+        let bit = frame.id() % BITS_PER_MAP_LINE;
+        if allocated {
+            self.alloc_map[line] |= 1 << bit;
+        } else {
+            self.alloc_map[line] &= !(1 << bit);
+        }
+    }
+}
+
+impl<const LINES: usize> Default for BitmapMemoryManager<LINES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static MEMORY_MANAGER: Mutex<BitmapMemoryManager> = Mutex::new(BitmapMemoryManager::new());
+
+pub fn init(memory_map: &MemoryMap, reserved: &[Range<u64>]) {
+    MEMORY_MANAGER.lock().init(memory_map, reserved)
+}
+
+pub fn memory_manager() -> MutexGuard<'static, BitmapMemoryManager> {
+    MEMORY_MANAGER.lock()
+}
+
+#[cfg(test)]
+mod tests {
+    use core::mem;
+
+    use super::*;
+    use crate::memory_map::{MemoryDescriptor, MemoryType};
+
+    type Manager = BitmapMemoryManager<16>;
+
+    fn map(descs: &[MemoryDescriptor]) -> MemoryMap {
+        unsafe {
+            MemoryMap::new(
+                descs.as_ptr().cast(),
+                mem::size_of_val(descs),
+                mem::size_of::<MemoryDescriptor>(),
+                1,
+            )
+        }
+    }
+
+    fn frame_addr(id: usize) -> u64 {
+        FrameID::new(id).addr()
+    }
+
+    #[test]
+    fn test_init() {
+        let descs = [
+            MemoryDescriptor::new(MemoryType::Conventional, 0, 0x10, 0),
+            MemoryDescriptor::new(MemoryType::LoaderData, frame_addr(0x10), 0x10, 0),
+            MemoryDescriptor::new(MemoryType::BootServicesData, frame_addr(0x20), 0x20, 0),
+            MemoryDescriptor::new(MemoryType::MemoryMappedIO, frame_addr(0x40), 0x10, 0),
+            MemoryDescriptor::new(MemoryType::Conventional, frame_addr(0x80), 0x100, 0),
+        ];
+        let mut m = Manager::new();
+        m.init(&map(&descs), &[frame_addr(0x90)..frame_addr(0xa0) + 1]);
+
+        assert!(m.is_allocated(FrameID::new(0)));
+        assert!(!m.is_allocated(FrameID::new(1)));
+        assert!(m.is_allocated(FrameID::new(0x10)));
+        assert!(!m.is_allocated(FrameID::new(0x20)));
+        assert!(m.is_allocated(FrameID::new(0x40)));
+        assert!(m.is_allocated(FrameID::new(0x50)));
+        assert!(m.is_allocated(FrameID::new(0x90)));
+        assert!(m.is_allocated(FrameID::new(0xa0)));
+        assert!(!m.is_allocated(FrameID::new(0xa1)));
+        assert!(m.is_allocated(FrameID::new(0x180)));
+        assert!(m.is_allocated(FrameID::new(Manager::FRAME_COUNT)));
+
+        assert_eq!(m.range(), FrameID::new(1)..FrameID::new(0x180));
+        assert_eq!(m.free_frame_count(), 0xf + 0x20 + 0x100 - 0x11);
+    }
+
+    #[test]
+    fn test_unaligned_region() {
+        let descs = [MemoryDescriptor::new(
+            MemoryType::Conventional,
+            0x1800,
+            4,
+            0,
+        )];
+        let mut m = Manager::new();
+        m.init(&map(&descs), &[]);
+
+        assert!(m.is_allocated(FrameID::new(1)));
+        assert!(!m.is_allocated(FrameID::new(2)));
+        assert!(!m.is_allocated(FrameID::new(4)));
+        assert!(m.is_allocated(FrameID::new(5)));
+    }
+
+    #[test]
+    fn test_allocate_free() {
+        let descs = [
+            MemoryDescriptor::new(MemoryType::Conventional, 0, 0x8, 0),
+            MemoryDescriptor::new(MemoryType::Conventional, frame_addr(0x10), 0x30, 0),
+        ];
+        let mut m = Manager::new();
+        m.init(&map(&descs), &[]);
+
+        // frame 1..8 is too small
+        let a = m.allocate(0x10).unwrap();
+        assert_eq!(a, FrameID::new(0x10));
+
+        let b = m.allocate(4).unwrap();
+        assert_eq!(b, FrameID::new(1));
+        let c = m.allocate(4).unwrap();
+        assert_eq!(c, FrameID::new(0x20));
+
+        assert_eq!(m.allocate(0x20), Err(Error::no_enough_memory()));
+        assert_eq!(m.allocate(0), Err(Error::invalid_frame_count()));
+
+        m.free(a, 0x10).unwrap();
+        assert_eq!(m.free(a, 0x10), Err(Error::frame_not_allocated()));
+        assert_eq!(
+            m.free(FrameID::new(Manager::FRAME_COUNT - 1), 2),
+            Err(Error::frame_out_of_range())
+        );
+
+        let d = m.allocate(0x14).unwrap();
+        assert_eq!(d, FrameID::new(0x24));
+        m.free(b, 4).unwrap();
+        m.free(c, 4).unwrap();
+        m.free(d, 0x14).unwrap();
+        assert_eq!(m.free_frame_count(), 7 + 0x30);
+    }
+
+    #[test]
+    fn test_uninitialized() {
+        let mut m = Manager::new();
+        assert_eq!(m.allocate(1), Err(Error::no_enough_memory()));
+    }
+}
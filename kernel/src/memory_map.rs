@@ -0,0 +1,349 @@
+use core::mem;
+
+/// UEFIのページサイズ
+pub const UEFI_PAGE_SIZE: usize = 4096;
+
+/// `EFI_MEMORY_TYPE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MemoryType {
+    Reserved,
+    LoaderCode,
+    LoaderData,
+    BootServicesCode,
+    BootServicesData,
+    RuntimeServicesCode,
+    RuntimeServicesData,
+    Conventional,
+    Unusable,
+    ACPIReclaim,
+    ACPINvs,
+    MemoryMappedIO,
+    MemoryMappedIOPortSpace,
+    PalCode,
+    Persistent,
+    Unaccepted,
+    Unknown(u32),
+}
+
+impl From<u32> for MemoryType {
+    fn from(value: u32) -> Self {
+        use MemoryType::*;
+        match value {
+            0 => Reserved,
+            1 => LoaderCode,
+            2 => LoaderData,
+            3 => BootServicesCode,
+            4 => BootServicesData,
+            5 => RuntimeServicesCode,
+            6 => RuntimeServicesData,
+            7 => Conventional,
+            8 => Unusable,
+            9 => ACPIReclaim,
+            10 => ACPINvs,
+            11 => MemoryMappedIO,
+            12 => MemoryMappedIOPortSpace,
+            13 => PalCode,
+            14 => Persistent,
+            15 => Unaccepted,
+            x => Unknown(x),
+        }
+    }
+}
+
+impl From<MemoryType> for u32 {
+    fn from(value: MemoryType) -> Self {
+        use MemoryType::*;
+        match value {
+            Reserved => 0,
+            LoaderCode => 1,
+            LoaderData => 2,
+            BootServicesCode => 3,
+            BootServicesData => 4,
+            RuntimeServicesCode => 5,
+            RuntimeServicesData => 6,
+            Conventional => 7,
+            Unusable => 8,
+            ACPIReclaim => 9,
+            ACPINvs => 10,
+            MemoryMappedIO => 11,
+            MemoryMappedIOPortSpace => 12,
+            PalCode => 13,
+            Persistent => 14,
+            Unaccepted => 15,
+            Unknown(x) => x,
+        }
+    }
+}
+
+/// カーネルから見たメモリ領域の分類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MemoryRegionKind {
+    /// 空き領域
+    Conventional,
+    /// ブートローダが確保した領域。カーネル本体やスタックが置かれている。
+    Loader,
+    /// boot serviceが使っていた領域。ExitBootServices後は空き領域として使える。
+    BootServices,
+    /// runtime serviceが使う領域
+    RuntimeServices,
+    /// ACPIテーブルやACPI NVS
+    ACPI,
+    /// MMIO領域
+    MMIO,
+    /// それ以外の使えない領域
+    Reserved,
+}
+
+impl From<MemoryType> for MemoryRegionKind {
+    fn from(value: MemoryType) -> Self {
+        use MemoryType::*;
+        match value {
+            Conventional => MemoryRegionKind::Conventional,
+            LoaderCode | LoaderData => MemoryRegionKind::Loader,
+            BootServicesCode | BootServicesData => MemoryRegionKind::BootServices,
+            RuntimeServicesCode | RuntimeServicesData => MemoryRegionKind::RuntimeServices,
+            ACPIReclaim | ACPINvs => MemoryRegionKind::ACPI,
+            MemoryMappedIO | MemoryMappedIOPortSpace => MemoryRegionKind::MMIO,
+            Reserved | Unusable | PalCode | Persistent | Unaccepted | Unknown(_) => {
+                MemoryRegionKind::Reserved
+            }
+        }
+    }
+}
+
+impl MemoryRegionKind {
+    /// ExitBootServices後にカーネルが自由に使える領域かどうか
+    pub fn is_available(self) -> bool {
+        matches!(
+            self,
+            MemoryRegionKind::Conventional | MemoryRegionKind::BootServices
+        )
+    }
+}
+
+/// `EFI_MEMORY_DESCRIPTOR`
+///
+/// 実際の要素のサイズは`MemoryMap::descriptor_size`であり、
+/// この構造体のサイズより大きいことがある。
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct MemoryDescriptor {
+    type_: u32,
+    physical_start: u64,
+    virtual_start: u64,
+    number_of_pages: u64,
+    attribute: u64,
+}
+
+impl MemoryDescriptor {
+    pub fn new(
+        type_: MemoryType,
+        physical_start: u64,
+        number_of_pages: u64,
+        attribute: u64,
+    ) -> Self {
+        Self {
+            type_: type_.into(),
+            physical_start,
+            virtual_start: 0,
+            number_of_pages,
+            attribute,
+        }
+    }
+
+    pub fn memory_type(&self) -> MemoryType {
+        MemoryType::from(self.type_)
+    }
+
+    pub fn kind(&self) -> MemoryRegionKind {
+        MemoryRegionKind::from(self.memory_type())
+    }
+
+    pub fn physical_start(&self) -> u64 {
+        self.physical_start
+    }
+
+    /// 領域の終端(含まない)
+    pub fn physical_end(&self) -> u64 {
+        self.physical_start + self.size() as u64
+    }
+
+    pub fn number_of_pages(&self) -> u64 {
+        self.number_of_pages
+    }
+
+    pub fn size(&self) -> usize {
+        self.number_of_pages as usize * UEFI_PAGE_SIZE
+    }
+
+    pub fn attribute(&self) -> u64 {
+        self.attribute
+    }
+}
+
+/// ExitBootServices直前に取得したメモリマップ。
+/// ブートローダからカーネルへ`KernelArg`経由で渡される。
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MemoryMap {
+    buffer: *const u8,
+    map_size: usize,
+    descriptor_size: usize,
+    descriptor_version: u32,
+}
+
+impl MemoryMap {
+    /// # Safety
+    /// `buffer`から`map_size`バイトは`descriptor_size`ごとに並んだ
+    /// `MemoryDescriptor`の配列であり、カーネルの実行中は有効である必要がある。
+    pub unsafe fn new(
+        buffer: *const u8,
+        map_size: usize,
+        descriptor_size: usize,
+        descriptor_version: u32,
+    ) -> Self {
+        Self {
+            buffer,
+            map_size,
+            descriptor_size,
+            descriptor_version,
+        }
+    }
+
+    pub const fn empty() -> Self {
+        Self {
+            buffer: core::ptr::null(),
+            map_size: 0,
+            descriptor_size: mem::size_of::<MemoryDescriptor>(),
+            descriptor_version: 0,
+        }
+    }
+
+    pub fn buffer(&self) -> *const u8 {
+        self.buffer
+    }
+
+    pub fn map_size(&self) -> usize {
+        self.map_size
+    }
+
+    pub fn descriptor_size(&self) -> usize {
+        self.descriptor_size
+    }
+
+    pub fn descriptor_version(&self) -> u32 {
+        self.descriptor_version
+    }
+
+    pub fn len(&self) -> usize {
+        if self.buffer.is_null() || self.descriptor_size < mem::size_of::<MemoryDescriptor>() {
+            0
+        } else {
+            self.map_size / self.descriptor_size
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { map: self, idx: 0 }
+    }
+
+    /// カーネルが使える領域だけを返す
+    pub fn available(&self) -> impl Iterator<Item = &MemoryDescriptor> {
+        self.iter().filter(|d| d.kind().is_available())
+    }
+}
+
+impl<'a> IntoIterator for &'a MemoryMap {
+    type Item = &'a MemoryDescriptor;
+
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+pub struct Iter<'a> {
+    map: &'a MemoryMap,
+    idx: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a MemoryDescriptor;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.map.len() <= self.idx {
+            return None;
+        }
+
+        let offset = self.idx * self.map.descriptor_size;
+        self.idx += 1;
+        // UEFIの仕様上、各要素は8バイト境界に置かれている
+        let ptr = unsafe { self.map.buffer.add(offset) } as *const MemoryDescriptor;
+        Some(unsafe { &*ptr })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// UEFIの実装はdescriptor_sizeを構造体より大きく取ることが多い
+    const DESCRIPTOR_SIZE: usize = 48;
+
+    #[repr(C, align(8))]
+    struct Buffer([u8; DESCRIPTOR_SIZE * 3]);
+
+    fn write(buf: &mut Buffer, idx: usize, desc: MemoryDescriptor) {
+        let ptr = buf.0[idx * DESCRIPTOR_SIZE..].as_mut_ptr() as *mut MemoryDescriptor;
+        unsafe { ptr.write(desc) }
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut buf = Buffer([0xff; DESCRIPTOR_SIZE * 3]);
+        let descs = [
+            MemoryDescriptor::new(MemoryType::Conventional, 0x1000, 0x9f, 0),
+            MemoryDescriptor::new(MemoryType::LoaderData, 0x10_0000, 0x10, 0),
+            MemoryDescriptor::new(MemoryType::BootServicesData, 0x20_0000, 0x20, 0),
+        ];
+        for (i, d) in descs.iter().enumerate() {
+            write(&mut buf, i, *d);
+        }
+
+        let map = unsafe { MemoryMap::new(buf.0.as_ptr(), buf.0.len(), DESCRIPTOR_SIZE, 1) };
+        assert_eq!(map.len(), 3);
+        assert!(map.iter().eq(descs.iter()));
+
+        let kinds = [
+            MemoryRegionKind::Conventional,
+            MemoryRegionKind::Loader,
+            MemoryRegionKind::BootServices,
+        ];
+        assert!(map.iter().map(|d| d.kind()).eq(kinds));
+        assert_eq!(map.available().count(), 2);
+        assert_eq!(descs[1].physical_end(), 0x11_0000);
+    }
+
+    #[test]
+    fn test_empty() {
+        let map = MemoryMap::empty();
+        assert!(map.is_empty());
+        assert_eq!(map.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_classify() {
+        let kind = |v: u32| MemoryRegionKind::from(MemoryType::from(v));
+        assert_eq!(kind(9), MemoryRegionKind::ACPI);
+        assert_eq!(kind(11), MemoryRegionKind::MMIO);
+        assert!(kind(3).is_available());
+        assert!(!kind(2).is_available());
+        assert_eq!(MemoryType::from(100), MemoryType::Unknown(100));
+        assert_eq!(u32::from(MemoryType::from(6)), 6);
+    }
+}
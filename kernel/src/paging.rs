@@ -0,0 +1,497 @@
+use core::ops::{BitOr, BitOrAssign, Range};
+
+use common::mutex::{Mutex, MutexGuard};
+
+use crate::{
+    allocater::{FrameSource, MemoryManagerFrames},
+    error::{Error, Result},
+    memory_map::MemoryMap,
+    x86,
+};
+
+pub const PAGE_SIZE_4K: u64 = 0x1000;
+pub const PAGE_SIZE_2M: u64 = 512 * PAGE_SIZE_4K;
+pub const PAGE_SIZE_1G: u64 = 512 * PAGE_SIZE_2M;
+
+/// 物理メモリ全体をこのアドレスからもマップする
+pub const PHYSICAL_MAP_OFFSET: u64 = 0xffff_8000_0000_0000;
+
+/// 少なくともこのアドレスまではidentity mapする。32bitのMMIOを含めるため。
+const MIN_IDENTITY_MAP_END: u64 = 4 * PAGE_SIZE_1G;
+
+const ENTRY_COUNT: usize = 512;
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// PATのエントリ1をWrite Combiningにした値。
+/// 他のエントリは電源投入時のデフォルトのまま。
+const PAT_VALUE: u64 = 0x0007_0406_0007_0106;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct PageFlags(u64);
+
+impl PageFlags {
+    pub const PRESENT: Self = Self(1 << 0);
+    pub const WRITABLE: Self = Self(1 << 1);
+    pub const USER: Self = Self(1 << 2);
+    pub const WRITE_THROUGH: Self = Self(1 << 3);
+    pub const CACHE_DISABLE: Self = Self(1 << 4);
+    pub const ACCESSED: Self = Self(1 << 5);
+    pub const DIRTY: Self = Self(1 << 6);
+    pub const HUGE_PAGE: Self = Self(1 << 7);
+    pub const GLOBAL: Self = Self(1 << 8);
+    pub const NO_EXECUTE: Self = Self(1 << 63);
+
+    /// PATのエントリ1を指す。`init`でWrite Combiningに設定される。
+    pub const WRITE_COMBINING: Self = Self::WRITE_THROUGH;
+    /// PATのエントリ3を指す。UC
+    pub const UNCACHED: Self = Self(Self::WRITE_THROUGH.0 | Self::CACHE_DISABLE.0);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn remove(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+impl BitOr for PageFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for PageFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0
+    }
+}
+
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PageTableEntry(u64);
+
+impl PageTableEntry {
+    pub fn is_present(self) -> bool {
+        self.flags().contains(PageFlags::PRESENT)
+    }
+
+    pub fn is_huge(self) -> bool {
+        self.flags().contains(PageFlags::HUGE_PAGE)
+    }
+
+    pub fn addr(self) -> u64 {
+        self.0 & ADDR_MASK
+    }
+
+    pub fn flags(self) -> PageFlags {
+        PageFlags(self.0 & !ADDR_MASK)
+    }
+
+    pub fn set(&mut self, addr: u64, flags: PageFlags) {
+        self.0 = (addr & ADDR_MASK) | flags.bits();
+    }
+
+    pub fn clear(&mut self) {
+        self.0 = 0;
+    }
+}
+
+#[repr(C, align(4096))]
+pub struct PageTable {
+    entries: [PageTableEntry; ENTRY_COUNT],
+}
+
+/// 4段のページテーブルを操作する。
+/// ページテーブル自体は物理アドレスでアクセスできる(identity mapされている)前提。
+pub struct Mapper<S> {
+    pml4: *mut PageTable,
+    source: S,
+}
+
+unsafe impl<S: Send> Send for Mapper<S> {}
+
+impl<S: FrameSource> Mapper<S> {
+    /// ページテーブルを持たない状態で作る
+    pub const fn empty(source: S) -> Self {
+        Self {
+            pml4: core::ptr::null_mut(),
+            source,
+        }
+    }
+
+    /// 空のPML4を確保して作る
+    pub fn new(mut source: S) -> Result<Self> {
+        let pml4 = allocate_table(&mut source)?;
+        Ok(Self { pml4, source })
+    }
+
+    /// # Safety
+    /// `pml4`は有効なページテーブルで、配下のテーブルはidentity mapされている必要がある
+    pub unsafe fn from_raw(pml4: *mut PageTable, source: S) -> Self {
+        Self { pml4, source }
+    }
+
+    pub fn pml4_addr(&self) -> u64 {
+        self.pml4 as u64
+    }
+
+    /// `virt`から`size`バイトを4KiBページで`phys`にマップする。
+    /// 既にマップされている場合は上書きし、2MiB以上のページは分割する。
+    pub fn map(&mut self, virt: u64, phys: u64, size: u64, flags: PageFlags) -> Result<()> {
+        self.map_pages(virt, phys, size, flags, 1)
+    }
+
+    /// 2MiBページでマップする
+    pub fn map_huge(&mut self, virt: u64, phys: u64, size: u64, flags: PageFlags) -> Result<()> {
+        self.map_pages(virt, phys, size, flags, 2)
+    }
+
+    /// `virt`から`size`バイトのマッピングを外す。ページテーブル自体は解放しない。
+    pub fn unmap(&mut self, virt: u64, size: u64) -> Result<()> {
+        check_aligned(virt, size, PAGE_SIZE_4K)?;
+
+        let end = virt + size;
+        let mut addr = virt;
+        while addr < end {
+            let (entry, level) = self.walk(addr).ok_or(Error::page_not_mapped())?;
+            let page_size = page_size(level);
+            let entry = unsafe { &mut *entry };
+
+            if level == 1 || (addr.is_multiple_of(page_size) && page_size <= end - addr) {
+                entry.clear();
+                invalidate(addr);
+                addr += page_size;
+            } else {
+                // 一部だけ外すので小さいページに分割してやり直す
+                self.split(entry, level)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 仮想アドレスを物理アドレスに変換する
+    pub fn translate(&self, virt: u64) -> Option<u64> {
+        let (entry, level) = self.walk(virt)?;
+        let entry = unsafe { *entry };
+        Some(entry.addr() + virt % page_size(level))
+    }
+
+    fn map_pages(
+        &mut self,
+        virt: u64,
+        phys: u64,
+        size: u64,
+        flags: PageFlags,
+        level: usize,
+    ) -> Result<()> {
+        let page_size = page_size(level);
+        check_aligned(virt, size, page_size)?;
+        if !phys.is_multiple_of(page_size) {
+            return Err(Error::unaligned_addr());
+        }
+
+        for offset in (0..size).step_by(page_size as usize) {
+            self.map_page(virt + offset, phys + offset, flags, level)?;
+        }
+        Ok(())
+    }
+
+    fn map_page(&mut self, virt: u64, phys: u64, flags: PageFlags, level: usize) -> Result<()> {
+        let mut table = self.pml4;
+        for l in (level + 1..=4).rev() {
+            let entry = unsafe { &mut (*table).entries[index(virt, l)] };
+            table = self.next_table(entry, l)?;
+        }
+
+        let entry = unsafe { &mut (*table).entries[index(virt, level)] };
+        let mut flags = flags | PageFlags::PRESENT;
+        if level > 1 {
+            flags |= PageFlags::HUGE_PAGE;
+        }
+        entry.set(phys, flags);
+        invalidate(virt);
+
+        Ok(())
+    }
+
+    /// `entry`が指す次の段のテーブルを返す。なければ作る。
+    fn next_table(&mut self, entry: &mut PageTableEntry, level: usize) -> Result<*mut PageTable> {
+        if !entry.is_present() {
+            let table = allocate_table(&mut self.source)?;
+            entry.set(table as u64, PageFlags::PRESENT | PageFlags::WRITABLE);
+        } else if entry.is_huge() {
+            self.split(entry, level)?;
+        }
+
+        Ok(entry.addr() as *mut PageTable)
+    }
+
+    /// 大きいページを同じ属性の1段小さいページに分割する
+    fn split(&mut self, entry: &mut PageTableEntry, level: usize) -> Result<()> {
+        let table = allocate_table(&mut self.source)?;
+        let flags = entry.flags();
+        let child_flags = if level - 1 == 1 {
+            flags.remove(PageFlags::HUGE_PAGE)
+        } else {
+            flags
+        };
+
+        let child_size = page_size(level - 1);
+        let table_ref = unsafe { &mut *table };
+        for (i, child) in table_ref.entries.iter_mut().enumerate() {
+            child.set(entry.addr() + i as u64 * child_size, child_flags);
+        }
+
+        let parent_flags = PageFlags::PRESENT
+            | PageFlags::WRITABLE
+            | PageFlags(flags.bits() & PageFlags::USER.bits());
+        entry.set(table as u64, parent_flags);
+        Ok(())
+    }
+
+    /// `virt`をマップしているエントリとその段を返す
+    fn walk(&self, virt: u64) -> Option<(*mut PageTableEntry, usize)> {
+        if self.pml4.is_null() {
+            return None;
+        }
+
+        let mut table = self.pml4;
+        for level in (1..=4).rev() {
+            let entry = unsafe { &mut (*table).entries[index(virt, level)] };
+            if !entry.is_present() {
+                return None;
+            }
+            if level == 1 || entry.is_huge() {
+                return Some((entry, level));
+            }
+            table = entry.addr() as *mut PageTable;
+        }
+
+        None
+    }
+}
+
+fn allocate_table<S: FrameSource>(source: &mut S) -> Result<*mut PageTable> {
+    let table = source.allocate_frames(1).ok_or(Error::no_enough_memory())? as *mut PageTable;
+    unsafe { table.write_bytes(0, 1) };
+    Ok(table)
+}
+
+fn check_aligned(virt: u64, size: u64, align: u64) -> Result<()> {
+    if !virt.is_multiple_of(align) || !size.is_multiple_of(align) {
+        Err(Error::unaligned_addr())
+    } else {
+        Ok(())
+    }
+}
+
+fn index(virt: u64, level: usize) -> usize {
+    ((virt >> (12 + 9 * (level - 1))) & 0x1ff) as usize
+}
+
+fn page_size(level: usize) -> u64 {
+    PAGE_SIZE_4K << (9 * (level - 1))
+}
+
+#[cfg(not(test))]
+fn invalidate(virt: u64) {
+    x86::invlpg(virt)
+}
+
+#[cfg(test)]
+fn invalidate(_: u64) {}
+
+static KERNEL_MAPPER: Mutex<Mapper<MemoryManagerFrames>> =
+    Mutex::new(Mapper::empty(MemoryManagerFrames));
+
+/// カーネルのページテーブルを作って切り替える。
+/// 物理メモリをidentity mapと`PHYSICAL_MAP_OFFSET`の両方にマップし、
+/// フレームバッファはWrite Combiningにする。
+pub fn init(memory_map: &MemoryMap, frame_buffer: Range<u64>) -> Result<()> {
+    let mut mapper = Mapper::new(MemoryManagerFrames)?;
+
+    let memory_end = memory_map
+        .available()
+        .map(|desc| desc.physical_end())
+        .max()
+        .unwrap_or(0)
+        .max(MIN_IDENTITY_MAP_END);
+    let memory_end = align_up(memory_end, PAGE_SIZE_2M);
+
+    mapper.map_huge(0, 0, memory_end, PageFlags::WRITABLE)?;
+    mapper.map_huge(PHYSICAL_MAP_OFFSET, 0, memory_end, PageFlags::WRITABLE)?;
+
+    let fb_start = frame_buffer.start & !(PAGE_SIZE_4K - 1);
+    let fb_end = align_up(frame_buffer.end, PAGE_SIZE_4K);
+    mapper.map(
+        fb_start,
+        fb_start,
+        fb_end - fb_start,
+        PageFlags::WRITABLE | PageFlags::WRITE_COMBINING,
+    )?;
+
+    unsafe {
+        x86::write_msr(x86::IA32_PAT, PAT_VALUE);
+        x86::write_cr3(mapper.pml4_addr());
+    }
+
+    *KERNEL_MAPPER.lock() = mapper;
+    Ok(())
+}
+
+/// MMIO領域をUCでidentity mapする
+pub fn map_mmio(addr: u64, size: u64) -> Result<()> {
+    let start = addr & !(PAGE_SIZE_4K - 1);
+    let end = align_up(addr + size, PAGE_SIZE_4K);
+    KERNEL_MAPPER.lock().map(
+        start,
+        start,
+        end - start,
+        PageFlags::WRITABLE | PageFlags::UNCACHED,
+    )
+}
+
+pub fn kernel_mapper() -> MutexGuard<'static, Mapper<MemoryManagerFrames>> {
+    KERNEL_MAPPER.lock()
+}
+
+pub const fn phys_to_virt(phys: u64) -> u64 {
+    phys + PHYSICAL_MAP_OFFSET
+}
+
+const fn align_up(v: u64, align: u64) -> u64 {
+    (v + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        vec::Vec,
+    };
+
+    use super::*;
+
+    #[derive(Default)]
+    struct HostFrames {
+        allocated: Vec<*mut u8>,
+    }
+
+    const LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(4096, 4096) };
+
+    impl FrameSource for HostFrames {
+        fn allocate_frames(&mut self, num_frames: usize) -> Option<*mut u8> {
+            assert_eq!(num_frames, 1);
+            let ptr = unsafe { System.alloc(LAYOUT) };
+            self.allocated.push(ptr);
+            Some(ptr)
+        }
+    }
+
+    impl Drop for HostFrames {
+        fn drop(&mut self) {
+            for &ptr in &self.allocated {
+                unsafe { System.dealloc(ptr, LAYOUT) }
+            }
+        }
+    }
+
+    fn mapper() -> Mapper<HostFrames> {
+        Mapper::new(HostFrames::default()).unwrap()
+    }
+
+    #[test]
+    fn test_map_translate() {
+        let mut m = mapper();
+        m.map(0x20_1000, 0x5000, 0x2000, PageFlags::WRITABLE)
+            .unwrap();
+
+        assert_eq!(m.translate(0x20_1000), Some(0x5000));
+        assert_eq!(m.translate(0x20_2abc), Some(0x6abc));
+        assert_eq!(m.translate(0x20_3000), None);
+        assert_eq!(m.translate(0x20_0000), None);
+        // PML4 + PDPT + PD + PT
+        assert_eq!(m.source.allocated.len(), 4);
+    }
+
+    #[test]
+    fn test_higher_half() {
+        let mut m = mapper();
+        m.map_huge(
+            PHYSICAL_MAP_OFFSET,
+            0,
+            2 * PAGE_SIZE_2M,
+            PageFlags::WRITABLE,
+        )
+        .unwrap();
+
+        assert_eq!(m.translate(phys_to_virt(0x12_3456)), Some(0x12_3456));
+        assert_eq!(m.translate(0x12_3456), None);
+    }
+
+    #[test]
+    fn test_split_huge_page() {
+        let mut m = mapper();
+        m.map_huge(0, 0, PAGE_SIZE_2M, PageFlags::WRITABLE).unwrap();
+        m.map(
+            0x3000,
+            0x3000,
+            0x1000,
+            PageFlags::WRITABLE | PageFlags::UNCACHED,
+        )
+        .unwrap();
+
+        let (entry, level) = m.walk(0x3000).unwrap();
+        assert_eq!(level, 1);
+        assert!(unsafe { *entry }.flags().contains(PageFlags::UNCACHED));
+
+        // 残りは元の属性のまま
+        let (entry, level) = m.walk(0x4000).unwrap();
+        assert_eq!(level, 1);
+        assert!(!unsafe { *entry }.flags().contains(PageFlags::CACHE_DISABLE));
+        assert_eq!(m.translate(0x1f_f123), Some(0x1f_f123));
+    }
+
+    #[test]
+    fn test_unmap() {
+        let mut m = mapper();
+        m.map_huge(0, 0, 2 * PAGE_SIZE_2M, PageFlags::WRITABLE)
+            .unwrap();
+
+        m.unmap(PAGE_SIZE_2M, PAGE_SIZE_2M).unwrap();
+        assert_eq!(m.translate(PAGE_SIZE_2M), None);
+
+        m.unmap(0, PAGE_SIZE_4K).unwrap();
+        assert_eq!(m.translate(0), None);
+        assert_eq!(m.translate(PAGE_SIZE_4K), Some(PAGE_SIZE_4K));
+
+        assert_eq!(m.unmap(0, PAGE_SIZE_4K), Err(Error::page_not_mapped()));
+    }
+
+    #[test]
+    fn test_unaligned() {
+        let mut m = mapper();
+        assert_eq!(
+            m.map(0x1234, 0, 0x1000, PageFlags::WRITABLE),
+            Err(Error::unaligned_addr())
+        );
+        assert_eq!(
+            m.map_huge(0, 0x1000, PAGE_SIZE_2M, PageFlags::WRITABLE),
+            Err(Error::unaligned_addr())
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Base Address Registerのデコード
+//!
+//! サイズは全ビット1を書き込んでから読み戻した値で求める。
+
+/// BARの下位ビットが示す種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarKind {
+    Memory32,
+    Memory64,
+    Io,
+}
+
+impl From<u32> for BarKind {
+    fn from(value: u32) -> Self {
+        if value & 0x1 != 0 {
+            return BarKind::Io;
+        }
+        match (value >> 1) & 0b11 {
+            0b10 => BarKind::Memory64,
+            _ => BarKind::Memory32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory32 {
+        addr: u32,
+        size: u32,
+        prefetchable: bool,
+    },
+    /// 2つのBARを使う
+    Memory64 {
+        addr: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u32,
+        size: u32,
+    },
+}
+
+impl Bar {
+    const MEMORY_FLAGS: u32 = 0xf;
+    const IO_FLAGS: u32 = 0x3;
+    const PREFETCHABLE: u32 = 1 << 3;
+
+    /// `value`は元の値、`probe`は全ビット1を書き込んだ後に読んだ値
+    pub fn memory32(value: u32, probe: u32) -> Self {
+        let mask = probe & !Self::MEMORY_FLAGS;
+        Bar::Memory32 {
+            addr: value & !Self::MEMORY_FLAGS,
+            size: size_from_mask(mask as u64 | 0xffff_ffff_0000_0000) as u32,
+            prefetchable: value & Self::PREFETCHABLE != 0,
+        }
+    }
+
+    /// 下位32bitに先頭のBAR、上位32bitに次のBARの値を入れて渡す
+    pub fn memory64(value: u64, probe: u64) -> Self {
+        let mask = probe & !(Self::MEMORY_FLAGS as u64);
+        Bar::Memory64 {
+            addr: value & !(Self::MEMORY_FLAGS as u64),
+            size: size_from_mask(mask),
+            prefetchable: value as u32 & Self::PREFETCHABLE != 0,
+        }
+    }
+
+    pub fn io(value: u32, probe: u32) -> Self {
+        let mut mask = probe & !Self::IO_FLAGS;
+        // 上位16bitを実装していないデバイスがある
+        if mask != 0 && mask >> 16 == 0 {
+            mask |= 0xffff_0000;
+        }
+        Bar::Io {
+            port: value & !Self::IO_FLAGS,
+            size: size_from_mask(mask as u64 | 0xffff_ffff_0000_0000) as u32,
+        }
+    }
+
+    /// メモリ空間ならアドレス、I/O空間ならポート番号
+    pub fn addr(&self) -> u64 {
+        match *self {
+            Bar::Memory32 { addr, .. } => addr as u64,
+            Bar::Memory64 { addr, .. } => addr,
+            Bar::Io { port, .. } => port as u64,
+        }
+    }
+
+    /// 実装されていないBARは0
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory32 { size, .. } => size as u64,
+            Bar::Memory64 { size, .. } => size,
+            Bar::Io { size, .. } => size as u64,
+        }
+    }
+
+    pub fn is_memory(&self) -> bool {
+        !matches!(self, Bar::Io { .. })
+    }
+
+    pub fn is_prefetchable(&self) -> bool {
+        match *self {
+            Bar::Memory32 { prefetchable, .. } | Bar::Memory64 { prefetchable, .. } => prefetchable,
+            Bar::Io { .. } => false,
+        }
+    }
+
+    /// 使っているBARの数
+    pub fn slots(&self) -> u8 {
+        match self {
+            Bar::Memory64 { .. } => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// 書き込めたアドレスビットから領域の大きさを求める
+fn size_from_mask(mask: u64) -> u64 {
+    if mask == 0 || mask == 0xffff_ffff_0000_0000 {
+        return 0;
+    }
+    (!mask).wrapping_add(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bar_kind() {
+        assert_eq!(BarKind::from(0xfebf_0000), BarKind::Memory32);
+        assert_eq!(BarKind::from(0xfebf_0004), BarKind::Memory64);
+        assert_eq!(BarKind::from(0x0000_c001), BarKind::Io);
+    }
+
+    #[test]
+    fn test_memory32() {
+        let bar = Bar::memory32(0xfebf_0008, 0xffff_c008);
+        assert_eq!(
+            bar,
+            Bar::Memory32 {
+                addr: 0xfebf_0000,
+                size: 0x4000,
+                prefetchable: true,
+            }
+        );
+        assert_eq!(bar.slots(), 1);
+        assert_eq!(Bar::memory32(0, 0).size(), 0);
+    }
+
+    #[test]
+    fn test_memory64() {
+        let bar = Bar::memory64(0x0000_0080_0000_000c, 0xffff_ffff_fff0_000c);
+        assert_eq!(bar.addr(), 0x80_0000_0000);
+        assert_eq!(bar.size(), 0x10_0000);
+        assert!(bar.is_prefetchable());
+        assert_eq!(bar.slots(), 2);
+
+        // 4GiBを超える領域
+        let bar = Bar::memory64(0x0000_0010_0000_0004, 0xffff_fff8_0000_0004);
+        assert_eq!(bar.size(), 0x8_0000_0000);
+    }
+
+    #[test]
+    fn test_io() {
+        let bar = Bar::io(0x0000_c041, 0xffff_ffe1);
+        assert_eq!(
+            bar,
+            Bar::Io {
+                port: 0xc040,
+                size: 0x20,
+            }
+        );
+        assert!(!bar.is_memory());
+
+        // 上位16bitが0で読める
+        assert_eq!(Bar::io(0xc041, 0x0000_ffe1).size(), 0x20);
+    }
+}
@@ -0,0 +1,686 @@
+//! PCI Capabilities List
+//!
+//! Status Registerのbit4が立っているデバイスは、0x34のCapabilities Pointerから
+//! 各Capabilityが連結リストになっている。各Capabilityの先頭は以下の形式。
+//!
+//! ```plaintext
+//! |31            16|15      8|7       0|
+//! | Capability固有 |  Next   |   ID    |
+//! ```
+
+use crate::error::{Error, Result};
+
+use super::Device;
+
+/// PCI Capability ID
+pub mod capability_id {
+    pub const POWER_MANAGEMENT: u8 = 0x01;
+    pub const MSI: u8 = 0x05;
+    pub const VENDOR_SPECIFIC: u8 = 0x09;
+    pub const PCI_EXPRESS: u8 = 0x10;
+    pub const MSIX: u8 = 0x11;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapabilityHeader {
+    id: u8,
+    next: u8,
+    offset: u8,
+}
+
+impl CapabilityHeader {
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn next(&self) -> u8 {
+        self.next
+    }
+
+    /// Configuration Space上のオフセット
+    pub fn offset(&self) -> u8 {
+        self.offset
+    }
+}
+
+/// Capabilityのレジスタを読み書きする。オフセットはCapabilityの先頭からの値。
+#[derive(Debug, Clone, Copy)]
+pub struct CapabilityRegs<'a> {
+    device: &'a Device,
+    header: CapabilityHeader,
+}
+
+impl<'a> CapabilityRegs<'a> {
+    pub fn header(&self) -> CapabilityHeader {
+        self.header
+    }
+
+    pub fn read32(&self, offset: u8) -> Result<u32> {
+        self.device.read_reg(self.reg(offset)?)
+    }
+
+    pub fn write32(&self, offset: u8, value: u32) -> Result<()> {
+        self.device.write_reg(self.reg(offset)?, value)
+    }
+
+    /// Configuration Space上のオフセット。末尾付近のCapabilityでは256バイトを超えうる。
+    fn reg(&self, offset: u8) -> Result<u8> {
+        self.header
+            .offset
+            .checked_add(offset)
+            .ok_or_else(Error::invalid_capability_offset)
+    }
+
+    /// 4バイト境界にない16bitのレジスタも読める
+    pub fn read16(&self, offset: u8) -> Result<u16> {
+        let shift = (offset & 0x2) * 8;
+        Ok((self.read32(offset & !0x3)? >> shift) as u16)
+    }
+
+    /// 同じdwordの残りの16bitは読んだ値を書き戻す。
+    /// RW1Cのビットがあるレジスタには使わないこと。
+    pub fn write16(&self, offset: u8, value: u16) -> Result<()> {
+        let shift = (offset & 0x2) * 8;
+        let aligned = offset & !0x3;
+        let old = self.read32(aligned)?;
+        let new = (old & !(0xffff << shift)) | ((value as u32) << shift);
+        self.write32(aligned, new)
+    }
+
+    pub fn modify32(&self, offset: u8, f: impl FnOnce(u32) -> u32) -> Result<()> {
+        let value = self.read32(offset)?;
+        self.write32(offset, f(value))
+    }
+
+    pub fn modify16(&self, offset: u8, f: impl FnOnce(u16) -> u16) -> Result<()> {
+        let value = self.read16(offset)?;
+        self.write16(offset, f(value))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Capability<'a> {
+    Msi(MsiCapability<'a>),
+    MsiX(MsiXCapability<'a>),
+    PowerManagement(PowerManagementCapability<'a>),
+    PciExpress(PciExpressCapability<'a>),
+    Vendor(VendorCapability<'a>),
+    Other(CapabilityRegs<'a>),
+}
+
+impl<'a> Capability<'a> {
+    fn new(regs: CapabilityRegs<'a>) -> Self {
+        match regs.header.id {
+            capability_id::MSI => Self::Msi(MsiCapability(regs)),
+            capability_id::MSIX => Self::MsiX(MsiXCapability(regs)),
+            capability_id::POWER_MANAGEMENT => {
+                Self::PowerManagement(PowerManagementCapability(regs))
+            }
+            capability_id::PCI_EXPRESS => Self::PciExpress(PciExpressCapability(regs)),
+            capability_id::VENDOR_SPECIFIC => Self::Vendor(VendorCapability(regs)),
+            _ => Self::Other(regs),
+        }
+    }
+
+    pub fn regs(&self) -> CapabilityRegs<'a> {
+        match self {
+            Capability::Msi(c) => c.0,
+            Capability::MsiX(c) => c.0,
+            Capability::PowerManagement(c) => c.0,
+            Capability::PciExpress(c) => c.0,
+            Capability::Vendor(c) => c.0,
+            Capability::Other(regs) => *regs,
+        }
+    }
+
+    pub fn header(&self) -> CapabilityHeader {
+        self.regs().header
+    }
+}
+
+pub struct Capabilities<'a> {
+    device: &'a Device,
+    next: u8,
+    /// 壊れたリストでループしないように数える
+    remain: u8,
+}
+
+impl<'a> Capabilities<'a> {
+    /// Configuration Spaceの標準ヘッダ以降に入るCapabilityの最大数
+    const MAX_CAPABILITIES: u8 = ((256 - 0x40) / 4) as u8;
+
+    pub(super) fn new(device: &'a Device) -> Self {
+        const CAPABILITIES_LIST: u32 = 1 << 20;
+        let has_list = device
+            .read_reg(0x04)
+            .map(|v| v & CAPABILITIES_LIST != 0)
+            .unwrap_or(false);
+        let next = if has_list {
+            device.read_reg(0x34).map(|v| v as u8).unwrap_or(0)
+        } else {
+            0
+        };
+
+        Self {
+            device,
+            next,
+            remain: Self::MAX_CAPABILITIES,
+        }
+    }
+}
+
+impl<'a> Iterator for Capabilities<'a> {
+    type Item = Capability<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // 0x40より前は標準ヘッダなので、それより小さい値は終端として扱う
+        let offset = self.next & 0xfc;
+        if offset < 0x40 || self.remain == 0 {
+            return None;
+        }
+        self.remain -= 1;
+
+        let reg = self.device.read_reg(offset).ok()?;
+        let header = CapabilityHeader {
+            id: reg as u8,
+            next: (reg >> 8) as u8,
+            offset,
+        };
+        self.next = header.next;
+
+        Some(Capability::new(CapabilityRegs {
+            device: self.device,
+            header,
+        }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiTriggerMode {
+    Edge = 0,
+    Level = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiDeliveryMode {
+    Fixed = 0b000,
+    LowestPriority = 0b001,
+    Smi = 0b010,
+    Nmi = 0b100,
+    Init = 0b101,
+    ExtInt = 0b111,
+}
+
+/// MSIで送るメモリ書き込みの内容
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+impl MsiMessage {
+    /// `apic_id`のLocal APICに`vector`の割り込みを送るメッセージ
+    pub fn fixed_destination(
+        apic_id: u8,
+        trigger_mode: MsiTriggerMode,
+        delivery_mode: MsiDeliveryMode,
+        vector: u8,
+    ) -> Self {
+        let address = 0xfee0_0000 | ((apic_id as u64) << 12);
+        let mut data = ((delivery_mode as u32) << 8) | vector as u32;
+        if trigger_mode == MsiTriggerMode::Level {
+            // Trigger ModeとLevel(assert)
+            data |= 0xc000;
+        }
+        Self { address, data }
+    }
+}
+
+/// MSI Capability
+///
+/// ```plaintext
+/// 00h | Message Control | Next | ID |
+/// 04h | Message Address                 |
+/// 08h | Message Upper Address (64bitのみ) |
+/// 08h/0Ch | Message Data                |
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct MsiCapability<'a>(CapabilityRegs<'a>);
+
+impl<'a> MsiCapability<'a> {
+    const ENABLE: u16 = 1 << 0;
+    const MULTIPLE_MESSAGE_CAPABLE: u16 = 0b111 << 1;
+    const MULTIPLE_MESSAGE_ENABLE: u16 = 0b111 << 4;
+    const ADDR_64BIT: u16 = 1 << 7;
+    const PER_VECTOR_MASKING: u16 = 1 << 8;
+
+    pub fn regs(&self) -> CapabilityRegs<'a> {
+        self.0
+    }
+
+    pub fn message_control(&self) -> Result<u16> {
+        self.0.read16(2)
+    }
+
+    pub fn is_enabled(&self) -> Result<bool> {
+        Ok(self.message_control()? & Self::ENABLE != 0)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) -> Result<()> {
+        self.0.modify16(2, |v| {
+            if enabled {
+                v | Self::ENABLE
+            } else {
+                v & !Self::ENABLE
+            }
+        })
+    }
+
+    pub fn is_64bit(&self) -> Result<bool> {
+        Ok(self.message_control()? & Self::ADDR_64BIT != 0)
+    }
+
+    pub fn has_per_vector_masking(&self) -> Result<bool> {
+        Ok(self.message_control()? & Self::PER_VECTOR_MASKING != 0)
+    }
+
+    /// 要求できるベクタ数の log2
+    pub fn multiple_message_capable(&self) -> Result<u8> {
+        Ok(((self.message_control()? & Self::MULTIPLE_MESSAGE_CAPABLE) >> 1) as u8)
+    }
+
+    /// 使うベクタ数を 2^`exponent` にする
+    pub fn set_multiple_message_enable(&self, exponent: u8) -> Result<()> {
+        let exponent = exponent.min(self.multiple_message_capable()?) as u16;
+        self.0.modify16(2, |v| {
+            (v & !Self::MULTIPLE_MESSAGE_ENABLE) | (exponent << 4)
+        })
+    }
+
+    fn data_offset(&self) -> Result<u8> {
+        Ok(if self.is_64bit()? { 0x0c } else { 0x08 })
+    }
+
+    pub fn message(&self) -> Result<MsiMessage> {
+        let mut address = self.0.read32(4)? as u64;
+        if self.is_64bit()? {
+            address |= (self.0.read32(8)? as u64) << 32;
+        }
+        let data = self.0.read16(self.data_offset()?)? as u32;
+        Ok(MsiMessage { address, data })
+    }
+
+    pub fn set_message(&self, message: MsiMessage) -> Result<()> {
+        self.0.write32(4, message.address as u32)?;
+        if self.is_64bit()? {
+            self.0.write32(8, (message.address >> 32) as u32)?;
+        }
+        self.0.write16(self.data_offset()?, message.data as u16)
+    }
+}
+
+/// MSI-X Capability
+///
+/// ```plaintext
+/// 00h | Message Control | Next | ID |
+/// 04h | Table Offset             | BIR |
+/// 08h | PBA Offset               | BIR |
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct MsiXCapability<'a>(CapabilityRegs<'a>);
+
+impl<'a> MsiXCapability<'a> {
+    const FUNCTION_MASK: u16 = 1 << 14;
+    const ENABLE: u16 = 1 << 15;
+    pub const TABLE_ENTRY_SIZE: u64 = 16;
+
+    pub fn regs(&self) -> CapabilityRegs<'a> {
+        self.0
+    }
+
+    pub fn message_control(&self) -> Result<u16> {
+        self.0.read16(2)
+    }
+
+    /// テーブルのエントリ数
+    pub fn table_size(&self) -> Result<u16> {
+        Ok((self.message_control()? & 0x7ff) + 1)
+    }
+
+    pub fn is_enabled(&self) -> Result<bool> {
+        Ok(self.message_control()? & Self::ENABLE != 0)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) -> Result<()> {
+        self.0.modify16(2, |v| {
+            if enabled {
+                v | Self::ENABLE
+            } else {
+                v & !Self::ENABLE
+            }
+        })
+    }
+
+    pub fn set_function_mask(&self, masked: bool) -> Result<()> {
+        self.0.modify16(2, |v| {
+            if masked {
+                v | Self::FUNCTION_MASK
+            } else {
+                v & !Self::FUNCTION_MASK
+            }
+        })
+    }
+
+    /// テーブルがあるBARの番号
+    pub fn table_bir(&self) -> Result<u8> {
+        Ok((self.0.read32(4)? & 0x7) as u8)
+    }
+
+    /// BARの先頭からのテーブルのオフセット
+    pub fn table_offset(&self) -> Result<u32> {
+        Ok(self.0.read32(4)? & !0x7)
+    }
+
+    pub fn pba_bir(&self) -> Result<u8> {
+        Ok((self.0.read32(8)? & 0x7) as u8)
+    }
+
+    pub fn pba_offset(&self) -> Result<u32> {
+        Ok(self.0.read32(8)? & !0x7)
+    }
+
+    /// テーブルの`index`番目のエントリを書き込み、マスクを外す
+    ///
+    /// # Safety
+    /// `table`はこのCapabilityのテーブルのアドレスで、UCでマップされている必要がある
+    pub unsafe fn write_table_entry(&self, table: u64, index: usize, message: MsiMessage) {
+        let entry = (table + index as u64 * Self::TABLE_ENTRY_SIZE) as *mut u32;
+        entry.write_volatile(message.address as u32);
+        entry.add(1).write_volatile((message.address >> 32) as u32);
+        entry.add(2).write_volatile(message.data);
+        // Vector Control
+        entry.add(3).write_volatile(0);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    D0,
+    D1,
+    D2,
+    D3Hot,
+}
+
+impl From<u16> for PowerState {
+    fn from(value: u16) -> Self {
+        match value & 0b11 {
+            0 => Self::D0,
+            1 => Self::D1,
+            2 => Self::D2,
+            _ => Self::D3Hot,
+        }
+    }
+}
+
+/// Power Management Capability
+///
+/// ```plaintext
+/// 00h | PMC  | Next | ID |
+/// 04h | Data | BSE | PMCSR |
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct PowerManagementCapability<'a>(CapabilityRegs<'a>);
+
+impl<'a> PowerManagementCapability<'a> {
+    pub fn regs(&self) -> CapabilityRegs<'a> {
+        self.0
+    }
+
+    pub fn version(&self) -> Result<u8> {
+        Ok((self.0.read16(2)? & 0x7) as u8)
+    }
+
+    pub fn power_state(&self) -> Result<PowerState> {
+        Ok(PowerState::from(self.0.read16(4)?))
+    }
+
+    pub fn set_power_state(&self, state: PowerState) -> Result<()> {
+        // PME_Status(bit15)はRW1Cなので書き戻さない
+        const PME_STATUS: u16 = 1 << 15;
+        self.0
+            .modify16(4, |v| (v & !(PME_STATUS | 0b11)) | state as u16)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciExpressPortType {
+    Endpoint,
+    LegacyEndpoint,
+    RootPort,
+    UpstreamSwitchPort,
+    DownstreamSwitchPort,
+    PcieToPciBridge,
+    PciToPcieBridge,
+    RootComplexIntegratedEndpoint,
+    RootComplexEventCollector,
+    Unknown(u8),
+}
+
+impl From<u8> for PciExpressPortType {
+    fn from(value: u8) -> Self {
+        match value {
+            0x0 => Self::Endpoint,
+            0x1 => Self::LegacyEndpoint,
+            0x4 => Self::RootPort,
+            0x5 => Self::UpstreamSwitchPort,
+            0x6 => Self::DownstreamSwitchPort,
+            0x7 => Self::PcieToPciBridge,
+            0x8 => Self::PciToPcieBridge,
+            0x9 => Self::RootComplexIntegratedEndpoint,
+            0xa => Self::RootComplexEventCollector,
+            x => Self::Unknown(x),
+        }
+    }
+}
+
+/// リンクの速度と幅
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkState {
+    /// 1: 2.5GT/s, 2: 5GT/s, 3: 8GT/s, ...
+    pub speed: u8,
+    /// レーン数
+    pub width: u8,
+}
+
+impl LinkState {
+    fn from_bits(bits: u32) -> Self {
+        Self {
+            speed: (bits & 0xf) as u8,
+            width: ((bits >> 4) & 0x3f) as u8,
+        }
+    }
+}
+
+/// PCI Express Capability
+///
+/// ```plaintext
+/// 00h | PCIe Capabilities | Next | ID |
+/// 04h | Device Capabilities          |
+/// 08h | Device Status | Device Control |
+/// 0Ch | Link Capabilities            |
+/// 10h | Link Status   | Link Control   |
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct PciExpressCapability<'a>(CapabilityRegs<'a>);
+
+impl<'a> PciExpressCapability<'a> {
+    pub fn regs(&self) -> CapabilityRegs<'a> {
+        self.0
+    }
+
+    pub fn version(&self) -> Result<u8> {
+        Ok((self.0.read16(2)? & 0xf) as u8)
+    }
+
+    pub fn port_type(&self) -> Result<PciExpressPortType> {
+        Ok(PciExpressPortType::from(
+            ((self.0.read16(2)? >> 4) & 0xf) as u8,
+        ))
+    }
+
+    /// 対応している最大の速度と幅
+    pub fn max_link(&self) -> Result<LinkState> {
+        Ok(LinkState::from_bits(self.0.read32(0x0c)?))
+    }
+
+    /// ネゴシエートされた現在の速度と幅
+    pub fn link_status(&self) -> Result<LinkState> {
+        Ok(LinkState::from_bits(self.0.read16(0x12)? as u32))
+    }
+}
+
+/// Vendor Specific Capability。3バイト目が長さで、残りはベンダー定義。
+#[derive(Debug, Clone, Copy)]
+pub struct VendorCapability<'a>(CapabilityRegs<'a>);
+
+impl<'a> VendorCapability<'a> {
+    pub fn regs(&self) -> CapabilityRegs<'a> {
+        self.0
+    }
+
+    pub fn length(&self) -> Result<u8> {
+        Ok((self.0.read32(0)? >> 16) as u8)
+    }
+
+    pub fn read32(&self, offset: u8) -> Result<u32> {
+        self.0.read32(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{boxed::Box, vec::Vec};
+
+    use super::{super::tests::FakeConfigSpace, *};
+    use crate::pci::{ClassCode, HeaderType};
+
+    /// Capabilities Listの先頭が`first`で、`regs`を持つファンクション
+    fn device(first: u8, regs: &[(u16, u32)]) -> Device {
+        let fake: &'static FakeConfigSpace = Box::leak(Box::default());
+        fake.add_function(
+            0,
+            0,
+            0,
+            &[
+                (0x00, 0x1234_8086),
+                (0x04, 0x0010_0000),
+                (0x34, first as u32),
+            ],
+        );
+        fake.add_function(0, 0, 0, regs);
+        Device::new(
+            fake,
+            0,
+            0,
+            0,
+            HeaderType::default(),
+            ClassCode::new(0x0c, 0x03, 0x30),
+        )
+    }
+
+    fn ids(dev: &Device) -> Vec<(u8, u8)> {
+        dev.capabilities()
+            .map(|c| (c.header().id(), c.header().offset()))
+            .collect()
+    }
+
+    #[test]
+    fn test_capabilities_end_at_zero() {
+        let dev = device(
+            0x40,
+            &[
+                (0x40, 0x0003_5001),
+                // Nextの下位2ビットは無視する
+                (0x50, 0x0000_6305),
+                (0x60, 0x0002_0010),
+            ],
+        );
+        assert_eq!(
+            ids(&dev),
+            [
+                (capability_id::POWER_MANAGEMENT, 0x40),
+                (capability_id::MSI, 0x50),
+                (capability_id::PCI_EXPRESS, 0x60),
+            ]
+        );
+        assert!(dev.msi().is_some());
+        assert!(dev.msix().is_none());
+    }
+
+    #[test]
+    fn test_capabilities_without_list() {
+        let dev = device(0x40, &[(0x04, 0), (0x40, 0x0000_0005)]);
+        assert!(ids(&dev).is_empty());
+    }
+
+    #[test]
+    fn test_capabilities_self_loop() {
+        let dev = device(0x40, &[(0x40, 0x0000_4009)]);
+        assert_eq!(
+            dev.capabilities().count(),
+            Capabilities::MAX_CAPABILITIES as usize
+        );
+    }
+
+    #[test]
+    fn test_msi_at_high_offset() {
+        // 64bitアドレスのMSIが標準のConfiguration Spaceの末尾まで使う
+        let dev = device(0xf0, &[(0xf0, 0x0086_0005)]);
+        let msi = dev.msi().unwrap();
+        assert_eq!(msi.regs().header().offset(), 0xf0);
+        assert_eq!(msi.is_64bit(), Ok(true));
+        assert_eq!(msi.multiple_message_capable(), Ok(3));
+
+        let message =
+            MsiMessage::fixed_destination(1, MsiTriggerMode::Level, MsiDeliveryMode::Fixed, 0x40);
+        msi.set_message(message).unwrap();
+        msi.set_multiple_message_enable(5).unwrap();
+        msi.set_enabled(true).unwrap();
+
+        assert_eq!(msi.message(), Ok(message));
+        assert_eq!(dev.read_config(0xf4), Ok(0xfee0_1000));
+        assert_eq!(dev.read_config(0xfc), Ok(0xc040));
+        // IDとNextは書き換えない
+        assert_eq!(dev.read_config(0xf0), Ok(0x00b7_0005));
+        assert_eq!(
+            msi.regs().read32(0x10),
+            Err(Error::invalid_capability_offset())
+        );
+    }
+
+    #[test]
+    fn test_register_past_config_space() {
+        let dev = device(0xfc, &[(0xfc, 0x0002_0010)]);
+        let pcie = dev.pci_express().unwrap();
+        assert_eq!(pcie.version(), Ok(2));
+        assert_eq!(pcie.max_link(), Err(Error::invalid_capability_offset()));
+        assert_eq!(
+            pcie.regs().write32(4, 0),
+            Err(Error::invalid_capability_offset())
+        );
+    }
+
+    #[test]
+    fn test_power_state_keeps_pme_status() {
+        let dev = device(0x40, &[(0x40, 0x0003_0001), (0x44, 0x0000_8003)]);
+        let pm = dev.power_management().unwrap();
+        assert_eq!(pm.power_state(), Ok(PowerState::D3Hot));
+
+        pm.set_power_state(PowerState::D0).unwrap();
+        assert_eq!(pm.power_state(), Ok(PowerState::D0));
+        // RW1CのPME_Statusに1を書き戻していない
+        assert_eq!(dev.read_config(0x44), Ok(0));
+    }
+}
@@ -0,0 +1,230 @@
+//! コンフィギュレーション空間へのアクセス方法
+//!
+//! I/Oポート(0xCF8/0xCFC)とPCI ExpressのECAMを同じトレイトで扱う。
+
+use core::{fmt, ptr};
+
+use crate::{
+    acpi,
+    error::{Error, Result},
+    paging,
+};
+
+use super::{FUNCTION_MAX, MAX_DEVICES};
+
+/// レガシーなコンフィギュレーション空間の大きさ
+pub const LEGACY_CONFIG_SPACE_SIZE: u16 = 0x100;
+/// PCI Expressの拡張コンフィギュレーション空間の大きさ
+pub const EXTENDED_CONFIG_SPACE_SIZE: u16 = 0x1000;
+
+/// `offset`は4バイト境界に揃っている必要がある
+pub trait ConfigAccess: Sync {
+    fn read32(&self, bus: u8, device: u8, function: u8, offset: u16) -> Result<u32>;
+
+    fn write32(&self, bus: u8, device: u8, function: u8, offset: u16, value: u32) -> Result<()>;
+
+    /// 1ファンクションあたりの読み書きできるバイト数
+    fn config_space_size(&self) -> u16;
+}
+
+impl fmt::Debug for dyn ConfigAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConfigAccess")
+            .field("config_space_size", &self.config_space_size())
+            .finish()
+    }
+}
+
+fn check_address(device: u8, function: u8, offset: u16, size: u16) -> Result<()> {
+    if device >= MAX_DEVICES
+        || function > FUNCTION_MAX
+        || offset >= size
+        || !offset.is_multiple_of(4)
+    {
+        return Err(Error::invalid_addr());
+    }
+    Ok(())
+}
+
+/// CONFIG_ADDRESS/CONFIG_DATAレジスタを使う方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PortIo;
+
+impl PortIo {
+    fn address(bus: u8, device: u8, function: u8, offset: u16) -> Result<u32> {
+        check_address(device, function, offset, LEGACY_CONFIG_SPACE_SIZE)?;
+
+        fn shl(x: u8, bit: u32) -> u32 {
+            (x as u32) << bit
+        }
+
+        Ok(shl(1, 31) | shl(bus, 16) | shl(device, 11) | shl(function, 8) | offset as u32)
+    }
+}
+
+impl ConfigAccess for PortIo {
+    fn read32(&self, bus: u8, device: u8, function: u8, offset: u16) -> Result<u32> {
+        let addr = Self::address(bus, device, function, offset)?;
+        Ok(port::read(addr))
+    }
+
+    fn write32(&self, bus: u8, device: u8, function: u8, offset: u16, value: u32) -> Result<()> {
+        let addr = Self::address(bus, device, function, offset)?;
+        port::write(addr, value);
+        Ok(())
+    }
+
+    fn config_space_size(&self) -> u16 {
+        LEGACY_CONFIG_SPACE_SIZE
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod port {
+    use core::arch::asm;
+
+    const CONFIG_ADDRESS: u16 = 0x0cf8;
+    const CONFIG_DATA: u16 = 0x0cfc;
+
+    fn io_out32(addr: u16, data: u32) {
+        unsafe { asm!("out dx, eax",in("dx") addr, in("eax") data) }
+    }
+
+    fn io_in32(addr: u16) -> u32 {
+        let mut result: u32;
+        unsafe { asm!("in eax, dx", out("eax") result, in("dx") addr) }
+        result
+    }
+
+    pub fn read(address: u32) -> u32 {
+        io_out32(CONFIG_ADDRESS, address);
+        u32::from_le(io_in32(CONFIG_DATA))
+    }
+
+    pub fn write(address: u32, value: u32) {
+        io_out32(CONFIG_ADDRESS, address);
+        io_out32(CONFIG_DATA, value.to_le());
+    }
+}
+
+/// Enhanced Configuration Access Mechanism
+///
+/// バスごとに1MiBの領域がメモリにマップされている。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ecam {
+    base: u64,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+impl Ecam {
+    /// # Safety
+    /// `base`から`start_bus`..=`end_bus`の分だけECAM領域がマップされている必要がある
+    pub const unsafe fn new(base: u64, start_bus: u8, end_bus: u8) -> Self {
+        Self {
+            base,
+            start_bus,
+            end_bus,
+        }
+    }
+
+    /// MCFGのエントリの`base_address`はバス0の位置を指す
+    pub fn from_mcfg_entry(entry: &acpi::McfgEntry) -> Result<Self> {
+        let ecam = unsafe { Self::new(entry.base_address(), entry.start_bus(), entry.end_bus()) };
+        let range = ecam.region();
+        paging::map_mmio(range.start, range.end - range.start)?;
+        Ok(ecam)
+    }
+
+    /// マップが必要な物理アドレスの範囲
+    pub fn region(&self) -> core::ops::Range<u64> {
+        let start = self.base + ((self.start_bus as u64) << 20);
+        let end = self.base + ((self.end_bus as u64 + 1) << 20);
+        start..end
+    }
+
+    fn address(&self, bus: u8, device: u8, function: u8, offset: u16) -> Result<*mut u32> {
+        check_address(device, function, offset, EXTENDED_CONFIG_SPACE_SIZE)?;
+        if !(self.start_bus..=self.end_bus).contains(&bus) {
+            return Err(Error::invalid_addr());
+        }
+
+        let addr = self.base
+            + ((bus as u64) << 20)
+            + ((device as u64) << 15)
+            + ((function as u64) << 12)
+            + offset as u64;
+        Ok(addr as *mut u32)
+    }
+}
+
+impl ConfigAccess for Ecam {
+    fn read32(&self, bus: u8, device: u8, function: u8, offset: u16) -> Result<u32> {
+        let addr = self.address(bus, device, function, offset)?;
+        Ok(u32::from_le(unsafe { ptr::read_volatile(addr) }))
+    }
+
+    fn write32(&self, bus: u8, device: u8, function: u8, offset: u16, value: u32) -> Result<()> {
+        let addr = self.address(bus, device, function, offset)?;
+        unsafe { ptr::write_volatile(addr, value.to_le()) };
+        Ok(())
+    }
+
+    fn config_space_size(&self) -> u16 {
+        EXTENDED_CONFIG_SPACE_SIZE
+    }
+}
+
+static mut ECAM: Option<Ecam> = None;
+
+/// MCFGにセグメント0のECAMがあればそれを、なければI/Oポートを使う。
+/// `paging::init`の後に一度だけ呼ぶ。
+pub fn select(acpi_rsdp: Option<u64>) -> &'static dyn ConfigAccess {
+    let ecam = acpi_rsdp
+        .and_then(|rsdp| unsafe { acpi::find_mcfg(rsdp) }.ok())
+        .and_then(|mcfg| mcfg.entries().find(|entry| entry.segment_group() == 0))
+        .and_then(|entry| Ecam::from_mcfg_entry(&entry).ok());
+
+    match ecam {
+        Some(ecam) => unsafe {
+            let slot = &mut *ptr::addr_of_mut!(ECAM);
+            slot.insert(ecam)
+        },
+        None => &PortIo,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+
+    use super::*;
+
+    #[test]
+    fn test_port_io_address() {
+        assert_eq!(PortIo::address(1, 2, 3, 0x10), Ok(0x8001_1310));
+        assert_eq!(PortIo::address(0, 32, 0, 0), Err(Error::invalid_addr()));
+        assert_eq!(PortIo::address(0, 0, 8, 0), Err(Error::invalid_addr()));
+        assert_eq!(PortIo::address(0, 0, 0, 0x100), Err(Error::invalid_addr()));
+    }
+
+    #[test]
+    fn test_ecam() {
+        // バス1だけの領域
+        let mut memory = vec![0u32; (1 << 20) / 4];
+        let base = memory.as_mut_ptr() as u64 - (1 << 20);
+        let ecam = unsafe { Ecam::new(base, 1, 1) };
+        assert_eq!(ecam.region(), base + (1 << 20)..base + (2 << 20));
+
+        ecam.write32(1, 2, 3, 0x104, 0xdead_beef).unwrap();
+        let index = ((2 << 15) + (3 << 12) + 0x104) / 4;
+        assert_eq!(memory[index], 0xdead_beef);
+        assert_eq!(ecam.read32(1, 2, 3, 0x104), Ok(0xdead_beef));
+
+        assert_eq!(ecam.read32(0, 0, 0, 0), Err(Error::invalid_addr()));
+        assert_eq!(ecam.read32(1, 0, 0, 0x1000), Err(Error::invalid_addr()));
+        assert_eq!(ecam.config_space_size(), EXTENDED_CONFIG_SPACE_SIZE);
+    }
+}
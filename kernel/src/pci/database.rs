@@ -0,0 +1,224 @@
+//! クラスコードとベンダID/デバイスIDの名前
+//!
+//! pci.idsからよく見るものだけを抜き出したテーブル。
+
+/// (base class, 名前)
+const CLASSES: &[(u8, &str)] = &[
+    (0x00, "Unclassified device"),
+    (0x01, "Mass storage controller"),
+    (0x02, "Network controller"),
+    (0x03, "Display controller"),
+    (0x04, "Multimedia controller"),
+    (0x05, "Memory controller"),
+    (0x06, "Bridge"),
+    (0x07, "Communication controller"),
+    (0x08, "Generic system peripheral"),
+    (0x09, "Input device controller"),
+    (0x0a, "Docking station"),
+    (0x0b, "Processor"),
+    (0x0c, "Serial bus controller"),
+    (0x0d, "Wireless controller"),
+    (0x0e, "Intelligent controller"),
+    (0x0f, "Satellite communications controller"),
+    (0x10, "Encryption controller"),
+    (0x11, "Signal processing controller"),
+    (0x12, "Processing accelerators"),
+    (0x13, "Non-Essential Instrumentation"),
+    (0xff, "Unassigned class"),
+];
+
+/// (base class, sub class, 名前)
+const SUBCLASSES: &[(u8, u8, &str)] = &[
+    (0x00, 0x00, "Non-VGA unclassified device"),
+    (0x00, 0x01, "VGA compatible unclassified device"),
+    (0x01, 0x00, "SCSI storage controller"),
+    (0x01, 0x01, "IDE interface"),
+    (0x01, 0x05, "ATA controller"),
+    (0x01, 0x06, "SATA controller"),
+    (0x01, 0x07, "Serial Attached SCSI controller"),
+    (0x01, 0x08, "Non-Volatile memory controller"),
+    (0x01, 0x80, "Mass storage controller"),
+    (0x02, 0x00, "Ethernet controller"),
+    (0x02, 0x80, "Network controller"),
+    (0x03, 0x00, "VGA compatible controller"),
+    (0x03, 0x01, "XGA compatible controller"),
+    (0x03, 0x02, "3D controller"),
+    (0x03, 0x80, "Display controller"),
+    (0x04, 0x00, "Multimedia video controller"),
+    (0x04, 0x01, "Multimedia audio controller"),
+    (0x04, 0x03, "Audio device"),
+    (0x05, 0x00, "RAM memory"),
+    (0x06, 0x00, "Host bridge"),
+    (0x06, 0x01, "ISA bridge"),
+    (0x06, 0x04, "PCI bridge"),
+    (0x06, 0x80, "Bridge"),
+    (0x07, 0x00, "Serial controller"),
+    (0x07, 0x80, "Communication controller"),
+    (0x08, 0x00, "PIC"),
+    (0x08, 0x05, "SD Host controller"),
+    (0x08, 0x80, "System peripheral"),
+    (0x09, 0x00, "Keyboard controller"),
+    (0x09, 0x02, "Mouse controller"),
+    (0x0c, 0x00, "FireWire (IEEE 1394)"),
+    (0x0c, 0x03, "USB controller"),
+    (0x0c, 0x05, "SMBus"),
+    (0x0c, 0x80, "Serial bus controller"),
+    (0x0d, 0x11, "Bluetooth"),
+    (0x0d, 0x80, "Wireless controller"),
+];
+
+/// (base class, sub class, programming interface, 名前)
+const PROG_IFS: &[(u8, u8, u8, &str)] = &[
+    (
+        0x01,
+        0x01,
+        0x80,
+        "ISA Compatibility mode-only controller, supports bus mastering",
+    ),
+    (0x01, 0x06, 0x00, "Vendor specific"),
+    (0x01, 0x06, 0x01, "AHCI 1.0"),
+    (0x01, 0x08, 0x02, "NVM Express"),
+    (0x03, 0x00, 0x00, "VGA controller"),
+    (0x06, 0x04, 0x00, "Normal decode"),
+    (0x06, 0x04, 0x01, "Subtractive decode"),
+    (0x07, 0x00, 0x02, "16550"),
+    (0x0c, 0x03, 0x00, "UHCI"),
+    (0x0c, 0x03, 0x10, "OHCI"),
+    (0x0c, 0x03, 0x20, "EHCI"),
+    (0x0c, 0x03, 0x30, "XHCI"),
+    (0x0c, 0x03, 0xfe, "USB Device"),
+];
+
+/// (ベンダID, 名前)
+const VENDORS: &[(u16, &str)] = &[
+    (0x1002, "Advanced Micro Devices, Inc. [AMD/ATI]"),
+    (0x1022, "Advanced Micro Devices, Inc. [AMD]"),
+    (0x1033, "NEC Corporation"),
+    (0x10de, "NVIDIA Corporation"),
+    (0x10ec, "Realtek Semiconductor Co., Ltd."),
+    (0x1106, "VIA Technologies, Inc."),
+    (0x1234, "QEMU"),
+    (0x144d, "Samsung Electronics Co Ltd"),
+    (0x14e4, "Broadcom Inc. and subsidiaries"),
+    (0x15ad, "VMware"),
+    (0x168c, "Qualcomm Atheros"),
+    (0x1af4, "Red Hat, Inc."),
+    (0x1b21, "ASMedia Technology Inc."),
+    (0x1b36, "Red Hat, Inc."),
+    (0x1b4b, "Marvell Technology Group Ltd."),
+    (0x1b73, "Fresco Logic"),
+    (0x80ee, "InnoTek Systemberatung GmbH"),
+    (0x8086, "Intel Corporation"),
+];
+
+/// (ベンダID, デバイスID, 名前)
+const DEVICES: &[(u16, u16, &str)] = &[
+    (0x1033, 0x0194, "uPD720200 USB 3.0 Host Controller"),
+    (0x1234, 0x1111, "QEMU Virtual Video Controller"),
+    (0x1af4, 0x1000, "Virtio network device"),
+    (0x1af4, 0x1001, "Virtio block device"),
+    (0x1af4, 0x1041, "Virtio 1.0 network device"),
+    (0x1af4, 0x1042, "Virtio 1.0 block device"),
+    (0x1b36, 0x0001, "QEMU PCI-PCI bridge"),
+    (0x1b36, 0x000c, "QEMU PCIe Root port"),
+    (0x1b36, 0x000d, "QEMU XHCI Host Controller"),
+    (0x1b73, 0x1100, "FL1100 USB 3.0 Host Controller"),
+    (0x8086, 0x100e, "82540EM Gigabit Ethernet Controller"),
+    (0x8086, 0x10d3, "82574L Gigabit Network Connection"),
+    (0x8086, 0x1237, "440FX - 82441FX PMC [Natoma]"),
+    (
+        0x8086,
+        0x1e31,
+        "7 Series/C210 Series Chipset Family USB xHCI Host Controller",
+    ),
+    (0x8086, 0x2918, "82801IB (ICH9) LPC Interface Controller"),
+    (
+        0x8086,
+        0x2922,
+        "82801IR/IO/IH (ICH9R/DO/DH) 6 port SATA Controller [AHCI mode]",
+    ),
+    (0x8086, 0x2930, "82801I (ICH9 Family) SMBus Controller"),
+    (0x8086, 0x29c0, "82G33/G31/P35/P31 Express DRAM Controller"),
+    (0x8086, 0x293e, "82801I (ICH9 Family) HD Audio Controller"),
+    (0x8086, 0x7000, "82371SB PIIX3 ISA [Natoma/Triton II]"),
+    (0x8086, 0x7010, "82371SB PIIX3 IDE [Natoma/Triton II]"),
+    (0x8086, 0x7113, "82371AB/EB/MB PIIX4 ACPI"),
+    (
+        0x8086,
+        0x8c31,
+        "8 Series/C220 Series Chipset Family USB xHCI",
+    ),
+    (0x8086, 0x9d2f, "Sunrise Point-LP USB 3.0 xHCI Controller"),
+    (
+        0x8086,
+        0xa36d,
+        "Cannon Lake PCH USB 3.1 xHCI Host Controller",
+    ),
+];
+
+pub fn class_name(base: u8) -> Option<&'static str> {
+    CLASSES
+        .iter()
+        .find(|&&(b, _)| b == base)
+        .map(|&(_, name)| name)
+}
+
+pub fn subclass_name(base: u8, sub: u8) -> Option<&'static str> {
+    SUBCLASSES
+        .iter()
+        .find(|&&(b, s, _)| (b, s) == (base, sub))
+        .map(|&(_, _, name)| name)
+}
+
+pub fn prog_if_name(base: u8, sub: u8, prog_if: u8) -> Option<&'static str> {
+    PROG_IFS
+        .iter()
+        .find(|&&(b, s, p, _)| (b, s, p) == (base, sub, prog_if))
+        .map(|&(_, _, _, name)| name)
+}
+
+pub fn vendor_name(vendor: u16) -> Option<&'static str> {
+    VENDORS
+        .iter()
+        .find(|&&(v, _)| v == vendor)
+        .map(|&(_, name)| name)
+}
+
+pub fn device_name(vendor: u16, device: u16) -> Option<&'static str> {
+    DEVICES
+        .iter()
+        .find(|&&(v, d, _)| (v, d) == (vendor, device))
+        .map(|&(_, _, name)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(class_name(0x0c), Some("Serial bus controller"));
+        assert_eq!(subclass_name(0x0c, 0x03), Some("USB controller"));
+        assert_eq!(prog_if_name(0x0c, 0x03, 0x30), Some("XHCI"));
+        assert_eq!(vendor_name(0x1b36), Some("Red Hat, Inc."));
+        assert_eq!(
+            device_name(0x1b36, 0x000d),
+            Some("QEMU XHCI Host Controller")
+        );
+
+        assert_eq!(class_name(0x42), None);
+        assert_eq!(subclass_name(0x0c, 0x42), None);
+        assert_eq!(vendor_name(0xffff), None);
+        assert_eq!(device_name(0x8086, 0xffff), None);
+    }
+
+    #[test]
+    fn test_tables_are_unique() {
+        for (i, a) in DEVICES.iter().enumerate() {
+            assert!(DEVICES[i + 1..].iter().all(|b| (a.0, a.1) != (b.0, b.1)));
+        }
+        for (i, a) in SUBCLASSES.iter().enumerate() {
+            assert!(SUBCLASSES[i + 1..].iter().all(|b| (a.0, a.1) != (b.0, b.1)));
+        }
+    }
+}
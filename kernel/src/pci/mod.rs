@@ -0,0 +1,876 @@
+/// # PCI Configuration Space Register Layout
+///
+/// PCIデバイスの設定と状態を管理するための256バイトのメモリ領域。
+///
+/// ## Register Map (Type 00 Header)
+///
+/// ```plaintext
+/// Offset (hex)   |31                                         16|15                                          0|
+/// ---------------+---------------------------------------------+---------------------------------------------|
+/// 00h            |     Device ID                               |     Vendor ID                               |
+/// ---------------+---------------------------------------------+---------------------------------------------|
+/// 04h            |        Status                               |      Command                                |
+/// ---------------+-------------------------------------------------------------------------------------------|
+/// 08h            |    Class Code                                                      |    Revision ID       |
+/// ---------------+-------------------------------------------------------------------------------------------|
+/// 0Ch            |         BIST         |     Header  Type     |    Latency  Timer    |    Cacheline Size    |
+/// ---------------+-------------------------------------------------------------------------------------------|
+/// 10h            |                               Base Address Registers (BARs)                               |
+/// ---------------+-------------------------------------------------------------------------------------------|
+/// 28h            |                                    Cardbus CIS Pointer                                    |
+/// ---------------+-------------------------------------------------------------------------------------------|
+/// 2Ch            |                 Subsystem ID                |             Subsystem Vendor ID             |
+/// ---------------+-------------------------------------------------------------------------------------------|
+/// 30h            |                                 Expansion ROM Base Address                                |
+/// ---------------+-------------------------------------------------------------------------------------------|
+/// 34h            |                               Reserved                             | Capabilities Pointer |
+/// ---------------+-------------------------------------------------------------------------------------------|
+/// 38h            |                                          Reserved                                         |
+/// ---------------+-------------------------------------------------------------------------------------------|
+/// 3Ch            |        Max_Lat       |        Min_Gnt       |      Interrupt Pin   |    Interrupt Line    |
+/// ---------------+-------------------------------------------------------------------------------------------|
+/// 40h            |                                  Device Specific Region                                   |
+/// ---------------+-------------------------------------------------------------------------------------------|
+/// ```
+mod bar;
+mod capability;
+pub mod config;
+pub mod database;
+
+use core::fmt;
+
+pub use bar::*;
+pub use capability::*;
+use common::{debug, info};
+pub use config::{ConfigAccess, Ecam, PortIo};
+
+use crate::{
+    error::{Error, Result},
+    paging,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct VenderID(u16);
+impl VenderID {
+    const INTEL: u16 = 0x8086;
+    const INVALID: u16 = 0xffff;
+
+    pub fn new(v: u16) -> Self {
+        Self(v)
+    }
+
+    pub fn is_invalid(self) -> bool {
+        self.0 == Self::INVALID
+    }
+
+    pub fn intel() -> Self {
+        Self::new(Self::INTEL)
+    }
+
+    pub fn is_intel(&self) -> bool {
+        self.0 == Self::INTEL
+    }
+
+    pub fn value(&self) -> u16 {
+        self.0
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        database::vendor_name(self.0)
+    }
+}
+
+fn read_vender_id(
+    access: &dyn ConfigAccess,
+    bus: u8,
+    device: u8,
+    function: u8,
+) -> Result<VenderID> {
+    Ok(VenderID::new(
+        access.read32(bus, device, function, 0x00)? as u16
+    ))
+}
+
+fn read_device_id(access: &dyn ConfigAccess, bus: u8, device: u8, function: u8) -> Result<u16> {
+    Ok((access.read32(bus, device, function, 0x00)? >> 16) as u16)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+struct HeaderType(u8);
+impl HeaderType {
+    pub fn new(v: u8) -> Self {
+        Self(v)
+    }
+
+    pub fn is_single_fn_divice(self) -> bool {
+        self.0 & 0x80 == 0
+    }
+
+    /// Type 0は6個、PCI-PCIブリッジのType 1は2個
+    pub fn bar_count(self) -> u8 {
+        match self.0 & 0x7f {
+            0x00 => 6,
+            0x01 => 2,
+            _ => 0,
+        }
+    }
+}
+
+fn read_header_type(
+    access: &dyn ConfigAccess,
+    bus: u8,
+    device: u8,
+    function: u8,
+) -> Result<HeaderType> {
+    Ok(HeaderType::new(
+        (access.read32(bus, device, function, 0x0c)? >> 16) as u8,
+    ))
+}
+
+fn read_class_code(
+    access: &dyn ConfigAccess,
+    bus: u8,
+    device: u8,
+    function: u8,
+) -> Result<ClassCode> {
+    let reg = access.read32(bus, device, function, 0x08)?;
+    let cc = ClassCode::new((reg >> 24) as u8, (reg >> 16) as u8, (reg >> 8) as u8);
+
+    Ok(cc)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeviceType {
+    PciPciBridge,
+    UsbController,
+    Unknown,
+}
+
+impl From<ClassCode> for DeviceType {
+    fn from(value: ClassCode) -> Self {
+        match (value.base, value.sub) {
+            (0x06, 0x04) => DeviceType::PciPciBridge,
+            (0x0c, 0x03) => DeviceType::UsbController,
+            _ => DeviceType::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Interface(u8);
+
+impl Interface {
+    pub fn new(v: u8) -> Self {
+        Self(v)
+    }
+
+    pub fn value(&self) -> u8 {
+        self.0
+    }
+}
+
+pub enum UsbType {
+    XHci,
+}
+
+impl TryFrom<Interface> for UsbType {
+    type Error = ();
+
+    fn try_from(value: Interface) -> core::result::Result<Self, Self::Error> {
+        match value.0 {
+            0x30 => Ok(UsbType::XHci),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct ClassCode {
+    base: u8,
+    sub: u8,
+    interface: Interface,
+}
+
+impl ClassCode {
+    pub fn new(base: u8, sub: u8, interface: u8) -> Self {
+        Self {
+            base,
+            sub,
+            interface: Interface::new(interface),
+        }
+    }
+
+    pub fn interface(&self) -> Interface {
+        self.interface
+    }
+
+    pub fn base(&self) -> u8 {
+        self.base
+    }
+
+    pub fn sub(&self) -> u8 {
+        self.sub
+    }
+
+    /// サブクラスの名前がなければベースクラスの名前
+    pub fn name(&self) -> Option<&'static str> {
+        database::subclass_name(self.base, self.sub).or_else(|| database::class_name(self.base))
+    }
+
+    pub fn interface_name(&self) -> Option<&'static str> {
+        database::prog_if_name(self.base, self.sub, self.interface.0)
+    }
+}
+
+fn read_bus_number(access: &dyn ConfigAccess, bus: u8, device: u8, function: u8) -> Result<u32> {
+    access.read32(bus, device, function, 0x18)
+}
+
+const MAX_DEVICES: u8 = 32;
+const FUNCTION_MAX: u8 = 7;
+const MAX_FUNCTION_NUM: u8 = FUNCTION_MAX + 1;
+pub struct Pci {
+    access: &'static dyn ConfigAccess,
+    devices_buffer: [Device; MAX_DEVICES as usize],
+    device_num: u8,
+}
+
+impl Pci {
+    /// I/Oポートでコンフィギュレーション空間にアクセスする
+    pub fn new() -> Self {
+        Self::with_access(&PortIo)
+    }
+
+    pub fn with_access(access: &'static dyn ConfigAccess) -> Self {
+        let d = Device::default();
+        Self {
+            access,
+            devices_buffer: [d; MAX_DEVICES as usize],
+            device_num: 0,
+        }
+    }
+
+    pub fn scan_all_bus(&mut self) -> Result<()> {
+        // host bridge
+        let header_type = read_header_type(self.access, 0, 0, 0)?;
+        if header_type.is_single_fn_divice() {
+            return self.scan_bus(0);
+        }
+
+        for function in 1..MAX_FUNCTION_NUM {
+            if read_vender_id(self.access, 0, 0, function)?.is_invalid() {
+                continue;
+            }
+
+            self.scan_bus(function)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn devices(&self) -> &[Device] {
+        &self.devices_buffer[0..self.device_num as usize]
+    }
+
+    /// 見つけたデバイスを`lspci`のような形式でログに出す
+    pub fn dump(&self) {
+        for dev in self.devices() {
+            match dev.describe() {
+                Ok(desc) => info!("{}", desc),
+                Err(e) => info!(
+                    "{:02x}:{:02x}.{} <{:?}>",
+                    dev.bus, dev.device, dev.function, e
+                ),
+            }
+        }
+    }
+
+    fn scan_bus(&mut self, bus: u8) -> Result<()> {
+        for device in 0..MAX_DEVICES {
+            if read_vender_id(self.access, bus, device, 0)?.is_invalid() {
+                continue;
+            }
+
+            self.scan_device(bus, device)?;
+        }
+
+        Ok(())
+    }
+
+    fn scan_device(&mut self, bus: u8, device: u8) -> Result<()> {
+        self.scan_function(bus, device, 0)?;
+
+        if read_header_type(self.access, bus, device, 0)?.is_single_fn_divice() {
+            return Ok(());
+        }
+
+        for function in 0..MAX_FUNCTION_NUM {
+            if read_vender_id(self.access, bus, device, function)?.is_invalid() {
+                continue;
+            }
+
+            self.scan_function(bus, device, function)?;
+        }
+
+        Ok(())
+    }
+
+    fn scan_function(&mut self, bus: u8, device: u8, function: u8) -> Result<()> {
+        let class_code = read_class_code(self.access, bus, device, function)?;
+        let hedaer_type = read_header_type(self.access, bus, device, function)?;
+        let dev = Device::new(self.access, bus, device, function, hedaer_type, class_code);
+        self.add_device(dev)?;
+
+        let device_type = DeviceType::from(class_code);
+
+        if matches!(device_type, DeviceType::PciPciBridge) {
+            let bus_number = read_bus_number(self.access, bus, device, function)?;
+            let secondary_bus = (bus_number >> 8) as u8;
+            return self.scan_bus(secondary_bus);
+        }
+
+        Ok(())
+    }
+
+    fn add_device(&mut self, dev: Device) -> Result<()> {
+        if self.device_num as usize == self.devices_buffer.len() {
+            return Err(Error::too_many_devices());
+        }
+
+        self.devices_buffer[self.device_num as usize] = dev;
+        self.device_num += 1;
+        Ok(())
+    }
+}
+
+impl Default for Pci {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PciExtUsb for Pci {
+    fn find_usb(&self) -> Option<&Device> {
+        self.devices()
+            .iter()
+            .fold(None, |acc: Option<&Device>, cur| {
+                match (
+                    DeviceType::from(cur.class_code()),
+                    UsbType::try_from(cur.class_code().interface()),
+                ) {
+                    (DeviceType::UsbController, Ok(UsbType::XHci)) => match acc {
+                        Some(device) => {
+                            if device.read_vender_id().ok()?.is_intel() {
+                                Some(device)
+                            } else {
+                                Some(cur)
+                            }
+                        }
+                        None => Some(cur),
+                    },
+                    _ => None,
+                }
+            })
+    }
+
+    fn switch_ehci2xhci(&self, usb_dev: &Device) -> Result<()> {
+        let intel_ehc_exits = self.devices().iter().any(|dev| {
+            matches!(
+                (
+                    DeviceType::from(dev.class_code()),
+                    UsbType::try_from(dev.class_code().interface())
+                ),
+                (DeviceType::UsbController, Ok(UsbType::XHci))
+            ) && dev.read_vender_id().map(|v| v.is_intel()).unwrap_or(false)
+        });
+
+        if !intel_ehc_exits {
+            return Ok(());
+        }
+
+        let super_speed_port = usb_dev.read_reg(0xdc)?; // USB3PRM
+        usb_dev.write_reg(0xd8, super_speed_port)?;
+        let ehci2xhci_ports = usb_dev.read_reg(0xd4)?;
+        usb_dev.write_reg(0xd0, ehci2xhci_ports)?;
+        debug!(
+            "switch ehci to xhci: ss= {}, xhci= {}",
+            super_speed_port, ehci2xhci_ports
+        );
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Device {
+    access: &'static dyn ConfigAccess,
+    bus: u8,
+    device: u8,
+    function: u8,
+    header_type: HeaderType,
+    class_code: ClassCode,
+}
+
+impl Device {
+    const BAR_OFFSET: u8 = 0x10;
+
+    fn new(
+        access: &'static dyn ConfigAccess,
+        bus: u8,
+        device: u8,
+        function: u8,
+        header_type: HeaderType,
+        class_code: ClassCode,
+    ) -> Self {
+        Self {
+            access,
+            bus,
+            device,
+            function,
+            header_type,
+            class_code,
+        }
+    }
+
+    pub fn read_vender_id(&self) -> Result<VenderID> {
+        read_vender_id(self.access, self.bus, self.device, self.function)
+    }
+
+    pub fn read_device_id(&self) -> Result<u16> {
+        read_device_id(self.access, self.bus, self.device, self.function)
+    }
+
+    /// IDを読んで名前を引く
+    pub fn describe(&self) -> Result<DeviceDescription> {
+        Ok(DeviceDescription {
+            bus: self.bus,
+            device: self.device,
+            function: self.function,
+            class_code: self.class_code,
+            vendor_id: self.read_vender_id()?,
+            device_id: self.read_device_id()?,
+        })
+    }
+
+    /// BARの生の値
+    pub fn read_bar(&self, bar_index: u8) -> Result<u32> {
+        if self.header_type.bar_count() <= bar_index {
+            return Err(Error::out_of_range_bar());
+        }
+
+        self.read_reg(Self::BAR_OFFSET + 4 * bar_index)
+    }
+
+    /// BARをデコードしてサイズも調べる。64bitのBARは`bar_index`と次のBARを合わせる。
+    pub fn bar(&self, bar_index: u8) -> Result<Bar> {
+        let low = self.read_bar(bar_index)?;
+        let low_probe = self.probe_bar(bar_index)?;
+
+        let bar = match BarKind::from(low) {
+            BarKind::Memory32 => Bar::memory32(low, low_probe),
+            BarKind::Io => Bar::io(low, low_probe),
+            BarKind::Memory64 => {
+                let high = self.read_bar(bar_index + 1)?;
+                let high_probe = self.probe_bar(bar_index + 1)?;
+                Bar::memory64(
+                    (high as u64) << 32 | low as u64,
+                    (high_probe as u64) << 32 | low_probe as u64,
+                )
+            }
+        };
+        Ok(bar)
+    }
+
+    /// 全ビット1を書き込んで読み戻し、元の値に戻す
+    fn probe_bar(&self, bar_index: u8) -> Result<u32> {
+        const COMMAND: u8 = 0x04;
+        const DECODE_ENABLE: u32 = 0b11;
+
+        let reg = Self::BAR_OFFSET + 4 * bar_index;
+        // 上位16bitのStatusはRW1Cなので0を書き込む
+        let command = self.read_reg(COMMAND)? & 0xffff;
+        // 書き換えている間に誤ったアドレスをデコードしないようにする
+        self.write_reg(COMMAND, command & !DECODE_ENABLE)?;
+
+        let original = self.read_reg(reg)?;
+        self.write_reg(reg, 0xffff_ffff)?;
+        let probe = self.read_reg(reg)?;
+        self.write_reg(reg, original)?;
+
+        self.write_reg(COMMAND, command)?;
+        Ok(probe)
+    }
+
+    fn read_reg(&self, reg: u8) -> Result<u32> {
+        self.read_config(reg as u16 & !0x3)
+    }
+
+    fn write_reg(&self, reg: u8, v: u32) -> Result<()> {
+        self.write_config(reg as u16 & !0x3, v)
+    }
+
+    /// `offset`はコンフィギュレーション空間の先頭からのバイト数。
+    /// ECAMなら0x100以降の拡張領域も読める。
+    pub fn read_config(&self, offset: u16) -> Result<u32> {
+        self.access
+            .read32(self.bus, self.device, self.function, offset)
+    }
+
+    pub fn write_config(&self, offset: u16, value: u32) -> Result<()> {
+        self.access
+            .write32(self.bus, self.device, self.function, offset, value)
+    }
+
+    pub fn config_space_size(&self) -> u16 {
+        self.access.config_space_size()
+    }
+
+    pub fn bus(&self) -> u8 {
+        self.bus
+    }
+
+    pub fn device(&self) -> u8 {
+        self.device
+    }
+
+    pub fn function(&self) -> u8 {
+        self.function
+    }
+
+    pub fn class_code(&self) -> ClassCode {
+        self.class_code
+    }
+
+    /// Capabilities Listをたどる
+    pub fn capabilities(&self) -> Capabilities<'_> {
+        Capabilities::new(self)
+    }
+
+    pub fn msi(&self) -> Option<MsiCapability<'_>> {
+        self.capabilities().find_map(|cap| match cap {
+            Capability::Msi(msi) => Some(msi),
+            _ => None,
+        })
+    }
+
+    pub fn msix(&self) -> Option<MsiXCapability<'_>> {
+        self.capabilities().find_map(|cap| match cap {
+            Capability::MsiX(msix) => Some(msix),
+            _ => None,
+        })
+    }
+
+    pub fn power_management(&self) -> Option<PowerManagementCapability<'_>> {
+        self.capabilities().find_map(|cap| match cap {
+            Capability::PowerManagement(pm) => Some(pm),
+            _ => None,
+        })
+    }
+
+    pub fn pci_express(&self) -> Option<PciExpressCapability<'_>> {
+        self.capabilities().find_map(|cap| match cap {
+            Capability::PciExpress(pcie) => Some(pcie),
+            _ => None,
+        })
+    }
+
+    /// MSIかMSI-Xで`apic_id`のLocal APICに`vector`の割り込みを送るようにする。
+    /// 両方ある場合はMSIを使う。
+    pub fn configure_msi_fixed_destination(
+        &self,
+        apic_id: u8,
+        trigger_mode: MsiTriggerMode,
+        delivery_mode: MsiDeliveryMode,
+        vector: u8,
+    ) -> Result<()> {
+        let message = MsiMessage::fixed_destination(apic_id, trigger_mode, delivery_mode, vector);
+
+        if let Some(msi) = self.msi() {
+            msi.set_message(message)?;
+            msi.set_multiple_message_enable(0)?;
+            msi.set_enabled(true)
+        } else if let Some(msix) = self.msix() {
+            // テーブルの先頭のエントリだけを使う
+            let table = self.bar(msix.table_bir()?)?.addr() + msix.table_offset()? as u64;
+            paging::map_mmio(table, MsiXCapability::TABLE_ENTRY_SIZE)?;
+            unsafe { msix.write_table_entry(table, 0, message) };
+
+            msix.set_function_mask(false)?;
+            msix.set_enabled(true)
+        } else {
+            Err(Error::capability_not_found())
+        }
+    }
+}
+
+/// `lspci`の1行分
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceDescription {
+    bus: u8,
+    device: u8,
+    function: u8,
+    class_code: ClassCode,
+    vendor_id: VenderID,
+    device_id: u16,
+}
+
+impl fmt::Display for DeviceDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cc = self.class_code;
+        write!(f, "{:02x}:{:02x}.{} ", self.bus, self.device, self.function)?;
+        match cc.name() {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "Class")?,
+        }
+        write!(f, " [{:02x}{:02x}]: ", cc.base, cc.sub)?;
+
+        let vendor = self.vendor_id.value();
+        match self.vendor_id.name() {
+            Some(name) => write!(f, "{} ", name)?,
+            None => write!(f, "Vendor {:04x} ", vendor)?,
+        }
+        match database::device_name(vendor, self.device_id) {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "Device {:04x}", self.device_id)?,
+        }
+        write!(f, " [{:04x}:{:04x}]", vendor, self.device_id)?;
+
+        let prog_if = cc.interface.value();
+        if prog_if != 0 {
+            write!(f, " (prog-if {:02x}", prog_if)?;
+            if let Some(name) = cc.interface_name() {
+                write!(f, " [{}]", name)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+/// コンフィギュレーション空間の同じファンクションを指していれば等しい
+impl PartialEq for Device {
+    fn eq(&self, other: &Self) -> bool {
+        (self.bus, self.device, self.function) == (other.bus, other.device, other.function)
+            && self.header_type == other.header_type
+            && self.class_code == other.class_code
+    }
+}
+
+impl Eq for Device {}
+
+impl Default for Device {
+    fn default() -> Self {
+        Self::new(
+            &PortIo,
+            0,
+            0,
+            0,
+            HeaderType::default(),
+            ClassCode::default(),
+        )
+    }
+}
+
+pub trait PciExtUsb {
+    fn find_usb(&self) -> Option<&Device>;
+    fn switch_ehci2xhci(&self, usb_dev: &Device) -> Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{boxed::Box, collections::BTreeMap, sync::Mutex, vec::Vec};
+
+    use super::*;
+
+    /// 登録したファンクションだけが存在するコンフィギュレーション空間
+    #[derive(Default)]
+    pub(super) struct FakeConfigSpace {
+        regs: Mutex<BTreeMap<(u8, u8, u8, u16), u32>>,
+        /// レジスタごとの書き込めるビット。登録がなければ全ビット
+        writable: Mutex<BTreeMap<(u8, u8, u8, u16), u32>>,
+    }
+
+    impl FakeConfigSpace {
+        pub(super) fn set_writable(
+            &self,
+            bus: u8,
+            device: u8,
+            function: u8,
+            offset: u16,
+            mask: u32,
+        ) {
+            self.writable
+                .lock()
+                .unwrap()
+                .insert((bus, device, function, offset), mask);
+        }
+
+        pub(super) fn add_function(&self, bus: u8, device: u8, function: u8, regs: &[(u16, u32)]) {
+            let mut map = self.regs.lock().unwrap();
+            for &(offset, value) in regs {
+                map.insert((bus, device, function, offset), value);
+            }
+        }
+    }
+
+    impl ConfigAccess for FakeConfigSpace {
+        fn read32(&self, bus: u8, device: u8, function: u8, offset: u16) -> Result<u32> {
+            let regs = self.regs.lock().unwrap();
+            if !regs.contains_key(&(bus, device, function, 0)) {
+                return Ok(0xffff_ffff);
+            }
+            Ok(*regs.get(&(bus, device, function, offset)).unwrap_or(&0))
+        }
+
+        fn write32(
+            &self,
+            bus: u8,
+            device: u8,
+            function: u8,
+            offset: u16,
+            value: u32,
+        ) -> Result<()> {
+            let key = (bus, device, function, offset);
+            let mask = *self.writable.lock().unwrap().get(&key).unwrap_or(&!0);
+            let mut regs = self.regs.lock().unwrap();
+            let old = *regs.get(&key).unwrap_or(&0);
+            regs.insert(key, (value & mask) | (old & !mask));
+            Ok(())
+        }
+
+        fn config_space_size(&self) -> u16 {
+            config::EXTENDED_CONFIG_SPACE_SIZE
+        }
+    }
+
+    #[test]
+    fn test_scan_all_bus() {
+        let fake: &'static FakeConfigSpace = Box::leak(Box::default());
+        // host bridge
+        fake.add_function(0, 0, 0, &[(0x00, 0x1234_8086), (0x08, 0x0600_0000)]);
+        // PCI-PCIブリッジ。配下はバス2
+        fake.add_function(
+            0,
+            3,
+            0,
+            &[
+                (0x00, 0x0001_8086),
+                (0x08, 0x0604_0000),
+                (0x18, 0x0002_0200),
+            ],
+        );
+        // xHC。MSIのCapabilityを持つ
+        fake.add_function(
+            2,
+            1,
+            0,
+            &[
+                (0x00, 0x0002_1033),
+                (0x04, 0x0010_0000),
+                (0x08, 0x0c03_3000),
+                (0x34, 0x50),
+                (0x50, 0x0000_0005),
+                (0x100, 0xabcd),
+            ],
+        );
+
+        let mut pci = Pci::with_access(fake);
+        pci.scan_all_bus().unwrap();
+
+        let found: Vec<_> = pci
+            .devices()
+            .iter()
+            .map(|d| (d.bus(), d.device(), d.function()))
+            .collect();
+        assert_eq!(found, [(0, 0, 0), (0, 3, 0), (2, 1, 0)]);
+
+        let xhc = pci.find_usb().unwrap();
+        assert_eq!(xhc.bus(), 2);
+        assert!(xhc.msi().is_some());
+        assert_eq!(xhc.config_space_size(), 0x1000);
+        assert_eq!(xhc.read_config(0x100), Ok(0xabcd));
+    }
+
+    #[test]
+    fn test_bar() {
+        let fake: &'static FakeConfigSpace = Box::leak(Box::default());
+        fake.add_function(
+            0,
+            0,
+            0,
+            &[
+                (0x00, 0x1234_8086),
+                (0x04, 0x0010_0006),
+                (0x08, 0x0c03_3000),
+                // 64bit, 64KiB
+                (0x10, 0xfebf_0004),
+                (0x14, 0x0000_0001),
+                // I/O, 32バイト
+                (0x18, 0x0000_c041),
+            ],
+        );
+        fake.set_writable(0, 0, 0, 0x10, 0xffff_0000);
+        fake.set_writable(0, 0, 0, 0x14, 0xffff_ffff);
+        fake.set_writable(0, 0, 0, 0x18, 0x0000_ffe0);
+        // 実装されていないBAR
+        fake.set_writable(0, 0, 0, 0x1c, 0);
+
+        let mut pci = Pci::with_access(fake);
+        pci.scan_all_bus().unwrap();
+        let dev = pci.devices()[0];
+
+        assert_eq!(
+            dev.bar(0),
+            Ok(Bar::Memory64 {
+                addr: 0x1_febf_0000,
+                size: 0x1_0000,
+                prefetchable: false,
+            })
+        );
+        assert_eq!(
+            dev.bar(2),
+            Ok(Bar::Io {
+                port: 0xc040,
+                size: 0x20,
+            })
+        );
+        assert_eq!(dev.bar(3).map(|bar| bar.size()), Ok(0));
+        assert_eq!(dev.bar(6), Err(Error::out_of_range_bar()));
+
+        // 元の値とCommandレジスタが戻っている
+        assert_eq!(dev.read_bar(0), Ok(0xfebf_0004));
+        assert_eq!(dev.read_config(0x04), Ok(0x0000_0006));
+    }
+
+    #[test]
+    fn test_describe() {
+        extern crate alloc;
+        use alloc::string::ToString;
+
+        let fake: &'static FakeConfigSpace = Box::leak(Box::default());
+        fake.add_function(0, 0, 0, &[(0x00, 0x29c0_8086), (0x08, 0x0600_0000)]);
+        fake.add_function(0, 4, 0, &[(0x00, 0x000d_1b36), (0x08, 0x0c03_3001)]);
+        fake.add_function(0, 5, 0, &[(0x00, 0x5678_abcd), (0x08, 0x4201_0000)]);
+
+        let mut pci = Pci::with_access(fake);
+        pci.scan_all_bus().unwrap();
+        let lines: Vec<_> = pci
+            .devices()
+            .iter()
+            .map(|d| d.describe().unwrap().to_string())
+            .collect();
+
+        assert_eq!(
+            lines,
+            [
+                "00:00.0 Host bridge [0600]: Intel Corporation \
+                 82G33/G31/P35/P31 Express DRAM Controller [8086:29c0]",
+                "00:04.0 USB controller [0c03]: Red Hat, Inc. QEMU XHCI Host Controller \
+                 [1b36:000d] (prog-if 30 [XHCI])",
+                "00:05.0 Class [4201]: Vendor abcd Device 5678 [abcd:5678]",
+            ]
+        );
+    }
+}
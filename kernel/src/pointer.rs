@@ -0,0 +1,18 @@
+//! USBマウスやタブレットから届いたポインタイベントのキュー
+
+use common::{mutex::Mutex, ring_buf::RingBuffer, Zeroed as _};
+use usb::usbd::pointer::PointerEvent;
+
+const QUEUE_SIZE: usize = 64;
+
+static EVENTS: Mutex<Option<RingBuffer<PointerEvent, QUEUE_SIZE>>> = Mutex::new(None);
+
+/// キューがいっぱいなら新しいイベントを捨てる
+pub fn push(event: PointerEvent) {
+    let mut events = EVENTS.lock();
+    let _ = events.get_or_insert_with(RingBuffer::zeroed).push(event);
+}
+
+pub fn pop() -> Option<PointerEvent> {
+    EVENTS.lock().as_mut()?.pop()
+}
@@ -0,0 +1,113 @@
+use core::{mem, ptr};
+
+use crate::x86::{self, DescriptorTablePointer};
+
+pub const KERNEL_CS: u16 = 1 << 3;
+pub const KERNEL_SS: u16 = 2 << 3;
+pub const TSS_SELECTOR: u16 = 3 << 3;
+
+/// #DFで使うISTの番号(1始まり)
+pub const DOUBLE_FAULT_IST: u8 = 1;
+
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+const GDT_LEN: usize = 5;
+
+/// 64bitのコードセグメント。DPL0, 実行/読み込み可
+const KERNEL_CODE: u64 = 0x00af_9a00_0000_ffff;
+/// データセグメント。DPL0, 読み書き可
+const KERNEL_DATA: u64 = 0x00cf_9200_0000_ffff;
+
+#[repr(C, packed(4))]
+pub struct TaskStateSegment {
+    _reserved0: u32,
+    pub rsp: [u64; 3],
+    _reserved1: u64,
+    /// IST1 ~ IST7
+    pub ist: [u64; 7],
+    _reserved2: u64,
+    _reserved3: u16,
+    pub iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    pub const fn new() -> Self {
+        Self {
+            _reserved0: 0,
+            rsp: [0; 3],
+            _reserved1: 0,
+            ist: [0; 7],
+            _reserved2: 0,
+            _reserved3: 0,
+            iomap_base: mem::size_of::<Self>() as u16,
+        }
+    }
+}
+
+impl Default for TaskStateSegment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 64bitのTSSディスクリプタ。GDTの2エントリ分を使う。
+pub fn tss_descriptor(base: u64, limit: u32) -> [u64; 2] {
+    let low = (limit as u64 & 0xffff)
+        | ((base & 0xff_ffff) << 16)
+        | (0x9 << 40) // available 64bit TSS
+        | (1 << 47) // present
+        | (((limit as u64 >> 16) & 0xf) << 48)
+        | (((base >> 24) & 0xff) << 56);
+    let high = base >> 32;
+    [low, high]
+}
+
+#[repr(C, align(16))]
+struct Stack([u8; IST_STACK_SIZE]);
+
+static mut DOUBLE_FAULT_STACK: Stack = Stack([0; IST_STACK_SIZE]);
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+static mut GDT: [u64; GDT_LEN] = [0; GDT_LEN];
+
+/// カーネル用のGDTとTSSを設定し、セグメントレジスタを読み込み直す。
+/// 割り込みを有効にする前に一度だけ呼ぶ。
+pub fn init() {
+    unsafe {
+        let stack = ptr::addr_of!(DOUBLE_FAULT_STACK) as u64;
+        // スタックは下位アドレスに向かって伸びる
+        (*ptr::addr_of_mut!(TSS)).ist[DOUBLE_FAULT_IST as usize - 1] =
+            stack + IST_STACK_SIZE as u64;
+
+        let tss = tss_descriptor(
+            ptr::addr_of!(TSS) as u64,
+            mem::size_of::<TaskStateSegment>() as u32 - 1,
+        );
+        let gdt = &mut *ptr::addr_of_mut!(GDT);
+        *gdt = [0, KERNEL_CODE, KERNEL_DATA, tss[0], tss[1]];
+
+        x86::lgdt(&DescriptorTablePointer {
+            limit: mem::size_of_val(gdt) as u16 - 1,
+            base: gdt.as_ptr() as u64,
+        });
+        x86::set_data_segments(KERNEL_SS);
+        x86::set_cs(KERNEL_CS);
+        x86::ltr(TSS_SELECTOR);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tss_size() {
+        assert_eq!(mem::size_of::<TaskStateSegment>(), 104);
+    }
+
+    #[test]
+    fn test_tss_descriptor() {
+        let [low, high] = tss_descriptor(0x1234_5678_9abc_def0, 103);
+        assert_eq!(low, 0x9a00_89bc_def0_0067);
+        assert_eq!(high, 0x1234_5678);
+    }
+}
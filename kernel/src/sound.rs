@@ -0,0 +1,50 @@
+//! USBオーディオで鳴らすテスト用の音
+//!
+//! 440Hzの三角波を、48kHz・16ビット・ステレオのPCMとしてパケットに詰める。
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use usb::usbd::audio::{CHANNELS, SAMPLE_RATE, SAMPLE_SIZE};
+
+const FREQUENCY: u64 = 440;
+/// 耳が痛くならないように最大振幅の1/4にとどめる
+const AMPLITUDE: i32 = i16::MAX as i32 / 4;
+/// 1サンプルで進む位相。1周を2^32とする。
+const PHASE_STEP: u32 = ((FREQUENCY << 32) / SAMPLE_RATE as u64) as u32;
+
+static PHASE: AtomicU32 = AtomicU32::new(0);
+
+/// Isoch OUTのパケットを、前のパケットの続きの波形で埋める
+pub fn fill(buf: &mut [u8]) {
+    let mut phase = PHASE.load(Ordering::Relaxed);
+    for frame in buf.chunks_exact_mut(CHANNELS * SAMPLE_SIZE) {
+        let sample = triangle(phase).to_le_bytes();
+        for channel in frame.chunks_exact_mut(SAMPLE_SIZE) {
+            channel.copy_from_slice(&sample);
+        }
+        phase = phase.wrapping_add(PHASE_STEP);
+    }
+    PHASE.store(phase, Ordering::Relaxed);
+}
+
+/// 位相0で0から上がり始め、1/4周で最大になる
+fn triangle(phase: u32) -> i16 {
+    // 1周を-2^16..2^16の範囲で見て、折り返した距離を振幅にする。前半の半周が正。
+    let x = (phase >> 15) as i32 - (1 << 16);
+    let level = (1 << 15) - (x.abs() - (1 << 15)).abs();
+    let level = if x < 0 { level } else { -level };
+    ((level * AMPLITUDE) >> 15) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_triangle() {
+        assert_eq!(triangle(0), 0);
+        assert_eq!(triangle(1 << 30), AMPLITUDE as i16);
+        assert_eq!(triangle(1 << 31), 0);
+        assert_eq!(triangle(3 << 30), -AMPLITUDE as i16);
+    }
+}
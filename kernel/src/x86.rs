@@ -0,0 +1,155 @@
+//! 特権命令やレジスタ操作のラッパー
+
+use core::arch::asm;
+
+pub const IA32_PAT: u32 = 0x277;
+
+/// # Safety
+/// 存在するMSRに正しい値を書き込む必要がある
+pub unsafe fn write_msr(msr: u32, value: u64) {
+    asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nostack, preserves_flags),
+    )
+}
+
+/// # Safety
+/// 存在するMSRを指定する必要がある
+pub unsafe fn read_msr(msr: u32) -> u64 {
+    let (lo, hi): (u32, u32);
+    asm!(
+        "rdmsr",
+        in("ecx") msr,
+        out("eax") lo,
+        out("edx") hi,
+        options(nomem, nostack, preserves_flags),
+    );
+    ((hi as u64) << 32) | lo as u64
+}
+
+pub fn read_cr3() -> u64 {
+    let value;
+    unsafe { asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+/// # Safety
+/// `pml4`は実行中のコードとデータをマップしたページテーブルである必要がある
+pub unsafe fn write_cr3(pml4: u64) {
+    asm!("mov cr3, {}", in(reg) pml4, options(nostack, preserves_flags))
+}
+
+/// `addr`のTLBを破棄する
+pub fn invlpg(addr: u64) {
+    unsafe { asm!("invlpg [{}]", in(reg) addr, options(nostack, preserves_flags)) }
+}
+
+/// `lgdt`と`lidt`に渡す構造体
+#[repr(C, packed(2))]
+#[derive(Debug, Clone, Copy)]
+pub struct DescriptorTablePointer {
+    pub limit: u16,
+    pub base: u64,
+}
+
+/// # Safety
+/// `ptr`は'staticなGDTを指している必要がある
+pub unsafe fn lgdt(ptr: &DescriptorTablePointer) {
+    asm!("lgdt [{}]", in(reg) ptr, options(readonly, nostack, preserves_flags))
+}
+
+/// # Safety
+/// `ptr`は'staticなIDTを指している必要がある
+pub unsafe fn lidt(ptr: &DescriptorTablePointer) {
+    asm!("lidt [{}]", in(reg) ptr, options(readonly, nostack, preserves_flags))
+}
+
+/// # Safety
+/// `selector`は有効なTSSディスクリプタを指している必要がある
+pub unsafe fn ltr(selector: u16) {
+    asm!("ltr {0:x}", in(reg) selector, options(nostack, preserves_flags))
+}
+
+/// far returnでCSを書き換える
+///
+/// # Safety
+/// `selector`は有効な64bitコードセグメントを指している必要がある
+pub unsafe fn set_cs(selector: u16) {
+    asm!(
+        "push {sel}",
+        "lea {tmp}, [rip + 2f]",
+        "push {tmp}",
+        "retfq",
+        "2:",
+        sel = in(reg) selector as u64,
+        tmp = lateout(reg) _,
+        options(preserves_flags),
+    )
+}
+
+/// DS, ES, SSを`selector`に、FS, GSをnullにする
+///
+/// # Safety
+/// `selector`は有効なデータセグメントを指している必要がある
+pub unsafe fn set_data_segments(selector: u16) {
+    asm!(
+        "mov ds, {0:x}",
+        "mov es, {0:x}",
+        "mov ss, {0:x}",
+        "mov fs, {1:x}",
+        "mov gs, {1:x}",
+        in(reg) selector,
+        in(reg) 0u16,
+        options(nostack, preserves_flags),
+    )
+}
+
+/// ページフォルトを起こしたアドレス
+pub fn read_cr2() -> u64 {
+    let value;
+    unsafe { asm!("mov {}, cr2", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+pub fn hlt() {
+    unsafe { asm!("hlt", options(nomem, nostack, preserves_flags)) }
+}
+
+/// 割り込みを有効にする
+pub fn sti() {
+    unsafe { asm!("sti", options(nomem, nostack)) }
+}
+
+/// 割り込みを無効にする
+pub fn cli() {
+    unsafe { asm!("cli", options(nomem, nostack)) }
+}
+
+/// 割り込みを有効にして次の割り込みまで停止する。
+/// `sti`の直後の命令までは割り込まれないので、確認と停止の間に来た割り込みを取りこぼさない。
+pub fn sti_hlt() {
+    unsafe { asm!("sti", "hlt", options(nomem, nostack)) }
+}
+
+pub fn interrupts_enabled() -> bool {
+    const IF: u64 = 1 << 9;
+    let rflags: u64;
+    unsafe { asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags)) };
+    rflags & IF != 0
+}
+
+/// 割り込みを無効にした状態で`f`を実行する
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = interrupts_enabled();
+    if enabled {
+        cli();
+    }
+    let result = f();
+    if enabled {
+        sti();
+    }
+    result
+}
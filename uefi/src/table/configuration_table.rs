@@ -0,0 +1,15 @@
+use crate::types::{Guid, Void};
+
+/// ACPI 2.0以降のRSDPを指すエントリのGUID
+pub const ACPI_20_TABLE_GUID: Guid = Guid::new(
+    0x8868E871,
+    0xE4F1,
+    0x11D3,
+    [0xBC, 0x22, 0x00, 0x80, 0xC7, 0x3C, 0x88, 0x81],
+);
+
+#[repr(C)]
+pub struct ConfigurationTable {
+    pub vendor_guid: Guid,
+    pub vendor_table: *const Void,
+}
@@ -1,3 +1,4 @@
 pub mod boot_services;
+pub mod configuration_table;
 pub mod header;
 pub mod system_table;
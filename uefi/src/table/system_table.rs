@@ -3,7 +3,9 @@ use crate::{
     types::{Char16, Handle, Status, Uint32, Uintn},
 };
 
-use super::{boot_services::BootServices, header::TableHeader};
+use super::{
+    boot_services::BootServices, configuration_table::ConfigurationTable, header::TableHeader,
+};
 
 #[repr(C)]
 pub struct SystemTable {
@@ -22,8 +24,7 @@ pub struct SystemTable {
     pub boot_services: *const BootServices,
     // pub boot_services: *const usize,
     pub number_of_table_entries: Uintn,
-    // pub configuration_table: *const ConfigurationTable,
-    pub configuration_table: *const usize,
+    pub configuration_table: *const ConfigurationTable,
 }
 
 impl SystemTable {
@@ -35,6 +36,15 @@ impl SystemTable {
         unsafe { &mut *(self.con_out) }
     }
 
+    pub fn configuration_tables(&self) -> &[ConfigurationTable] {
+        if self.configuration_table.is_null() {
+            return &[];
+        }
+        unsafe {
+            core::slice::from_raw_parts(self.configuration_table, self.number_of_table_entries)
+        }
+    }
+
     pub fn clear_screen(&mut self) -> Status {
         let stdout = self.stdout();
         (stdout.clear_screen)(stdout)
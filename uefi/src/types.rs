@@ -100,7 +100,8 @@ impl CStr16 {
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
-#[repr(C, align(64))]
+// EFI_GUIDは64bit境界に配置する
+#[repr(C, align(8))]
 pub struct Guid {
     data_1: u32,
     data_2: u16,
@@ -0,0 +1,97 @@
+//! USB Audio Class 1.0の再生
+//!
+//! AudioStreamingインターフェースのうちIsoch OUTエンドポイントを持つ代替設定をSET_INTERFACEで選び、
+//! 1フレームごとにPCMを送る。サンプリング周波数などの設定はしないので、FORMAT_TYPEディスクリプタが
+//! 送る形式だけを受け付けている代替設定を選ぶ。
+
+use super::descriptor::{ClassSpecificDescriptor, EndpointDescriptor, InterfaceDescriptor};
+
+/// インターフェースディスクリプタのbInterfaceClass
+pub const AUDIO_CLASS: u8 = 0x01;
+pub const AUDIO_STREAMING_SUBCLASS: u8 = 0x02;
+
+/// QEMUの`usb-audio`が受け付ける形式。48kHz、16ビット、2チャンネルのPCM。
+pub const SAMPLE_RATE: usize = 48000;
+pub const CHANNELS: usize = 2;
+pub const SAMPLE_SIZE: usize = 2;
+/// AudioStreamingインターフェースのクラス固有ディスクリプタのbDescriptorSubtype
+const FORMAT_TYPE: u8 = 0x02;
+const FORMAT_TYPE_I: u8 = 0x01;
+
+/// 1秒に送るバイト数
+pub const BYTES_PER_SECOND: usize = SAMPLE_RATE * CHANNELS * SAMPLE_SIZE;
+
+/// 再生に使うAudioStreamingインターフェースの代替設定とエンドポイント
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Playback {
+    pub interface_number: u8,
+    pub alternate_setting: u8,
+    pub endpoint: EndpointDescriptor,
+}
+
+pub fn is_audio_streaming(desc: &InterfaceDescriptor) -> bool {
+    (desc.interface_class, desc.interface_sub_class) == (AUDIO_CLASS, AUDIO_STREAMING_SUBCLASS)
+}
+
+/// 再生に使えるIsoch OUTエンドポイントか
+pub fn is_playback_endpoint(desc: &EndpointDescriptor) -> bool {
+    desc.get_attributes_transfer_type() == 1 && !desc.get_endpoint_address_dir_in()
+}
+
+/// Type IのFORMAT_TYPEディスクリプタが、48kHz、16ビット、2チャンネルのPCMだけを受け付けているか。
+/// 複数の周波数や範囲を受け付けるものは、どれで再生されるかわからないので選ばない。
+pub fn is_supported_format(desc: &ClassSpecificDescriptor) -> bool {
+    if desc.subtype() != FORMAT_TYPE {
+        return false;
+    }
+    // bFormatType, bNrChannels, bSubframeSize, bBitResolution, bSamFreqType, tSamFreq[0]
+    let [FORMAT_TYPE_I, channels, subframe_size, _, 1, f0, f1, f2, ..] = *desc.body() else {
+        return false;
+    };
+    channels as usize == CHANNELS
+        && subframe_size as usize == SAMPLE_SIZE
+        && u32::from_le_bytes([f0, f1, f2, 0]) as usize == SAMPLE_RATE
+}
+
+/// 2^`interval`×125usのサービス間隔ごとに送るバイト数
+pub fn bytes_per_interval(interval: u8) -> usize {
+    (BYTES_PER_SECOND << interval.min(13)) / 8000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes_per_interval() {
+        // Full Speedの1フレームに48サンプル
+        assert_eq!(bytes_per_interval(3), 192);
+        assert_eq!(bytes_per_interval(0), 24);
+    }
+
+    #[test]
+    fn test_is_supported_format() {
+        let format = |bytes: &[u8]| ClassSpecificDescriptor::try_from(bytes).unwrap();
+        assert!(is_supported_format(&format(&[
+            11, 0x24, 2, 1, 2, 2, 16, 1, 0x80, 0xbb, 0x00
+        ])));
+        // 44.1kHz
+        assert!(!is_supported_format(&format(&[
+            11, 0x24, 2, 1, 2, 2, 16, 1, 0x44, 0xac, 0x00
+        ])));
+        // モノラル
+        assert!(!is_supported_format(&format(&[
+            11, 0x24, 2, 1, 1, 2, 16, 1, 0x80, 0xbb, 0x00
+        ])));
+        // 24ビットを3バイトで送る
+        assert!(!is_supported_format(&format(&[
+            11, 0x24, 2, 1, 2, 3, 24, 1, 0x80, 0xbb, 0x00
+        ])));
+        // 32kHzから48kHzの範囲
+        assert!(!is_supported_format(&format(&[
+            14, 0x24, 2, 1, 2, 2, 16, 0, 0x00, 0x7d, 0x00, 0x80, 0xbb, 0x00
+        ])));
+        // AS_GENERAL
+        assert!(!is_supported_format(&format(&[7, 0x24, 1, 1, 1, 1, 0])));
+    }
+}
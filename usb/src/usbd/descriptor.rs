@@ -1,3 +1,5 @@
+use core::{fmt, ops::Deref};
+
 use common::Zeroed;
 use macros::bitfield_struct;
 
@@ -24,49 +26,40 @@ pub enum Descriptor {
     Interface(InterfaceDescriptor),
     Endpoint(EndpointDescriptor),
     HIDDescriptor(HIDDescriptor),
+    ClassSpecificInterface(ClassSpecificDescriptor),
 }
 
 impl TryFrom<&[u8]> for Descriptor {
     type Error = TryFromBytesError;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        fn packed_size(ty: Type) -> usize {
-            use Type::*;
-            match ty {
-                Device => DeviceDescriptor::SIZE,
-                Configuration => ConfigurationDescriptor::SIZE,
-                String => todo!(),
-                Interface => InterfaceDescriptor::SIZE,
-                Endpoint => EndpointDescriptor::SIZE,
-                HID => HIDDescriptor::SIZE,
+        /// bLengthもスライスも構造体の大きさ以上あるときだけ読む
+        fn read<T: PackedSize + Copy>(len: u8, value: &[u8]) -> Result<T, TryFromBytesError> {
+            if (len as usize) < T::SIZE || value.len() < T::SIZE {
+                return Err(TryFromBytesError::InvalidLength);
             }
+            Ok(unsafe { *value.as_ptr().cast() })
         }
 
-        unsafe fn cast(ty: Type, value: &[u8]) -> Descriptor {
-            use Type::*;
-            unsafe {
-                match ty {
-                    Device => Descriptor::Device(*value.as_ptr().cast()),
-                    Configuration => Descriptor::Configuration(*value.as_ptr().cast()),
-                    String => todo!(),
-                    Interface => Descriptor::Interface(*value.as_ptr().cast()),
-                    Endpoint => Descriptor::Endpoint(*value.as_ptr().cast()),
-                    HID => Descriptor::HIDDescriptor(*value.as_ptr().cast()),
-                }
-            }
-        }
-
-        let [len, ty, ..] = value else {
+        let [len, ty, ..] = *value else {
             return Err(TryFromBytesError::InvalidLength);
         };
 
-        let ty = Type::try_from(*ty).map_err(|_| TryFromBytesError::InvalidType)?;
+        let ty = Type::try_from(ty).map_err(|_| TryFromBytesError::InvalidType)?;
 
-        if (*len as usize) < packed_size(ty) {
-            return Err(TryFromBytesError::InvalidLength);
+        use Type::*;
+        match ty {
+            Device => read(len, value).map(Descriptor::Device),
+            Configuration => read(len, value).map(Descriptor::Configuration),
+            // 文字列ディスクリプタは長さが決まっていないので`UsbString`で読む
+            String => Err(TryFromBytesError::InvalidType),
+            Interface => read(len, value).map(Descriptor::Interface),
+            Endpoint => read(len, value).map(Descriptor::Endpoint),
+            HID => read(len, value).map(Descriptor::HIDDescriptor),
+            ClassSpecificInterface => {
+                ClassSpecificDescriptor::try_from(value).map(Descriptor::ClassSpecificInterface)
+            }
         }
-
-        Ok(unsafe { cast(ty, value) })
     }
 }
 
@@ -78,6 +71,7 @@ pub enum Type {
     Interface = 4,
     Endpoint = 5,
     HID = 33,
+    ClassSpecificInterface = 36,
 }
 
 impl TryFrom<u8> for Type {
@@ -92,6 +86,7 @@ impl TryFrom<u8> for Type {
             4 => Ok(Interface),
             5 => Ok(Endpoint),
             33 => Ok(HID),
+            36 => Ok(ClassSpecificInterface),
             _ => Err(()),
         }
     }
@@ -224,6 +219,222 @@ pub struct HIDDescriptor {
 
 impl HIDDescriptor {
     pub const TYPE: Type = Type::HID;
+
+    /// 続くレポートディスクリプタの長さ
+    pub fn report_descriptor_length(&self) -> u16 {
+        self.report_descriptor_length
+    }
 }
 
 impl PackedSize for HIDDescriptor {}
+
+/// インターフェースに続くクラス固有のディスクリプタ。中身はクラスごとに違うのでバイト列のまま持つ。
+/// 入り切らない分は切り捨てる。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassSpecificDescriptor {
+    buf: [u8; Self::CAPACITY],
+    len: u8,
+}
+
+impl ClassSpecificDescriptor {
+    pub const CAPACITY: usize = 32;
+
+    /// bDescriptorSubtype
+    pub fn subtype(&self) -> u8 {
+        self.buf[2]
+    }
+
+    /// bDescriptorSubtypeより後ろ
+    pub fn body(&self) -> &[u8] {
+        &self.buf[3..self.len as usize]
+    }
+}
+
+impl TryFrom<&[u8]> for ClassSpecificDescriptor {
+    type Error = TryFromBytesError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let [len, ty, ..] = *value else {
+            return Err(TryFromBytesError::InvalidLength);
+        };
+        if ty != Type::ClassSpecificInterface as u8 {
+            return Err(TryFromBytesError::InvalidType);
+        }
+        let len = len as usize;
+        if len < 3 || value.len() < len {
+            return Err(TryFromBytesError::InvalidLength);
+        }
+
+        let len = len.min(Self::CAPACITY);
+        let mut buf = [0; Self::CAPACITY];
+        buf[..len].copy_from_slice(&value[..len]);
+        Ok(Self {
+            buf,
+            len: len as u8,
+        })
+    }
+}
+
+/// 文字列ディスクリプタ(UTF-16LE)をUTF-8に変換したもの。入り切らない分は切り捨てる。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct UsbString {
+    buf: [u8; Self::CAPACITY],
+    len: usize,
+}
+
+impl UsbString {
+    pub const CAPACITY: usize = 256;
+
+    pub fn as_str(&self) -> &str {
+        // 文字単位で書き込んでいるので常にUTF-8として正しい
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or_default()
+    }
+
+    fn push(&mut self, c: char) -> bool {
+        let len = c.len_utf8();
+        if self.len + len > Self::CAPACITY {
+            return false;
+        }
+        c.encode_utf8(&mut self.buf[self.len..]);
+        self.len += len;
+        true
+    }
+}
+
+impl TryFrom<&[u8]> for UsbString {
+    type Error = TryFromBytesError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let body = string_descriptor_body(value)?;
+        let units = body
+            .chunks_exact(2)
+            .map(|v| u16::from_le_bytes([v[0], v[1]]));
+
+        let mut s = Self {
+            buf: [0; Self::CAPACITY],
+            len: 0,
+        };
+        for c in char::decode_utf16(units) {
+            if !s.push(c.unwrap_or(char::REPLACEMENT_CHARACTER)) {
+                break;
+            }
+        }
+        Ok(s)
+    }
+}
+
+impl Deref for UsbString {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl fmt::Display for UsbString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for UsbString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// 文字列ディスクリプタ0に並んでいる言語ID
+pub fn language_ids(value: &[u8]) -> Result<impl Iterator<Item = u16> + '_, TryFromBytesError> {
+    let body = string_descriptor_body(value)?;
+    Ok(body
+        .chunks_exact(2)
+        .map(|v| u16::from_le_bytes([v[0], v[1]])))
+}
+
+/// bLengthとbDescriptorTypeを確かめてその後ろを返す
+fn string_descriptor_body(value: &[u8]) -> Result<&[u8], TryFromBytesError> {
+    let [len, ty, ..] = value else {
+        return Err(TryFromBytesError::InvalidLength);
+    };
+    if *ty != Type::String as u8 {
+        return Err(TryFromBytesError::InvalidType);
+    }
+    let len = *len as usize;
+    if len < 2 || value.len() < len {
+        return Err(TryFromBytesError::InvalidLength);
+    }
+    Ok(&value[2..len])
+}
+
+/// デバイスディスクリプタが指している文字列。インデックスが0なら`None`。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeviceStrings {
+    pub manufacturer: Option<UsbString>,
+    pub product: Option<UsbString>,
+    pub serial_number: Option<UsbString>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usb_string() {
+        let bytes = [10, 3, b'Q', 0, b'E', 0, b'M', 0, b'U', 0];
+        let s = UsbString::try_from(bytes.as_slice()).unwrap();
+        assert_eq!(s.as_str(), "QEMU");
+
+        // bLengthより後ろは無視する
+        let bytes = [6, 3, 0x42, 0x30, 0x3d, 0xd8, 0xff, 0xff];
+        let s = UsbString::try_from(bytes.as_slice()).unwrap();
+        assert_eq!(&*s, "あ\u{fffd}");
+
+        assert_eq!(
+            UsbString::try_from([4, 2, 0, 0].as_slice()),
+            Err(TryFromBytesError::InvalidType)
+        );
+        assert_eq!(
+            UsbString::try_from([8, 3, 0, 0].as_slice()),
+            Err(TryFromBytesError::InvalidLength)
+        );
+    }
+
+    #[test]
+    fn test_descriptor_string() {
+        assert_eq!(
+            Descriptor::try_from([4, 3, 9, 4].as_slice()),
+            Err(TryFromBytesError::InvalidType)
+        );
+        // bLengthが足りていても、スライスが短ければ読まない
+        assert_eq!(
+            Descriptor::try_from([9, 4, 0, 0].as_slice()),
+            Err(TryFromBytesError::InvalidLength)
+        );
+    }
+
+    #[test]
+    fn test_class_specific_descriptor() {
+        let bytes = [7, 0x24, 1, 1, 1, 1, 0, 0xff];
+        let Ok(Descriptor::ClassSpecificInterface(d)) = Descriptor::try_from(bytes.as_slice())
+        else {
+            panic!("not a class-specific descriptor");
+        };
+        assert_eq!(d.subtype(), 1);
+        assert_eq!(d.body(), [1, 1, 1, 0]);
+
+        assert_eq!(
+            Descriptor::try_from([2, 0x24].as_slice()),
+            Err(TryFromBytesError::InvalidLength)
+        );
+    }
+
+    #[test]
+    fn test_language_ids() {
+        let bytes = [6, 3, 0x09, 0x04, 0x11, 0x04];
+        let ids: [u16; 2] = {
+            let mut iter = language_ids(&bytes).unwrap();
+            [iter.next().unwrap(), iter.next().unwrap()]
+        };
+        assert_eq!(ids, [0x0409, 0x0411]);
+    }
+}
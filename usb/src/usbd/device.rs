@@ -1,18 +1,86 @@
 use core::{marker::PhantomPinned, pin::Pin};
 
-use super::endpoint::EndpointID;
-use crate::{
-    usbd::endpoint::HCP_ENDPOINT_ID,
-    xhci::{
-        device::{Device as XHCIDevice, SlotId, DEFAULT_TRANSFER_RING_SIZE},
-        doorbell::DCDoorbell,
-        ring::TCRing,
-        trb::{DataStage, SetupStage, StatusStage},
-    },
+use super::{
+    descriptor::Type as DescriptorType,
+    endpoint::{Direction, EndpointID, HCP_ENDPOINT_ID},
+    request::{hid, SetupPacket},
+};
+use crate::xhci::{
+    device::{Device as XHCIDevice, SlotId},
+    doorbell::DCDoorbell,
+    trb::{DataStage, Isoch, Normal, SetupStage, StatusStage, TransferEvent},
 };
 use common::{debug, Zeroed};
 
 pub const DEFAULT_BUF_SIZE: usize = 256;
+/// Normal TRBのバッファは64KiB境界をまたげない
+const TRB_BUFFER_BOUNDARY: u64 = 64 * 1024;
+/// 1つのTDに使うNormal TRBの最大数
+pub const MAX_TD_TRBS: usize = 8;
+/// 1つのTDで転送できるバイト数。バッファがどこにあっても`MAX_TD_TRBS`個のTRBに収まる。
+pub const MAX_TD_LENGTH: usize = (MAX_TD_TRBS - 1) * TRB_BUFFER_BOUNDARY as usize;
+/// Isoch TRBのFrame IDは11ビット
+const FRAME_ID_MASK: u16 = 0x7ff;
+
+/// 発行した転送の完了を待つためのハンドル
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Transfer {
+    slot_id: SlotId,
+    dci: u8,
+    /// TDのうちイベントを発生させうるTRBのアドレスと長さ。
+    /// 最後のTRBの完了か、途中のTRBのShort Packetでイベントが来る。
+    trbs: [(u64, u32); MAX_TD_TRBS],
+    trb_count: usize,
+}
+
+impl Transfer {
+    fn new(slot_id: SlotId, dci: u8, trb: u64, length: usize) -> Self {
+        let mut trbs = [(0, 0); MAX_TD_TRBS];
+        trbs[0] = (trb, length as u32);
+        Self {
+            slot_id,
+            dci,
+            trbs,
+            trb_count: 1,
+        }
+    }
+
+    fn trbs(&self) -> &[(u64, u32)] {
+        &self.trbs[..self.trb_count]
+    }
+
+    pub fn slot_id(&self) -> SlotId {
+        self.slot_id
+    }
+
+    pub fn dci(&self) -> u8 {
+        self.dci
+    }
+
+    /// IOCを立てたTDの最後のTRBのアドレス
+    pub fn trb_pointer(&self) -> u64 {
+        self.trbs()[self.trb_count - 1].0
+    }
+
+    /// `event`がこの転送の完了を通知するものか
+    pub fn is_completed_by(&self, event: &TransferEvent) -> bool {
+        event.get_control_slot_id() == self.slot_id
+            && event.get_control_endpoint_id() == self.dci
+            && self.trbs().iter().any(|t| t.0 == event.trb_pointer())
+    }
+
+    /// 実際に転送したバイト数。イベントが指すTRBより前のTRBは全部転送できている。
+    pub fn transferred(&self, event: &TransferEvent) -> usize {
+        let trbs = self.trbs();
+        let Some(idx) = trbs.iter().position(|t| t.0 == event.trb_pointer()) else {
+            return 0;
+        };
+        let before: usize = trbs[..idx].iter().map(|t| t.1 as usize).sum();
+        let residual = event.get_status_trb_transfer_length() as usize;
+        before + (trbs[idx].1 as usize).saturating_sub(residual)
+    }
+}
+
 pub struct Device<'a, 'b, const BUF: usize = DEFAULT_BUF_SIZE> {
     buf: &'b mut [u8; BUF],
     device: Pin<&'a mut XHCIDevice>,
@@ -28,10 +96,6 @@ impl<'a, 'b, const BUF: usize> Device<'a, 'b, BUF> {
         }
     }
 
-    unsafe fn ring_mut(&mut self, id: u8) -> &mut TCRing<DEFAULT_TRANSFER_RING_SIZE> {
-        unsafe { self.device.as_mut().get_unchecked_mut().ring_mut(id) }
-    }
-
     pub fn buf(&self) -> &[u8; BUF] {
         &self.buf
     }
@@ -40,150 +104,321 @@ impl<'a, 'b, const BUF: usize> Device<'a, 'b, BUF> {
         self.device.slot_id()
     }
 
-    pub fn request_device_descripter(
+    /// デフォルトコントロールエンドポイントにSetup/Data/Statusの各TRBを積んでドアベルを鳴らす。
+    ///
+    /// `data`は返したハンドルの完了イベントを受け取るまで有効である必要がある。
+    /// `dir`はData Stageの向きで、`data`が無ければ無視する。
+    pub fn control_transfer(
         &mut self,
-        endponint_id: EndpointID,
+        setup: SetupPacket,
+        data: Option<&mut [u8]>,
+        dir: Direction,
+        doorbell: DCDoorbell,
+    ) -> Transfer {
+        Self::transfer(self.device.as_mut(), setup, data, dir, doorbell)
+    }
+
+    fn transfer(
+        mut device: Pin<&mut XHCIDevice>,
+        setup: SetupPacket,
+        data: Option<&mut [u8]>,
+        dir: Direction,
         mut doorbell: DCDoorbell,
-        interface_num: u16,
-    ) {
-        let buf_len = BUF;
-        let setup = SetupStage::zeroed()
-            .with_parameter0_bm_request_type(0b10000000)
-            .with_parameter0_b_request(6)
-            .with_parameter0_w_value(0x0100)
-            .with_parameter1_w_index(interface_num)
-            .with_parameter1_w_length(buf_len as u16)
+    ) -> Transfer {
+        let data = data.filter(|buf| !buf.is_empty());
+        let length = data.as_ref().map_or(0, |buf| buf.len());
+        debug_assert!(length <= setup.length() as usize);
+
+        let transfer_type = match (&data, dir) {
+            (None, _) => 0,
+            (Some(_), Direction::Out) => 2,
+            (Some(_), Direction::In) => 3,
+        };
+        let setup_trb = SetupStage::zeroed()
+            .with_parameter0_bm_request_type(setup.request_type().raw())
+            .with_parameter0_b_request(setup.request())
+            .with_parameter0_w_value(setup.value())
+            .with_parameter1_w_index(setup.index())
+            .with_parameter1_w_length(setup.length())
             .with_status_trb_transfer_length(8)
-            .with_control_transfer_type(3)
-            // .with_remain_interrupt_on_completion(true)
+            .with_control_transfer_type(transfer_type)
             .with_remain_immediate_data(true);
 
-        let buf_ptr = self.buf.as_mut_ptr();
-        let data = DataStage::zeroed()
-            .with_buf_ptr_lo(buf_ptr as u32)
-            .with_buf_ptr_hi(((buf_ptr as usize) >> 32) as u32)
-            .with_status_trb_transfer_length(buf_len as u32)
-            .with_status_td_size(0)
-            .with_control_dir(true)
-            .with_remain_interrupt_on_completion(true);
-
-        let status = StatusStage::zeroed().with_control_direction(false);
-        // .with_remain_interrupt_on_completion(true);
-
-        let transfer_ring = unsafe { self.ring_mut(endponint_id.dci()) };
+        let slot_id = device.slot_id();
+        let dci = HCP_ENDPOINT_ID.dci();
+        let transfer_ring = unsafe { device.as_mut().get_unchecked_mut().ring_mut(dci) };
         debug!(
             "ring_ptr in request: {}",
             transfer_ring.as_mut_ptr() as usize
         );
-        transfer_ring.push(setup);
-        transfer_ring.push(data);
-        transfer_ring.push(status);
-        doorbell.notify_endpoint(HCP_ENDPOINT_ID.dci());
+        transfer_ring.push(setup_trb);
+
+        // Data Stageがあればそこで、なければStatus Stageで完了イベントを受け取る
+        let trb = match data {
+            Some(buf) => {
+                let buf_ptr = buf.as_mut_ptr();
+                let data_trb = DataStage::zeroed()
+                    .with_buf_ptr_lo(buf_ptr as u32)
+                    .with_buf_ptr_hi(((buf_ptr as usize) >> 32) as u32)
+                    .with_status_trb_transfer_length(length as u32)
+                    .with_status_td_size(0)
+                    .with_control_dir(dir == Direction::In)
+                    .with_remain_interrupt_on_completion(true);
+                let trb = transfer_ring.push(data_trb);
+                transfer_ring
+                    .push(StatusStage::zeroed().with_control_direction(dir == Direction::Out));
+                trb
+            }
+            None => transfer_ring.push(
+                StatusStage::zeroed()
+                    .with_control_direction(true)
+                    .with_remain_interrupt_on_completion(true),
+            ),
+        };
+        doorbell.notify_endpoint(dci);
+
+        Transfer::new(slot_id, dci, trb, length)
     }
 
-    pub fn request_configuration_descriptor(
+    /// Interrupt INエンドポイントにTDを1つ積む。`buf`の扱いは`control_transfer`と同じ。
+    pub fn interrupt_in(
         &mut self,
-        endponint_id: EndpointID,
-        mut doorbell: DCDoorbell,
-        interface_num: u16,
-    ) {
-        let buf_len = BUF;
-        let setup = SetupStage::zeroed()
-            .with_parameter0_bm_request_type(0b10000000)
-            .with_parameter0_b_request(6)
-            .with_parameter0_w_value(0x0200)
-            .with_parameter1_w_index(interface_num)
-            .with_parameter1_w_length(buf_len as u16)
-            .with_status_trb_transfer_length(8)
-            .with_control_transfer_type(3)
-            // .with_remain_interrupt_on_completion(true)
-            .with_remain_immediate_data(true);
+        endpoint_id: EndpointID,
+        buf: &mut [u8],
+        doorbell: DCDoorbell,
+    ) -> Transfer {
+        self.normal(endpoint_id, buf.as_mut_ptr() as u64, buf.len(), doorbell)
+    }
 
-        let buf_ptr = self.buf.as_mut_ptr();
-        let data = DataStage::zeroed()
-            .with_buf_ptr_lo(buf_ptr as u32)
-            .with_buf_ptr_hi(((buf_ptr as usize) >> 32) as u32)
-            .with_status_trb_transfer_length(buf_len as u32)
-            .with_status_td_size(0)
-            .with_control_dir(true)
-            .with_remain_interrupt_on_completion(true);
+    pub fn bulk_in(
+        &mut self,
+        endpoint_id: EndpointID,
+        buf: &mut [u8],
+        doorbell: DCDoorbell,
+    ) -> Transfer {
+        self.normal(endpoint_id, buf.as_mut_ptr() as u64, buf.len(), doorbell)
+    }
 
-        let status = StatusStage::zeroed().with_control_direction(false);
-        // .with_remain_interrupt_on_completion(true);
+    pub fn bulk_out(
+        &mut self,
+        endpoint_id: EndpointID,
+        buf: &[u8],
+        doorbell: DCDoorbell,
+    ) -> Transfer {
+        self.normal(endpoint_id, buf.as_ptr() as u64, buf.len(), doorbell)
+    }
 
-        let transfer_ring = unsafe { self.ring_mut(endponint_id.dci()) };
-        debug!(
-            "ring_ptr in request: {}",
-            transfer_ring.as_mut_ptr() as usize
-        );
-        transfer_ring.push(setup);
-        transfer_ring.push(data);
-        transfer_ring.push(status);
-        doorbell.notify_endpoint(HCP_ENDPOINT_ID.dci());
+    /// Isoch OUTエンドポイントにTDを1つ積む。`frame_id`のフレームで送り、
+    /// `None`ならxHCが前のTDに続けて送る。
+    pub fn isoch_out(
+        &mut self,
+        endpoint_id: EndpointID,
+        buf: &[u8],
+        frame_id: Option<u16>,
+        doorbell: DCDoorbell,
+    ) -> Transfer {
+        self.isoch(
+            endpoint_id,
+            buf.as_ptr() as u64,
+            buf.len(),
+            frame_id,
+            doorbell,
+        )
     }
 
-    pub fn request_boot_protocol_descriptor(
+    pub fn isoch_in(
         &mut self,
-        endponint_id: EndpointID,
-        mut doorbell: DCDoorbell,
-    ) {
-        let setup = SetupStage::zeroed()
-            .with_parameter0_bm_request_type(0b00100001)
-            .with_parameter0_b_request(11)
-            .with_parameter0_w_value(0)
-            .with_parameter1_w_index(0)
-            .with_parameter1_w_length(0)
-            .with_status_trb_transfer_length(8)
-            .with_control_transfer_type(0)
-            // .with_remain_interrupt_on_completion(true)
-            .with_remain_immediate_data(true);
+        endpoint_id: EndpointID,
+        buf: &mut [u8],
+        frame_id: Option<u16>,
+        doorbell: DCDoorbell,
+    ) -> Transfer {
+        self.isoch(
+            endpoint_id,
+            buf.as_mut_ptr() as u64,
+            buf.len(),
+            frame_id,
+            doorbell,
+        )
+    }
 
-        let transfer_ring = unsafe { self.ring_mut(endponint_id.dci()) };
-        debug!(
-            "ring_ptr in request: {}",
-            transfer_ring.as_mut_ptr() as usize
-        );
-        transfer_ring.push(setup);
-        doorbell.notify_endpoint(HCP_ENDPOINT_ID.dci());
+    /// 先頭をIsoch TRBにしたTDを積む。1つのTDが1回のサービス間隔の転送になる。
+    fn isoch(
+        &mut self,
+        endpoint_id: EndpointID,
+        buf_ptr: u64,
+        length: usize,
+        frame_id: Option<u16>,
+        doorbell: DCDoorbell,
+    ) -> Transfer {
+        let cx = &self.device.context.device_contexts[endpoint_id.dci() as usize - 1];
+        let max_packet_size = cx.get_data_1_max_packet_size().max(1) as usize;
+        let burst = cx.get_data_1_max_burst_size() as usize + 1;
+        // 長さ0でも1パケット送る
+        let packets = length.div_ceil(max_packet_size).max(1);
+
+        let isoch = Isoch::default()
+            .with_remain_transfer_burst_count((packets.div_ceil(burst) - 1) as u8)
+            .with_control_transfer_last_burst_packet_count(((packets - 1) % burst) as u8)
+            .with_control_frame_id(frame_id.unwrap_or(0) & FRAME_ID_MASK)
+            .with_control_start_isoch_asap(frame_id.is_none());
+        self.push_td(endpoint_id, buf_ptr, length, Some(isoch), doorbell)
+    }
+
+    fn normal(
+        &mut self,
+        endpoint_id: EndpointID,
+        buf_ptr: u64,
+        length: usize,
+        doorbell: DCDoorbell,
+    ) -> Transfer {
+        self.push_td(endpoint_id, buf_ptr, length, None, doorbell)
     }
 
-    pub fn request_mouse(
+    /// バッファを64KiB境界で区切ったTRBをChainでつないで積む。`isoch`があれば先頭をIsoch TRBにする。
+    /// IOCは最後のTRBだけに立て、Short Packetならそこでイベントが来るようにISPは全部に立てる。
+    /// `length`は`MAX_TD_LENGTH`まで。
+    fn push_td(
         &mut self,
-        endponint_id: EndpointID,
+        endpoint_id: EndpointID,
+        buf_ptr: u64,
+        length: usize,
+        mut isoch: Option<Isoch>,
         mut doorbell: DCDoorbell,
+    ) -> Transfer {
+        debug_assert!(length <= MAX_TD_LENGTH);
+        let slot_id = self.slot_id();
+        let dci = endpoint_id.dci();
+        let max_packet_size = self.device.context.device_contexts[dci as usize - 1]
+            .get_data_1_max_packet_size()
+            .max(1) as usize;
+
+        let mut transfer = Transfer::new(slot_id, dci, 0, 0);
+        transfer.trb_count = 0;
+        let transfer_ring = unsafe { self.device.as_mut().get_unchecked_mut() }.ring_mut(dci);
+        let mut chunks = trb_chunks(buf_ptr, length).peekable();
+        let mut remaining = length;
+        while let Some((ptr, len)) = chunks.next() {
+            remaining -= len;
+            let is_last = chunks.peek().is_none();
+            // TD Size: このTRBより後に残っているパケットの数
+            let td_size = remaining.div_ceil(max_packet_size).min(31) as u8;
+            let ptr = match isoch.take() {
+                Some(isoch) => transfer_ring.push(
+                    isoch
+                        .with_data_buffer_pointer(ptr)
+                        .with_status_trb_transfer_length(len as u32)
+                        .with_status_td_size(td_size)
+                        .with_remain_interrupt_on_short_packet(true)
+                        .with_remain_chain_bit(!is_last)
+                        .with_remain_interrupt_on_completion(is_last),
+                ),
+                None => transfer_ring.push(
+                    Normal::default()
+                        .with_data_buffer_pointer(ptr)
+                        .with_status_trb_transfer_length(len as u32)
+                        .with_status_td_size(td_size)
+                        .with_remain_interrupt_on_short_packet(true)
+                        .with_remain_chain_bit(!is_last)
+                        .with_remain_interrupt_on_completion(is_last),
+                ),
+            };
+            transfer.trbs[transfer.trb_count] = (ptr, len as u32);
+            transfer.trb_count += 1;
+        }
+        doorbell.notify_endpoint(dci);
+
+        transfer
+    }
+
+    pub fn request_device_descripter(&mut self, doorbell: DCDoorbell) -> Transfer {
+        let setup = SetupPacket::get_descriptor(DescriptorType::Device, 0, 0, BUF as u16);
+        Self::transfer(
+            self.device.as_mut(),
+            setup,
+            Some(&mut self.buf[..]),
+            Direction::In,
+            doorbell,
+        )
+    }
+
+    pub fn request_configuration_descriptor(&mut self, doorbell: DCDoorbell) -> Transfer {
+        let setup = SetupPacket::get_descriptor(DescriptorType::Configuration, 0, 0, BUF as u16);
+        Self::transfer(
+            self.device.as_mut(),
+            setup,
+            Some(&mut self.buf[..]),
+            Direction::In,
+            doorbell,
+        )
+    }
+
+    pub fn request_boot_protocol_descriptor(
+        &mut self,
+        doorbell: DCDoorbell,
         interface_num: u16,
-    ) {
-        let buf_len = BUF;
-        let setup = SetupStage::zeroed()
-            .with_parameter0_bm_request_type(0b10100001)
-            .with_parameter0_b_request(1)
-            .with_parameter0_w_value(0x0100)
-            .with_parameter1_w_index(interface_num)
-            .with_parameter1_w_length(buf_len as u16)
-            .with_status_trb_transfer_length(8)
-            .with_control_transfer_type(3)
-            // .with_remain_interrupt_on_completion(true)
-            .with_remain_immediate_data(true);
+    ) -> Transfer {
+        let setup = SetupPacket::hid_set_protocol(hid::PROTOCOL_BOOT, interface_num);
+        self.control_transfer(setup, None, Direction::Out, doorbell)
+    }
 
-        let buf_ptr = self.buf.as_mut_ptr();
-        let data = DataStage::zeroed()
-            .with_buf_ptr_lo(buf_ptr as u32)
-            .with_buf_ptr_hi(((buf_ptr as usize) >> 32) as u32)
-            .with_status_trb_transfer_length(buf_len as u32)
-            .with_status_td_size(0)
-            .with_control_dir(true)
-            .with_remain_interrupt_on_completion(true);
+    pub fn request_mouse(&mut self, doorbell: DCDoorbell, interface_num: u16) -> Transfer {
+        let setup =
+            SetupPacket::hid_get_report(hid::REPORT_TYPE_INPUT, 0, interface_num, BUF as u16);
+        Self::transfer(
+            self.device.as_mut(),
+            setup,
+            Some(&mut self.buf[..]),
+            Direction::In,
+            doorbell,
+        )
+    }
+}
 
-        let status = StatusStage::zeroed().with_control_direction(false);
+/// `buf`から`length`バイトを64KiB境界で区切ったアドレスと長さ。長さが0でも1つ返す。
+fn trb_chunks(buf: u64, length: usize) -> impl Iterator<Item = (u64, usize)> {
+    let end = buf + length as u64;
+    let mut next = Some(buf);
+    core::iter::from_fn(move || {
+        let ptr = next?;
+        let boundary = (ptr / TRB_BUFFER_BOUNDARY + 1) * TRB_BUFFER_BOUNDARY;
+        let chunk_end = boundary.min(end);
+        next = (chunk_end < end).then_some(chunk_end);
+        Some((ptr, (chunk_end - ptr) as usize))
+    })
+}
 
-        let transfer_ring = unsafe { self.ring_mut(endponint_id.dci()) };
-        debug!(
-            "ring_ptr in request: {}",
-            transfer_ring.as_mut_ptr() as usize
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    const K64: u64 = 64 * 1024;
+
+    fn chunks(buf: u64, length: usize) -> Vec<(u64, usize)> {
+        trb_chunks(buf, length).collect()
+    }
+
+    #[test]
+    fn test_trb_chunks() {
+        assert_eq!(chunks(0x1000, 0x100), [(0x1000, 0x100)]);
+        assert_eq!(chunks(0x1000, 0), [(0x1000, 0)]);
+        // 境界ちょうどで終わる
+        assert_eq!(chunks(K64, K64 as usize), [(K64, K64 as usize)]);
+        assert_eq!(
+            chunks(K64 - 0x10, K64 as usize + 0x20),
+            [(K64 - 0x10, 0x10), (K64, K64 as usize), (2 * K64, 0x10)]
         );
-        transfer_ring.push(setup);
-        transfer_ring.push(data);
-        transfer_ring.push(status);
-        doorbell.notify_endpoint(HCP_ENDPOINT_ID.dci());
+    }
+
+    #[test]
+    fn test_max_td_length() {
+        // どこから始まっても収まる
+        for buf in [0, 1, K64 / 2, K64 - 1] {
+            assert!(trb_chunks(buf, MAX_TD_LENGTH).count() <= MAX_TD_TRBS);
+        }
     }
 }
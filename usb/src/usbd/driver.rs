@@ -1,36 +1,174 @@
 use core::{
     ops::IndexMut,
     pin::{pin, Pin},
+    task::Poll,
 };
 
-use common::{info, map::FixedMap, Zeroed};
+use common::{block::BlockDevice, debug, error, info, map::FixedMap, Zeroed};
 
 use crate::{
-    usbd::descriptor::{
-        ConfigurationDescriptor, DeviceDescriptor, EndpointDescriptor, HIDDescriptor,
-        InterfaceDescriptor, PackedSize,
+    usbd::{
+        audio::{self, Playback},
+        descriptor::{
+            self, DeviceDescriptor, DeviceStrings, EndpointDescriptor, InterfaceDescriptor,
+            UsbString,
+        },
+        hub::{self, HubDescriptor, PortStatus},
+        isoch::{self, IsochHandler, Schedule},
+        keyboard::{self, KeyEvent, Keyboard},
+        mass_storage::{self, CommandBlockWrapper, CommandStatus, CommandStatusWrapper},
+        pointer::{self, Pointer, PointerEvent},
+        report_descriptor::ReportLayout,
+        request::{feature, hid, Recipient, SetupPacket, LANGUAGE_ID_EN_US},
+        scsi::{self, Capacity},
     },
     xhci::{
         context::{EndpointContxt, InputContext},
         device::SlotId,
         driver::{Controller, Running, Uninitialized},
+        error::Error as XHCIError,
         port::PortConfigPhase,
-        trb::{ConfigureEndpointCommand, Trb, TrbType, Type},
+        route::{speed, Route},
+        trb::{CompletionCode, ConfigureEndpointCommand, TransferEvent, Trb, Type},
     },
 };
 
 use super::{
     descriptor::Descriptor,
-    device::Device,
-    endpoint::HCP_ENDPOINT_ID,
+    device::{Device, Transfer, MAX_TD_LENGTH},
+    endpoint::{Direction, EndpointID, HCP_ENDPOINT_ID},
     error::{Error, Result},
 };
 
 const DEVICE_NUM: usize = 16;
+/// 割り込み転送でレポートを受け取るエンドポイントの数
+const INTERRUPT_ENDPOINT_NUM: usize = 32;
+
+/// ブートプロトコルで動かしているキーボード
+struct KeyboardDevice {
+    endpoint_id: EndpointID,
+    keyboard: Keyboard,
+    /// 転送中はxHCが書き込むので`Driver`ごと動かしてはいけない
+    buf: [u8; keyboard::REPORT_SIZE],
+    pending: Option<Transfer>,
+}
+
+/// レポートプロトコルで動かしているマウスやタブレット
+struct PointerDevice {
+    endpoint_id: EndpointID,
+    pointer: Pointer,
+    buf: [u8; POINTER_REPORT_SIZE],
+    /// エンドポイントの最大パケットサイズ
+    report_len: usize,
+    pending: Option<Transfer>,
+}
+
+/// コンフィギュレーションディスクリプタの中のBulk-Only Transportのインターフェース
+#[derive(Debug, Clone, Copy)]
+struct StorageInterface {
+    interface_number: u8,
+    bulk_in: EndpointID,
+    bulk_out: EndpointID,
+}
+
+/// Bulk-Only Transportで動かしているマスストレージ。LUNは0だけ使う。
+struct StorageDevice {
+    /// Bulk-Only Mass Storage Resetの宛先
+    interface_number: u8,
+    bulk_in: EndpointID,
+    bulk_out: EndpointID,
+    /// 次のCBWのタグ
+    tag: u32,
+    capacity: Capacity,
+}
+
+/// Isochエンドポイントで続けている転送
+struct IsochStream {
+    endpoint_id: EndpointID,
+    handler: IsochHandler,
+    schedule: Schedule,
+    /// 1つのTDで転送するバイト数
+    packet_len: usize,
+    /// 転送中はxHCが読み書きするので`Driver`ごと動かしてはいけない
+    bufs: [[u8; isoch::BUFFER_SIZE]; isoch::BUFFER_NUM],
+    /// `bufs`のそれぞれで転送中のTD
+    pending: [Option<Transfer>; isoch::BUFFER_NUM],
+}
+
+/// `submit_*`で発行した転送の状態
+#[derive(Debug)]
+enum Completion {
+    /// xHCが実行している
+    Pending,
+    /// 実行中に`discard_transfer`で捨てられた。完了したら枠を空ける。
+    Discarded,
+    Done(Result<TransferEvent>),
+}
+
+/// 割り込み転送の完了を受け取るクラスドライバ
+// アロケータが無いので`FixedMap`にそのまま置く
+#[allow(clippy::large_enum_variant)]
+enum InterruptDriver {
+    Keyboard(KeyboardDevice),
+    Pointer(PointerDevice),
+}
+
+impl InterruptDriver {
+    fn pending_mut(&mut self) -> &mut Option<Transfer> {
+        match self {
+            InterruptDriver::Keyboard(kbd) => &mut kbd.pending,
+            InterruptDriver::Pointer(ptr) => &mut ptr.pending,
+        }
+    }
+}
+
+const POINTER_REPORT_SIZE: usize = 64;
+/// コンフィギュレーションディスクリプタから読み取るディスクリプタの最大数。
+/// オーディオはクラス固有のディスクリプタが多い。
+const CONFIG_DESCRIPTOR_NUM: usize = 16;
+/// 読み込むレポートディスクリプタの最大の長さ
+const REPORT_DESCRIPTOR_SIZE: usize = 512;
+const MAX_HID_INTERFACES: usize = 4;
+/// READ(10)/WRITE(10)1回で転送する最大のバイト数
+const MAX_STORAGE_TRANSFER: usize = 64 * 1024;
+/// TEST UNIT READYを繰り返す回数
+const STORAGE_READY_RETRY: usize = 4;
+/// ハブのポートのリセットや電源投入の完了を確かめるGET_STATUSの回数
+const HUB_PORT_POLL_LIMIT: usize = 100;
+/// ハブのポートのGET_STATUSの間隔(1ms)
+const HUB_PORT_POLL_INTERVAL: u16 = 8;
+/// ハブの先のデバイスにアドレスが割り当てられるまで待つマイクロフレーム数(1s)
+const HUB_PORT_ADDRESS_TIMEOUT: u16 = 8 * 1000;
+/// `submit_*`で発行して完了を受け取っていない転送の最大数
+const MAX_PENDING_TRANSFERS: usize = 32;
+/// 同時に続けられるIsochのストリームの数
+const ISOCH_STREAM_NUM: usize = 4;
+
+/// コンフィギュレーションディスクリプタの中のHIDインターフェース
+#[derive(Debug, Clone, Copy)]
+struct HidInterface {
+    interface: InterfaceDescriptor,
+    report_descriptor_length: u16,
+    endpoint: EndpointDescriptor,
+}
 
 pub struct Driver<'a> {
     xhcid: Controller<'a, Running>,
-    devices: FixedMap<SlotId, (InputContext, bool)>,
+    devices: FixedMap<SlotId, (InputContext, bool), DEVICE_NUM>,
+    /// スロットIDとDCIで転送イベントの行き先を決める
+    interrupt_drivers: FixedMap<(SlotId, u8), InterruptDriver, INTERRUPT_ENDPOINT_NUM>,
+    key_handler: Option<fn(KeyEvent)>,
+    pointer_handler: Option<fn(PointerEvent)>,
+    storages: FixedMap<SlotId, StorageDevice, DEVICE_NUM>,
+    hubs: FixedMap<SlotId, HubDescriptor, DEVICE_NUM>,
+    detach_handler: Option<fn(SlotId)>,
+    /// 続けて送っているIsochのストリーム。スロットIDとDCIで引き、完了したTDの後に次のTDを積む。
+    isoch_streams: FixedMap<(SlotId, u8), IsochStream, ISOCH_STREAM_NUM>,
+    audios: FixedMap<SlotId, Playback, DEVICE_NUM>,
+    audio_handler: Option<fn(&mut [u8])>,
+    /// `submit_*`で発行した転送。完了したら`poll_transfer`で取り出されるか、
+    /// `discard_transfer`で捨てられるまで持っておく。
+    completions: FixedMap<Transfer, Completion, MAX_PENDING_TRANSFERS>,
 }
 
 impl<'a> Driver<'a> {
@@ -46,19 +184,232 @@ impl<'a> Driver<'a> {
         Ok(Self {
             xhcid,
             devices: FixedMap::new(),
+            interrupt_drivers: FixedMap::new(),
+            key_handler: None,
+            pointer_handler: None,
+            storages: FixedMap::new(),
+            hubs: FixedMap::new(),
+            detach_handler: None,
+            isoch_streams: FixedMap::new(),
+            audios: FixedMap::new(),
+            audio_handler: None,
+            completions: FixedMap::new(),
         })
     }
 
     pub fn process(&mut self) -> Result<()> {
-        let event = self.xhcid.process_primary_event()?;
+        if let Some(trb) = self.process_event()? {
+            debug!("unhandled event: {:?}", trb);
+        }
+        self.handle_detached();
+        self.handle_recovered()
+    }
+
+    /// xHCが停止から復旧させたエンドポイントの後始末。読み飛ばされた転送を取り消し、
+    /// デバイス側のHaltを解いて、割り込み転送なら次を積み直す。
+    /// 復旧に失敗したエンドポイントは、待っている転送をそのエラーで終わらせ、転送を積み直さずにエラーを返す。
+    fn handle_recovered(&mut self) -> Result<()> {
+        while let Some(((slot_id, dci), recovered)) = self.xhcid.pop_recovered_endpoint() {
+            let cancelled = match &recovered {
+                Ok(()) => Error::transfer_cancelled(),
+                Err(e) => Error::from(e.clone()),
+            };
+            self.fail_completions(|t| t.slot_id() == slot_id && t.dci() == dci, &cancelled);
+            if !self.xhcid.devices_mut().any(|d| d.slot_id() == slot_id) {
+                continue;
+            }
+            if recovered.is_err() {
+                if let Some(driver) = self.interrupt_drivers.get_mut(&(slot_id, dci)) {
+                    *driver.pending_mut() = None;
+                }
+                self.isoch_streams.remove(&(slot_id, dci));
+                return Err(cancelled);
+            }
 
+            // コントロールエンドポイントのSTALLは次のSetup Stageで解ける
+            if dci != HCP_ENDPOINT_ID.dci() {
+                let endpoint_id = EndpointID::from_dci(dci);
+                self.clear_feature(
+                    slot_id,
+                    Recipient::Endpoint,
+                    feature::ENDPOINT_HALT,
+                    endpoint_id.address() as u16,
+                )?;
+            }
+            if self.interrupt_drivers.contains_key(&(slot_id, dci)) {
+                self.poll_interrupt((slot_id, dci))?;
+            }
+            if let Some(stream) = self.isoch_streams.get_mut(&(slot_id, dci)) {
+                stream.pending = [None; isoch::BUFFER_NUM];
+                stream.schedule.reset();
+                self.fill_isoch((slot_id, dci))?;
+            }
+        }
         Ok(())
     }
 
+    /// xHCがReset EndpointとSet TR Dequeue Pointerを終えるまでイベントを処理して、後始末をする
+    fn wait_endpoint_recovery(&mut self, slot_id: SlotId, dci: u8) -> Result<()> {
+        while self.xhcid.is_recovering_endpoint(slot_id, dci) {
+            if let Some(trb) = self.process_event()? {
+                debug!("unhandled event: {:?}", trb);
+            }
+        }
+        self.handle_recovered()
+    }
+
+    /// イベントを1つ処理する。`submit_*`で発行した転送の完了は`completions`に記録し、
+    /// 割り込み転送の完了はクラスドライバに渡す。どちらでもないイベントを返す。
+    fn process_event(&mut self) -> Result<Option<Trb>> {
+        let Some(trb) = self.xhcid.process_primary_event()? else {
+            return Ok(None);
+        };
+        let Trb::TransferEvent(e) = trb else {
+            return Ok(Some(trb));
+        };
+
+        let transfer = self
+            .completions
+            .iter()
+            .find(|(t, _)| t.is_completed_by(&e))
+            .map(|(t, _)| *t);
+        if let Some(transfer) = transfer {
+            let completion = self.completions.get_mut(&transfer).unwrap();
+            if let Completion::Discarded = completion {
+                self.completions.remove(&transfer);
+            } else {
+                *completion = Completion::Done(e.check().map_err(Into::into));
+            }
+        } else if self
+            .interrupt_drivers
+            .contains_key(&(e.get_control_slot_id(), e.get_control_endpoint_id()))
+        {
+            self.handle_transfer_event(e)?;
+        } else if self
+            .isoch_streams
+            .contains_key(&(e.get_control_slot_id(), e.get_control_endpoint_id()))
+        {
+            self.handle_isoch_event(e)?;
+        } else {
+            return Ok(Some(trb));
+        }
+
+        Ok(None)
+    }
+
+    /// 取り外されたデバイスのスロットIDを受け取る関数。`process`の中から呼ばれる。
+    pub fn set_detach_handler(&mut self, handler: fn(SlotId)) {
+        self.detach_handler = Some(handler);
+    }
+
+    /// xHCがスロットを解放したデバイスをクラスドライバから取り除く。
+    /// 同じスロットIDが次のデバイスに使われる前に呼ばなければならない。
+    fn handle_detached(&mut self) {
+        while let Some(slot_id) = self.xhcid.pop_detached_slot() {
+            info!("slot {}: detached", slot_id);
+            self.release_slot(slot_id);
+            if let Some(handler) = self.detach_handler {
+                handler(slot_id);
+            }
+        }
+    }
+
+    /// スロットのドライバと待っている転送を片付ける
+    fn release_slot(&mut self, slot_id: SlotId) {
+        self.devices.remove(&slot_id);
+        self.interrupt_drivers.retain(|k, _| k.0 != slot_id);
+        self.storages.remove(&slot_id);
+        self.hubs.remove(&slot_id);
+        self.isoch_streams.retain(|k, _| k.0 != slot_id);
+        self.audios.remove(&slot_id);
+        self.fail_completions(
+            |t| t.slot_id() == slot_id,
+            &Error::device_not_found(slot_id),
+        );
+    }
+
+    /// 転送リングから外された転送を`error`で終わらせる。捨てられた転送はもう完了しないので枠を空ける。
+    fn fail_completions(&mut self, mut f: impl FnMut(&Transfer) -> bool, error: &Error) {
+        self.completions.retain(|t, completion| {
+            if !f(t) {
+                return true;
+            }
+            match completion {
+                Completion::Pending => {
+                    *completion = Completion::Done(Err(error.clone()));
+                    true
+                }
+                Completion::Discarded => false,
+                Completion::Done(_) => true,
+            }
+        });
+    }
+
+    /// キーボードのイベントを受け取る関数。`process`の中から呼ばれる。
+    pub fn set_keyboard_handler(&mut self, handler: fn(KeyEvent)) {
+        self.key_handler = Some(handler);
+    }
+
+    pub fn is_keyboard(&self, slot_id: SlotId) -> bool {
+        self.interrupt_drivers
+            .iter()
+            .any(|(k, d)| k.0 == slot_id && matches!(d, InterruptDriver::Keyboard(_)))
+    }
+
+    /// マウスやタブレットのイベントを受け取る関数。`process`の中から呼ばれる。
+    pub fn set_pointer_handler(&mut self, handler: fn(PointerEvent)) {
+        self.pointer_handler = Some(handler);
+    }
+
+    pub fn is_pointer(&self, slot_id: SlotId) -> bool {
+        self.interrupt_drivers
+            .iter()
+            .any(|(k, d)| k.0 == slot_id && matches!(d, InterruptDriver::Pointer(_)))
+    }
+
+    pub fn is_hub(&self, slot_id: SlotId) -> bool {
+        self.hubs.contains_key(&slot_id)
+    }
+
+    /// 再生するPCMを詰める関数。デバイスを設定するより前に渡しておく。`process`の中から呼ばれる。
+    /// 渡さなければ無音を送る。
+    pub fn set_audio_handler(&mut self, handler: fn(&mut [u8])) {
+        self.audio_handler = Some(handler);
+    }
+
+    pub fn is_audio(&self, slot_id: SlotId) -> bool {
+        self.audios.contains_key(&slot_id)
+    }
+
+    pub fn is_storage(&self, slot_id: SlotId) -> bool {
+        self.storages.contains_key(&slot_id)
+    }
+
+    /// マスストレージを`BlockDevice`として使う
+    pub fn storage(&mut self, slot_id: SlotId) -> Option<Storage<'_, 'a>> {
+        self.is_storage(slot_id).then_some(Storage {
+            driver: self,
+            slot_id,
+        })
+    }
+
+    /// event ringのイベントをキューに移す。割り込みハンドラから呼ぶ。
+    pub fn drain_events(&mut self) -> usize {
+        self.xhcid.drain_primary_events()
+    }
+
+    pub fn has_pending_events(&self) -> bool {
+        self.xhcid.has_pending_events()
+    }
+
+    /// アドレスが割り当てられてまだ設定していないデバイスを1つ設定する。なければ`None`。
+    /// 設定に失敗したデバイスはもう選ばないので、エラーが返っても続けて呼べば残りのデバイスを設定できる。
+    /// 設定の途中で取り外されたら、取り外しの後始末をしてから`DeviceNotFound`を返す。
     pub fn configure_device(&mut self) -> Result<Option<SlotId>> {
+        self.handle_detached();
         let slot_id = self.xhcid.devices_mut().find(|d| {
             let slot_id = d.slot_id();
-            self.devices.get(&slot_id).map(|v| !v.1).unwrap_or(true)
+            d.is_addressed() && self.devices.get(&slot_id).map(|v| !v.1).unwrap_or(true)
         });
 
         let Some(slot_id) = slot_id else {
@@ -66,17 +417,69 @@ impl<'a> Driver<'a> {
         };
 
         let slot_id = slot_id.slot_id();
+        let result = self.configure_slot(slot_id);
+        if result.is_err() && !self.xhcid.devices_mut().any(|d| d.slot_id() == slot_id) {
+            // 設定の途中で抜かれた。途中まで始めたドライバを片付けて、取り外しを知らせる。
+            // 取り外しがもう処理されていても、同じスロットIDで次のデバイスを設定できるようにしておく。
+            self.release_slot(slot_id);
+            self.handle_detached();
+        } else if result.is_err() {
+            // 失敗したデバイスも設定済みにして、同じデバイスで失敗し続けないようにする
+            match self.devices.get_mut(&slot_id) {
+                Some(v) => v.1 = true,
+                None => {
+                    let _ = self.devices.insert(slot_id, (InputContext::zeroed(), true));
+                }
+            }
+        }
+        result
+    }
+
+    /// デバイスを読んで、使えるインターフェースのドライバを始める
+    fn configure_slot(&mut self, slot_id: SlotId) -> Result<Option<SlotId>> {
         let d = self.get_device_descriptor(slot_id)?;
         let Some(device_descriptor) = d else {
             return Ok(None);
         };
         info!("{:?}", device_descriptor);
 
+        // 文字列やステータスはログに出すだけなので、STALLするデバイスでも設定を続ける
+        let strings = self
+            .get_device_strings(slot_id, &device_descriptor)
+            .unwrap_or_else(|e| {
+                error!("slot {}: failed to read strings: {:?}", slot_id, e);
+                DeviceStrings::default()
+            });
+        let status = self
+            .get_status(slot_id, Recipient::Device, 0)
+            .inspect_err(|e| error!("slot {}: failed to get status: {:?}", slot_id, e))
+            .ok();
+        info!(
+            "slot {}: manufacturer: {:?}, product: {:?}, serial: {:?}, status: {:x?}",
+            slot_id, strings.manufacturer, strings.product, strings.serial_number, status
+        );
+
         let d = self.get_config_descriptor(slot_id)?;
         let Some(configuration_descriptor) = d else {
             return Ok(None);
         };
         info!("{:?}", configuration_descriptor);
+        let configuration_value = configuration_descriptor
+            .iter()
+            .find_map(|v| match v {
+                Some(Descriptor::Configuration(c)) => Some(c.configuration_value),
+                _ => None,
+            })
+            .ok_or_else(Error::unexpected_descriptor)?;
+
+        let is_hub = device_descriptor.device_class == hub::HUB_CLASS
+            || configuration_descriptor.iter().any(|d| match d {
+                Some(Descriptor::Interface(i)) => hub::is_hub(i),
+                _ => false,
+            });
+        let hid_interfaces = Self::find_hid_interfaces(&configuration_descriptor);
+        let storage = Self::find_storage_interface(&configuration_descriptor);
+        let playback = Self::find_playback_interface(&configuration_descriptor);
 
         let descriptors = configuration_descriptor.iter().filter_map(|v| {
             v.iter().find_map(|v| match v {
@@ -85,52 +488,321 @@ impl<'a> Driver<'a> {
             })
         });
 
-        self.configure_endpoint(descriptors)?;
+        self.configure_endpoint(slot_id, descriptors)?;
+
+        self.set_configuration(slot_id, configuration_value)?;
+
+        let mut started = false;
+        if is_hub {
+            info!("slot {}: hub", slot_id);
+            self.start_hub(slot_id)?;
+            started = true;
+        } else if let Some(storage) = storage {
+            info!("slot {}: mass storage", slot_id);
+            self.start_storage(slot_id, storage)?;
+            started = true;
+        } else if let Some(playback) = playback {
+            info!("slot {}: audio playback", slot_id);
+            self.start_playback(slot_id, playback)?;
+            started = true;
+        }
+        for hid in hid_interfaces.iter().flatten() {
+            let interface = hid.interface.interface_number;
+            let endpoint_id = EndpointID::new(hid.endpoint.get_endpoint_address_number(), true);
+            if keyboard::is_boot_keyboard(&hid.interface) {
+                info!("slot {}: boot keyboard", slot_id);
+                self.set_boot_mode(slot_id, interface)?;
+                self.start_keyboard(slot_id, interface, endpoint_id)?;
+                started = true;
+            } else if let Some(pointer) = self.get_pointer(slot_id, hid)? {
+                info!("slot {}: pointer {:?}", slot_id, pointer);
+                self.start_pointer(slot_id, hid, pointer, false)?;
+                started = true;
+            } else if Self::is_boot_mouse(&hid.interface) {
+                // レポートディスクリプタを読めなかったマウスはブートプロトコルで使う
+                info!("slot {}: boot mouse", slot_id);
+                self.start_pointer(slot_id, hid, Pointer::boot_mouse(), true)?;
+                started = true;
+            }
+        }
+        if !started {
+            info!("slot {}: no supported interface", slot_id);
+        }
 
-        self.set_boot_mode()?;
+        if let Some(v) = self.devices.get_mut(&slot_id) {
+            v.1 = true;
+        }
 
         Ok(Some(slot_id))
     }
 
-    fn size(d: &Descriptor) -> usize {
-        match d {
-            Descriptor::Device(_) => DeviceDescriptor::SIZE,
-            Descriptor::Configuration(_) => ConfigurationDescriptor::SIZE,
-            Descriptor::Interface(_) => InterfaceDescriptor::SIZE,
-            Descriptor::Endpoint(_) => EndpointDescriptor::SIZE,
-            Descriptor::HIDDescriptor(_) => HIDDescriptor::SIZE,
+    /// `transfer`の完了イベントが来るまでイベントを処理する。ほかの転送の完了は`process_event`が振り分ける。
+    /// 途中でデバイスが取り外されたらエラーになる。
+    /// エンドポイントが停止したら、復旧させてからエラーを返す。
+    fn wait_transfer(&mut self, transfer: Transfer) -> Result<TransferEvent> {
+        loop {
+            match self.process_event()? {
+                Some(Trb::TransferEvent(e)) if transfer.is_completed_by(&e) => {
+                    if e.completion_code().halts_endpoint() {
+                        self.wait_endpoint_recovery(transfer.slot_id(), transfer.dci())?;
+                    }
+                    return Ok(e.check()?);
+                }
+                Some(trb) => debug!("unhandled event: {:?}", trb),
+                None => {}
+            }
+
+            let slot_id = transfer.slot_id();
+            if !self.xhcid.devices_mut().any(|d| d.slot_id() == slot_id) {
+                return Err(Error::device_not_found(slot_id));
+            }
+        }
+    }
+
+    /// `submit_*`で発行した転送の完了を確かめる。イベントを1つだけ処理して、まだなら`Pending`を返す。
+    /// `Ready`を返したハンドルはもう使えない。
+    pub fn poll_transfer(&mut self, transfer: &Transfer) -> Poll<Result<TransferEvent>> {
+        match self.completions.get(transfer) {
+            None | Some(Completion::Discarded) => {
+                return Poll::Ready(Err(Error::unknown_transfer()))
+            }
+            Some(Completion::Done(_)) => {}
+            Some(Completion::Pending) => {
+                if let Err(e) = self.process() {
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+
+        match self.completions.get(transfer) {
+            Some(Completion::Done(_)) => match self.completions.remove(transfer) {
+                Some(Completion::Done(result)) => Poll::Ready(result),
+                _ => unreachable!(),
+            },
+            _ => Poll::Pending,
+        }
+    }
+
+    /// 結果を受け取らない転送を捨てて、`submit_*`で使う枠を空ける。
+    /// 終わっていればすぐに、まだならxHCが完了を知らせたときに空く。
+    /// xHCは転送をやめないので、渡したバッファは完了するまで有効にしておかなければならない。
+    pub fn discard_transfer(&mut self, transfer: &Transfer) {
+        match self.completions.get_mut(transfer) {
+            Some(completion @ Completion::Pending) => *completion = Completion::Discarded,
+            Some(Completion::Done(_)) => {
+                self.completions.remove(transfer);
+            }
+            None | Some(Completion::Discarded) => {}
         }
     }
 
-    fn get_device_descriptor(&mut self, slot_id: SlotId) -> Result<Option<DeviceDescriptor>> {
-        let mut buf = [0; 32];
+    /// `poll_transfer`が`Ready`になるまで待つ
+    pub fn wait_completion(&mut self, transfer: &Transfer) -> Result<TransferEvent> {
+        loop {
+            if let Poll::Ready(result) = self.poll_transfer(transfer) {
+                return result;
+            }
+        }
+    }
+
+    fn reserve_completion(&self) -> Result<()> {
+        if self.completions.len() < MAX_PENDING_TRANSFERS {
+            Ok(())
+        } else {
+            Err(Error::too_many_transfers())
+        }
+    }
 
+    /// デフォルトコントロールエンドポイントでリクエストを発行して、完了を待たずにハンドルを返す。
+    ///
+    /// # Safety
+    /// `data`はxHCが転送を終えるまで有効で、動かしてはいけない。
+    /// `poll_transfer`が`Ready`を返せば終わっている。
+    pub unsafe fn submit_control_transfer(
+        &mut self,
+        slot_id: SlotId,
+        setup: SetupPacket,
+        data: Option<&mut [u8]>,
+    ) -> Result<Transfer> {
+        self.reserve_completion()?;
         let dev = unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }
             .devices_mut()
             .find(|d| d.slot_id() == slot_id)
-            .unwrap();
+            .ok_or_else(|| Error::device_not_found(slot_id))?;
 
-        let mut dev = Device::<32>::new(&mut buf, dev);
+        let mut dev = Device::new(&mut [0u8; 0], dev);
+        let doorbell = self.xhcid.doorbell_registers.slot(slot_id);
+        let transfer = dev.control_transfer(setup, data, setup.direction(), doorbell);
 
-        let slot_id = dev.slot_id();
+        self.completions
+            .insert(transfer, Completion::Pending)
+            .unwrap();
+        Ok(transfer)
+    }
+
+    /// Bulkエンドポイントに転送を積んで、完了を待たずにハンドルを返す。
+    /// 向きは`endpoint_id`で決まり、OUTなら`buf`の中身を送る。
+    /// `buf`は`MAX_TD_LENGTH`まで。
+    ///
+    /// # Safety
+    /// `buf`はxHCが転送を終えるまで有効で、動かしてはいけない。
+    /// `poll_transfer`が`Ready`を返せば終わっている。
+    pub unsafe fn submit_bulk_transfer(
+        &mut self,
+        slot_id: SlotId,
+        endpoint_id: EndpointID,
+        buf: &mut [u8],
+    ) -> Result<Transfer> {
+        if buf.len() > MAX_TD_LENGTH {
+            return Err(Error::transfer_too_long(buf.len()));
+        }
+        self.reserve_completion()?;
+        let dev = unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }
+            .devices_mut()
+            .find(|d| d.slot_id() == slot_id)
+            .ok_or_else(|| Error::device_not_found(slot_id))?;
         let doorbell = self.xhcid.doorbell_registers.slot(slot_id);
-        dev.request_device_descripter(HCP_ENDPOINT_ID, doorbell, 0);
 
-        let a = loop {
-            if let Some(v) = self.xhcid.process_primary_event()? {
-                break v;
-            }
+        let mut dev = Device::new(&mut [0u8; 0], dev);
+        let transfer = match endpoint_id.direction() {
+            Direction::In => dev.bulk_in(endpoint_id, buf, doorbell),
+            Direction::Out => dev.bulk_out(endpoint_id, buf, doorbell),
         };
 
-        let Trb::TransferEvent(e) = a else {
-            return Err(Error::unexpected_trb(TrbType::TransferEvent, a));
+        self.completions
+            .insert(transfer, Completion::Pending)
+            .unwrap();
+        Ok(transfer)
+    }
+
+    /// デフォルトコントロールエンドポイントでリクエストを発行して完了を待つ。
+    /// Data Stageで転送したバイト数を返す。
+    pub fn control_transfer(
+        &mut self,
+        slot_id: SlotId,
+        setup: SetupPacket,
+        data: Option<&mut [u8]>,
+    ) -> Result<usize> {
+        let dev = unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }
+            .devices_mut()
+            .find(|d| d.slot_id() == slot_id)
+            .ok_or_else(|| Error::device_not_found(slot_id))?;
+
+        let mut dev = Device::new(&mut [0u8; 0], dev);
+        let doorbell = self.xhcid.doorbell_registers.slot(slot_id);
+        let transfer = dev.control_transfer(setup, data, setup.direction(), doorbell);
+
+        let e = self.wait_transfer(transfer)?;
+        Ok(transfer.transferred(&e))
+    }
+
+    /// BulkかInterruptのエンドポイントと`buf`の間で転送して完了を待つ。
+    /// 向きは`endpoint_id`で決まる。長さに制限はなく、`MAX_TD_LENGTH`ずつのTDに分けて積む。
+    /// INでShort Packetが来たらそこで止め、それまでに転送したバイト数を返す。
+    pub fn normal_transfer(
+        &mut self,
+        slot_id: SlotId,
+        endpoint_id: EndpointID,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let data = match endpoint_id.direction() {
+            Direction::In => DataPhase::In(buf),
+            Direction::Out => DataPhase::Out(buf),
         };
+        self.bulk_transfer(slot_id, endpoint_id, data)
+    }
+
+    pub fn set_configuration(&mut self, slot_id: SlotId, value: u8) -> Result<()> {
+        self.control_transfer(slot_id, SetupPacket::set_configuration(value), None)?;
+        Ok(())
+    }
+
+    pub fn get_status(&mut self, slot_id: SlotId, recipient: Recipient, index: u16) -> Result<u16> {
+        let mut buf = [0u8; 2];
+        let setup = SetupPacket::get_status(recipient, index);
+        self.control_transfer(slot_id, setup, Some(&mut buf))?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    pub fn clear_feature(
+        &mut self,
+        slot_id: SlotId,
+        recipient: Recipient,
+        feature: u16,
+        index: u16,
+    ) -> Result<()> {
+        let setup = SetupPacket::clear_feature(recipient, feature, index);
+        self.control_transfer(slot_id, setup, None)?;
+        Ok(())
+    }
+
+    pub fn get_string(
+        &mut self,
+        slot_id: SlotId,
+        index: u8,
+        language_id: u16,
+    ) -> Result<UsbString> {
+        let mut buf = [0u8; 255];
+        let setup = SetupPacket::get_string(index, language_id, buf.len() as u16);
+        let len = self.control_transfer(slot_id, setup, Some(&mut buf))?;
+        Ok(UsbString::try_from(&buf[..len])?)
+    }
 
-        info!("{:?}", e.get_status_completion_code());
-        if !e.get_status_completion_code().is_success() {
-            // todo!("{:?}", e.get_status_completion_code())
+    /// 文字列ディスクリプタ0の先頭の言語ID
+    pub fn get_language_id(&mut self, slot_id: SlotId) -> Result<Option<u16>> {
+        let mut buf = [0u8; 255];
+        let setup = SetupPacket::get_string(0, 0, buf.len() as u16);
+        let len = self.control_transfer(slot_id, setup, Some(&mut buf))?;
+        let language_id = descriptor::language_ids(&buf[..len])?.next();
+        Ok(language_id)
+    }
+
+    /// 文字列を一つも持たないデバイスには何も要求しない
+    pub fn get_device_strings(
+        &mut self,
+        slot_id: SlotId,
+        device_descriptor: &DeviceDescriptor,
+    ) -> Result<DeviceStrings> {
+        let indices = [
+            device_descriptor.manufactuer,
+            device_descriptor.product,
+            device_descriptor.serial_number,
+        ];
+        if indices.iter().all(|&i| i == 0) {
+            return Ok(DeviceStrings::default());
         }
 
+        let language_id = self.get_language_id(slot_id)?.unwrap_or(LANGUAGE_ID_EN_US);
+        let [manufacturer, product, serial_number] = indices.map(|i| (i != 0).then_some(i));
+        let mut get = |index: Option<u8>| -> Result<Option<UsbString>> {
+            index
+                .map(|i| self.get_string(slot_id, i, language_id))
+                .transpose()
+        };
+
+        Ok(DeviceStrings {
+            manufacturer: get(manufacturer)?,
+            product: get(product)?,
+            serial_number: get(serial_number)?,
+        })
+    }
+
+    fn get_device_descriptor(&mut self, slot_id: SlotId) -> Result<Option<DeviceDescriptor>> {
+        let mut buf = [0; 32];
+
+        let dev = unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }
+            .devices_mut()
+            .find(|d| d.slot_id() == slot_id)
+            .unwrap();
+
+        let mut dev = Device::<32>::new(&mut buf, dev);
+
+        let slot_id = dev.slot_id();
+        let doorbell = self.xhcid.doorbell_registers.slot(slot_id);
+        let transfer = dev.request_device_descripter(doorbell);
+
+        self.wait_transfer(transfer)?;
+
         let Descriptor::Device(v) = Descriptor::try_from(buf.as_slice())? else {
             return Err(Error::unexpected_descriptor());
         };
@@ -141,7 +813,7 @@ impl<'a> Driver<'a> {
     fn get_config_descriptor(
         &mut self,
         slot_id: SlotId,
-    ) -> Result<Option<[Option<Descriptor>; 10]>> {
+    ) -> Result<Option<[Option<Descriptor>; CONFIG_DESCRIPTOR_NUM]>> {
         let mut buf = [0; 256];
         let dev = unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }
             .devices_mut()
@@ -151,36 +823,32 @@ impl<'a> Driver<'a> {
         let mut dev = Device::new(&mut buf, dev);
         let slot_id = dev.slot_id();
         let doorbell = self.xhcid.doorbell_registers.slot(slot_id);
-        dev.request_configuration_descriptor(HCP_ENDPOINT_ID, doorbell, 0);
-
-        let a = loop {
-            if let Some(v) = self.xhcid.process_primary_event()? {
-                break v;
-            }
-        };
-
-        let Trb::TransferEvent(e) = a else {
-            return Err(Error::unexpected_trb(TrbType::TransferEvent, a));
-        };
+        let transfer = dev.request_configuration_descriptor(doorbell);
 
-        if !e.get_status_completion_code().is_success() {
-            info!("{:?}", e.get_status_completion_code());
-        }
+        let e = self.wait_transfer(transfer)?;
 
-        let len = buf.len() - e.get_status_trb_transfer_length() as usize;
-        let mut tmp: [Option<Descriptor>; 10] = [(); 10].map(|_| None);
+        let len = transfer.transferred(&e);
+        let mut tmp: [Option<Descriptor>; CONFIG_DESCRIPTOR_NUM] =
+            [(); CONFIG_DESCRIPTOR_NUM].map(|_| None);
 
-        let mut read_bytes = 0;
         let mut idx = 0;
-
-        let mut buf = buf.as_slice();
-        while read_bytes < len {
-            let d = Descriptor::try_from(buf)?;
-            let size = Self::size(&d);
-            tmp[idx] = Some(d);
-            idx += 1;
-            read_bytes += size;
-            buf = &buf[size..];
+        let mut buf = &buf[..len];
+        // bLengthずつ読み進める。オーディオのエンドポイントディスクリプタは標準より長い。
+        while let [length, ..] = *buf {
+            let length = length as usize;
+            if length < 2 || length > buf.len() || idx == tmp.len() {
+                break;
+            }
+            match Descriptor::try_from(buf) {
+                Ok(d) => {
+                    tmp[idx] = Some(d);
+                    idx += 1;
+                }
+                // クラス固有のディスクリプタは読み飛ばす
+                Err(descriptor::TryFromBytesError::InvalidType) => {}
+                Err(e) => return Err(e.into()),
+            }
+            buf = &buf[length..];
         }
 
         Ok(Some(tmp))
@@ -188,9 +856,27 @@ impl<'a> Driver<'a> {
 
     fn configure_endpoint<'b>(
         &mut self,
+        slot_id: SlotId,
         descriptors: impl Iterator<Item = &'b EndpointDescriptor>,
     ) -> Result<()> {
-        fn configute_ep_cx(cx: &mut EndpointContxt, desc: &EndpointDescriptor) {
+        fn configute_ep_cx(cx: &mut EndpointContxt, desc: &EndpointDescriptor, speed: u8) {
+            if desc.get_attributes_transfer_type() == 1 {
+                let max_packet_size = desc.get_max_packet_size();
+                // High Speedではビット12:11が1マイクロフレームに追加するトランザクションの数
+                let burst = if speed == speed::HIGH {
+                    ((max_packet_size >> 11) & 0b11) as u8
+                } else {
+                    0
+                };
+                cx.init_isoch_endpoint(
+                    desc.get_endpoint_address_dir_in(),
+                    max_packet_size & 0x7ff,
+                    burst,
+                    isoch_interval(speed, desc.get_interval()),
+                );
+                return;
+            }
+
             let ty = match (
                 desc.get_endpoint_address_dir_in(),
                 desc.get_attributes_transfer_type(),
@@ -217,12 +903,11 @@ impl<'a> Driver<'a> {
 
         let mut dev_iter = unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }.devices_mut();
 
-        let Some(dev) = dev_iter.find(|v| !self.devices.contains_key(&v.slot_id())) else {
-            return Ok(());
+        let Some(mut dev) = dev_iter.find(|v| v.slot_id() == slot_id) else {
+            return Err(Error::device_not_found(slot_id));
         };
 
-        let slot_id = dev.slot_id();
-
+        let speed = dev.context.slot_context.get_data_0_speed();
         let mut input_context = InputContext::zeroed();
         input_context.slot = dev.context.slot_context;
         input_context.enable_slot_context();
@@ -232,14 +917,31 @@ impl<'a> Driver<'a> {
                 desc.get_endpoint_address_number() * 2 + desc.get_endpoint_address_dir_in() as u8;
             input_context.enable_endpoint(dci);
 
-            configute_ep_cx(input_context.ep_contexts.index_mut(dci as usize), desc);
+            // ep_contextsの先頭はDCI 1
+            let cx = input_context.ep_contexts.index_mut(dci as usize - 1);
+            configute_ep_cx(cx, desc, speed);
+
+            let ring = unsafe { dev.as_mut().get_unchecked_mut() }.ring_mut(dci);
+            let ring_ptr = ring.as_ptr() as usize;
+            cx.set_data_2_tr_dequeue_pointer_lo((ring_ptr as u32) >> 4);
+            cx.set_data_3_tr_dequeue_pointer_hi((ring_ptr >> 32) as u32);
+            cx.set_data_2_dequeue_cycle_state(ring.cycle_bit());
         }
         self.devices
             .insert(slot_id, (input_context, false))
             .unwrap();
         drop(dev_iter);
 
-        let ptr = &self.devices.get(&slot_id).unwrap().0 as *const InputContext as usize;
+        self.issue_configure_endpoint(slot_id)
+    }
+
+    /// `devices`に入っているInput ContextでConfigure Endpointコマンドを発行して完了を待つ
+    fn issue_configure_endpoint(&mut self, slot_id: SlotId) -> Result<()> {
+        let ptr = &self
+            .devices
+            .get(&slot_id)
+            .ok_or_else(|| Error::device_not_found(slot_id))?
+            .0 as *const InputContext as usize;
         let cycle_bit = self.xhcid.cx.command_ring().cycle_bit();
         let trb = ConfigureEndpointCommand::zeroed()
             .with_parameter0_input_context_ptr_lo((ptr as u32) >> 4)
@@ -247,92 +949,1785 @@ impl<'a> Driver<'a> {
             .with_control_slot_id(slot_id)
             .with_remain_cycle_bit(cycle_bit);
 
-        unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }.issue_command(trb);
+        let command = unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }.issue_command(trb);
         self.xhcid
             .doorbell_registers
             .host_controller_mut()
             .notify_host_controller();
 
         let e = loop {
-            if let Some(x) = self.xhcid.process_primary_event()? {
-                if let Trb::CommandCompletionEvent(e) = x {
-                    break e;
-                } else {
-                    info!("{:?}", x);
+            match self.process_event()? {
+                Some(Trb::CommandCompletionEvent(e)) if e.command_trb_pointer() == command => {
+                    break e
                 }
+                Some(trb) => debug!("unhandled event: {:?}", trb),
+                None => {}
             }
         };
 
         if !e.is_success() {
-            info!("{:?}", e.get_status_completion_code());
+            let issuer = unsafe { e.issuer() };
+            return Err(
+                XHCIError::command_not_success(e.get_status_completion_code(), issuer).into(),
+            );
         }
-
-        info!("{:?}", e);
-
         Ok(())
     }
 
-    fn set_boot_mode(&mut self) -> Result<()> {
-        let mut dev_iter = unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }.devices_mut();
-
-        let Some(dev) = dev_iter.find(|v| !self.devices.contains_key(&v.slot_id())) else {
-            return Ok(());
-        };
-
-        let slot_id = dev.slot_id();
+    fn set_boot_mode(&mut self, slot_id: SlotId, interface: u8) -> Result<()> {
+        let dev = unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }
+            .devices_mut()
+            .find(|d| d.slot_id() == slot_id)
+            .ok_or_else(|| Error::device_not_found(slot_id))?;
         let doorbell = self.xhcid.doorbell_registers.slot(slot_id);
 
         let mut dev = Device::new(&mut [0u8; 0], dev);
-        dev.request_boot_protocol_descriptor(HCP_ENDPOINT_ID, doorbell);
+        let transfer = dev.request_boot_protocol_descriptor(doorbell, interface as u16);
 
-        drop(dev_iter);
+        self.wait_transfer(transfer)?;
 
-        let a = loop {
-            if let Some(v) = self.xhcid.process_primary_event()? {
-                break v;
+        Ok(())
+    }
+
+    /// Interrupt INエンドポイントを持つHIDインターフェース
+    fn find_hid_interfaces(
+        descriptors: &[Option<Descriptor>],
+    ) -> [Option<HidInterface>; MAX_HID_INTERFACES] {
+        let mut found = [None; MAX_HID_INTERFACES];
+        let mut found_len = 0;
+        let mut current: Option<(InterfaceDescriptor, u16)> = None;
+        for d in descriptors.iter().flatten() {
+            match d {
+                Descriptor::Interface(i) => {
+                    current = (i.interface_class == keyboard::HID_CLASS).then_some((*i, 0));
+                }
+                Descriptor::HIDDescriptor(h) => {
+                    if let Some((_, len)) = current.as_mut() {
+                        *len = h.report_descriptor_length();
+                    }
+                }
+                Descriptor::Endpoint(e)
+                    if e.get_endpoint_address_dir_in() && e.get_attributes_transfer_type() == 3 =>
+                {
+                    let Some((interface, report_descriptor_length)) = current.take() else {
+                        continue;
+                    };
+                    if let Some(slot) = found.get_mut(found_len) {
+                        *slot = Some(HidInterface {
+                            interface,
+                            report_descriptor_length,
+                            endpoint: *e,
+                        });
+                        found_len += 1;
+                    }
+                }
+                _ => {}
             }
-        };
+        }
+        found
+    }
 
-        let Trb::TransferEvent(e) = a else {
-            return Err(Error::unexpected_trb(TrbType::TransferEvent, a));
-        };
+    /// ハブディスクリプタをSlot Contextに反映し、下流ポートの電源を入れてつながっているデバイスを列挙する
+    /// ポートの状態変化は追わないので、列挙した後に差されたデバイスは見つけられない。
+    fn start_hub(&mut self, slot_id: SlotId) -> Result<()> {
+        let mut buf = [0u8; hub::MAX_DESCRIPTOR_LEN];
+        let setup = SetupPacket::get_hub_descriptor(buf.len() as u16);
+        let len = self.control_transfer(slot_id, setup, Some(&mut buf))?;
+        let hub_descriptor =
+            HubDescriptor::parse(&buf[..len]).ok_or_else(Error::unexpected_descriptor)?;
+        info!("slot {}: {:?}", slot_id, hub_descriptor);
+
+        let (input_context, _) = self
+            .devices
+            .get_mut(&slot_id)
+            .ok_or_else(|| Error::device_not_found(slot_id))?;
+        input_context.clear_context_flags();
+        input_context.enable_slot_context();
+        let slot = &mut input_context.slot;
+        slot.set_data_0_hub(true);
+        slot.set_data_1_number_of_ports(hub_descriptor.num_ports);
+        if slot.get_data_0_speed() == speed::HIGH {
+            slot.set_data_2_ttt(hub_descriptor.think_time());
+        }
+        let hub_route = Route::from_slot_context(slot);
+        self.issue_configure_endpoint(slot_id)?;
+        self.hubs.insert(slot_id, hub_descriptor).unwrap();
 
-        info!("{:?}", e);
+        for port in 1..=hub_descriptor.num_ports {
+            let setup = SetupPacket::set_port_feature(hub::port_feature::PORT_POWER, port);
+            self.control_transfer(slot_id, setup, None)?;
+        }
 
-        self.devices.get_mut(&slot_id).unwrap().1 = true;
+        for port in 1..=hub_descriptor.num_ports {
+            self.wait_hub_port(slot_id, port, |s| s.is_powered())?;
+        }
+        // PORT_POWERが立っても、bPwrOn2PwrGoodが過ぎるまでは接続状態が当てにならない
+        self.xhcid
+            .wait_microframes(hub_descriptor.power_on_to_good_microframes());
 
+        // 割り込みエンドポイントは読まないので、ここでつながっていないポートはもう見ない
+        for port in 1..=hub_descriptor.num_ports {
+            let status = self.get_port_status(slot_id, port)?;
+            if !status.is_connected() {
+                continue;
+            }
+            // 1つのポートの失敗で他のポートを諦めない
+            if let Err(e) = self.enumerate_hub_port(slot_id, hub_route, port) {
+                error!("slot {}: hub port {}: {:?}", slot_id, port, e);
+            }
+        }
         Ok(())
     }
 
-    pub fn get_mouse(&mut self, slot_id: SlotId) -> Result<[u8; 3]> {
-        let mut buf = [0; 3];
-
-        let dev = unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }
-            .devices_mut()
-            .find(|d| d.slot_id() == slot_id)
-            .unwrap();
+    fn get_port_status(&mut self, slot_id: SlotId, port: u8) -> Result<PortStatus> {
+        let mut buf = [0u8; 4];
+        self.control_transfer(slot_id, SetupPacket::get_port_status(port), Some(&mut buf))?;
+        Ok(PortStatus::from_bytes(buf))
+    }
 
-        let mut dev = Device::new(&mut buf, dev);
+    /// `f`がtrueを返すまで、`HUB_PORT_POLL_INTERVAL`ごとにポートの状態を読む
+    fn wait_hub_port(
+        &mut self,
+        slot_id: SlotId,
+        port: u8,
+        f: impl Fn(&PortStatus) -> bool,
+    ) -> Result<PortStatus> {
+        for _ in 0..HUB_PORT_POLL_LIMIT {
+            let status = self.get_port_status(slot_id, port)?;
+            if f(&status) {
+                return Ok(status);
+            }
+            self.xhcid.wait_microframes(HUB_PORT_POLL_INTERVAL);
+        }
+        Err(Error::hub_port_timeout(slot_id, port))
+    }
 
-        let slot_id = dev.slot_id();
-        let doorbell = self.xhcid.doorbell_registers.slot(slot_id);
-        dev.request_mouse(HCP_ENDPOINT_ID, doorbell, 0);
+    /// ポートをリセットして有効にし、xHCにスロットとアドレスを割り当てさせる
+    fn enumerate_hub_port(
+        &mut self,
+        hub_slot_id: SlotId,
+        hub_route: Route,
+        port: u8,
+    ) -> Result<SlotId> {
+        let setup = SetupPacket::set_port_feature(hub::port_feature::PORT_RESET, port);
+        self.control_transfer(hub_slot_id, setup, None)?;
+        let status = self.wait_hub_port(hub_slot_id, port, |s| s.is_reset_changed())?;
+        for feature in [
+            hub::port_feature::C_PORT_RESET,
+            hub::port_feature::C_PORT_CONNECTION,
+        ] {
+            let setup = SetupPacket::clear_port_feature(feature, port);
+            self.control_transfer(hub_slot_id, setup, None)?;
+        }
+        if !status.is_enabled() {
+            return Err(XHCIError::port_disabled().into());
+        }
+
+        let route = hub_route
+            .child(hub_slot_id, port, status.speed())
+            .ok_or_else(XHCIError::invalid_port_id)?;
+
+        // ルートポートの処理中なら終わるまで待つ
+        loop {
+            match self.xhcid.enumerate_route(route) {
+                Ok(()) => break,
+                Err(e) if e == XHCIError::already_port_processing() => self.process()?,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // ハブが外されたり、コマンドが完了しなかったりしたら諦めて、次のポートを処理できるようにする
+        let start = self.xhcid.microframe_index();
+        loop {
+            match self.process_event()? {
+                Some(Trb::CommandCompletionEvent(e))
+                    if matches!(unsafe { e.issuer() }, Trb::AddressDeviceCommand(_)) =>
+                {
+                    let slot_id = e.get_control_slot_id();
+                    info!("slot {}: hub {} port {}", slot_id, hub_slot_id, port);
+                    return Ok(slot_id);
+                }
+                Some(trb) => debug!("unhandled event: {:?}", trb),
+                None => {}
+            }
+
+            if !self.xhcid.devices_mut().any(|d| d.slot_id() == hub_slot_id) {
+                self.xhcid.abort_route_enumeration();
+                return Err(Error::device_not_found(hub_slot_id));
+            }
+            if self.xhcid.microframes_since(start) >= HUB_PORT_ADDRESS_TIMEOUT {
+                self.xhcid.abort_route_enumeration();
+                return Err(Error::hub_port_timeout(hub_slot_id, port));
+            }
+        }
+    }
+
+    /// SCSIのBulk-Only Transportのインターフェースが持つBulk INとBulk OUTのエンドポイント
+    fn find_storage_interface(descriptors: &[Option<Descriptor>]) -> Option<StorageInterface> {
+        let mut interface = None;
+        let (mut bulk_in, mut bulk_out) = (None, None);
+        for d in descriptors.iter().flatten() {
+            match d {
+                Descriptor::Interface(i) => {
+                    if bulk_in.is_some() && bulk_out.is_some() {
+                        break;
+                    }
+                    interface = mass_storage::is_bulk_only_storage(i).then_some(i.interface_number);
+                    (bulk_in, bulk_out) = (None, None);
+                }
+                Descriptor::Endpoint(e)
+                    if interface.is_some() && e.get_attributes_transfer_type() == 2 =>
+                {
+                    let dir_in = e.get_endpoint_address_dir_in();
+                    let endpoint_id = EndpointID::new(e.get_endpoint_address_number(), dir_in);
+                    if dir_in {
+                        bulk_in = Some(endpoint_id);
+                    } else {
+                        bulk_out = Some(endpoint_id);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(StorageInterface {
+            interface_number: interface?,
+            bulk_in: bulk_in?,
+            bulk_out: bulk_out?,
+        })
+    }
+
+    /// Isoch OUTエンドポイントを持ち、送る形式を受け付けるAudioStreamingインターフェースの代替設定
+    fn find_playback_interface(descriptors: &[Option<Descriptor>]) -> Option<Playback> {
+        let mut interface = None;
+        let mut supported = false;
+        for d in descriptors.iter().flatten() {
+            match d {
+                Descriptor::Interface(i) => {
+                    interface = audio::is_audio_streaming(i).then_some(i);
+                    supported = false;
+                }
+                Descriptor::ClassSpecificInterface(c) if interface.is_some() => {
+                    supported |= audio::is_supported_format(c);
+                }
+                Descriptor::Endpoint(e) if supported && audio::is_playback_endpoint(e) => {
+                    if let Some(i) = interface {
+                        return Some(Playback {
+                            interface_number: i.interface_number,
+                            alternate_setting: i.alternate_setting,
+                            endpoint: *e,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// 再生用の代替設定を選んで、サービス間隔ごとに`audio_handler`が詰めたPCMを送り始める
+    fn start_playback(&mut self, slot_id: SlotId, playback: Playback) -> Result<()> {
+        let setup = SetupPacket::set_interface(
+            playback.interface_number as u16,
+            playback.alternate_setting as u16,
+        );
+        self.control_transfer(slot_id, setup, None)?;
+
+        let endpoint_id = EndpointID::new(playback.endpoint.get_endpoint_address_number(), false);
+        let interval = self.endpoint_interval(slot_id, endpoint_id)?;
+        let max_packet_size = (playback.endpoint.get_max_packet_size() & 0x7ff) as usize;
+        let packet_len = audio::bytes_per_interval(interval).min(max_packet_size);
+        let handler = self.audio_handler.unwrap_or(|buf| buf.fill(0));
+        self.start_isoch_stream(slot_id, endpoint_id, packet_len, IsochHandler::Out(handler))?;
+
+        self.audios.insert(slot_id, playback).unwrap();
+        Ok(())
+    }
+
+    /// Endpoint ContextのInterval。2^interval×125usごとに転送する。
+    fn endpoint_interval(&mut self, slot_id: SlotId, endpoint_id: EndpointID) -> Result<u8> {
+        let dev = unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }
+            .devices_mut()
+            .find(|d| d.slot_id() == slot_id)
+            .ok_or_else(|| Error::device_not_found(slot_id))?;
+        Ok(dev.context.device_contexts[endpoint_id.dci() as usize - 1].get_data_0_interval())
+    }
+
+    /// Isochエンドポイントで転送を続ける。サービス間隔ごとに`packet_len`バイトのTDを1つ転送する。
+    /// 送るデータは`handler`が詰め、受け取ったデータは`handler`に渡す。
+    pub fn start_isoch_stream(
+        &mut self,
+        slot_id: SlotId,
+        endpoint_id: EndpointID,
+        packet_len: usize,
+        handler: IsochHandler,
+    ) -> Result<()> {
+        if packet_len > isoch::BUFFER_SIZE {
+            return Err(Error::transfer_too_long(packet_len));
+        }
+        let interval = self.endpoint_interval(slot_id, endpoint_id)?;
+        let key = (slot_id, endpoint_id.dci());
+        let stream = IsochStream {
+            endpoint_id,
+            handler,
+            schedule: Schedule::new(interval),
+            packet_len,
+            bufs: [[0; isoch::BUFFER_SIZE]; isoch::BUFFER_NUM],
+            pending: [None; isoch::BUFFER_NUM],
+        };
+        self.isoch_streams
+            .insert(key, stream)
+            .map_err(|_| Error::too_many_streams())?;
+        self.fill_isoch(key)
+    }
 
-        let a = loop {
-            if let Some(v) = self.xhcid.process_primary_event()? {
-                break v;
+    /// 空いているバッファを全部Isoch TDにして、MFINDEXから決めたフレームに積む
+    fn fill_isoch(&mut self, key: (SlotId, u8)) -> Result<()> {
+        let slot_id = key.0;
+        let mfindex = self.xhcid.microframe_index();
+        let threshold = self.xhcid.isochronous_scheduling_threshold();
+        let Some(stream) = self.isoch_streams.get_mut(&key) else {
+            return Ok(());
+        };
+        let dev = unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }
+            .devices_mut()
+            .find(|d| d.slot_id() == slot_id)
+            .ok_or_else(|| Error::device_not_found(slot_id))?;
+
+        let mut dev = Device::new(&mut [0u8; 0], dev);
+        for (buf, pending) in stream.bufs.iter_mut().zip(stream.pending.iter_mut()) {
+            if pending.is_some() {
+                continue;
             }
+            let buf = &mut buf[..stream.packet_len];
+            let frame_id = stream.schedule.next_frame_id(mfindex, threshold);
+            let doorbell = self.xhcid.doorbell_registers.slot(slot_id);
+            *pending = Some(match stream.handler {
+                IsochHandler::Out(fill) => {
+                    fill(buf);
+                    dev.isoch_out(stream.endpoint_id, buf, frame_id, doorbell)
+                }
+                IsochHandler::In(_) => dev.isoch_in(stream.endpoint_id, buf, frame_id, doorbell),
+            });
+        }
+        Ok(())
+    }
+
+    /// Isoch TDの完了。受け取ったデータを渡して、空いたバッファを積み直す。
+    fn handle_isoch_event(&mut self, e: TransferEvent) -> Result<()> {
+        let key = (e.get_control_slot_id(), e.get_control_endpoint_id());
+        let Some(stream) = self.isoch_streams.get_mut(&key) else {
+            return Ok(());
         };
+        match e.completion_code() {
+            // 積んだTDを全部転送し終えて止まった。次は今のフレームから数え直す。
+            CompletionCode::RingUnderrun | CompletionCode::RingOverrun => {
+                debug!("slot {}: isochronous ring is empty", key.0);
+                stream.schedule.reset();
+            }
+            code => {
+                let Some(idx) = stream
+                    .pending
+                    .iter()
+                    .position(|t| t.is_some_and(|t| t.is_completed_by(&e)))
+                else {
+                    debug!("unhandled transfer event: {:?}", e);
+                    return Ok(());
+                };
+                let transfer = stream.pending[idx].take().unwrap();
+                if code.is_error() {
+                    // 間に合わなかったTDも終わったものとして、バッファは使い回す
+                    error!("slot {}: isochronous transfer failed: {:?}", key.0, code);
+                } else if let IsochHandler::In(handler) = stream.handler {
+                    handler(&stream.bufs[idx][..transfer.transferred(&e)]);
+                }
+            }
+        }
+        self.fill_isoch(key)
+    }
+
+    /// INQUIRYで中身を確認し、使えるようになるのを待って容量を読む
+    fn start_storage(&mut self, slot_id: SlotId, interface: StorageInterface) -> Result<()> {
+        self.storages
+            .insert(
+                slot_id,
+                StorageDevice {
+                    interface_number: interface.interface_number,
+                    bulk_in: interface.bulk_in,
+                    bulk_out: interface.bulk_out,
+                    tag: 1,
+                    capacity: Capacity::default(),
+                },
+            )
+            .unwrap();
 
-        let Trb::TransferEvent(e) = a else {
-            return Err(Error::unexpected_trb(TrbType::TransferEvent, a));
+        let mut buf = [0u8; scsi::INQUIRY_DATA_LEN];
+        let command = scsi::Command::inquiry(buf.len() as u16);
+        self.scsi_command(slot_id, command, DataPhase::In(&mut buf))?;
+        if let Some(inquiry) = scsi::InquiryData::parse(&buf) {
+            info!(
+                "slot {}: {} {} {} (type {}, removable: {})",
+                slot_id,
+                inquiry.vendor(),
+                inquiry.product(),
+                inquiry.revision(),
+                inquiry.peripheral_device_type,
+                inquiry.removable
+            );
+        }
+
+        // リセット直後はUNIT ATTENTIONで失敗するので、センスデータを読んでやり直す
+        let mut ready = false;
+        for _ in 0..STORAGE_READY_RETRY {
+            match self.scsi_command(slot_id, scsi::Command::test_unit_ready(), DataPhase::None) {
+                Ok(_) => {
+                    ready = true;
+                    break;
+                }
+                Err(e) if e == Error::command_failed(CommandStatus::Failed) => {
+                    let sense = self.request_sense(slot_id)?;
+                    debug!("slot {}: {:?}", slot_id, sense);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if !ready {
+            self.storages.remove(&slot_id);
+            return Err(Error::storage_not_ready());
+        }
+
+        let mut buf = [0u8; scsi::CAPACITY_10_DATA_LEN];
+        let command = scsi::Command::read_capacity_10();
+        self.scsi_command(slot_id, command, DataPhase::In(&mut buf))?;
+        let capacity = Capacity::parse(&buf).ok_or_else(Error::invalid_csw)?;
+        info!(
+            "slot {}: {} blocks x {} bytes",
+            slot_id,
+            capacity.block_count(),
+            capacity.block_length
+        );
+
+        if let Some(storage) = self.storages.get_mut(&slot_id) {
+            storage.capacity = capacity;
+        }
+        Ok(())
+    }
+
+    fn request_sense(&mut self, slot_id: SlotId) -> Result<Option<scsi::SenseData>> {
+        let mut buf = [0u8; scsi::SENSE_DATA_LEN];
+        let command = scsi::Command::request_sense(buf.len() as u8);
+        self.scsi_command(slot_id, command, DataPhase::In(&mut buf))?;
+        Ok(scsi::SenseData::parse(&buf))
+    }
+
+    /// CBW、データ、CSWの順に転送する。転送されなかったデータのバイト数を返す。
+    /// CSWが読めないかPhase Errorなら、Reset Recoveryをしてからエラーを返す。
+    fn scsi_command(
+        &mut self,
+        slot_id: SlotId,
+        command: scsi::Command,
+        mut data: DataPhase,
+    ) -> Result<usize> {
+        let storage = self
+            .storages
+            .get_mut(&slot_id)
+            .ok_or_else(|| Error::device_not_found(slot_id))?;
+        let (bulk_in, bulk_out) = (storage.bulk_in, storage.bulk_out);
+        let tag = storage.tag;
+        storage.tag = storage.tag.wrapping_add(1);
+
+        let cbw = CommandBlockWrapper {
+            tag,
+            data_transfer_length: data.len() as u32,
+            direction: data.direction(),
+            lun: 0,
+            command,
         };
+        let cbw = cbw.to_bytes();
+        self.bulk_transfer(slot_id, bulk_out, DataPhase::Out(&cbw))?;
 
-        // info!("{:?}", e.get_status_completion_code());
-        if !e.get_status_completion_code().is_success() {
-            // todo!("{:?}", e.get_status_completion_code())
+        if data.len() > 0 {
+            let endpoint_id = match data.direction() {
+                Direction::In => bulk_in,
+                Direction::Out => bulk_out,
+            };
+            match self.bulk_transfer(slot_id, endpoint_id, data.reborrow()) {
+                // 返せるデータが無いとデバイスはSTALLする。Haltを解いたらCSWを読む。
+                Err(e) if e.completion_code() == Some(CompletionCode::StallError) => {}
+                result => {
+                    result?;
+                }
+            }
         }
 
+        let csw = match self.read_csw(slot_id, bulk_in, tag) {
+            Ok(csw) => csw,
+            Err(e) if e == Error::invalid_csw() || e.completion_code().is_some() => {
+                self.reset_recovery(slot_id)?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        match csw.status {
+            CommandStatus::Passed => Ok(csw.data_residue as usize),
+            CommandStatus::Failed => Err(Error::command_failed(CommandStatus::Failed)),
+            CommandStatus::PhaseError => {
+                self.reset_recovery(slot_id)?;
+                Err(Error::command_failed(CommandStatus::PhaseError))
+            }
+        }
+    }
+
+    /// CSWを読んで、長さとシグネチャ、CBWのタグを確かめる。STALLしたらHaltを解いてもう一度だけ読む。
+    fn read_csw(
+        &mut self,
+        slot_id: SlotId,
+        bulk_in: EndpointID,
+        tag: u32,
+    ) -> Result<CommandStatusWrapper> {
+        let mut buf = [0u8; mass_storage::CSW_LEN];
+        let len = match self.bulk_transfer(slot_id, bulk_in, DataPhase::In(&mut buf)) {
+            Err(e) if e.completion_code() == Some(CompletionCode::StallError) => {
+                self.bulk_transfer(slot_id, bulk_in, DataPhase::In(&mut buf))?
+            }
+            result => result?,
+        };
+        let csw = CommandStatusWrapper::try_from(&buf[..len])?;
+        if csw.tag != tag {
+            return Err(Error::invalid_csw());
+        }
+        Ok(csw)
+    }
+
+    /// Bulk-Only Mass Storage Resetで次のCBWを受け付けるようにして、両方のBulkエンドポイントのHaltを解く
+    fn reset_recovery(&mut self, slot_id: SlotId) -> Result<()> {
+        let storage = self
+            .storages
+            .get(&slot_id)
+            .ok_or_else(|| Error::device_not_found(slot_id))?;
+        let interface = storage.interface_number as u16;
+        let (bulk_in, bulk_out) = (storage.bulk_in, storage.bulk_out);
+
+        info!("slot {}: bulk-only reset recovery", slot_id);
+        self.control_transfer(slot_id, SetupPacket::bulk_only_reset(interface), None)?;
+        for endpoint_id in [bulk_in, bulk_out] {
+            self.clear_feature(
+                slot_id,
+                Recipient::Endpoint,
+                feature::ENDPOINT_HALT,
+                endpoint_id.address() as u16,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Bulkエンドポイントに転送して完了を待つ。`MAX_TD_LENGTH`ずつのTDに分けて、
+    /// 短く終わったTDがあればそこで止める。転送したバイト数を返す。
+    fn bulk_transfer(
+        &mut self,
+        slot_id: SlotId,
+        endpoint_id: EndpointID,
+        mut data: DataPhase,
+    ) -> Result<usize> {
+        let len = data.len();
+        let mut offset = 0;
+        loop {
+            let chunk = (len - offset).min(MAX_TD_LENGTH);
+            let dev = unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }
+                .devices_mut()
+                .find(|d| d.slot_id() == slot_id)
+                .ok_or_else(|| Error::device_not_found(slot_id))?;
+            let doorbell = self.xhcid.doorbell_registers.slot(slot_id);
+
+            let mut dev = Device::new(&mut [0u8; 0], dev);
+            let transfer = match &mut data {
+                DataPhase::None => return Ok(0),
+                DataPhase::In(buf) => {
+                    dev.bulk_in(endpoint_id, &mut buf[offset..offset + chunk], doorbell)
+                }
+                DataPhase::Out(buf) => {
+                    dev.bulk_out(endpoint_id, &buf[offset..offset + chunk], doorbell)
+                }
+            };
+
+            let e = self.wait_transfer(transfer)?;
+            let n = transfer.transferred(&e);
+            offset += n;
+            if n < chunk || offset == len {
+                return Ok(offset);
+            }
+        }
+    }
+
+    /// READ(10)/WRITE(10)を`MAX_STORAGE_TRANSFER`ずつに分けて発行する
+    fn storage_rw(&mut self, slot_id: SlotId, lba: u64, mut data: DataPhase) -> Result<()> {
+        let capacity = self
+            .storages
+            .get(&slot_id)
+            .ok_or_else(|| Error::device_not_found(slot_id))?
+            .capacity;
+        let block_size = capacity.block_length as usize;
+        let len = data.len();
+        let blocks = (len / block_size.max(1)) as u64;
+        if block_size == 0
+            || !len.is_multiple_of(block_size)
+            || lba + blocks > capacity.block_count()
+        {
+            return Err(Error::invalid_block_range(lba, len));
+        }
+
+        let blocks_per_command = (MAX_STORAGE_TRANSFER / block_size).min(u16::MAX as usize);
+        let mut offset = 0;
+        while offset < len {
+            let chunk = (len - offset).min(blocks_per_command * block_size);
+            let lba = (lba + (offset / block_size) as u64) as u32;
+            let blocks = (chunk / block_size) as u16;
+            let (command, chunk_data) = match &mut data {
+                DataPhase::In(buf) => (
+                    scsi::Command::read_10(lba, blocks),
+                    DataPhase::In(&mut buf[offset..offset + chunk]),
+                ),
+                DataPhase::Out(buf) => (
+                    scsi::Command::write_10(lba, blocks),
+                    DataPhase::Out(&buf[offset..offset + chunk]),
+                ),
+                DataPhase::None => return Ok(()),
+            };
+
+            let residue = self.scsi_command(slot_id, command, chunk_data)?;
+            if residue != 0 {
+                return Err(Error::incomplete_transfer(chunk, chunk - residue));
+            }
+            offset += chunk;
+        }
+        Ok(())
+    }
+
+    /// レポートディスクリプタを読んで、X/Yを持っていれば`Pointer`にする
+    fn get_pointer(&mut self, slot_id: SlotId, hid: &HidInterface) -> Result<Option<Pointer>> {
+        let mut buf = [0u8; REPORT_DESCRIPTOR_SIZE];
+        let len = (hid.report_descriptor_length as usize).min(buf.len());
+        let setup = SetupPacket::get_hid_report_descriptor(
+            hid.interface.interface_number as u16,
+            len as u16,
+        );
+        let len = self.control_transfer(slot_id, setup, Some(&mut buf[..len]))?;
+
+        match ReportLayout::parse(&buf[..len]) {
+            Ok(layout) => Ok(Pointer::from_layout(&layout)),
+            Err(e) => {
+                error!("slot {}: invalid report descriptor: {:?}", slot_id, e);
+                Ok(None)
+            }
+        }
+    }
+
+    fn is_boot_mouse(desc: &InterfaceDescriptor) -> bool {
+        (desc.interface_sub_class, desc.interface_protocol)
+            == (keyboard::BOOT_INTERFACE_SUBCLASS, pointer::MOUSE_PROTOCOL)
+    }
+
+    /// ブートインターフェースは最初どちらのプロトコルか分からないので、`boot`に合わせて切り替えておく
+    fn start_pointer(
+        &mut self,
+        slot_id: SlotId,
+        hid: &HidInterface,
+        pointer: Pointer,
+        boot: bool,
+    ) -> Result<()> {
+        let interface = hid.interface.interface_number as u16;
+        if hid.interface.interface_sub_class == keyboard::BOOT_INTERFACE_SUBCLASS {
+            let protocol = if boot {
+                hid::PROTOCOL_BOOT
+            } else {
+                hid::PROTOCOL_REPORT
+            };
+            let setup = SetupPacket::hid_set_protocol(protocol, interface);
+            self.control_transfer(slot_id, setup, None)?;
+        }
+
+        let endpoint_id = EndpointID::new(hid.endpoint.get_endpoint_address_number(), true);
+        let report_len = (hid.endpoint.get_max_packet_size() as usize).min(POINTER_REPORT_SIZE);
+        let driver = InterruptDriver::Pointer(PointerDevice {
+            endpoint_id,
+            pointer,
+            buf: [0; POINTER_REPORT_SIZE],
+            report_len,
+            pending: None,
+        });
+        self.start_interrupt(slot_id, endpoint_id, driver)
+    }
+
+    fn start_interrupt(
+        &mut self,
+        slot_id: SlotId,
+        endpoint_id: EndpointID,
+        driver: InterruptDriver,
+    ) -> Result<()> {
+        let key = (slot_id, endpoint_id.dci());
+        if self.interrupt_drivers.insert(key, driver).is_err() {
+            error!("slot {}: too many interrupt endpoints", slot_id);
+            return Ok(());
+        }
+        self.poll_interrupt(key)
+    }
+
+    /// 次のレポートを受け取るNormal TRBを積む
+    fn poll_interrupt(&mut self, key: (SlotId, u8)) -> Result<()> {
+        let slot_id = key.0;
+        let Some(driver) = self.interrupt_drivers.get_mut(&key) else {
+            return Ok(());
+        };
+        let dev = unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }
+            .devices_mut()
+            .find(|d| d.slot_id() == slot_id)
+            .ok_or_else(|| Error::device_not_found(slot_id))?;
+        let doorbell = self.xhcid.doorbell_registers.slot(slot_id);
+
+        let mut dev = Device::new(&mut [0u8; 0], dev);
+        let (endpoint_id, buf, pending) = match driver {
+            InterruptDriver::Keyboard(kbd) => (kbd.endpoint_id, &mut kbd.buf[..], &mut kbd.pending),
+            InterruptDriver::Pointer(ptr) => (
+                ptr.endpoint_id,
+                &mut ptr.buf[..ptr.report_len],
+                &mut ptr.pending,
+            ),
+        };
+        *pending = Some(dev.interrupt_in(endpoint_id, buf, doorbell));
+        Ok(())
+    }
+
+    /// 変化が無くても`keyboard::IDLE_MS`ごとにレポートを送らせて、最初の転送を積む
+    fn start_keyboard(
+        &mut self,
+        slot_id: SlotId,
+        interface: u8,
+        endpoint_id: EndpointID,
+    ) -> Result<()> {
+        let setup = SetupPacket::hid_set_idle(keyboard::IDLE_RATE, 0, interface as u16);
+        self.control_transfer(slot_id, setup, None)?;
+
+        let driver = InterruptDriver::Keyboard(KeyboardDevice {
+            endpoint_id,
+            keyboard: Keyboard::default(),
+            buf: [0; keyboard::REPORT_SIZE],
+            pending: None,
+        });
+        self.start_interrupt(slot_id, endpoint_id, driver)
+    }
+
+    /// 割り込み転送の完了。HIDのレポートならイベントにして次を積む。
+    fn handle_transfer_event(&mut self, e: TransferEvent) -> Result<()> {
+        let key = (e.get_control_slot_id(), e.get_control_endpoint_id());
+        let Some(driver) = self.interrupt_drivers.get_mut(&key) else {
+            debug!("unhandled transfer event: {:?}", e);
+            return Ok(());
+        };
+        let pending = driver.pending_mut();
+        let Some(transfer) = pending.filter(|t| t.is_completed_by(&e)) else {
+            debug!("unhandled transfer event: {:?}", e);
+            return Ok(());
+        };
+        *pending = None;
+
+        if let Err(err) = e.check() {
+            error!("slot {}: interrupt transfer failed: {:?}", key.0, err);
+            // 停止したエンドポイントは復旧が終わってから`handle_recovered`で積み直す
+            if e.completion_code().halts_endpoint() {
+                return Ok(());
+            }
+            return self.poll_interrupt(key);
+        }
+
+        let len = transfer.transferred(&e);
+        match driver {
+            InterruptDriver::Keyboard(kbd) => {
+                let handler = self.key_handler;
+                kbd.keyboard.update(&kbd.buf[..len], |event| {
+                    if let Some(handler) = handler {
+                        handler(event);
+                    }
+                });
+            }
+            InterruptDriver::Pointer(ptr) => {
+                if let (Some(event), Some(handler)) =
+                    (ptr.pointer.parse(&ptr.buf[..len]), self.pointer_handler)
+                {
+                    handler(event);
+                }
+            }
+        }
+
+        self.poll_interrupt(key)
+    }
+
+    pub fn get_mouse(&mut self, slot_id: SlotId) -> Result<[u8; 3]> {
+        let mut buf = [0; 3];
+
+        let dev = unsafe { self.xhcid.cx.as_mut().get_unchecked_mut() }
+            .devices_mut()
+            .find(|d| d.slot_id() == slot_id)
+            .unwrap();
+
+        let mut dev = Device::new(&mut buf, dev);
+
+        let slot_id = dev.slot_id();
+        let doorbell = self.xhcid.doorbell_registers.slot(slot_id);
+        let transfer = dev.request_mouse(doorbell, 0);
+
+        self.wait_transfer(transfer)?;
+
         Ok(buf)
     }
 }
+
+/// Bulk-Only Transportのデータの向きとバッファ
+enum DataPhase<'b> {
+    None,
+    In(&'b mut [u8]),
+    Out(&'b [u8]),
+}
+
+impl DataPhase<'_> {
+    fn len(&self) -> usize {
+        match self {
+            DataPhase::None => 0,
+            DataPhase::In(buf) => buf.len(),
+            DataPhase::Out(buf) => buf.len(),
+        }
+    }
+
+    /// データが無いときはOut
+    fn direction(&self) -> Direction {
+        match self {
+            DataPhase::In(_) => Direction::In,
+            _ => Direction::Out,
+        }
+    }
+
+    fn reborrow(&mut self) -> DataPhase<'_> {
+        match self {
+            DataPhase::None => DataPhase::None,
+            DataPhase::In(buf) => DataPhase::In(buf),
+            DataPhase::Out(buf) => DataPhase::Out(buf),
+        }
+    }
+}
+
+/// `Driver::storage`で借りたマスストレージ
+pub struct Storage<'d, 'a> {
+    driver: &'d mut Driver<'a>,
+    slot_id: SlotId,
+}
+
+impl Storage<'_, '_> {
+    pub fn slot_id(&self) -> SlotId {
+        self.slot_id
+    }
+
+    fn capacity(&self) -> Capacity {
+        self.driver
+            .storages
+            .get(&self.slot_id)
+            .map(|s| s.capacity)
+            .unwrap_or_default()
+    }
+}
+
+impl BlockDevice for Storage<'_, '_> {
+    type Error = Error;
+
+    fn block_size(&self) -> usize {
+        self.capacity().block_length as usize
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity().block_count()
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        self.driver
+            .storage_rw(self.slot_id, lba, DataPhase::In(buf))
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+        self.driver
+            .storage_rw(self.slot_id, lba, DataPhase::Out(buf))
+    }
+}
+
+/// IsochエンドポイントのbInterval(1〜16)をEndpoint ContextのIntervalにする。
+/// bIntervalはFull Speedなら2^(bInterval-1)フレーム、High Speed以上ならマイクロフレーム。
+fn isoch_interval(speed: u8, b_interval: u8) -> u8 {
+    let exp = b_interval.clamp(1, 16) - 1;
+    if speed == speed::FULL {
+        exp + 3
+    } else {
+        exp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{
+        boxed::Box,
+        cell::{Cell, RefCell},
+        vec,
+        vec::Vec,
+    };
+
+    use super::*;
+    use crate::{
+        usbd::{
+            keyboard::KeyEventKind, pointer::Motion, report_descriptor::tests::QEMU_TABLET,
+            request::standard,
+        },
+        xhci::{
+            driver::Context,
+            fake::{FakeDevice, FakeXhc, StorageFault},
+            trb::{CompletionCode, TrbType},
+        },
+    };
+
+    const FULL_SPEED: u8 = 1;
+    /// Interrupt INエンドポイント1のDCI
+    const INTERRUPT_IN_DCI: u8 = 3;
+    const BULK_OUT_DCI: u8 = 4;
+    const BULK_IN_DCI: u8 = 5;
+    /// Isoch OUTエンドポイント1のDCI
+    const ISOCH_OUT_DCI: u8 = 2;
+
+    std::thread_local! {
+        static KEYS: RefCell<Vec<KeyEvent>> = const { RefCell::new(Vec::new()) };
+        static POINTER: RefCell<Vec<PointerEvent>> = const { RefCell::new(Vec::new()) };
+        static PACKETS: Cell<u8> = const { Cell::new(0) };
+        static DETACHED: RefCell<Vec<SlotId>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record_key(e: KeyEvent) {
+        KEYS.with(|v| v.borrow_mut().push(e));
+    }
+
+    fn record_pointer(e: PointerEvent) {
+        POINTER.with(|v| v.borrow_mut().push(e));
+    }
+
+    fn record_detached(slot_id: SlotId) {
+        DETACHED.with(|v| v.borrow_mut().push(slot_id));
+    }
+
+    /// 詰めた順番の番号でパケットを埋める
+    fn fill_packet(buf: &mut [u8]) {
+        let n = PACKETS.with(|v| v.replace(v.get().wrapping_add(1)));
+        buf.fill(n);
+    }
+
+    /// HIDインターフェースを1つ持つデバイス
+    fn hid_device(sub_class: u8, protocol: u8, report_descriptor: &[u8]) -> FakeDevice {
+        let device = [
+            18, 1, 0x00, 0x02, 0, 0, 0, 8, 0x27, 0x06, 0x01, 0x00, 0x00, 0x00, 1, 2, 0, 1,
+        ];
+        let [len_lo, len_hi] = (report_descriptor.len() as u16).to_le_bytes();
+        let config = [
+            // Configuration
+            9, 2, 34, 0, 1, 1, 0, 0xa0, 50, // Interface
+            9, 4, 0, 0, 1, 3, sub_class, protocol, 0, // HID
+            9, 0x21, 0x11, 0x01, 0, 1, 0x22, len_lo, len_hi,
+            // Endpoint 0x81, Interrupt, 8バイト
+            7, 5, 0x81, 3, 8, 0, 10,
+        ];
+        FakeDevice::new(FULL_SPEED)
+            .with_descriptor(1, 0, &device)
+            .with_descriptor(2, 0, &config)
+            .with_descriptor(3, 0, &[4, 3, 0x09, 0x04])
+            .with_string(1, "QEMU")
+            .with_string(2, "QEMU USB HID")
+            .with_descriptor(0x22, 0, report_descriptor)
+    }
+
+    fn keyboard() -> FakeDevice {
+        hid_device(1, 1, &[])
+    }
+
+    /// Bulk IN 0x82とBulk OUT 0x02だけを持つベンダー固有のデバイス
+    fn vendor_device() -> FakeDevice {
+        let device = [
+            18, 1, 0x00, 0x02, 0xff, 0, 0, 64, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0, 0, 0, 1,
+        ];
+        let config = [
+            // Configuration
+            9, 2, 32, 0, 1, 1, 0, 0x80, 50, // Interface
+            9, 4, 0, 0, 2, 0xff, 0, 0, 0, // Endpoint 0x82, Bulk, 64バイト
+            7, 5, 0x82, 2, 64, 0, 0, // Endpoint 0x02, Bulk, 64バイト
+            7, 5, 0x02, 2, 64, 0, 0,
+        ];
+        FakeDevice::new(FULL_SPEED)
+            .with_descriptor(1, 0, &device)
+            .with_descriptor(2, 0, &config)
+    }
+
+    /// xHCを初期化して、最初のデバイスの設定が終わるまでイベントを処理する
+    fn configure(driver: &mut Driver) -> SlotId {
+        for _ in 0..100 {
+            driver.process().unwrap();
+            if let Some(slot_id) = driver.configure_device().unwrap() {
+                return slot_id;
+            }
+        }
+        panic!("device is not configured");
+    }
+
+    fn new_context() -> Pin<Box<Context>> {
+        Box::pin(Context::zeroed())
+    }
+
+    /// `device`をポート1につないだ偽のxHCと、それを使うドライバ。
+    /// ドライバは返したContextを指しているので、ドライバより先に捨てない。
+    fn setup(device: FakeDevice) -> (FakeXhc, Pin<Box<Context>>, Driver<'static>) {
+        let xhc = FakeXhc::new();
+        xhc.attach(1, device);
+        xhc.install();
+        let mut cx = new_context();
+        // Boxの中身は動かないので、Boxごと返してもドライバから指し続けられる
+        let cx_ref =
+            unsafe { Pin::new_unchecked(&mut *(cx.as_mut().get_unchecked_mut() as *mut Context)) };
+        let driver = Driver::new(unsafe { Controller::new(xhc.bar(), cx_ref) }).unwrap();
+        (xhc, cx, driver)
+    }
+
+    #[test]
+    fn test_configure_keyboard() {
+        let (xhc, _cx, mut driver) = setup(keyboard());
+        driver.set_keyboard_handler(record_key);
+
+        let slot_id = configure(&mut driver);
+        assert!(driver.is_keyboard(slot_id));
+        assert!(!driver.is_pointer(slot_id));
+
+        let requests: Vec<_> = xhc
+            .device(1)
+            .requests
+            .iter()
+            .map(|r| (r.request_type, r.request, r.value))
+            .collect();
+        // SET_CONFIGURATION(1)、SET_PROTOCOL(boot)、SET_IDLE
+        assert!(requests.contains(&(0x00, 0x09, 1)));
+        assert!(requests.contains(&(0x21, 0x0b, hid::PROTOCOL_BOOT)));
+        assert!(requests.contains(&(0x21, 0x0a, (keyboard::IDLE_RATE as u16) << 8)));
+
+        // 'a'を押して離す
+        xhc.send(1, INTERRUPT_IN_DCI, &[0, 0, 4, 0, 0, 0, 0, 0]);
+        driver.process().unwrap();
+        xhc.send(1, INTERRUPT_IN_DCI, &[0; 8]);
+        driver.process().unwrap();
+
+        let events = KEYS.with(|v| v.take());
+        let events: Vec<_> = events.iter().map(|e| (e.usage, e.kind)).collect();
+        assert_eq!(
+            events,
+            [(4, KeyEventKind::Press), (4, KeyEventKind::Release)]
+        );
+    }
+
+    #[test]
+    fn test_configure_tablet() {
+        let (xhc, _cx, mut driver) = setup(hid_device(0, 0, QEMU_TABLET));
+        driver.set_pointer_handler(record_pointer);
+
+        let slot_id = configure(&mut driver);
+        assert!(driver.is_pointer(slot_id));
+
+        // 最大パケットサイズより短いレポートはShort Packetで届く
+        xhc.send(1, INTERRUPT_IN_DCI, &[0b001, 0xff, 0x3f, 0x00, 0x10, 0x00]);
+        driver.process().unwrap();
+
+        let events = POINTER.with(|v| v.take());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].buttons, 0b001);
+        assert!(matches!(
+            events[0].motion,
+            Motion::Absolute {
+                x: 0x3fff,
+                y: 0x1000,
+                ..
+            }
+        ));
+    }
+
+    /// QEMUのusb-audioと同じく、AudioStreamingの代替設定1でIsoch OUT 0x01を使うデバイス
+    /// `sample_rate`だけを受け付けるオーディオデバイス
+    fn audio_device(sample_rate: u32) -> FakeDevice {
+        let [f0, f1, f2, _] = sample_rate.to_le_bytes();
+        let device = [
+            18, 1, 0x00, 0x01, 0, 0, 0, 8, 0x46, 0x00, 0x02, 0x00, 0x00, 0x00, 0, 0, 0, 1,
+        ];
+        let config = [
+            // Configuration
+            9, 2, 79, 0, 2, 1, 0, 0xc0, 50, // AudioControl
+            9, 4, 0, 0, 0, 1, 1, 0, 0, // AudioControlのヘッダ
+            9, 0x24, 1, 0x00, 0x01, 9, 0, 1, 1, // AudioStreaming 代替設定0
+            9, 4, 1, 0, 0, 1, 2, 0, 0, // AudioStreaming 代替設定1
+            9, 4, 1, 1, 1, 1, 2, 0, 0, // AS_GENERAL
+            7, 0x24, 1, 1, 1, 1, 0, // FORMAT_TYPE 2チャンネル16ビット
+            11, 0x24, 2, 1, 2, 2, 16, 1, f0, f1, f2,
+            // Endpoint 0x01, Isoch Adaptive, 192バイト, 1フレームごと
+            9, 5, 0x01, 0x09, 192, 0, 1, 0, 0, // AS_GENERAL
+            7, 0x25, 1, 0, 0, 0, 0,
+        ];
+        FakeDevice::new(FULL_SPEED)
+            .with_descriptor(1, 0, &device)
+            .with_descriptor(2, 0, &config)
+    }
+
+    /// 届いているイベントをすべて処理する
+    fn process_all(driver: &mut Driver) {
+        while driver.has_pending_events() || driver.drain_events() > 0 {
+            driver.process().unwrap();
+        }
+    }
+
+    /// フレームを1つずつ進めて、完了したTDのイベントを処理する
+    fn run_frames(xhc: &FakeXhc, driver: &mut Driver, frames: usize) {
+        for _ in 0..frames {
+            xhc.advance_frames(1);
+            process_all(driver);
+        }
+    }
+
+    #[test]
+    fn test_audio_playback() {
+        let (xhc, _cx, mut driver) = setup(audio_device(48000));
+        driver.set_audio_handler(fill_packet);
+        let slot_id = configure(&mut driver);
+        assert!(driver.is_audio(slot_id));
+
+        let requests: Vec<_> = xhc
+            .device(1)
+            .requests
+            .iter()
+            .map(|r| (r.request_type, r.request, r.value, r.index))
+            .collect();
+        // SET_INTERFACE(インターフェース1, 代替設定1)
+        assert!(requests.contains(&(0x01, 0x0b, 1, 1)));
+
+        // MFINDEXが0のときに積んだので、閾値と余裕を空けたフレーム3から毎フレーム送る
+        run_frames(&xhc, &mut driver, 20);
+        let device = xhc.device(1);
+        assert_eq!(device.isoch_frames, (3..=20).collect::<Vec<u16>>());
+        let packets: Vec<_> = device
+            .outputs
+            .iter()
+            .filter(|v| v.0 == ISOCH_OUT_DCI)
+            .collect();
+        assert_eq!(packets.len(), 18);
+        // 空いたバッファから順に詰め直して途切れずに送っている
+        for (i, packet) in packets.iter().enumerate() {
+            assert_eq!(packet.1, std::vec![i as u8; audio::bytes_per_interval(3)]);
+        }
+    }
+
+    #[test]
+    fn test_audio_unsupported_format() {
+        let (xhc, _cx, mut driver) = setup(audio_device(44100));
+        let slot_id = configure(&mut driver);
+        assert!(!driver.is_audio(slot_id));
+
+        run_frames(&xhc, &mut driver, 5);
+        let device = xhc.device(1);
+        assert!(device.isoch_frames.is_empty());
+        assert!(!device.requests.iter().any(|r| r.request == 0x0b));
+    }
+
+    #[test]
+    fn test_audio_resync() {
+        let (xhc, _cx, mut driver) = setup(audio_device(48000));
+        configure(&mut driver);
+
+        // イベントを処理しないうちに、積んであった8つのTDを送り終えてしまう
+        xhc.advance_frames(30);
+        assert_eq!(xhc.device(1).isoch_frames, (3..=10).collect::<Vec<u16>>());
+
+        // 続きのフレーム11はもう過ぎているので、今のフレーム30から数え直して積む
+        process_all(&mut driver);
+        run_frames(&xhc, &mut driver, 10);
+        assert_eq!(
+            xhc.device(1).isoch_frames[8..],
+            (33..=40).collect::<Vec<u16>>()
+        );
+    }
+
+    /// 64KiB境界をまたぐ長さを含むテスト用のデータ
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    #[test]
+    fn test_bulk_out_chained() {
+        let (xhc, _cx, mut driver) = setup(vendor_device());
+        let slot_id = configure(&mut driver);
+
+        // MAX_TD_LENGTHを超えるのでTDは2つになる
+        let len = MAX_TD_LENGTH + 100 * 1024;
+        let mut buf = pattern(len);
+        let n = driver
+            .normal_transfer(slot_id, EndpointID::new(2, false), &mut buf)
+            .unwrap();
+        assert_eq!(n, len);
+
+        let outputs = xhc.device(1).outputs;
+        let tds: Vec<_> = outputs.iter().filter(|v| v.0 == BULK_OUT_DCI).collect();
+        assert_eq!(tds.len(), 2);
+        assert_eq!(tds[0].1.len(), MAX_TD_LENGTH);
+        assert_eq!([&tds[0].1[..], &tds[1].1[..]].concat(), buf);
+    }
+
+    #[test]
+    fn test_bulk_in_short_packet() {
+        let (xhc, _cx, mut driver) = setup(vendor_device());
+        let slot_id = configure(&mut driver);
+
+        // 100KiBのバッファに70KiBだけ届く。途中のTRBでShort Packetになる。
+        let data = pattern(70 * 1024);
+        xhc.send(1, BULK_IN_DCI, &data);
+        let mut buf = std::vec![0u8; 100 * 1024];
+        let n = driver
+            .normal_transfer(slot_id, EndpointID::new(2, true), &mut buf)
+            .unwrap();
+        assert_eq!(n, data.len());
+        assert_eq!(&buf[..n], &data[..]);
+
+        // 次の転送は止まらずにできる
+        xhc.send(1, BULK_IN_DCI, &[1, 2, 3]);
+        let n = driver
+            .normal_transfer(slot_id, EndpointID::new(2, true), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..n], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_bulk_in_multiple_tds() {
+        let (xhc, _cx, mut driver) = setup(vendor_device());
+        let slot_id = configure(&mut driver);
+
+        let data = pattern(MAX_TD_LENGTH + 1000);
+        xhc.send(1, BULK_IN_DCI, &data[..MAX_TD_LENGTH]);
+        xhc.send(1, BULK_IN_DCI, &data[MAX_TD_LENGTH..]);
+        let mut buf = std::vec![0u8; MAX_TD_LENGTH + 4096];
+        let n = driver
+            .normal_transfer(slot_id, EndpointID::new(2, true), &mut buf)
+            .unwrap();
+        assert_eq!(n, data.len());
+        assert_eq!(&buf[..n], &data[..]);
+
+        // 1つのTDに収まらない非同期の転送は積まない
+        assert!(unsafe {
+            driver.submit_bulk_transfer(slot_id, EndpointID::new(2, true), &mut buf)
+        }
+        .is_err());
+    }
+
+    #[test]
+    fn test_submit_out_of_order() {
+        let (xhc, _cx, mut driver) = setup(vendor_device());
+        driver.set_keyboard_handler(record_key);
+        let vendor = configure(&mut driver);
+        xhc.attach(2, keyboard());
+        let keyboard = configure(&mut driver);
+
+        let mut in_buf = [0u8; 64];
+        let mut out_buf = pattern(64);
+        let bulk_in =
+            unsafe { driver.submit_bulk_transfer(vendor, EndpointID::new(2, true), &mut in_buf) }
+                .unwrap();
+        let bulk_out =
+            unsafe { driver.submit_bulk_transfer(vendor, EndpointID::new(2, false), &mut out_buf) }
+                .unwrap();
+
+        // 後から積んだOUTが先に終わる
+        let event = driver.wait_completion(&bulk_out).unwrap();
+        assert_eq!(bulk_out.transferred(&event), 64);
+        assert_eq!(driver.poll_transfer(&bulk_in), Poll::Pending);
+
+        // 待っている間に届いたキーボードのレポートはハンドラに渡る
+        xhc.send(2, INTERRUPT_IN_DCI, &[0, 0, 4, 0, 0, 0, 0, 0]);
+        assert_eq!(driver.poll_transfer(&bulk_in), Poll::Pending);
+        let events = KEYS.with(|v| v.take());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].usage, 4);
+        assert!(driver.is_keyboard(keyboard));
+
+        xhc.send(1, BULK_IN_DCI, &pattern(10));
+        let event = driver.wait_completion(&bulk_in).unwrap();
+        assert_eq!(bulk_in.transferred(&event), 10);
+        assert_eq!(&in_buf[..10], &pattern(10)[..]);
+        assert_eq!(
+            driver.poll_transfer(&bulk_in),
+            Poll::Ready(Err(Error::unknown_transfer()))
+        );
+    }
+
+    #[test]
+    fn test_submit_detach() {
+        let (xhc, _cx, mut driver) = setup(vendor_device());
+        let slot_id = configure(&mut driver);
+
+        let mut buf = [0u8; 64];
+        let transfer =
+            unsafe { driver.submit_bulk_transfer(slot_id, EndpointID::new(2, true), &mut buf) }
+                .unwrap();
+        assert_eq!(driver.poll_transfer(&transfer), Poll::Pending);
+
+        xhc.detach(1);
+        assert_eq!(
+            driver.wait_completion(&transfer),
+            Err(Error::device_not_found(slot_id))
+        );
+    }
+
+    #[test]
+    fn test_discard_transfer() {
+        let (xhc, _cx, mut driver) = setup(vendor_device());
+        let slot_id = configure(&mut driver);
+
+        // 完了を受け取らなくても枠は空く
+        let mut out_buf = pattern(64);
+        for _ in 0..MAX_PENDING_TRANSFERS * 2 {
+            let transfer = unsafe {
+                driver.submit_bulk_transfer(slot_id, EndpointID::new(2, false), &mut out_buf)
+            }
+            .unwrap();
+            driver.discard_transfer(&transfer);
+            process_all(&mut driver);
+        }
+        assert!(driver.completions.is_empty());
+
+        // 実行中に捨てた転送は、完了したときに枠を空ける
+        let mut in_buf = [0u8; 64];
+        let transfer =
+            unsafe { driver.submit_bulk_transfer(slot_id, EndpointID::new(2, true), &mut in_buf) }
+                .unwrap();
+        driver.discard_transfer(&transfer);
+        assert_eq!(
+            driver.poll_transfer(&transfer),
+            Poll::Ready(Err(Error::unknown_transfer()))
+        );
+        assert_eq!(driver.completions.len(), 1);
+        xhc.send(1, BULK_IN_DCI, &pattern(10));
+        process_all(&mut driver);
+        assert!(driver.completions.is_empty());
+    }
+
+    #[test]
+    fn test_configure_without_strings() {
+        let (_xhc, _cx, mut driver) = setup(keyboard().without_strings());
+
+        // 文字列の読み出しがSTALLしても、キーボードとして使える
+        let slot_id = configure(&mut driver);
+        assert!(driver.is_keyboard(slot_id));
+    }
+
+    #[test]
+    fn test_configure_failure_does_not_block_others() {
+        // コンフィギュレーションディスクリプタを返さないデバイス
+        let device = [
+            18, 1, 0x00, 0x02, 0xff, 0, 0, 64, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0, 0, 0, 1,
+        ];
+        let (xhc, _cx, mut driver) =
+            setup(FakeDevice::new(FULL_SPEED).with_descriptor(1, 0, &device));
+        xhc.attach(2, keyboard());
+
+        let mut errors = 0;
+        let mut configured = Vec::new();
+        for _ in 0..100 {
+            driver.process().unwrap();
+            match driver.configure_device() {
+                Ok(Some(slot_id)) => configured.push(slot_id),
+                Ok(None) => {}
+                Err(_) => errors += 1,
+            }
+        }
+        // 失敗したデバイスは繰り返し設定しない
+        assert_eq!(errors, 1);
+        assert_eq!(configured.len(), 1);
+        assert!(driver.is_keyboard(configured[0]));
+    }
+
+    #[test]
+    fn test_detach_while_configuring() {
+        let (xhc, _cx, mut driver) =
+            setup(keyboard().detach_on_request(standard::SET_CONFIGURATION));
+        driver.set_detach_handler(record_detached);
+
+        let err = (0..100)
+            .find_map(|_| {
+                driver.process().unwrap();
+                driver.configure_device().err()
+            })
+            .unwrap();
+        assert_eq!(err, Error::device_not_found(1));
+        // 取り外しを知らせ、始めかけたドライバは残さない
+        assert_eq!(DETACHED.with(|v| v.borrow().clone()), [1]);
+        assert!(!driver.is_keyboard(1));
+
+        // 差し直したデバイスは同じスロットIDでもう一度設定できる
+        xhc.attach(1, keyboard());
+        let slot_id = configure(&mut driver);
+        assert_eq!(slot_id, 1);
+        assert!(driver.is_keyboard(slot_id));
+    }
+
+    #[test]
+    fn test_detach_while_enabling_slot() {
+        let (xhc, _cx, mut driver) = setup(keyboard());
+        driver.set_detach_handler(record_detached);
+
+        // ポートをリセットしてEnable Slotを発行したところで抜く
+        xhc.hold_commands();
+        for _ in 0..4 {
+            driver.process().unwrap();
+        }
+        assert_eq!(driver.xhcid.processing_port_num(), Some(1));
+        xhc.detach(1);
+        process_all(&mut driver);
+        assert_eq!(driver.xhcid.processing_port_num(), None);
+
+        // 遅れて届いたEnable Slotの完了で、使わないスロットを無効にする
+        xhc.release_commands();
+        process_all(&mut driver);
+        assert_eq!(driver.configure_device(), Ok(None));
+        assert!(DETACHED.with(|v| v.borrow().is_empty()));
+
+        // スロットは残っていないので、差し直すと同じスロットIDになる
+        xhc.attach(1, keyboard());
+        assert_eq!(configure(&mut driver), 1);
+    }
+
+    #[test]
+    fn test_disable_slot_failure() {
+        let (xhc, _cx, mut driver) = setup(keyboard());
+        driver.set_detach_handler(record_detached);
+        let slot_id = configure(&mut driver);
+
+        xhc.fail_next_command(
+            TrbType::DisableSlotCommand,
+            CompletionCode::SlotNotEnabledError,
+        );
+        xhc.detach(1);
+        process_all(&mut driver);
+
+        // 失敗してもスロットを解放して取り外しを知らせる
+        assert_eq!(DETACHED.with(|v| v.borrow().clone()), [slot_id]);
+        assert!(!driver.is_keyboard(slot_id));
+    }
+
+    #[test]
+    fn test_recovery_failure() {
+        let (xhc, _cx, mut driver) = setup(keyboard());
+        driver.set_keyboard_handler(record_key);
+        let slot_id = configure(&mut driver);
+
+        // 偽のデバイスはGET_REPORTに応えずSTALLする
+        xhc.fail_next_command(
+            TrbType::ResetEndpointCommand,
+            CompletionCode::ContextStateError,
+        );
+        let mut report = [0; 8];
+        let mut status = [0; 2];
+        let get_report = SetupPacket::hid_get_report(1, 0, 0, report.len() as u16);
+        let get_status = SetupPacket::get_status(Recipient::Device, 0);
+        let stalled =
+            unsafe { driver.submit_control_transfer(slot_id, get_report, Some(&mut report)) }
+                .unwrap();
+        let queued =
+            unsafe { driver.submit_control_transfer(slot_id, get_status, Some(&mut status)) }
+                .unwrap();
+        let err = driver.wait_completion(&stalled).unwrap_err();
+        assert_eq!(err.completion_code(), Some(CompletionCode::StallError));
+        // 後ろに積まれていた転送は、復旧の失敗で終わる
+        while driver.has_pending_events() || driver.drain_events() > 0 {
+            assert!(driver.process().is_err());
+        }
+        let Poll::Ready(Err(err)) = driver.poll_transfer(&queued) else {
+            panic!("queued transfer is still pending");
+        };
+        assert_eq!(
+            err.completion_code(),
+            Some(CompletionCode::ContextStateError)
+        );
+
+        // 割り込み転送は積み直さず、エラーを返す
+        xhc.fail_next_command(
+            TrbType::ResetEndpointCommand,
+            CompletionCode::ContextStateError,
+        );
+        xhc.stall(1, INTERRUPT_IN_DCI);
+        let err = (0..10).find_map(|_| driver.process().err()).unwrap();
+        assert_eq!(
+            err.completion_code(),
+            Some(CompletionCode::ContextStateError)
+        );
+        let keyboard = driver
+            .interrupt_drivers
+            .get_mut(&(slot_id, INTERRUPT_IN_DCI))
+            .unwrap();
+        assert!(keyboard.pending_mut().is_none());
+        assert!(!driver
+            .xhcid
+            .is_recovering_endpoint(slot_id, INTERRUPT_IN_DCI));
+    }
+
+    #[test]
+    fn test_recover_control_stall() {
+        let (_xhc, _cx, mut driver) = setup(keyboard());
+        let slot_id = configure(&mut driver);
+
+        // 偽のデバイスはGET_REPORTに応えずSTALLする
+        assert!(driver.get_mouse(slot_id).is_err());
+        assert!(!driver
+            .xhcid
+            .is_recovering_endpoint(slot_id, HCP_ENDPOINT_ID.dci()));
+
+        // Reset EndpointとSet TR Dequeue Pointerの後は次の転送ができる
+        assert_eq!(driver.get_status(slot_id, Recipient::Device, 0).unwrap(), 0);
+    }
+
+    /// 512バイトのブロックを16個持つBulk-Only Transportのマスストレージ。
+    /// 最初の`not_ready`回のTEST UNIT READYは失敗する。
+    fn storage_device(not_ready: usize) -> FakeDevice {
+        let device = [
+            18, 1, 0x00, 0x02, 0, 0, 0, 64, 0x46, 0x00, 0x01, 0x00, 0x00, 0x00, 0, 0, 0, 1,
+        ];
+        let config = [
+            // Configuration
+            9, 2, 32, 0, 1, 1, 0, 0x80, 50, // Interface SCSI, Bulk-Only
+            9, 4, 0, 0, 2, 0x08, 0x06, 0x50, 0, // Endpoint 0x82, Bulk, 64バイト
+            7, 5, 0x82, 2, 64, 0, 0, // Endpoint 0x02, Bulk, 64バイト
+            7, 5, 0x02, 2, 64, 0, 0,
+        ];
+        FakeDevice::new(FULL_SPEED)
+            .with_descriptor(1, 0, &device)
+            .with_descriptor(2, 0, &config)
+            .with_storage(BULK_IN_DCI, BULK_OUT_DCI, 512, pattern(512 * 16), not_ready)
+    }
+
+    /// Bulk-Only Mass Storage ResetとBulk IN/OUTへのCLEAR_FEATURE(ENDPOINT_HALT)の数
+    fn reset_recovery_requests(xhc: &FakeXhc) -> (usize, usize) {
+        let requests = xhc.device(1).requests;
+        let resets = requests
+            .iter()
+            .filter(|r| (r.request_type, r.request, r.index) == (0x21, 0xff, 0))
+            .count();
+        let clears = requests
+            .iter()
+            .filter(|r| (r.request_type, r.request) == (0x02, standard::CLEAR_FEATURE))
+            .filter(|r| r.index == 0x82 || r.index == 0x02)
+            .count();
+        (resets, clears)
+    }
+
+    #[test]
+    fn test_storage_read_write() {
+        let (xhc, _cx, mut driver) = setup(storage_device(1));
+        let slot_id = configure(&mut driver);
+        assert!(driver.is_storage(slot_id));
+
+        let storage = xhc.device(1).storage.unwrap();
+        assert_eq!(
+            storage.commands,
+            [
+                scsi::opcode::INQUIRY,
+                scsi::opcode::TEST_UNIT_READY,
+                scsi::opcode::REQUEST_SENSE,
+                scsi::opcode::TEST_UNIT_READY,
+                scsi::opcode::READ_CAPACITY_10,
+            ]
+        );
+
+        let mut storage = driver.storage(slot_id).unwrap();
+        assert_eq!(storage.block_size(), 512);
+        assert_eq!(storage.block_count(), 16);
+
+        let mut buf = vec![0; 512 * 3];
+        storage.read_blocks(2, &mut buf).unwrap();
+        assert_eq!(buf, pattern(512 * 16)[512 * 2..512 * 5]);
+
+        let data = vec![0xa5; 512 * 2];
+        storage.write_blocks(14, &data).unwrap();
+        assert_eq!(xhc.device(1).storage.unwrap().data[512 * 14..], data);
+
+        // 最後のブロックを越える読み込みは送らずにエラーにする
+        assert_eq!(
+            storage.read_blocks(15, &mut buf),
+            Err(Error::invalid_block_range(15, buf.len()))
+        );
+        assert_eq!(reset_recovery_requests(&xhc), (0, 0));
+    }
+
+    #[test]
+    fn test_storage_not_ready() {
+        let (_xhc, _cx, mut driver) = setup(storage_device(STORAGE_READY_RETRY));
+        let result = (0..100).find_map(|_| {
+            driver.process().unwrap();
+            driver.configure_device().transpose()
+        });
+        assert_eq!(result, Some(Err(Error::storage_not_ready())));
+        assert!(driver.storages.is_empty());
+    }
+
+    #[test]
+    fn test_storage_reset_recovery() {
+        let (xhc, _cx, mut driver) = setup(storage_device(0));
+        let slot_id = configure(&mut driver);
+        let mut buf = vec![0; 512];
+
+        xhc.storage_fault(1, StorageFault::WrongTag);
+        let result = driver.storage(slot_id).unwrap().read_blocks(0, &mut buf);
+        assert_eq!(result, Err(Error::invalid_csw()));
+        assert_eq!(reset_recovery_requests(&xhc), (1, 2));
+
+        xhc.storage_fault(1, StorageFault::PhaseError);
+        let result = driver.storage(slot_id).unwrap().read_blocks(0, &mut buf);
+        assert_eq!(
+            result,
+            Err(Error::command_failed(CommandStatus::PhaseError))
+        );
+        assert_eq!(reset_recovery_requests(&xhc), (2, 4));
+
+        // リセットの後は次のコマンドを受け付ける
+        let mut storage = driver.storage(slot_id).unwrap();
+        storage.read_blocks(1, &mut buf).unwrap();
+        assert_eq!(buf, pattern(512 * 2)[512..]);
+    }
+
+    #[test]
+    fn test_storage_csw_stall() {
+        let (xhc, _cx, mut driver) = setup(storage_device(0));
+        let slot_id = configure(&mut driver);
+
+        // STALLしたCSWは、Haltを解いてから読み直せばリセットしなくてよい
+        xhc.storage_fault(1, StorageFault::StallCsw);
+        let mut buf = vec![0; 512];
+        driver
+            .storage(slot_id)
+            .unwrap()
+            .read_blocks(3, &mut buf)
+            .unwrap();
+        assert_eq!(buf, pattern(512 * 4)[512 * 3..]);
+        // Bulk INのHaltを解くCLEAR_FEATUREだけが送られる
+        assert_eq!(reset_recovery_requests(&xhc), (0, 1));
+    }
+
+    /// `ports`を下流ポートにつないだFull SpeedのUSB 2.0ハブ
+    fn hub_device(ports: Vec<Option<FakeDevice>>) -> FakeDevice {
+        let device = [
+            18, 1, 0x00, 0x02, 9, 0, 0, 64, 0x09, 0x04, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 1,
+        ];
+        let config = [
+            // Configuration
+            9, 2, 25, 0, 1, 1, 0, 0xe0, 0, // Interface Hub
+            9, 4, 0, 0, 1, 9, 0, 0, 0, // Endpoint 0x81, Interrupt, 1バイト
+            7, 5, 0x81, 3, 1, 0, 255,
+        ];
+        FakeDevice::new(FULL_SPEED)
+            .with_descriptor(1, 0, &device)
+            .with_descriptor(2, 0, &config)
+            .with_hub(ports)
+    }
+
+    /// ハブのポートに送ったSET_FEATUREの(機能, ポート)
+    fn hub_port_features(xhc: &FakeXhc) -> Vec<(u16, u16)> {
+        xhc.device(1)
+            .requests
+            .iter()
+            .filter(|r| (r.request_type, r.request) == (0x23, standard::SET_FEATURE))
+            .map(|r| (r.value, r.index))
+            .collect()
+    }
+
+    #[test]
+    fn test_hub_enumeration() {
+        let (xhc, _cx, mut driver) = setup(hub_device(vec![None, Some(keyboard())]));
+        let hub_slot_id = configure(&mut driver);
+        assert!(driver.is_hub(hub_slot_id));
+        // 両方のポートの電源を入れ、デバイスがつながっているポート2だけをリセットする
+        assert_eq!(
+            hub_port_features(&xhc),
+            [
+                (hub::port_feature::PORT_POWER, 1),
+                (hub::port_feature::PORT_POWER, 2),
+                (hub::port_feature::PORT_RESET, 2),
+            ]
+        );
+
+        let slot_id = configure(&mut driver);
+        assert_ne!(slot_id, hub_slot_id);
+        assert!(driver.is_keyboard(slot_id));
+        let keyboard = xhc.device(1).hub.unwrap().ports[1].clone().unwrap();
+        assert!(keyboard
+            .requests
+            .iter()
+            .any(|r| (r.request_type, r.request, r.value) == (0x00, 0x09, 1)));
+    }
+
+    #[test]
+    fn test_hub_port_reset_timeout() {
+        let hub = hub_device(vec![Some(keyboard()), Some(keyboard())]).with_stuck_reset(1);
+        let (xhc, _cx, mut driver) = setup(hub);
+        xhc.free_run_mfindex();
+
+        // リセットが終わらないポート1を諦めて、ポート2のデバイスは使えるようにする
+        let hub_slot_id = configure(&mut driver);
+        let slot_id = configure(&mut driver);
+        assert!(driver.is_keyboard(slot_id));
+        let polls = xhc
+            .device(1)
+            .requests
+            .iter()
+            .filter(|r| (r.request_type, r.request, r.index) == (0xa3, standard::GET_STATUS, 1))
+            .count();
+        assert!(polls >= HUB_PORT_POLL_LIMIT);
+
+        assert_eq!(
+            driver.wait_hub_port(hub_slot_id, 1, |s| s.is_reset_changed()),
+            Err(Error::hub_port_timeout(hub_slot_id, 1))
+        );
+    }
+
+    #[test]
+    fn test_hub_port_address_timeout() {
+        let (xhc, _cx, mut driver) = setup(hub_device(vec![Some(keyboard())]));
+        let hub_slot_id = configure(&mut driver);
+        configure(&mut driver);
+
+        // Enable Slotが完了しないまま時間が過ぎたら、次のポートを処理できるようにして諦める
+        xhc.hold_commands();
+        xhc.free_run_mfindex();
+        let hub_route = Route::root(1, FULL_SPEED);
+        assert_eq!(
+            driver.enumerate_hub_port(hub_slot_id, hub_route, 1),
+            Err(Error::hub_port_timeout(hub_slot_id, 1))
+        );
+        assert!(!driver.xhcid.is_enumerating_route());
+
+        // 遅れて完了したEnable Slotのスロットは使わない
+        xhc.release_commands();
+        process_all(&mut driver);
+        assert_eq!(driver.configure_device(), Ok(None));
+    }
+
+    #[test]
+    fn test_configure_endpoint_failure() {
+        let (xhc, _cx, mut driver) = setup(keyboard());
+        xhc.fail_next_command(TrbType::ConfigureEndpoint, CompletionCode::ResourceError);
+
+        let result = (0..100).find_map(|_| {
+            driver.process().unwrap();
+            driver.configure_device().transpose()
+        });
+        let error = result.unwrap().unwrap_err();
+        assert_eq!(error.completion_code(), Some(CompletionCode::ResourceError));
+        // 最初に有効にしたスロットは1
+        assert!(!driver.is_keyboard(1));
+    }
+}
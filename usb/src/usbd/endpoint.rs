@@ -23,5 +23,18 @@ impl EndpointID {
     pub fn dci(self) -> u8 {
         self.0 * 2 + self.1 as u8
     }
+
+    pub fn direction(self) -> Direction {
+        self.1
+    }
+
+    pub fn from_dci(dci: u8) -> Self {
+        Self(dci / 2, Direction::from(dci % 2 == 1))
+    }
+
+    /// bEndpointAddress。CLEAR_FEATURE(ENDPOINT_HALT)のwIndexに使う。
+    pub fn address(self) -> u8 {
+        self.0 | ((self.1 as u8) << 7)
+    }
 }
 pub const HCP_ENDPOINT_ID: EndpointID = EndpointID(0, Direction::In);
@@ -1,9 +1,10 @@
 use crate::xhci::{
+    device::SlotId,
     error::Error as XHCIError,
-    trb::{Trb, TrbType},
+    trb::{CompletionCode, Trb, TrbType},
 };
 
-use super::descriptor::TryFromBytesError;
+use super::{descriptor::TryFromBytesError, mass_storage::CommandStatus};
 
 pub type Result<T> = core::result::Result<T, Error>;
 
@@ -18,12 +19,94 @@ impl Error {
     pub fn unexpected_descriptor() -> Self {
         Self(ErrorKind::UnexpectedDescriptor)
     }
+
+    pub fn device_not_found(slot_id: SlotId) -> Self {
+        Self(ErrorKind::DeviceNotFound(slot_id))
+    }
+
+    /// CSWのシグネチャやステータスがおかしい、またはCBWとタグが合わない
+    pub fn invalid_csw() -> Self {
+        Self(ErrorKind::InvalidCsw)
+    }
+
+    pub fn command_failed(status: CommandStatus) -> Self {
+        Self(ErrorKind::CommandFailed(status))
+    }
+
+    /// TEST UNIT READYを繰り返しても使えるようにならない
+    pub fn storage_not_ready() -> Self {
+        Self(ErrorKind::StorageNotReady)
+    }
+
+    /// ハブの下流ポートが待っても期待した状態にならない
+    pub fn hub_port_timeout(slot_id: SlotId, port: u8) -> Self {
+        Self(ErrorKind::HubPortTimeout { slot_id, port })
+    }
+
+    pub fn incomplete_transfer(expected: usize, actual: usize) -> Self {
+        Self(ErrorKind::IncompleteTransfer { expected, actual })
+    }
+
+    /// ブロックサイズの倍数でない、またはディスクの外を指している
+    pub fn invalid_block_range(lba: u64, len: usize) -> Self {
+        Self(ErrorKind::InvalidBlockRange { lba, len })
+    }
+
+    /// 完了を待てる転送の数を超えた
+    pub fn too_many_transfers() -> Self {
+        Self(ErrorKind::TooManyTransfers)
+    }
+
+    /// 同時に続けられるIsochのストリームの数を超えた
+    pub fn too_many_streams() -> Self {
+        Self(ErrorKind::TooManyStreams)
+    }
+
+    /// 1つのTDで転送できる長さ(`MAX_TD_LENGTH`)を超えた
+    pub fn transfer_too_long(len: usize) -> Self {
+        Self(ErrorKind::TransferTooLong { len })
+    }
+
+    /// `submit_*`で発行していない、完了を受け取り済み、または捨てた転送
+    pub fn unknown_transfer() -> Self {
+        Self(ErrorKind::UnknownTransfer)
+    }
+
+    /// エンドポイントの停止から復旧するときに読み飛ばされた転送
+    pub fn transfer_cancelled() -> Self {
+        Self(ErrorKind::TransferCancelled)
+    }
+
+    /// 転送の途中でデバイスが取り外された
+    pub fn is_device_not_found(&self) -> bool {
+        matches!(self.0, ErrorKind::DeviceNotFound(_))
+    }
+
+    /// xHCのコマンドや転送が失敗したときの完了コード
+    pub fn completion_code(&self) -> Option<CompletionCode> {
+        match &self.0 {
+            ErrorKind::XHCIError(e) => e.completion_code(),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ErrorKind {
     UnexpectedTrb(TrbType, Trb),
     UnexpectedDescriptor,
+    DeviceNotFound(SlotId),
+    InvalidCsw,
+    CommandFailed(CommandStatus),
+    StorageNotReady,
+    HubPortTimeout { slot_id: SlotId, port: u8 },
+    IncompleteTransfer { expected: usize, actual: usize },
+    InvalidBlockRange { lba: u64, len: usize },
+    TooManyTransfers,
+    TransferTooLong { len: usize },
+    TooManyStreams,
+    UnknownTransfer,
+    TransferCancelled,
     Descriptor(TryFromBytesError),
     XHCIError(XHCIError),
 }
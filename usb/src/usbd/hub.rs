@@ -0,0 +1,153 @@
+//! USB 2.0ハブクラス
+//!
+//! ハブディスクリプタと、GET_STATUSで読める下流ポートの状態。
+//!
+//! 状態変化を知らせる割り込みエンドポイントは読まないので、ハブを設定した時点で
+//! 下流ポートにつながっているデバイスだけを使える。あとから差したデバイスを使うには、ハブごと差し直す。
+
+use super::descriptor::InterfaceDescriptor;
+use crate::xhci::route::speed;
+
+/// デバイスやインターフェースディスクリプタのbDeviceClass/bInterfaceClass
+pub const HUB_CLASS: u8 = 0x09;
+/// ハブディスクリプタのbDescriptorType
+pub const DESCRIPTOR_TYPE_HUB: u8 = 0x29;
+/// ハブディスクリプタの最大の長さ(ポートが多いとビットマップが伸びる)
+pub const MAX_DESCRIPTOR_LEN: usize = 71;
+
+/// SET_FEATURE/CLEAR_FEATUREのポートの機能セレクタ
+pub mod port_feature {
+    pub const PORT_ENABLE: u16 = 1;
+    pub const PORT_RESET: u16 = 4;
+    pub const PORT_POWER: u16 = 8;
+    pub const C_PORT_CONNECTION: u16 = 16;
+    pub const C_PORT_ENABLE: u16 = 17;
+    pub const C_PORT_RESET: u16 = 20;
+}
+
+pub fn is_hub(desc: &InterfaceDescriptor) -> bool {
+    desc.interface_class == HUB_CLASS
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HubDescriptor {
+    pub num_ports: u8,
+    pub characteristics: u16,
+    /// ポートの電源を入れてから使えるようになるまでの時間(2ms単位)
+    pub power_on_to_good: u8,
+}
+
+impl HubDescriptor {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let [len, ty, num_ports, c0, c1, power_on_to_good, ..] = *data else {
+            return None;
+        };
+        if ty != DESCRIPTOR_TYPE_HUB || (len as usize) < 7 {
+            return None;
+        }
+        Some(Self {
+            num_ports,
+            characteristics: u16::from_le_bytes([c0, c1]),
+            power_on_to_good,
+        })
+    }
+
+    /// ポートの電源を入れてから接続状態を読めるようになるまでのマイクロフレーム数
+    pub fn power_on_to_good_microframes(&self) -> u16 {
+        // 2ms単位で、1msは8マイクロフレーム
+        self.power_on_to_good as u16 * 2 * 8
+    }
+
+    /// TT Think Time。Slot ContextのTTTにそのまま入れる。
+    pub fn think_time(&self) -> u8 {
+        ((self.characteristics >> 5) & 0b11) as u8
+    }
+}
+
+/// GET_STATUS(ポート)で返る4バイト
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct PortStatus {
+    pub status: u16,
+    pub change: u16,
+}
+
+impl PortStatus {
+    pub fn from_bytes(bytes: [u8; 4]) -> Self {
+        Self {
+            status: u16::from_le_bytes([bytes[0], bytes[1]]),
+            change: u16::from_le_bytes([bytes[2], bytes[3]]),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.status & (1 << 0) != 0
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.status & (1 << 1) != 0
+    }
+
+    pub fn is_resetting(&self) -> bool {
+        self.status & (1 << 4) != 0
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.status & (1 << 8) != 0
+    }
+
+    pub fn is_connection_changed(&self) -> bool {
+        self.change & (1 << 0) != 0
+    }
+
+    pub fn is_reset_changed(&self) -> bool {
+        self.change & (1 << 4) != 0
+    }
+
+    /// つながっているデバイスのxHCIでの速度
+    pub fn speed(&self) -> u8 {
+        if self.status & (1 << 9) != 0 {
+            speed::LOW
+        } else if self.status & (1 << 10) != 0 {
+            speed::HIGH
+        } else {
+            speed::FULL
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hub_descriptor() {
+        // QEMUのusb-hub
+        let data = [0x0a, 0x29, 0x08, 0x0a, 0x00, 0x01, 0x00, 0x00, 0x00, 0xff];
+        let hub = HubDescriptor::parse(&data).unwrap();
+        assert_eq!(hub.num_ports, 8);
+        assert_eq!(hub.power_on_to_good, 1);
+        assert_eq!(hub.power_on_to_good_microframes(), 16);
+        assert_eq!(hub.think_time(), 0);
+
+        let mut data = data;
+        data[3] = 0b0110_0000;
+        assert_eq!(HubDescriptor::parse(&data).unwrap().think_time(), 3);
+
+        data[1] = 0x2a;
+        assert_eq!(HubDescriptor::parse(&data), None);
+        assert_eq!(HubDescriptor::parse(&[0x09, 0x29]), None);
+    }
+
+    #[test]
+    fn test_port_status() {
+        let status = PortStatus::from_bytes([0x03, 0x03, 0x11, 0x00]);
+        assert!(status.is_connected() && status.is_enabled() && status.is_powered());
+        assert!(status.is_connection_changed() && status.is_reset_changed());
+        assert_eq!(status.speed(), speed::LOW);
+
+        let status = PortStatus::from_bytes([0x03, 0x05, 0x00, 0x00]);
+        assert_eq!(status.speed(), speed::HIGH);
+        assert!(!status.is_reset_changed());
+        assert_eq!(PortStatus::default().speed(), speed::FULL);
+    }
+}
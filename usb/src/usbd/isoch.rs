@@ -0,0 +1,112 @@
+//! Isochronous転送のスケジュール
+//!
+//! 各TDにはMFINDEXから数えたFrame IDを付けてサービス間隔ごとに並べる。
+//! 積むのが遅れて間に合わなくなったら、今のフレームから数え直す。
+
+/// 1つのTDで転送する最大のバイト数。Full SpeedのIsochの最大パケットサイズが入る。
+pub const BUFFER_SIZE: usize = 1024;
+/// 輪にして使うバッファの数。この数のサービス間隔だけ先まで積んでおく。
+pub const BUFFER_NUM: usize = 8;
+/// MFINDEXは14ビット
+const MICROFRAME_INDEX_MASK: u16 = 0x3fff;
+/// 数え直すときにスケジューリングの閾値に加えて空けるフレーム数
+const START_DELAY_FRAMES: u16 = 2;
+
+/// 転送の向きと、バッファを詰める、または受け取る関数
+#[derive(Debug, Clone, Copy)]
+pub enum IsochHandler {
+    /// 送るデータを詰める
+    Out(fn(&mut [u8])),
+    /// 受け取ったデータ
+    In(fn(&[u8])),
+}
+
+/// 次のTDを転送するフレームを決める
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    /// サービス間隔(マイクロフレーム)
+    interval: u16,
+    /// 次のTDを転送するマイクロフレーム
+    next: Option<u16>,
+}
+
+impl Schedule {
+    /// `interval`はEndpoint Contextと同じく2^`interval`×125usの指数
+    pub fn new(interval: u8) -> Self {
+        Self {
+            interval: 1 << interval.min(13),
+            next: None,
+        }
+    }
+
+    /// 次のTDのFrame ID。`mfindex`は今のマイクロフレーム、`threshold`はxHCが積まれたTDを
+    /// 実行できるようになるまでのマイクロフレーム数。
+    /// サービス間隔が1フレームより短いとFrame IDで指定できないので`None`を返し、xHCに続けて転送させる。
+    pub fn next_frame_id(&mut self, mfindex: u16, threshold: u16) -> Option<u16> {
+        if self.interval < 8 {
+            return None;
+        }
+        let earliest = mfindex.wrapping_add(threshold) & MICROFRAME_INDEX_MASK;
+        let next = match self.next {
+            Some(next) if distance(earliest, next) <= MICROFRAME_INDEX_MASK / 2 => next,
+            // 間に合わないので、フレームの頭にそろえて数え直す
+            _ => (earliest + START_DELAY_FRAMES * 8 + 7) & !7 & MICROFRAME_INDEX_MASK,
+        };
+        self.next = Some(next.wrapping_add(self.interval) & MICROFRAME_INDEX_MASK);
+        Some(next >> 3)
+    }
+
+    /// 転送が途切れたので、次のTDは今のフレームから数え直す
+    pub fn reset(&mut self) {
+        self.next = None;
+    }
+}
+
+/// `from`から`to`までのマイクロフレーム数
+fn distance(from: u16, to: u16) -> u16 {
+    to.wrapping_sub(from) & MICROFRAME_INDEX_MASK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule_every_frame() {
+        // Full Speedの1フレームごと
+        let mut schedule = Schedule::new(3);
+        assert_eq!(schedule.next_frame_id(0x100, 4), Some(0x23));
+        assert_eq!(schedule.next_frame_id(0x100, 4), Some(0x24));
+        // 時間が進んでも間に合っていれば続きのフレーム
+        assert_eq!(schedule.next_frame_id(0x118, 4), Some(0x25));
+    }
+
+    #[test]
+    fn test_schedule_resync() {
+        let mut schedule = Schedule::new(4);
+        assert_eq!(schedule.next_frame_id(0, 8), Some(3));
+        // 次に積むはずだったマイクロフレーム0x28を過ぎたので数え直す
+        assert_eq!(schedule.next_frame_id(0x40, 8), Some(0x0b));
+        assert_eq!(schedule.next_frame_id(0x40, 8), Some(0x0d));
+
+        schedule.reset();
+        assert_eq!(schedule.next_frame_id(0x40, 8), Some(0x0b));
+    }
+
+    #[test]
+    fn test_schedule_wrap() {
+        let mut schedule = Schedule::new(3);
+        assert_eq!(schedule.next_frame_id(0x3fe0, 0), Some(0x7fe));
+        assert_eq!(schedule.next_frame_id(0x3fe0, 0), Some(0x7ff));
+        assert_eq!(schedule.next_frame_id(0x3fe0, 0), Some(0));
+        // MFINDEXが一周しても続きとして扱う
+        assert_eq!(schedule.next_frame_id(0x0004, 0), Some(1));
+    }
+
+    #[test]
+    fn test_schedule_microframes() {
+        // High Speedで1フレームより短い間隔
+        let mut schedule = Schedule::new(1);
+        assert_eq!(schedule.next_frame_id(0x100, 4), None);
+    }
+}
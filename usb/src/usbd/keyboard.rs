@@ -0,0 +1,444 @@
+//! ブートプロトコルのキーボード
+//!
+//! 8バイトのレポートを前回の内容と比べて押下/解放のイベントにする。
+//! キーリピートはSET_IDLEで一定間隔ごとに届く同じレポートを数えて作る。
+
+use super::descriptor::InterfaceDescriptor;
+
+/// レポートの大きさ
+pub const REPORT_SIZE: usize = 8;
+/// SET_IDLEで指定するレポートの間隔
+pub const IDLE_MS: u32 = 32;
+/// SET_IDLEのwValueの上位バイト。単位は4ms。
+pub const IDLE_RATE: u8 = (IDLE_MS / 4) as u8;
+/// 押し続けてからリピートが始まるまで
+pub const REPEAT_DELAY_MS: u32 = 500;
+/// リピートの間隔
+pub const REPEAT_INTERVAL_MS: u32 = 32;
+
+/// 押されたキーが多すぎるときにすべてのスロットに入る値
+const ERROR_ROLL_OVER: u8 = 0x01;
+/// 0x01..=0x03はエラー表示
+const FIRST_KEY_USAGE: u8 = 0x04;
+const CAPS_LOCK: u8 = 0x39;
+/// 修飾キーのUsage IDはLeft Controlから順に並んでいる
+const LEFT_CONTROL: u8 = 0xe0;
+
+/// インターフェースディスクリプタのbInterfaceClass
+pub const HID_CLASS: u8 = 3;
+/// ブートプロトコルに対応しているインターフェースのbInterfaceSubClass
+pub const BOOT_INTERFACE_SUBCLASS: u8 = 1;
+/// ブートインターフェースのbInterfaceProtocol
+pub const KEYBOARD_PROTOCOL: u8 = 1;
+
+/// HIDクラス、Boot Interfaceサブクラス、キーボードプロトコルのインターフェースか
+pub fn is_boot_keyboard(desc: &InterfaceDescriptor) -> bool {
+    (
+        desc.interface_class,
+        desc.interface_sub_class,
+        desc.interface_protocol,
+    ) == (HID_CLASS, BOOT_INTERFACE_SUBCLASS, KEYBOARD_PROTOCOL)
+}
+
+/// レポートの先頭バイト
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const LEFT_CTRL: u8 = 1 << 0;
+    pub const LEFT_SHIFT: u8 = 1 << 1;
+    pub const LEFT_ALT: u8 = 1 << 2;
+    pub const LEFT_GUI: u8 = 1 << 3;
+    pub const RIGHT_CTRL: u8 = 1 << 4;
+    pub const RIGHT_SHIFT: u8 = 1 << 5;
+    pub const RIGHT_ALT: u8 = 1 << 6;
+    pub const RIGHT_GUI: u8 = 1 << 7;
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn contains(self, mask: u8) -> bool {
+        self.0 & mask != 0
+    }
+
+    pub const fn ctrl(self) -> bool {
+        self.contains(Self::LEFT_CTRL | Self::RIGHT_CTRL)
+    }
+
+    pub const fn shift(self) -> bool {
+        self.contains(Self::LEFT_SHIFT | Self::RIGHT_SHIFT)
+    }
+
+    pub const fn alt(self) -> bool {
+        self.contains(Self::LEFT_ALT | Self::RIGHT_ALT)
+    }
+
+    pub const fn gui(self) -> bool {
+        self.contains(Self::LEFT_GUI | Self::RIGHT_GUI)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyEventKind {
+    Press,
+    Release,
+    Repeat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyEvent {
+    /// HID Usage ID (Keyboard/Keypad Page)
+    pub usage: u8,
+    pub kind: KeyEventKind,
+    /// イベントが起きたときの修飾キー
+    pub modifiers: Modifiers,
+    pub caps_lock: bool,
+}
+
+impl KeyEvent {
+    pub fn is_modifier(&self) -> bool {
+        self.usage >= LEFT_CONTROL
+    }
+
+    /// USレイアウトで入力される文字。解放と文字にならないキーは`None`。
+    pub fn char(&self) -> Option<char> {
+        if self.kind == KeyEventKind::Release {
+            return None;
+        }
+        let &(normal, shifted) = US_LAYOUT.get(self.usage as usize)?;
+        let shift = if normal.is_ascii_lowercase() {
+            self.modifiers.shift() != self.caps_lock
+        } else {
+            self.modifiers.shift()
+        };
+        let c = if shift { shifted } else { normal };
+        (c != '\0').then_some(c)
+    }
+}
+
+/// Usage IDごとの(通常, Shift)。0x64より後ろは文字にならない。
+const US_LAYOUT: [(char, char); 0x64] = {
+    let mut table = [('\0', '\0'); 0x64];
+    let mut i = 0;
+    while i < 26 {
+        table[0x04 + i] = ((b'a' + i as u8) as char, (b'A' + i as u8) as char);
+        i += 1;
+    }
+    let digits = *b"1234567890";
+    let symbols = *b"!@#$%^&*()";
+    let mut i = 0;
+    while i < 10 {
+        table[0x1e + i] = (digits[i] as char, symbols[i] as char);
+        table[0x59 + i] = (digits[i] as char, digits[i] as char);
+        i += 1;
+    }
+    table[0x28] = ('\n', '\n');
+    table[0x2a] = ('\x08', '\x08');
+    table[0x2b] = ('\t', '\t');
+    table[0x2c] = (' ', ' ');
+    table[0x2d] = ('-', '_');
+    table[0x2e] = ('=', '+');
+    table[0x2f] = ('[', '{');
+    table[0x30] = (']', '}');
+    table[0x31] = ('\\', '|');
+    table[0x32] = ('#', '~');
+    table[0x33] = (';', ':');
+    table[0x34] = ('\'', '"');
+    table[0x35] = ('`', '~');
+    table[0x36] = (',', '<');
+    table[0x37] = ('.', '>');
+    table[0x38] = ('/', '?');
+    table[0x54] = ('/', '/');
+    table[0x55] = ('*', '*');
+    table[0x56] = ('-', '-');
+    table[0x57] = ('+', '+');
+    table[0x58] = ('\n', '\n');
+    table[0x63] = ('.', '.');
+    table
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct BootReport {
+    pub modifiers: Modifiers,
+    /// 押されているキー。空きは0。
+    pub keys: [u8; 6],
+}
+
+impl BootReport {
+    /// 予約バイトを含む8バイトに満たなければ`None`
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; REPORT_SIZE] = bytes.get(..REPORT_SIZE)?.try_into().ok()?;
+        let mut keys = [0; 6];
+        keys.copy_from_slice(&bytes[2..]);
+        Some(Self {
+            modifiers: Modifiers::from_bits(bytes[0]),
+            keys,
+        })
+    }
+
+    /// 同時押しが多すぎてキーの状態が分からない
+    pub fn is_roll_over(&self) -> bool {
+        self.keys.iter().all(|&k| k == ERROR_ROLL_OVER)
+    }
+
+    fn contains(&self, usage: u8) -> bool {
+        self.keys.contains(&usage)
+    }
+
+    fn pressed(&self) -> impl Iterator<Item = u8> + '_ {
+        self.keys.iter().copied().filter(|&k| k >= FIRST_KEY_USAGE)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Repeat {
+    usage: u8,
+    elapsed_ms: u32,
+}
+
+/// 前回のレポートとキーリピートの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keyboard {
+    prev: BootReport,
+    caps_lock: bool,
+    repeat: Option<Repeat>,
+    /// 変化が無いときにレポートが届く間隔
+    idle_ms: u32,
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new(IDLE_MS)
+    }
+}
+
+impl Keyboard {
+    pub const fn new(idle_ms: u32) -> Self {
+        Self {
+            prev: BootReport {
+                modifiers: Modifiers(0),
+                keys: [0; 6],
+            },
+            caps_lock: false,
+            repeat: None,
+            idle_ms,
+        }
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        self.prev.modifiers
+    }
+
+    pub fn caps_lock(&self) -> bool {
+        self.caps_lock
+    }
+
+    /// レポートを1つ処理して、起きたイベントを`emit`に渡す
+    pub fn update(&mut self, report: &[u8], mut emit: impl FnMut(KeyEvent)) {
+        let Some(mut report) = BootReport::from_bytes(report) else {
+            return;
+        };
+        // キーの状態は前回のまま、修飾キーだけ反映する
+        if report.is_roll_over() {
+            report.keys = self.prev.keys;
+        }
+
+        let prev = self.prev;
+        let mut event = |usage, kind, modifiers| {
+            emit(KeyEvent {
+                usage,
+                kind,
+                modifiers,
+                caps_lock: self.caps_lock,
+            })
+        };
+
+        for bit in 0..8 {
+            let mask = 1 << bit;
+            let (before, after) = (
+                prev.modifiers.contains(mask),
+                report.modifiers.contains(mask),
+            );
+            if before != after {
+                let kind = if after {
+                    KeyEventKind::Press
+                } else {
+                    KeyEventKind::Release
+                };
+                event(LEFT_CONTROL + bit, kind, report.modifiers);
+            }
+        }
+
+        for usage in prev.pressed().filter(|&k| !report.contains(k)) {
+            event(usage, KeyEventKind::Release, report.modifiers);
+            if self.repeat.is_some_and(|r| r.usage == usage) {
+                self.repeat = None;
+            }
+        }
+
+        let mut changed = false;
+        for usage in report.pressed().filter(|&k| !prev.contains(k)) {
+            if usage == CAPS_LOCK {
+                self.caps_lock = !self.caps_lock;
+            }
+            emit(KeyEvent {
+                usage,
+                kind: KeyEventKind::Press,
+                modifiers: report.modifiers,
+                caps_lock: self.caps_lock,
+            });
+            self.repeat = Some(Repeat {
+                usage,
+                elapsed_ms: 0,
+            });
+            changed = true;
+        }
+
+        if !changed && report.keys == prev.keys {
+            if let Some(repeat) = self.repeat.as_mut() {
+                repeat.elapsed_ms += self.idle_ms;
+                if repeat.elapsed_ms >= REPEAT_DELAY_MS {
+                    repeat.elapsed_ms -= REPEAT_INTERVAL_MS;
+                    emit(KeyEvent {
+                        usage: repeat.usage,
+                        kind: KeyEventKind::Repeat,
+                        modifiers: report.modifiers,
+                        caps_lock: self.caps_lock,
+                    });
+                }
+            }
+        }
+
+        self.prev = report;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn update(keyboard: &mut Keyboard, report: [u8; 8]) -> Vec<KeyEvent> {
+        let mut events = Vec::new();
+        keyboard.update(&report, |e| events.push(e));
+        events
+    }
+
+    fn kinds(events: &[KeyEvent]) -> Vec<(u8, KeyEventKind)> {
+        events.iter().map(|e| (e.usage, e.kind)).collect()
+    }
+
+    #[test]
+    fn test_press_release() {
+        let mut keyboard = Keyboard::default();
+
+        let events = update(&mut keyboard, [0, 0, 0x04, 0, 0, 0, 0, 0]);
+        assert_eq!(kinds(&events), [(0x04, KeyEventKind::Press)]);
+        assert_eq!(events[0].char(), Some('a'));
+
+        let events = update(&mut keyboard, [0, 0, 0x04, 0x05, 0, 0, 0, 0]);
+        assert_eq!(kinds(&events), [(0x05, KeyEventKind::Press)]);
+
+        let events = update(&mut keyboard, [0, 0, 0x05, 0, 0, 0, 0, 0]);
+        assert_eq!(kinds(&events), [(0x04, KeyEventKind::Release)]);
+        assert_eq!(events[0].char(), None);
+
+        assert!(update(&mut keyboard, [0, 0, 0, 0, 0, 0, 0, 0])
+            .iter()
+            .all(|e| e.kind == KeyEventKind::Release));
+    }
+
+    #[test]
+    fn test_modifiers() {
+        let mut keyboard = Keyboard::default();
+
+        let events = update(&mut keyboard, [Modifiers::LEFT_SHIFT, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(kinds(&events), [(0xe1, KeyEventKind::Press)]);
+        assert!(events[0].is_modifier());
+
+        let events = update(
+            &mut keyboard,
+            [Modifiers::LEFT_SHIFT, 0, 0x1e, 0, 0, 0, 0, 0],
+        );
+        assert!(events[0].modifiers.shift());
+        assert_eq!(events[0].char(), Some('!'));
+
+        let events = update(&mut keyboard, [0, 0, 0x1e, 0, 0, 0, 0, 0]);
+        assert_eq!(kinds(&events), [(0xe1, KeyEventKind::Release)]);
+        assert!(!keyboard.modifiers().shift());
+    }
+
+    #[test]
+    fn test_caps_lock() {
+        let mut keyboard = Keyboard::default();
+        update(&mut keyboard, [0, 0, CAPS_LOCK, 0, 0, 0, 0, 0]);
+        update(&mut keyboard, [0; 8]);
+        assert!(keyboard.caps_lock());
+
+        let events = update(&mut keyboard, [0, 0, 0x04, 0, 0, 0, 0, 0]);
+        assert_eq!(events[0].char(), Some('A'));
+        // 数字には効かない
+        let events = update(&mut keyboard, [0, 0, 0x04, 0x1f, 0, 0, 0, 0]);
+        assert_eq!(events[0].char(), Some('2'));
+    }
+
+    #[test]
+    fn test_roll_over() {
+        let mut keyboard = Keyboard::default();
+        update(&mut keyboard, [0, 0, 0x04, 0x05, 0, 0, 0, 0]);
+
+        let events = update(&mut keyboard, [Modifiers::LEFT_CTRL, 0, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(kinds(&events), [(0xe0, KeyEventKind::Press)]);
+
+        let events = update(&mut keyboard, [0, 0, 0x05, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            kinds(&events),
+            [(0xe0, KeyEventKind::Release), (0x04, KeyEventKind::Release)]
+        );
+    }
+
+    #[test]
+    fn test_repeat() {
+        let mut keyboard = Keyboard::new(100);
+        let report = [0, 0, 0x2c, 0, 0, 0, 0, 0];
+        update(&mut keyboard, report);
+
+        let repeats: Vec<_> = (0..8)
+            .map(|_| update(&mut keyboard, report))
+            .map(|events| kinds(&events))
+            .collect();
+        let none: Vec<(u8, KeyEventKind)> = Vec::new();
+        let repeat = std::vec![(0x2c, KeyEventKind::Repeat)];
+        assert_eq!(
+            repeats[..4],
+            [none.clone(), none.clone(), none.clone(), none]
+        );
+        assert!(repeats[4..].iter().all(|r| *r == repeat));
+
+        update(&mut keyboard, [0; 8]);
+        assert!(update(&mut keyboard, [0; 8]).is_empty());
+    }
+
+    #[test]
+    fn test_layout() {
+        let event = |usage, modifiers| KeyEvent {
+            usage,
+            kind: KeyEventKind::Press,
+            modifiers: Modifiers::from_bits(modifiers),
+            caps_lock: false,
+        };
+        assert_eq!(event(0x28, 0).char(), Some('\n'));
+        assert_eq!(event(0x38, Modifiers::RIGHT_SHIFT).char(), Some('?'));
+        assert_eq!(event(0x62, 0).char(), Some('0'));
+        assert_eq!(event(0x3a, 0).char(), None);
+        assert_eq!(event(0xe0, 0).char(), None);
+    }
+}
@@ -0,0 +1,137 @@
+//! マスストレージクラスのBulk-Only Transport
+//!
+//! Bulk OUTでCBWを送り、必要ならデータを転送して、最後にBulk INでCSWを受け取る。
+
+use super::{descriptor::InterfaceDescriptor, endpoint::Direction, error::Error, scsi};
+
+/// インターフェースディスクリプタのbInterfaceClass
+pub const MASS_STORAGE_CLASS: u8 = 0x08;
+/// SCSI transparent command set
+pub const SCSI_SUBCLASS: u8 = 0x06;
+pub const BULK_ONLY_PROTOCOL: u8 = 0x50;
+
+pub const CBW_SIGNATURE: u32 = 0x4342_5355;
+pub const CSW_SIGNATURE: u32 = 0x5342_5355;
+pub const CBW_LEN: usize = 31;
+pub const CSW_LEN: usize = 13;
+
+/// SCSIコマンドをBulk-Only Transportで送るインターフェースか
+pub fn is_bulk_only_storage(desc: &InterfaceDescriptor) -> bool {
+    (
+        desc.interface_class,
+        desc.interface_sub_class,
+        desc.interface_protocol,
+    ) == (MASS_STORAGE_CLASS, SCSI_SUBCLASS, BULK_ONLY_PROTOCOL)
+}
+
+/// Command Block Wrapper
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CommandBlockWrapper {
+    pub tag: u32,
+    pub data_transfer_length: u32,
+    /// データが無いときは無視される
+    pub direction: Direction,
+    pub lun: u8,
+    pub command: scsi::Command,
+}
+
+impl CommandBlockWrapper {
+    pub fn to_bytes(&self) -> [u8; CBW_LEN] {
+        let command = self.command.as_bytes();
+        let mut bytes = [0; CBW_LEN];
+        bytes[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.data_transfer_length.to_le_bytes());
+        bytes[12] = ((self.direction == Direction::In) as u8) << 7;
+        bytes[13] = self.lun & 0x0f;
+        bytes[14] = command.len() as u8;
+        bytes[15..15 + command.len()].copy_from_slice(command);
+        bytes
+    }
+}
+
+/// CSWのbCSWStatus
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommandStatus {
+    Passed,
+    /// REQUEST SENSEで理由を読める
+    Failed,
+    /// リセットしないと次のコマンドを受け付けない
+    PhaseError,
+}
+
+/// Command Status Wrapper
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CommandStatusWrapper {
+    pub tag: u32,
+    /// 要求したデータのうち転送されなかったバイト数
+    pub data_residue: u32,
+    pub status: CommandStatus,
+}
+
+impl TryFrom<&[u8]> for CommandStatusWrapper {
+    type Error = Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let value = value.get(..CSW_LEN).ok_or_else(Error::invalid_csw)?;
+        let u32_at = |i: usize| u32::from_le_bytes(value[i..i + 4].try_into().unwrap());
+        if u32_at(0) != CSW_SIGNATURE {
+            return Err(Error::invalid_csw());
+        }
+        let status = match value[12] {
+            0 => CommandStatus::Passed,
+            1 => CommandStatus::Failed,
+            2 => CommandStatus::PhaseError,
+            _ => return Err(Error::invalid_csw()),
+        };
+
+        Ok(Self {
+            tag: u32_at(4),
+            data_residue: u32_at(8),
+            status,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cbw_bytes() {
+        let cbw = CommandBlockWrapper {
+            tag: 0x1234_5678,
+            data_transfer_length: 512,
+            direction: Direction::In,
+            lun: 0,
+            command: scsi::Command::read_10(2, 1),
+        };
+        assert_eq!(
+            cbw.to_bytes(),
+            [
+                0x55, 0x53, 0x42, 0x43, 0x78, 0x56, 0x34, 0x12, 0x00, 0x02, 0, 0, 0x80, 0, 10,
+                0x28, 0, 0, 0, 0, 2, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_csw() {
+        let mut bytes = [0x55, 0x53, 0x42, 0x53, 1, 0, 0, 0, 4, 0, 0, 0, 1];
+        assert_eq!(
+            CommandStatusWrapper::try_from(bytes.as_slice()),
+            Ok(CommandStatusWrapper {
+                tag: 1,
+                data_residue: 4,
+                status: CommandStatus::Failed,
+            })
+        );
+
+        assert!(CommandStatusWrapper::try_from(&bytes[..12]).is_err());
+        bytes[12] = 3;
+        assert!(CommandStatusWrapper::try_from(bytes.as_slice()).is_err());
+        bytes[0] = 0;
+        bytes[12] = 0;
+        assert!(CommandStatusWrapper::try_from(bytes.as_slice()).is_err());
+    }
+}
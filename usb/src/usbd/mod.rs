@@ -1,5 +1,14 @@
+pub mod audio;
 pub mod descriptor;
 pub mod device;
 pub mod driver;
 pub mod endpoint;
 pub mod error;
+pub mod hub;
+pub mod isoch;
+pub mod keyboard;
+pub mod mass_storage;
+pub mod pointer;
+pub mod report_descriptor;
+pub mod request;
+pub mod scsi;
@@ -0,0 +1,202 @@
+//! レポートプロトコルのマウス/タブレット
+//!
+//! レポートディスクリプタからボタン、X/Y、ホイールのフィールドを探しておき、届いたレポートから取り出す。
+
+use super::report_descriptor::{usage, usage_page, Field, ReportKind, ReportLayout};
+
+/// 扱うボタンの数
+pub const MAX_BUTTONS: usize = 8;
+
+/// HID仕様書Appendix Bのブートプロトコルのマウスのレポートディスクリプタ
+const BOOT_MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x09, 0x01, 0xa1, 0x00, 0x05, 0x09, 0x19, 0x01, 0x29, 0x03,
+    0x15, 0x00, 0x25, 0x01, 0x95, 0x03, 0x75, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x05, 0x81, 0x01,
+    0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x15, 0x81, 0x25, 0x7f, 0x75, 0x08, 0x95, 0x02, 0x81, 0x06,
+    0xc0, 0xc0,
+];
+
+/// インターフェースディスクリプタのbInterfaceProtocol
+pub const MOUSE_PROTOCOL: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Motion {
+    Relative {
+        dx: i32,
+        dy: i32,
+    },
+    /// `x`、`y`は`0..=max_x`、`0..=max_y`に直してある
+    Absolute {
+        x: i32,
+        y: i32,
+        max_x: i32,
+        max_y: i32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PointerEvent {
+    /// 押されているボタン。ボタン1が最下位ビット。
+    pub buttons: u8,
+    pub motion: Motion,
+    /// 手前に回すと負
+    pub wheel: i32,
+}
+
+/// レポートのどこに何が入っているか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pointer {
+    has_report_ids: bool,
+    report_id: u8,
+    x: Field,
+    y: Field,
+    wheel: Option<Field>,
+    buttons: [Option<Field>; MAX_BUTTONS],
+}
+
+impl Pointer {
+    /// X/YのInputフィールドが無ければ`None`
+    pub fn from_layout(layout: &ReportLayout) -> Option<Self> {
+        let find = |page, usage| layout.find(ReportKind::Input, page, usage).copied();
+        let x = find(usage_page::GENERIC_DESKTOP, usage::X)?;
+        let y = find(usage_page::GENERIC_DESKTOP, usage::Y)?;
+        // 同じレポートに入っているものだけ使う
+        let same_report = |f: &Field| f.report_id == x.report_id;
+        if !same_report(&y) {
+            return None;
+        }
+
+        let wheel = find(usage_page::GENERIC_DESKTOP, usage::WHEEL).filter(same_report);
+        let mut buttons = [None; MAX_BUTTONS];
+        for (i, button) in buttons.iter_mut().enumerate() {
+            *button = find(usage_page::BUTTON, i as u16 + 1).filter(same_report);
+        }
+
+        Some(Self {
+            has_report_ids: layout.has_report_ids(),
+            report_id: x.report_id,
+            x,
+            y,
+            wheel,
+            buttons,
+        })
+    }
+
+    /// ブートプロトコルに切り替えたマウスの3バイトのレポート
+    pub fn boot_mouse() -> Self {
+        let layout = ReportLayout::parse(BOOT_MOUSE_REPORT_DESCRIPTOR).unwrap();
+        Self::from_layout(&layout).unwrap()
+    }
+
+    pub fn is_absolute(&self) -> bool {
+        !self.x.is_relative()
+    }
+
+    /// 別のReport IDのレポートや短すぎるレポートなら`None`
+    pub fn parse(&self, report: &[u8]) -> Option<PointerEvent> {
+        let data = if self.has_report_ids {
+            let (&id, data) = report.split_first()?;
+            if id != self.report_id {
+                return None;
+            }
+            data
+        } else {
+            report
+        };
+
+        let x = self.x.extract(data)?;
+        let y = self.y.extract(data)?;
+        let motion = if self.is_absolute() {
+            let clamp = |v: i32, f: &Field| v.clamp(f.logical_min, f.logical_max) - f.logical_min;
+            Motion::Absolute {
+                x: clamp(x, &self.x),
+                y: clamp(y, &self.y),
+                max_x: self.x.logical_max - self.x.logical_min,
+                max_y: self.y.logical_max - self.y.logical_min,
+            }
+        } else {
+            Motion::Relative { dx: x, dy: y }
+        };
+
+        let wheel = self.wheel.and_then(|f| f.extract(data)).unwrap_or(0);
+        let buttons = self
+            .buttons
+            .iter()
+            .enumerate()
+            .filter_map(|(i, f)| Some((i, f.as_ref()?.extract(data)?)))
+            .fold(0u8, |acc, (i, v)| acc | (((v != 0) as u8) << i));
+
+        Some(PointerEvent {
+            buttons,
+            motion,
+            wheel,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbd::report_descriptor::tests::{QEMU_TABLET, WHEEL_MOUSE};
+
+    #[test]
+    fn test_tablet() {
+        let layout = ReportLayout::parse(QEMU_TABLET).unwrap();
+        let pointer = Pointer::from_layout(&layout).unwrap();
+        assert!(pointer.is_absolute());
+
+        let event = pointer
+            .parse(&[0b011, 0xff, 0x3f, 0x00, 0x10, 0x01])
+            .unwrap();
+        assert_eq!(
+            event,
+            PointerEvent {
+                buttons: 0b011,
+                motion: Motion::Absolute {
+                    x: 0x3fff,
+                    y: 0x1000,
+                    max_x: 0x7fff,
+                    max_y: 0x7fff,
+                },
+                wheel: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_wheel_mouse() {
+        let layout = ReportLayout::parse(WHEEL_MOUSE).unwrap();
+        let pointer = Pointer::from_layout(&layout).unwrap();
+        assert!(!pointer.is_absolute());
+
+        let event = pointer.parse(&[2, 0b10000, 0xfb, 0x03, 0xff]).unwrap();
+        assert_eq!(event.buttons, 0b10000);
+        assert_eq!(event.motion, Motion::Relative { dx: -5, dy: 3 });
+        assert_eq!(event.wheel, -1);
+
+        // 別のReport ID
+        assert_eq!(pointer.parse(&[1, 0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn test_boot_mouse() {
+        let pointer = Pointer::boot_mouse();
+        assert!(!pointer.is_absolute());
+
+        // ホイールの入った4バイト目は読まない
+        let event = pointer.parse(&[0b101, 0xfe, 3, 1]).unwrap();
+        assert_eq!(
+            event,
+            PointerEvent {
+                buttons: 0b101,
+                motion: Motion::Relative { dx: -2, dy: 3 },
+                wheel: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_no_pointer() {
+        let layout = ReportLayout::parse(&[0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, 0xc0]).unwrap();
+        assert_eq!(Pointer::from_layout(&layout), None);
+    }
+}
@@ -0,0 +1,600 @@
+//! HIDレポートディスクリプタの解析
+//!
+//! Main/Global/Localの各アイテムをたどって、レポート中のどのビットにどのUsageの値が入るかを求める。
+//! Variableのアイテムは値ごとに、Arrayのアイテムはまとめて1つの`Field`にする。
+
+/// 1つのディスクリプタから取り出せる`Field`の数
+pub const MAX_FIELDS: usize = 64;
+/// 1つのMainアイテムに付けられるUsageの数
+const MAX_USAGES: usize = 16;
+/// Push/Popのネストの深さ
+const GLOBAL_STACK_SIZE: usize = 4;
+/// Report IDごとに位置を覚えておくレポートの数
+const MAX_REPORTS: usize = 16;
+
+pub mod usage_page {
+    pub const GENERIC_DESKTOP: u16 = 0x01;
+    pub const KEYBOARD: u16 = 0x07;
+    pub const LED: u16 = 0x08;
+    pub const BUTTON: u16 = 0x09;
+    pub const CONSUMER: u16 = 0x0c;
+}
+
+/// Generic Desktopページのusage
+pub mod usage {
+    pub const POINTER: u16 = 0x01;
+    pub const MOUSE: u16 = 0x02;
+    pub const KEYBOARD: u16 = 0x06;
+    pub const X: u16 = 0x30;
+    pub const Y: u16 = 0x31;
+    pub const WHEEL: u16 = 0x38;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// アイテムのデータが途中で切れている
+    Truncated,
+    TooManyFields,
+    TooManyUsages,
+    TooManyReports,
+    PushOverflow,
+    PopWithoutPush,
+    UnbalancedCollection,
+    /// Report Sizeが32ビットより大きい
+    InvalidReportSize,
+    /// Report Size×Report Countでレポートのビット位置があふれる
+    ReportTooLong,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub enum ReportKind {
+    #[default]
+    Input,
+    Output,
+    Feature,
+}
+
+/// Input/Output/Featureアイテムのデータ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct MainFlags(u32);
+
+impl MainFlags {
+    pub fn is_constant(self) -> bool {
+        self.0 & (1 << 0) != 0
+    }
+
+    /// falseならArray
+    pub fn is_variable(self) -> bool {
+        self.0 & (1 << 1) != 0
+    }
+
+    /// falseならAbsolute
+    pub fn is_relative(self) -> bool {
+        self.0 & (1 << 2) != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct Field {
+    pub kind: ReportKind,
+    /// Report IDを使わないディスクリプタでは0
+    pub report_id: u8,
+    pub usage_page: u16,
+    /// Arrayのときは範囲の先頭
+    pub usage: u16,
+    /// Arrayのときの範囲の最後。Variableでは`usage`と同じ。
+    pub usage_max: u16,
+    /// Report IDのバイトを除いたレポートの先頭からの位置
+    pub bit_offset: u32,
+    pub bit_size: u8,
+    /// Arrayの要素数。Variableでは1。
+    pub count: u8,
+    pub logical_min: i32,
+    pub logical_max: i32,
+    pub flags: MainFlags,
+}
+
+impl Field {
+    pub fn is_relative(&self) -> bool {
+        self.flags.is_relative()
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.logical_min < 0
+    }
+
+    /// `index`番目の値。`data`はReport IDを除いたレポート。
+    pub fn extract_at(&self, data: &[u8], index: u8) -> Option<i32> {
+        if index >= self.count || self.bit_size == 0 || self.bit_size > 32 {
+            return None;
+        }
+        let start = self.bit_offset + self.bit_size as u32 * index as u32;
+        let raw = read_bits(data, start, self.bit_size)?;
+        Some(if self.is_signed() {
+            sign_extend(raw, self.bit_size)
+        } else {
+            raw as i32
+        })
+    }
+
+    pub fn extract(&self, data: &[u8]) -> Option<i32> {
+        self.extract_at(data, 0)
+    }
+}
+
+fn read_bits(data: &[u8], start: u32, size: u8) -> Option<u32> {
+    let end = start + size as u32;
+    if end.div_ceil(8) as usize > data.len() {
+        return None;
+    }
+    let mut value = 0u64;
+    for (i, byte) in data[(start / 8) as usize..end.div_ceil(8) as usize]
+        .iter()
+        .enumerate()
+    {
+        value |= (*byte as u64) << (i * 8);
+    }
+    value >>= start % 8;
+    Some((value & ((1u64 << size) - 1)) as u32)
+}
+
+fn sign_extend(value: u32, bits: u8) -> i32 {
+    let shift = 32 - bits as u32;
+    ((value << shift) as i32) >> shift
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct GlobalState {
+    usage_page: u16,
+    logical_min: i32,
+    logical_max: i32,
+    report_size: u32,
+    report_id: u8,
+    report_count: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct LocalState {
+    /// (usage page, usage)。ページは拡張Usage(4バイト)でなければ0。
+    usages: [(u16, u16); MAX_USAGES],
+    usage_len: usize,
+    usage_min: Option<(u16, u16)>,
+    usage_max: Option<(u16, u16)>,
+}
+
+impl LocalState {
+    fn push_usage(&mut self, usage: (u16, u16)) -> Result<(), ParseError> {
+        let slot = self
+            .usages
+            .get_mut(self.usage_len)
+            .ok_or(ParseError::TooManyUsages)?;
+        *slot = usage;
+        self.usage_len += 1;
+        Ok(())
+    }
+
+    /// Variableアイテムの`index`番目の値に対応するUsage。足りなければ最後のものを使い続ける。
+    fn usage(&self, index: u32) -> Option<(u16, u16)> {
+        if self.usage_len > 0 {
+            let i = (index as usize).min(self.usage_len - 1);
+            return Some(self.usages[i]);
+        }
+        let (page, min) = self.usage_min?;
+        let max = self.usage_max.map_or(min, |(_, max)| max);
+        let usage = (min as u32 + index).min(max as u32) as u16;
+        Some((page, usage))
+    }
+}
+
+/// ディスクリプタから取り出したフィールドの一覧
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportLayout {
+    fields: [Field; MAX_FIELDS],
+    len: usize,
+    has_report_ids: bool,
+}
+
+impl ReportLayout {
+    pub fn parse(descriptor: &[u8]) -> Result<Self, ParseError> {
+        Parser::default().parse(descriptor)
+    }
+
+    pub fn fields(&self) -> &[Field] {
+        &self.fields[..self.len]
+    }
+
+    /// レポートの先頭1バイトがReport IDか
+    pub fn has_report_ids(&self) -> bool {
+        self.has_report_ids
+    }
+
+    pub fn find(&self, kind: ReportKind, usage_page: u16, usage: u16) -> Option<&Field> {
+        self.fields().iter().find(|f| {
+            f.kind == kind && f.usage_page == usage_page && (f.usage..=f.usage_max).contains(&usage)
+        })
+    }
+
+    /// 受け取ったレポートをReport IDとデータに分ける
+    pub fn split_report<'r>(&self, report: &'r [u8]) -> Option<(u8, &'r [u8])> {
+        if self.has_report_ids {
+            let (&id, data) = report.split_first()?;
+            Some((id, data))
+        } else {
+            Some((0, report))
+        }
+    }
+
+    fn push(&mut self, field: Field) -> Result<(), ParseError> {
+        let slot = self
+            .fields
+            .get_mut(self.len)
+            .ok_or(ParseError::TooManyFields)?;
+        *slot = field;
+        self.len += 1;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Parser {
+    global: GlobalState,
+    stack: [GlobalState; GLOBAL_STACK_SIZE],
+    stack_len: usize,
+    local: LocalState,
+    collection_depth: usize,
+    /// (Report ID, 種類, 次のフィールドのビット位置)
+    offsets: [(u8, ReportKind, u32); MAX_REPORTS],
+    offsets_len: usize,
+}
+
+impl Parser {
+    fn parse(mut self, descriptor: &[u8]) -> Result<ReportLayout, ParseError> {
+        let mut layout = ReportLayout {
+            fields: [Field::default(); MAX_FIELDS],
+            len: 0,
+            has_report_ids: false,
+        };
+
+        let mut rest = descriptor;
+        while let Some((&prefix, tail)) = rest.split_first() {
+            // Long item
+            if prefix == 0xfe {
+                let [size, _tag, ..] = tail else {
+                    return Err(ParseError::Truncated);
+                };
+                let size = 2 + *size as usize;
+                rest = tail.get(size..).ok_or(ParseError::Truncated)?;
+                continue;
+            }
+
+            let size = match prefix & 0b11 {
+                3 => 4,
+                n => n as usize,
+            };
+            let data = tail.get(..size).ok_or(ParseError::Truncated)?;
+            rest = &tail[size..];
+
+            let mut bytes = [0u8; 4];
+            bytes[..size].copy_from_slice(data);
+            let unsigned = u32::from_le_bytes(bytes);
+            let signed = match size {
+                0 => 0,
+                4 => unsigned as i32,
+                n => sign_extend(unsigned, n as u8 * 8),
+            };
+
+            let item_type = (prefix >> 2) & 0b11;
+            let tag = prefix >> 4;
+            match item_type {
+                0 => self.main_item(tag, unsigned, &mut layout)?,
+                1 => self.global_item(tag, unsigned, signed, &mut layout)?,
+                2 => self.local_item(tag, unsigned, size)?,
+                _ => {}
+            }
+        }
+
+        if self.collection_depth != 0 {
+            return Err(ParseError::UnbalancedCollection);
+        }
+        Ok(layout)
+    }
+
+    fn main_item(
+        &mut self,
+        tag: u8,
+        data: u32,
+        layout: &mut ReportLayout,
+    ) -> Result<(), ParseError> {
+        let kind = match tag {
+            0x8 => ReportKind::Input,
+            0x9 => ReportKind::Output,
+            0xb => ReportKind::Feature,
+            0xa => {
+                self.collection_depth += 1;
+                self.local = LocalState::default();
+                return Ok(());
+            }
+            0xc => {
+                self.collection_depth = self
+                    .collection_depth
+                    .checked_sub(1)
+                    .ok_or(ParseError::UnbalancedCollection)?;
+                self.local = LocalState::default();
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+
+        let flags = MainFlags(data);
+        let global = self.global;
+        let offset = self.offset_mut(global.report_id, kind)?;
+        let start = *offset;
+        *offset = global
+            .report_size
+            .checked_mul(global.report_count)
+            .and_then(|bits| start.checked_add(bits))
+            .ok_or(ParseError::ReportTooLong)?;
+
+        // 定数はパディングなので位置だけ進める
+        if !flags.is_constant() && global.report_size != 0 {
+            // Logical Minimumが0以上ならLogical Maximumは符号なしとして読む
+            let logical_max = if global.logical_min >= 0 && global.logical_max < global.logical_min
+            {
+                (global.logical_max as u32 & size_mask(global.report_size)) as i32
+            } else {
+                global.logical_max
+            };
+            let field = |usage_page, usage, usage_max, bit_offset, count| Field {
+                kind,
+                report_id: global.report_id,
+                usage_page,
+                usage,
+                usage_max,
+                bit_offset,
+                bit_size: global.report_size as u8,
+                count,
+                logical_min: global.logical_min,
+                logical_max,
+                flags,
+            };
+            let page = |page: u16| if page == 0 { global.usage_page } else { page };
+
+            if flags.is_variable() {
+                for i in 0..global.report_count {
+                    let Some((usage_page, usage)) = self.local.usage(i) else {
+                        break;
+                    };
+                    let bit_offset = start + global.report_size * i;
+                    layout.push(field(page(usage_page), usage, usage, bit_offset, 1))?;
+                }
+            } else {
+                let (usage_page, min, max) = match (self.local.usage_min, self.local.usage_max) {
+                    (Some((page, min)), Some((_, max))) => (page, min, max),
+                    _ => {
+                        let (page, usage) = self.local.usage(0).unwrap_or_default();
+                        (page, usage, usage)
+                    }
+                };
+                let count = global.report_count.min(u8::MAX as u32) as u8;
+                layout.push(field(page(usage_page), min, max, start, count))?;
+            }
+        }
+
+        self.local = LocalState::default();
+        Ok(())
+    }
+
+    fn global_item(
+        &mut self,
+        tag: u8,
+        unsigned: u32,
+        signed: i32,
+        layout: &mut ReportLayout,
+    ) -> Result<(), ParseError> {
+        match tag {
+            0x0 => self.global.usage_page = unsigned as u16,
+            0x1 => self.global.logical_min = signed,
+            0x2 => self.global.logical_max = signed,
+            // 32ビットまでしかフィールドの値を取り出せない
+            0x7 if unsigned > 32 => return Err(ParseError::InvalidReportSize),
+            0x7 => self.global.report_size = unsigned,
+            0x8 => {
+                self.global.report_id = unsigned as u8;
+                layout.has_report_ids = true;
+            }
+            0x9 => self.global.report_count = unsigned,
+            0xa => {
+                let slot = self
+                    .stack
+                    .get_mut(self.stack_len)
+                    .ok_or(ParseError::PushOverflow)?;
+                *slot = self.global;
+                self.stack_len += 1;
+            }
+            0xb => {
+                self.stack_len = self
+                    .stack_len
+                    .checked_sub(1)
+                    .ok_or(ParseError::PopWithoutPush)?;
+                self.global = self.stack[self.stack_len];
+            }
+            // Physical Minimum/Maximum、Unit Exponent、Unitは使わない
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn local_item(&mut self, tag: u8, data: u32, size: usize) -> Result<(), ParseError> {
+        // 4バイトのUsageは上位16bitがUsage Page
+        let usage = if size == 4 {
+            ((data >> 16) as u16, data as u16)
+        } else {
+            (0, data as u16)
+        };
+        match tag {
+            0x0 => self.local.push_usage(usage)?,
+            0x1 => self.local.usage_min = Some(usage),
+            0x2 => self.local.usage_max = Some(usage),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn offset_mut(&mut self, report_id: u8, kind: ReportKind) -> Result<&mut u32, ParseError> {
+        let index = match self.offsets[..self.offsets_len]
+            .iter()
+            .position(|&(id, k, _)| (id, k) == (report_id, kind))
+        {
+            Some(i) => i,
+            None => {
+                let slot = self
+                    .offsets
+                    .get_mut(self.offsets_len)
+                    .ok_or(ParseError::TooManyReports)?;
+                *slot = (report_id, kind, 0);
+                self.offsets_len += 1;
+                self.offsets_len - 1
+            }
+        };
+        Ok(&mut self.offsets[index].2)
+    }
+}
+
+fn size_mask(bits: u32) -> u32 {
+    if bits >= 32 {
+        u32::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// QEMUのusb-tabletのレポートディスクリプタ
+    pub const QEMU_TABLET: &[u8] = &[
+        0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x09, 0x01, 0xa1, 0x00, 0x05, 0x09, 0x19, 0x01, 0x29,
+        0x03, 0x15, 0x00, 0x25, 0x01, 0x95, 0x03, 0x75, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x05,
+        0x81, 0x01, 0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x15, 0x00, 0x26, 0xff, 0x7f, 0x35, 0x00,
+        0x46, 0xff, 0x7f, 0x75, 0x10, 0x95, 0x02, 0x81, 0x02, 0x05, 0x01, 0x09, 0x38, 0x15, 0x81,
+        0x25, 0x7f, 0x35, 0x00, 0x45, 0x00, 0x75, 0x08, 0x95, 0x01, 0x81, 0x06, 0xc0, 0xc0,
+    ];
+
+    /// Report IDを使うホイール付きマウス
+    pub const WHEEL_MOUSE: &[u8] = &[
+        0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x85, 0x02, 0x09, 0x01, 0xa1, 0x00, 0x05, 0x09, 0x19,
+        0x01, 0x29, 0x05, 0x15, 0x00, 0x25, 0x01, 0x95, 0x05, 0x75, 0x01, 0x81, 0x02, 0x95, 0x01,
+        0x75, 0x03, 0x81, 0x03, 0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x09, 0x38, 0x15, 0x81, 0x25,
+        0x7f, 0x75, 0x08, 0x95, 0x03, 0x81, 0x06, 0xc0, 0xc0,
+    ];
+
+    #[test]
+    fn test_parse_tablet() {
+        let layout = ReportLayout::parse(QEMU_TABLET).unwrap();
+        assert!(!layout.has_report_ids());
+        // ボタン3つとX、Y、ホイール
+        assert_eq!(layout.fields().len(), 6);
+
+        let button3 = layout
+            .find(ReportKind::Input, usage_page::BUTTON, 3)
+            .unwrap();
+        assert_eq!((button3.bit_offset, button3.bit_size), (2, 1));
+
+        let x = layout
+            .find(ReportKind::Input, usage_page::GENERIC_DESKTOP, usage::X)
+            .unwrap();
+        assert_eq!((x.bit_offset, x.bit_size), (8, 16));
+        assert_eq!((x.logical_min, x.logical_max), (0, 0x7fff));
+        assert!(!x.is_relative());
+
+        let wheel = layout
+            .find(ReportKind::Input, usage_page::GENERIC_DESKTOP, usage::WHEEL)
+            .unwrap();
+        assert_eq!(wheel.bit_offset, 40);
+        assert!(wheel.is_relative());
+
+        let report = [0b101, 0x34, 0x12, 0xff, 0x7f, 0xfe];
+        assert_eq!(button3.extract(&report), Some(1));
+        assert_eq!(x.extract(&report), Some(0x1234));
+        assert_eq!(wheel.extract(&report), Some(-2));
+        assert_eq!(wheel.extract(&report[..5]), None);
+    }
+
+    #[test]
+    fn test_parse_report_id() {
+        let layout = ReportLayout::parse(WHEEL_MOUSE).unwrap();
+        assert!(layout.has_report_ids());
+        assert!(layout.fields().iter().all(|f| f.report_id == 2));
+
+        let y = layout
+            .find(ReportKind::Input, usage_page::GENERIC_DESKTOP, usage::Y)
+            .unwrap();
+        assert_eq!(y.bit_offset, 16);
+
+        let (id, data) = layout.split_report(&[2, 0, 1, 0xff, 3]).unwrap();
+        assert_eq!(id, 2);
+        assert_eq!(y.extract(data), Some(-1));
+    }
+
+    #[test]
+    fn test_parse_keyboard_array() {
+        // 修飾キー8つ(Variable)、予約1バイト、キー6つ(Array)
+        let descriptor = [
+            0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, 0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00,
+            0x25, 0x01, 0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x01,
+            0x95, 0x06, 0x75, 0x08, 0x15, 0x00, 0x25, 0xff, 0x05, 0x07, 0x19, 0x00, 0x29, 0xff,
+            0x81, 0x00, 0xc0,
+        ];
+        let layout = ReportLayout::parse(&descriptor).unwrap();
+        assert_eq!(layout.fields().len(), 9);
+
+        let keys = layout
+            .find(ReportKind::Input, usage_page::KEYBOARD, 0x04)
+            .unwrap();
+        assert_eq!((keys.bit_offset, keys.count), (16, 6));
+        // 1バイトの0xffは符号なしとして読む
+        assert_eq!(keys.logical_max, 0xff);
+        let report = [0, 0, 0x04, 0x05, 0, 0, 0, 0];
+        assert_eq!(keys.extract_at(&report, 1), Some(0x05));
+        assert_eq!(keys.extract_at(&report, 6), None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(ReportLayout::parse(&[0x05]), Err(ParseError::Truncated));
+        assert_eq!(
+            ReportLayout::parse(&[0xa1, 0x01]),
+            Err(ParseError::UnbalancedCollection)
+        );
+        assert_eq!(
+            ReportLayout::parse(&[0xb4]),
+            Err(ParseError::PopWithoutPush)
+        );
+    }
+
+    #[test]
+    fn test_parse_oversized_report() {
+        // Report Size 0xffffffff、Report Count 2
+        assert_eq!(
+            ReportLayout::parse(&[
+                0x77, 0xff, 0xff, 0xff, 0xff, 0x95, 0x02, 0x09, 0x30, 0x81, 0x02
+            ]),
+            Err(ParseError::InvalidReportSize)
+        );
+        // Report Size 32、Report Count 0xffffffff
+        assert_eq!(
+            ReportLayout::parse(&[
+                0x75, 0x20, 0x97, 0xff, 0xff, 0xff, 0xff, 0x09, 0x30, 0x81, 0x02
+            ]),
+            Err(ParseError::ReportTooLong)
+        );
+        // 1つずつは収まっていても、続けるとあふれる
+        assert_eq!(
+            ReportLayout::parse(&[
+                0x75, 0x20, 0x97, 0x00, 0x00, 0x00, 0x04, 0x81, 0x03, 0x81, 0x03
+            ]),
+            Err(ParseError::ReportTooLong)
+        );
+    }
+}
@@ -0,0 +1,411 @@
+//! コントロール転送のリクエスト
+//!
+//! Setup Stageに載せる8バイトとbRequestの値。
+
+use super::{descriptor::Type as DescriptorType, endpoint::Direction, hub};
+
+/// bmRequestTypeのType
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestKind {
+    Standard = 0,
+    Class = 1,
+    Vendor = 2,
+}
+
+/// bmRequestTypeのRecipient
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Recipient {
+    Device = 0,
+    Interface = 1,
+    Endpoint = 2,
+    Other = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestType(u8);
+
+impl RequestType {
+    pub const fn new(dir: Direction, kind: RequestKind, recipient: Recipient) -> Self {
+        Self(((dir as u8) << 7) | ((kind as u8) << 5) | recipient as u8)
+    }
+
+    pub const fn from_raw(value: u8) -> Self {
+        Self(value)
+    }
+
+    pub const fn raw(self) -> u8 {
+        self.0
+    }
+
+    pub fn direction(self) -> Direction {
+        Direction::from(self.0 & 0x80 != 0)
+    }
+}
+
+/// 標準リクエストのbRequest
+pub mod standard {
+    pub const GET_STATUS: u8 = 0;
+    pub const CLEAR_FEATURE: u8 = 1;
+    pub const SET_FEATURE: u8 = 3;
+    pub const SET_ADDRESS: u8 = 5;
+    pub const GET_DESCRIPTOR: u8 = 6;
+    pub const SET_DESCRIPTOR: u8 = 7;
+    pub const GET_CONFIGURATION: u8 = 8;
+    pub const SET_CONFIGURATION: u8 = 9;
+    pub const GET_INTERFACE: u8 = 10;
+    pub const SET_INTERFACE: u8 = 11;
+}
+
+/// CLEAR_FEATURE/SET_FEATUREの機能セレクタ
+pub mod feature {
+    pub const ENDPOINT_HALT: u16 = 0;
+    pub const DEVICE_REMOTE_WAKEUP: u16 = 1;
+    pub const TEST_MODE: u16 = 2;
+}
+
+/// 英語(米国)。文字列ディスクリプタ0が読めなかったときに使う。
+pub const LANGUAGE_ID_EN_US: u16 = 0x0409;
+
+/// HIDクラスリクエストのbRequest
+pub mod hid {
+    pub const GET_REPORT: u8 = 0x01;
+    pub const GET_IDLE: u8 = 0x02;
+    pub const GET_PROTOCOL: u8 = 0x03;
+    pub const SET_REPORT: u8 = 0x09;
+    pub const SET_IDLE: u8 = 0x0a;
+    pub const SET_PROTOCOL: u8 = 0x0b;
+
+    /// GET_REPORTのwValueの上位バイト
+    pub const REPORT_TYPE_INPUT: u8 = 1;
+    /// SET_PROTOCOLのwValue
+    pub const PROTOCOL_BOOT: u16 = 0;
+    pub const PROTOCOL_REPORT: u16 = 1;
+
+    /// GET_DESCRIPTORのwValueの上位バイト
+    pub const DESCRIPTOR_TYPE_REPORT: u8 = 0x22;
+}
+
+/// マスストレージクラスリクエストのbRequest
+pub mod mass_storage {
+    pub const BULK_ONLY_RESET: u8 = 0xff;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SetupPacket {
+    request_type: RequestType,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+}
+
+impl SetupPacket {
+    pub const fn new(
+        request_type: RequestType,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Self {
+        Self {
+            request_type,
+            request,
+            value,
+            index,
+            length,
+        }
+    }
+
+    pub const fn request_type(&self) -> RequestType {
+        self.request_type
+    }
+
+    pub const fn request(&self) -> u8 {
+        self.request
+    }
+
+    pub const fn value(&self) -> u16 {
+        self.value
+    }
+
+    pub const fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub const fn length(&self) -> u16 {
+        self.length
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.request_type.direction()
+    }
+
+    /// Setup Stage TRBにそのまま入るリトルエンディアンの8バイト
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let [v0, v1] = self.value.to_le_bytes();
+        let [i0, i1] = self.index.to_le_bytes();
+        let [l0, l1] = self.length.to_le_bytes();
+        [
+            self.request_type.raw(),
+            self.request,
+            v0,
+            v1,
+            i0,
+            i1,
+            l0,
+            l1,
+        ]
+    }
+
+    /// `language_id`は文字列ディスクリプタ以外では0
+    pub const fn get_descriptor(
+        ty: DescriptorType,
+        index: u8,
+        language_id: u16,
+        length: u16,
+    ) -> Self {
+        Self::new(
+            RequestType::new(Direction::In, RequestKind::Standard, Recipient::Device),
+            standard::GET_DESCRIPTOR,
+            ((ty as u16) << 8) | index as u16,
+            language_id,
+            length,
+        )
+    }
+
+    /// インデックス0を指定すると対応している言語IDの一覧が返る
+    pub const fn get_string(index: u8, language_id: u16, length: u16) -> Self {
+        Self::get_descriptor(DescriptorType::String, index, language_id, length)
+    }
+
+    pub const fn set_configuration(value: u8) -> Self {
+        Self::new(
+            RequestType::new(Direction::Out, RequestKind::Standard, Recipient::Device),
+            standard::SET_CONFIGURATION,
+            value as u16,
+            0,
+            0,
+        )
+    }
+
+    /// インターフェースの代替設定を選ぶ
+    pub const fn set_interface(interface: u16, alternate_setting: u16) -> Self {
+        Self::new(
+            RequestType::new(Direction::Out, RequestKind::Standard, Recipient::Interface),
+            standard::SET_INTERFACE,
+            alternate_setting,
+            interface,
+            0,
+        )
+    }
+
+    /// `index`はRecipientがInterfaceならインターフェース番号、Endpointならエンドポイントアドレス
+    pub const fn get_status(recipient: Recipient, index: u16) -> Self {
+        Self::new(
+            RequestType::new(Direction::In, RequestKind::Standard, recipient),
+            standard::GET_STATUS,
+            0,
+            index,
+            2,
+        )
+    }
+
+    pub const fn clear_feature(recipient: Recipient, feature: u16, index: u16) -> Self {
+        Self::new(
+            RequestType::new(Direction::Out, RequestKind::Standard, recipient),
+            standard::CLEAR_FEATURE,
+            feature,
+            index,
+            0,
+        )
+    }
+
+    pub const fn set_feature(recipient: Recipient, feature: u16, index: u16) -> Self {
+        Self::new(
+            RequestType::new(Direction::Out, RequestKind::Standard, recipient),
+            standard::SET_FEATURE,
+            feature,
+            index,
+            0,
+        )
+    }
+
+    /// ハブディスクリプタ。デバイス宛てのクラスリクエスト。
+    pub const fn get_hub_descriptor(length: u16) -> Self {
+        Self::new(
+            RequestType::new(Direction::In, RequestKind::Class, Recipient::Device),
+            standard::GET_DESCRIPTOR,
+            (hub::DESCRIPTOR_TYPE_HUB as u16) << 8,
+            0,
+            length,
+        )
+    }
+
+    /// ハブの下流ポートの状態と変化。`port`は1から。
+    pub const fn get_port_status(port: u8) -> Self {
+        Self::new(
+            RequestType::new(Direction::In, RequestKind::Class, Recipient::Other),
+            standard::GET_STATUS,
+            0,
+            port as u16,
+            4,
+        )
+    }
+
+    pub const fn set_port_feature(feature: u16, port: u8) -> Self {
+        Self::new(
+            RequestType::new(Direction::Out, RequestKind::Class, Recipient::Other),
+            standard::SET_FEATURE,
+            feature,
+            port as u16,
+            0,
+        )
+    }
+
+    pub const fn clear_port_feature(feature: u16, port: u8) -> Self {
+        Self::new(
+            RequestType::new(Direction::Out, RequestKind::Class, Recipient::Other),
+            standard::CLEAR_FEATURE,
+            feature,
+            port as u16,
+            0,
+        )
+    }
+
+    pub const fn hid_get_report(
+        report_type: u8,
+        report_id: u8,
+        interface: u16,
+        length: u16,
+    ) -> Self {
+        Self::new(
+            RequestType::new(Direction::In, RequestKind::Class, Recipient::Interface),
+            hid::GET_REPORT,
+            ((report_type as u16) << 8) | report_id as u16,
+            interface,
+            length,
+        )
+    }
+
+    /// HIDディスクリプタの`report_descriptor_length`だけ読む
+    pub const fn get_hid_report_descriptor(interface: u16, length: u16) -> Self {
+        Self::new(
+            RequestType::new(Direction::In, RequestKind::Standard, Recipient::Interface),
+            standard::GET_DESCRIPTOR,
+            (hid::DESCRIPTOR_TYPE_REPORT as u16) << 8,
+            interface,
+            length,
+        )
+    }
+
+    /// `duration`は4ms単位で、0なら変化があったときだけレポートを送る
+    pub const fn hid_set_idle(duration: u8, report_id: u8, interface: u16) -> Self {
+        Self::new(
+            RequestType::new(Direction::Out, RequestKind::Class, Recipient::Interface),
+            hid::SET_IDLE,
+            ((duration as u16) << 8) | report_id as u16,
+            interface,
+            0,
+        )
+    }
+
+    pub const fn hid_set_protocol(protocol: u16, interface: u16) -> Self {
+        Self::new(
+            RequestType::new(Direction::Out, RequestKind::Class, Recipient::Interface),
+            hid::SET_PROTOCOL,
+            protocol,
+            interface,
+            0,
+        )
+    }
+
+    /// Bulk-Only Transportを次のCBWを待つ状態に戻す。エンドポイントのHaltは解かない。
+    pub const fn bulk_only_reset(interface: u16) -> Self {
+        Self::new(
+            RequestType::new(Direction::Out, RequestKind::Class, Recipient::Interface),
+            mass_storage::BULK_ONLY_RESET,
+            0,
+            interface,
+            0,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_type() {
+        let ty = RequestType::new(Direction::In, RequestKind::Class, Recipient::Interface);
+        assert_eq!(ty.raw(), 0b1010_0001);
+        assert_eq!(ty.direction(), Direction::In);
+        assert_eq!(
+            RequestType::from_raw(0b0010_0001).direction(),
+            Direction::Out
+        );
+    }
+
+    #[test]
+    fn test_setup_packet_bytes() {
+        let setup = SetupPacket::get_descriptor(DescriptorType::Configuration, 0, 0, 256);
+        assert_eq!(setup.to_bytes(), [0x80, 6, 0x00, 0x02, 0, 0, 0x00, 0x01]);
+
+        let setup = SetupPacket::hid_set_protocol(hid::PROTOCOL_BOOT, 1);
+        assert_eq!(setup.to_bytes(), [0x21, 0x0b, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(setup.direction(), Direction::Out);
+    }
+
+    #[test]
+    fn test_standard_requests() {
+        assert_eq!(
+            SetupPacket::set_configuration(1).to_bytes(),
+            [0x00, 9, 1, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            SetupPacket::get_status(Recipient::Device, 0).to_bytes(),
+            [0x80, 0, 0, 0, 0, 0, 2, 0]
+        );
+        assert_eq!(
+            SetupPacket::clear_feature(Recipient::Endpoint, feature::ENDPOINT_HALT, 0x81)
+                .to_bytes(),
+            [0x02, 1, 0, 0, 0x81, 0, 0, 0]
+        );
+        assert_eq!(
+            SetupPacket::get_string(2, LANGUAGE_ID_EN_US, 255).to_bytes(),
+            [0x80, 6, 2, 3, 0x09, 0x04, 0xff, 0]
+        );
+        assert_eq!(
+            SetupPacket::set_interface(1, 1).to_bytes(),
+            [0x01, 11, 1, 0, 1, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_hub_requests() {
+        assert_eq!(
+            SetupPacket::get_hub_descriptor(71).to_bytes(),
+            [0xa0, 6, 0, 0x29, 0, 0, 71, 0]
+        );
+        assert_eq!(
+            SetupPacket::get_port_status(3).to_bytes(),
+            [0xa3, 0, 0, 0, 3, 0, 4, 0]
+        );
+        assert_eq!(
+            SetupPacket::set_port_feature(hub::port_feature::PORT_RESET, 2).to_bytes(),
+            [0x23, 3, 4, 0, 2, 0, 0, 0]
+        );
+        assert_eq!(
+            SetupPacket::clear_port_feature(hub::port_feature::C_PORT_RESET, 2).to_bytes(),
+            [0x23, 1, 20, 0, 2, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_mass_storage_requests() {
+        assert_eq!(
+            SetupPacket::bulk_only_reset(1).to_bytes(),
+            [0x21, 0xff, 0, 0, 1, 0, 0, 0]
+        );
+    }
+}
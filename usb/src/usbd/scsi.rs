@@ -0,0 +1,214 @@
+//! SCSIコマンド
+//!
+//! マスストレージのCBWに載せるコマンドブロック(CDB)と、返ってくるデータの解析。
+//! 複数バイトの値はすべてビッグエンディアン。
+
+pub mod opcode {
+    pub const TEST_UNIT_READY: u8 = 0x00;
+    pub const REQUEST_SENSE: u8 = 0x03;
+    pub const INQUIRY: u8 = 0x12;
+    pub const READ_CAPACITY_10: u8 = 0x25;
+    pub const READ_10: u8 = 0x28;
+    pub const WRITE_10: u8 = 0x2a;
+}
+
+/// CBWに入るCDBの最大の長さ
+pub const MAX_COMMAND_LEN: usize = 16;
+/// INQUIRYで読む標準データの長さ
+pub const INQUIRY_DATA_LEN: usize = 36;
+pub const CAPACITY_10_DATA_LEN: usize = 8;
+/// 固定形式のセンスデータの長さ
+pub const SENSE_DATA_LEN: usize = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Command {
+    bytes: [u8; MAX_COMMAND_LEN],
+    len: u8,
+}
+
+impl Command {
+    fn new<const N: usize>(cdb: [u8; N]) -> Self {
+        let mut bytes = [0; MAX_COMMAND_LEN];
+        bytes[..N].copy_from_slice(&cdb);
+        Self {
+            bytes,
+            len: N as u8,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    pub fn opcode(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    pub fn test_unit_ready() -> Self {
+        Self::new([opcode::TEST_UNIT_READY, 0, 0, 0, 0, 0])
+    }
+
+    pub fn request_sense(allocation_length: u8) -> Self {
+        Self::new([opcode::REQUEST_SENSE, 0, 0, 0, allocation_length, 0])
+    }
+
+    pub fn inquiry(allocation_length: u16) -> Self {
+        let [l0, l1] = allocation_length.to_be_bytes();
+        Self::new([opcode::INQUIRY, 0, 0, l0, l1, 0])
+    }
+
+    pub fn read_capacity_10() -> Self {
+        Self::new([opcode::READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+    }
+
+    /// `blocks`はブロック数で、バイト数ではない
+    pub fn read_10(lba: u32, blocks: u16) -> Self {
+        Self::rw_10(opcode::READ_10, lba, blocks)
+    }
+
+    pub fn write_10(lba: u32, blocks: u16) -> Self {
+        Self::rw_10(opcode::WRITE_10, lba, blocks)
+    }
+
+    fn rw_10(opcode: u8, lba: u32, blocks: u16) -> Self {
+        let [a0, a1, a2, a3] = lba.to_be_bytes();
+        let [b0, b1] = blocks.to_be_bytes();
+        Self::new([opcode, 0, a0, a1, a2, a3, 0, b0, b1, 0])
+    }
+}
+
+/// INQUIRYの標準データ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InquiryData {
+    /// 0ならダイレクトアクセスデバイス(ディスク)
+    pub peripheral_device_type: u8,
+    pub removable: bool,
+    vendor: [u8; 8],
+    product: [u8; 16],
+    revision: [u8; 4],
+}
+
+impl InquiryData {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let data: &[u8; INQUIRY_DATA_LEN] = data.get(..INQUIRY_DATA_LEN)?.try_into().ok()?;
+        Some(Self {
+            peripheral_device_type: data[0] & 0x1f,
+            removable: data[1] & 0x80 != 0,
+            vendor: data[8..16].try_into().unwrap(),
+            product: data[16..32].try_into().unwrap(),
+            revision: data[32..36].try_into().unwrap(),
+        })
+    }
+
+    pub fn vendor(&self) -> &str {
+        ascii_field(&self.vendor)
+    }
+
+    pub fn product(&self) -> &str {
+        ascii_field(&self.product)
+    }
+
+    pub fn revision(&self) -> &str {
+        ascii_field(&self.revision)
+    }
+}
+
+/// 空白で埋められたASCIIの欄。ASCIIでなければ空文字列。
+fn ascii_field(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("").trim_end()
+}
+
+/// READ CAPACITY(10)のデータ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Capacity {
+    pub last_lba: u32,
+    pub block_length: u32,
+}
+
+impl Capacity {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let data = data.get(..CAPACITY_10_DATA_LEN)?;
+        Some(Self {
+            last_lba: u32::from_be_bytes(data[0..4].try_into().unwrap()),
+            block_length: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+        })
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.last_lba as u64 + 1
+    }
+}
+
+/// 固定形式のセンスデータのうち、エラーの理由を表す部分
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SenseData {
+    pub sense_key: u8,
+    /// Additional Sense Code
+    pub asc: u8,
+    /// Additional Sense Code Qualifier
+    pub ascq: u8,
+}
+
+impl SenseData {
+    /// 電源投入やリセットの後、最初のコマンドで報告される
+    pub const UNIT_ATTENTION: u8 = 0x6;
+    pub const NOT_READY: u8 = 0x2;
+
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let data = data.get(..14)?;
+        Some(Self {
+            sense_key: data[2] & 0x0f,
+            asc: data[12],
+            ascq: data[13],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(Command::test_unit_ready().as_bytes(), &[0; 6]);
+        assert_eq!(Command::inquiry(36).as_bytes(), &[0x12, 0, 0, 0, 36, 0]);
+        assert_eq!(
+            Command::read_10(0x01020304, 0x0506).as_bytes(),
+            &[0x28, 0, 1, 2, 3, 4, 0, 5, 6, 0]
+        );
+        assert_eq!(Command::write_10(1, 1).opcode(), opcode::WRITE_10);
+    }
+
+    #[test]
+    fn test_inquiry_data() {
+        let mut data = [0u8; INQUIRY_DATA_LEN];
+        data[1] = 0x80;
+        data[8..16].copy_from_slice(b"QEMU    ");
+        data[16..32].copy_from_slice(b"QEMU HARDDISK   ");
+        data[32..36].copy_from_slice(b"2.5+");
+
+        let inquiry = InquiryData::parse(&data).unwrap();
+        assert_eq!(inquiry.peripheral_device_type, 0);
+        assert!(inquiry.removable);
+        assert_eq!(inquiry.vendor(), "QEMU");
+        assert_eq!(inquiry.product(), "QEMU HARDDISK");
+        assert_eq!(inquiry.revision(), "2.5+");
+
+        assert_eq!(InquiryData::parse(&data[..35]), None);
+    }
+
+    #[test]
+    fn test_capacity_and_sense() {
+        let capacity = Capacity::parse(&[0, 0, 0x7f, 0xff, 0, 0, 2, 0]).unwrap();
+        assert_eq!(capacity.block_count(), 0x8000);
+        assert_eq!(capacity.block_length, 512);
+
+        let mut sense = [0u8; SENSE_DATA_LEN];
+        sense[0] = 0x70;
+        sense[2] = SenseData::UNIT_ATTENTION;
+        sense[12] = 0x29;
+        let sense = SenseData::parse(&sense).unwrap();
+        assert_eq!(sense.sense_key, SenseData::UNIT_ATTENTION);
+        assert_eq!((sense.asc, sense.ascq), (0x29, 0));
+    }
+}
@@ -3,7 +3,7 @@ use core::ops::IndexMut;
 use common::Zeroed;
 use macros::bitfield_struct;
 
-use super::port::PortWrapper;
+use super::{port::PortWrapper, route::Route};
 
 bitfield_struct! {
     #[repr(C)]
@@ -143,6 +143,32 @@ bitfield_struct! {
     }
 }
 
+impl EndpointContxt {
+    /// Isochエンドポイントとして設定する。`interval`は2^`interval`×125usの指数。
+    /// 1回のサービス間隔で`max_packet_size`×(`max_burst`+1)バイトまで転送する。
+    pub fn init_isoch_endpoint(
+        &mut self,
+        dir_in: bool,
+        max_packet_size: u16,
+        max_burst: u8,
+        interval: u8,
+    ) {
+        self.set_data_1_ep_type(if dir_in { 5 } else { 1 });
+        self.set_data_1_max_packet_size(max_packet_size);
+        self.set_data_1_max_burst_size(max_burst);
+        self.set_data_0_interval(interval);
+        self.set_data_0_max_primary_streams(0);
+        self.set_data_0_mult(0);
+        // Isochは再送しない
+        self.set_data_1_error_count(0);
+
+        let payload = max_packet_size as u32 * (max_burst as u32 + 1);
+        self.set_data_0_max_esit_payload_hi((payload >> 16) as u8);
+        self.set_data_4_max_esit_payload_lo(payload as u16);
+        self.set_data_4_average_trb_length(payload as u16);
+    }
+}
+
 const MAX_DEVICE_CONTEXT: usize = 31;
 
 #[repr(C, align(64))]
@@ -184,6 +210,12 @@ pub struct InputContext {
 }
 
 impl InputContext {
+    /// 前のコマンドで使ったAdd/Dropフラグを消す
+    pub fn clear_context_flags(&mut self) {
+        self.input_control_context.add_context_flags = 0;
+        self.input_control_context.drop_context_flags = 0;
+    }
+
     pub fn enable_slot_context(&mut self) {
         self.input_control_context.add_context_flags |= 1;
     }
@@ -193,11 +225,18 @@ impl InputContext {
     }
 
     pub fn init_slot_cx(&mut self, port: &PortWrapper) {
+        self.init_slot_cx_with_route(&Route::root(port.number(), port.speed()));
+    }
+
+    /// ハブの先のデバイスはRoute StringとTTも設定する
+    pub fn init_slot_cx_with_route(&mut self, route: &Route) {
         let slot = &mut self.slot;
-        slot.set_data_0_route_string(0);
-        slot.set_data_1_root_hub_port_number(port.number());
+        slot.set_data_0_route_string(route.route_string);
+        slot.set_data_1_root_hub_port_number(route.root_port);
         slot.set_data_0_context_entries(1);
-        slot.set_data_0_speed(port.speed());
+        slot.set_data_0_speed(route.speed);
+        slot.set_data_2_tt_hub_slot_id(route.tt_hub_slot_id);
+        slot.set_data_2_tt_port_number(route.tt_port_number);
     }
 
     pub fn init_ep0_endpoint(
@@ -62,6 +62,17 @@ impl<const RING_SIZE: usize, const RING_NUM: usize> Device<RING_SIZE, RING_NUM>
         self.context.slot_context.get_data_1_root_hub_port_number()
     }
 
+    /// Enable Slotの完了時に設定したルートハブのポート番号。アドレスの割り当て前でも使える。
+    pub fn root_port_num(&self) -> u8 {
+        self.input_context.slot.get_data_1_root_hub_port_number()
+    }
+
+    /// Address Deviceコマンドが完了していて、コントロール転送ができる
+    pub fn is_addressed(&self) -> bool {
+        // 2: Addressed, 3: Configured
+        matches!(self.context.slot_context.get_data_3_slot_state(), 2 | 3)
+    }
+
     /// default controls pipe's transfer ring
     pub fn dcp_ring_mut(&mut self) -> &mut TCRing<RING_SIZE> {
         self.transfer_rings.index_mut(0)
@@ -102,6 +113,11 @@ impl<const N: usize, const RING_SIZE: usize, const RING_NUM: usize>
         Ok(self.devices[slot_id as usize].as_mut().unwrap())
     }
 
+    /// Disable Slotコマンドの完了後に呼ぶ。リングもいっしょに解放される。
+    pub fn free_device(&mut self, slot_id: SlotId) -> Option<Device<RING_SIZE, RING_NUM>> {
+        self.devices.get_mut(slot_id as usize)?.take()
+    }
+
     pub fn device(&self, slot_id: SlotId) -> Option<&Device<RING_SIZE, RING_NUM>> {
         self.devices.index(slot_id as usize).as_ref()
     }
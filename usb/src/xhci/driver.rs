@@ -1,21 +1,23 @@
 use crate::xhci::{
     doorbell::HCDoorbell,
     register_map::Doorbell,
-    trb::{AddressDeviceCommand, EnableSlotCommand},
+    trb::{AddressDeviceCommand, DisableSlotCommand, EnableSlotCommand},
 };
 
 use super::{
     context::DeviceContext,
-    device::{Device, DeviceManager},
+    device::{Device, DeviceManager, SlotId},
     error::{Error, Result},
     port::{PortConfigPhase, PortWrapper, PortsConfigPhase},
+    recovery::EndpointRecovery,
     register_map::{
         CapabilityRegisters, DoorbellRegisters, OperationalRegisters, RuntimeRegisters,
     },
     ring::{EventRing, EventRingSegmentTableEntry, TCRing},
+    route::Route,
     trb::{CommandCompletionEvent, PortStatusChangeEvent, Trb, TrbRaw, Type},
 };
-use common::{debug, info, Zeroed};
+use common::{debug, error, info, map::FixedMap, ring_buf::RingBuffer, Zeroed};
 use core::{
     marker::{PhantomData, PhantomPinned},
     ops::IndexMut,
@@ -33,6 +35,12 @@ const DEFAULT_EVENT_RING_SEGMENT_SIZE: usize = 16;
 const DEFAULT_EVENT_RING_SEGMENTS_NUM: usize = 1;
 const DEFAULT_EVENT_RING_SEGMENT_TABLE_SIZE: usize = DEFAULT_EVENT_RING_SEGMENTS_NUM;
 const DEFAULT_DEVICE_MANAGER_SIZE: usize = 16;
+/// 割り込みハンドラがevent ringから取り出したイベントを溜めておく数
+const EVENT_QUEUE_SIZE: usize = 32;
+/// 切断されてスロットを解放したデバイスを`usbd`に知らせるまで溜めておく数
+const DETACHED_QUEUE_SIZE: usize = 16;
+/// 同時に復旧させられる停止したエンドポイントの数
+const RECOVERY_NUM: usize = 8;
 
 pub struct Context<
     const DEV: usize = DEFAULT_NUM_DEVICE_CONTEXT,
@@ -47,6 +55,12 @@ pub struct Context<
     event_ring_segments: [EventRing<SEG_SIZE>; SEG_NUM],
     event_ring_segment_table: [EventRingSegmentTableEntry; TAB_SIZE],
     device_manager: DeviceManager<DEV_MNGR_SIZE>,
+    event_queue: RingBuffer<TrbRaw, EVENT_QUEUE_SIZE>,
+    detached_slots: RingBuffer<SlotId, DETACHED_QUEUE_SIZE>,
+    /// 停止から復旧させているエンドポイント。キーはスロットIDとDCI。
+    recoveries: FixedMap<(SlotId, u8), EndpointRecovery, RECOVERY_NUM>,
+    /// 復旧が終わって`usbd`に知らせるエンドポイントと、復旧できたか
+    recovered_endpoints: RingBuffer<((SlotId, u8), Result<()>), RECOVERY_NUM>,
     _phantom_pinned: PhantomPinned,
 }
 
@@ -63,7 +77,8 @@ impl<
         self.event_ring_segments.index_mut(0)
     }
 
-    pub fn issue_command(&mut self, cmd: impl Into<TrbRaw> + Type + Copy) {
+    /// 積んだコマンドTRBのアドレスを返す
+    pub fn issue_command(&mut self, cmd: impl Into<TrbRaw> + Type + Copy) -> u64 {
         self.command_ring.push(cmd)
     }
 
@@ -93,6 +108,10 @@ impl<
         let event_ring_segments = [(); SEG_NUM].map(|_| EventRing::new());
         let event_ring_segment_table = [EventRingSegmentTableEntry::zeroed(); TAB_SIZE];
         let device_manager = DeviceManager::new();
+        let event_queue = RingBuffer::zeroed();
+        let detached_slots = RingBuffer::zeroed();
+        let recoveries = FixedMap::new();
+        let recovered_endpoints = RingBuffer::zeroed();
 
         Self {
             device_context_ptrs,
@@ -100,6 +119,10 @@ impl<
             event_ring_segments,
             event_ring_segment_table,
             device_manager,
+            event_queue,
+            detached_slots,
+            recoveries,
+            recovered_endpoints,
             _phantom_pinned: PhantomPinned,
         }
     }
@@ -121,6 +144,8 @@ pub struct Controller<
     runtime_registers: RuntimeRegisters<'static>,
     pub doorbell_registers: DoorbellRegisters<'static>,
     ports_config_phase: PortsConfigPhase,
+    /// アドレスを割り当てている途中のハブの先のデバイス
+    enumerating_route: Option<Route>,
     // 配列のポインタが動かないようにしたい
     // 今の所move以外は大丈夫
     pub cx: Pin<&'a mut Context<DEV, CMD, SEG_SIZE, SEG_NUM, TAB_SIZE, DEVICE_MANAGER_SIZE>>,
@@ -181,6 +206,7 @@ impl<
             runtime_registers,
             doorbell_registers,
             ports_config_phase: PortsConfigPhase::default(),
+            enumerating_route: None,
             cx,
         }
     }
@@ -202,6 +228,7 @@ impl<
             runtime_registers: self.runtime_registers,
             doorbell_registers: self.doorbell_registers,
             ports_config_phase: self.ports_config_phase,
+            enumerating_route: self.enumerating_route,
             cx: self.cx,
         })
     }
@@ -415,6 +442,7 @@ impl<
             runtime_registers: self.runtime_registers,
             doorbell_registers: self.doorbell_registers,
             ports_config_phase: self.ports_config_phase,
+            enumerating_route: self.enumerating_route,
             cx: self.cx,
         }
     }
@@ -441,6 +469,32 @@ impl<
         self.ports_config_phase.processing_port()
     }
 
+    /// ハブの下流ポートにつながったデバイスにスロットを割り当て、アドレスを設定する。
+    /// ハブのポートはリセット済みで有効になっている必要がある。
+    /// 終わるとAddress Deviceコマンドの完了イベントが返る。
+    pub fn enumerate_route(&mut self, route: Route) -> Result<()> {
+        if self.enumerating_route.is_some() || self.ports_config_phase.is_resetting_port_exist() {
+            return Err(Error::already_port_processing());
+        }
+        self.enumerating_route = Some(route);
+
+        unsafe { self.cx.as_mut().get_unchecked_mut() }.issue_command(EnableSlotCommand::default());
+        self.doorbell_registers
+            .host_controller_mut()
+            .notify_host_controller();
+        Ok(())
+    }
+
+    pub fn is_enumerating_route(&self) -> bool {
+        self.enumerating_route.is_some()
+    }
+
+    /// 完了を待つのをやめた`enumerate_route`を取り消して、次のポートを処理できるようにする。
+    /// あとからEnable Slotが完了しても、そのスロットは使わずに無効にする。
+    pub fn abort_route_enumeration(&mut self) {
+        self.enumerating_route = None;
+    }
+
     pub fn devices_mut(&mut self) -> impl Iterator<Item = Pin<&mut Device>> {
         unsafe {
             let devices = self
@@ -453,14 +507,103 @@ impl<
         }
     }
 
-    pub fn process_primary_event(&mut self) -> Result<Option<Trb>> {
+    /// primary event ringに溜まっているイベントをキューに移し、割り込みを受け付け済みにする。
+    /// 割り込みハンドラから呼ばれることを想定している。
+    pub fn drain_primary_events(&mut self) -> usize {
         let primary = self.runtime_registers.get_primary_interrupter_mut();
+        let cx = unsafe { self.cx.as_mut().get_unchecked_mut() };
+
+        let mut count = 0;
+        while !cx.event_queue.is_full() {
+            let Some(trb) = cx.primary_ring_mut().pop::<TrbRaw>(primary) else {
+                break;
+            };
+            // is_fullを確認しているので失敗しない
+            let _ = cx.event_queue.push(trb);
+            count += 1;
+        }
+
+        // IPは1を書き込むとクリアされる
+        let iman = primary
+            .interrupt_management()
+            .read()
+            .with_data_interrupt_pending(true);
+        primary.interrupt_management_mut().write(iman);
+
+        count
+    }
+
+    pub fn has_pending_events(&self) -> bool {
+        !self.cx.event_queue.is_empty()
+    }
+
+    /// 切断されてDisable Slotが完了したスロット
+    pub fn pop_detached_slot(&mut self) -> Option<SlotId> {
+        unsafe { self.cx.as_mut().get_unchecked_mut() }
+            .detached_slots
+            .pop()
+    }
+
+    /// Reset EndpointとSet TR Dequeue Pointerが終わったエンドポイント。スロットIDとDCIを返す。
+    /// どちらかのコマンドが失敗していればそのエラーも返す。そのエンドポイントは止まったまま。
+    pub fn pop_recovered_endpoint(&mut self) -> Option<((SlotId, u8), Result<()>)> {
+        unsafe { self.cx.as_mut().get_unchecked_mut() }
+            .recovered_endpoints
+            .pop()
+    }
+
+    /// 停止したエンドポイントの復旧コマンドを待っている
+    pub fn is_recovering_endpoint(&self, slot_id: SlotId, dci: u8) -> bool {
+        self.cx.recoveries.contains_key(&(slot_id, dci))
+    }
+
+    /// 今のマイクロフレーム(125us)の番号。14ビットで一周する。
+    pub fn microframe_index(&self) -> u16 {
+        self.runtime_registers
+            .microframe_index()
+            .read()
+            .get_data_microframe_index()
+    }
+
+    /// MFINDEXで`microframes`×125usだけ待つ。一周する2048msより短くなければならない。
+    pub fn wait_microframes(&self, microframes: u16) {
+        let start = self.microframe_index();
+        while self.microframes_since(start) < microframes {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// `microframe_index`で読んだ`start`から経ったマイクロフレーム数。2048msで一周する。
+    pub fn microframes_since(&self, start: u16) -> u16 {
+        self.microframe_index().wrapping_sub(start) & 0x3fff
+    }
+
+    /// Isoch TDを積んでからxHCが実行できるようになるまでのマイクロフレーム数
+    pub fn isochronous_scheduling_threshold(&self) -> u16 {
+        let ist = self
+            .capability_registers
+            .hcs_paracm2()
+            .read()
+            .get_data_isochronous_scheduling_threshold() as u16;
+        // ビット3が立っていればフレーム単位
+        if ist & 0b1000 != 0 {
+            (ist & 0b111) * 8
+        } else {
+            ist
+        }
+    }
+
+    pub fn process_primary_event(&mut self) -> Result<Option<Trb>> {
+        self.drain_primary_events();
 
         let Some(event) = unsafe { self.cx.as_mut().get_unchecked_mut() }
-            .primary_ring_mut()
-            .pop::<Trb>(primary)
+            .event_queue
+            .pop()
+            .map(Trb::from)
         else {
-            if !self.ports_config_phase.is_resetting_port_exist() {
+            if !self.ports_config_phase.is_resetting_port_exist()
+                && self.enumerating_route.is_none()
+            {
                 if let Some(port_num) = self.ports_config_phase.waiting_reset_port() {
                     self.ports_config_phase.set_processing_port(port_num)?;
                     let phase = self.ports_config_phase.phase_mut(port_num);
@@ -473,19 +616,51 @@ impl<
 
         info!("process event");
         match event {
-            Trb::Normal => todo!(),
-            Trb::SetupStage => todo!(),
-            Trb::DataStage => todo!(),
-            Trb::StatusStage => todo!(),
-            Trb::Link(_) => todo!(),
-            Trb::NoOp => todo!(),
-            Trb::EnableSlotCommand(_) => todo!(),
-            Trb::AddressDeviceCommand(_) => todo!(),
-            Trb::ConfigureEndpointCommand(_) => todo!(),
-            Trb::NoOpCommand => todo!(),
+            Trb::TransferEvent(e) if e.completion_code().halts_endpoint() => {
+                info!(
+                    "slot {}: endpoint {} halted: {:?}",
+                    e.get_control_slot_id(),
+                    e.get_control_endpoint_id(),
+                    e.completion_code()
+                );
+                self.start_endpoint_recovery(e.get_control_slot_id(), e.get_control_endpoint_id())?;
+            }
             Trb::TransferEvent(_) => (),
             Trb::CommandCompletionEvent(e) => self.process_command_completion_event(e)?,
             Trb::PortStatusChangeEvent(e) => self.process_port_status_change_event(e)?,
+            Trb::HostControllerEvent(e) => {
+                info!(
+                    "host controller event: {:?}",
+                    e.get_status_completion_code()
+                );
+            }
+            Trb::BandwidthRequestEvent(_)
+            | Trb::DoorbellEvent(_)
+            | Trb::DeviceNotificationEvent(_)
+            | Trb::MfindexWrapEvent(_) => {
+                debug!("process event. ignored event: {:?}", event);
+            }
+            // イベントリングには載らないはずのTRB
+            Trb::Normal(_)
+            | Trb::SetupStage(_)
+            | Trb::DataStage(_)
+            | Trb::StatusStage(_)
+            | Trb::Isoch(_)
+            | Trb::Link(_)
+            | Trb::EventData(_)
+            | Trb::NoOp(_)
+            | Trb::EnableSlotCommand(_)
+            | Trb::DisableSlotCommand(_)
+            | Trb::AddressDeviceCommand(_)
+            | Trb::ConfigureEndpointCommand(_)
+            | Trb::EvaluateContextCommand(_)
+            | Trb::ResetEndpointCommand(_)
+            | Trb::StopEndpointCommand(_)
+            | Trb::SetTrDequeuePointerCommand(_)
+            | Trb::ResetDeviceCommand(_)
+            | Trb::NoOpCommand(_) => {
+                debug!("process event. unexpected trb: {:?}", event);
+            }
             Trb::Unknown(_) => {
                 debug!("process event. unknown trb: {:?}", event);
             }
@@ -500,11 +675,32 @@ impl<
             event.get_status_completion_code(),
             event
         );
+        let recovery = self
+            .cx
+            .recoveries
+            .iter()
+            .find(|(_, r)| r.is_completed_by(&event))
+            .map(|(k, _)| *k);
+        if let Some(key) = recovery {
+            return self.process_recovery_completion(key, event);
+        }
+
         let issuer = unsafe { event.issuer() };
         let slot_id = event.get_control_slot_id();
         debug!("slot_id: {}, issuer: {:?}", slot_id, issuer);
 
+        if let Trb::DisableSlotCommand(_) = issuer {
+            self.process_disable_slot_completion(slot_id, event);
+            return Ok(());
+        }
+
         if !event.is_success() {
+            if matches!(
+                issuer,
+                Trb::EnableSlotCommand(_) | Trb::AddressDeviceCommand(_)
+            ) {
+                self.enumerating_route = None;
+            }
             return Err(Error::command_not_success(
                 event.get_status_completion_code(),
                 issuer,
@@ -521,9 +717,26 @@ impl<
                         _ => panic!("unknown speed {}", speed),
                     }
                 }
-                let processing_port_num = self
-                    .processing_port_num()
-                    .ok_or(Error::empty_processing_port())?;
+                let route = match self.enumerating_route {
+                    Some(route) => route,
+                    None => {
+                        // Enable Slotの完了を待つ間にポートから外れていたら、使わずに無効にする
+                        let Some(processing_port_num) = self.processing_port_num() else {
+                            info!("slot {}: port disconnected while enabling slot", slot_id);
+                            let cx = unsafe { self.cx.as_mut().get_unchecked_mut() };
+                            cx.issue_command(DisableSlotCommand::new(slot_id));
+                            self.doorbell_registers
+                                .host_controller_mut()
+                                .notify_host_controller();
+                            return Ok(());
+                        };
+                        let phase = self.ports_config_phase.phase_mut(processing_port_num);
+                        let mut port =
+                            port(&mut self.operational_registers, processing_port_num, phase);
+                        port.set_phase(PortConfigPhase::AddressingDevice);
+                        Route::root(port.number(), port.speed())
+                    }
+                };
 
                 // may not move cx
                 unsafe {
@@ -541,20 +754,15 @@ impl<
                     input_context.enable_endpoint(1);
                     input_context.enable_slot_context();
 
-                    let phase = self.ports_config_phase.phase_mut(processing_port_num);
-                    let mut port =
-                        port(&mut self.operational_registers, processing_port_num, phase);
-
-                    input_context.init_slot_cx(&port);
+                    input_context.init_slot_cx_with_route(&route);
 
                     input_context.init_ep0_endpoint(
                         ring_ptr.cast(),
                         cycle_bit,
-                        determin_max_packet_size(port.speed()),
+                        determin_max_packet_size(route.speed),
                     );
-                    debug!("port speed: {}", port.speed());
+                    debug!("route: {:?}", route);
 
-                    port.set_phase(PortConfigPhase::AddressingDevice);
                     let cmd =
                         AddressDeviceCommand::new(input_context as *mut _ as *mut u8, slot_id);
                     cx.issue_command(cmd);
@@ -565,6 +773,11 @@ impl<
                 }
             }
             Trb::AddressDeviceCommand(_cmd) => {
+                if let Some(route) = self.enumerating_route.take() {
+                    info!("slot {}: addressed device behind hub {:?}", slot_id, route);
+                    return Ok(());
+                }
+
                 let processing_port_num = self
                     .processing_port_num()
                     .ok_or(Error::empty_processing_port())?;
@@ -614,6 +827,16 @@ impl<
             .phases_mut()
             .index_mut(port_num as usize);
 
+        let is_connected = port(
+            &mut self.operational_registers,
+            port_num,
+            self.ports_config_phase.phase_mut(port_num),
+        )
+        .is_connected();
+        if !is_connected && phase != PortConfigPhase::NotConnected {
+            return self.process_port_disconnect(port_num);
+        }
+
         match phase {
             PortConfigPhase::ResettingPort => {
                 fn enable_slot<const N: usize>(
@@ -669,6 +892,130 @@ impl<
             }
         }
     }
+
+    /// 停止したエンドポイントにReset Endpointコマンドを発行する。復旧中なら何もしない。
+    fn start_endpoint_recovery(&mut self, slot_id: SlotId, dci: u8) -> Result<()> {
+        let key = (slot_id, dci);
+        if self.cx.recoveries.contains_key(&key) {
+            return Ok(());
+        }
+        let cx = unsafe { self.cx.as_mut().get_unchecked_mut() };
+        if cx
+            .recoveries
+            .insert(key, EndpointRecovery::new(slot_id, dci))
+            .is_err()
+        {
+            debug!("too many halted endpoints. slot: {}, dci: {}", slot_id, dci);
+            return Ok(());
+        }
+        self.issue_recovery_command(key)
+    }
+
+    fn issue_recovery_command(&mut self, key: (SlotId, u8)) -> Result<()> {
+        let cx = unsafe { self.cx.as_mut().get_unchecked_mut() };
+        let recovery = cx.recoveries.get_mut(&key).unwrap();
+        let Some(device) = cx.device_manager.device_mut(key.0) else {
+            cx.recoveries.remove(&key);
+            return Err(Error::invalid_slot_id());
+        };
+        let command_ring = &mut cx.command_ring;
+        recovery.issue_next(device.ring_mut(key.1), |trb| command_ring.push(trb));
+
+        self.doorbell_registers
+            .host_controller_mut()
+            .notify_host_controller();
+        Ok(())
+    }
+
+    fn process_recovery_completion(
+        &mut self,
+        key: (SlotId, u8),
+        event: CommandCompletionEvent,
+    ) -> Result<()> {
+        let cx = unsafe { self.cx.as_mut().get_unchecked_mut() };
+        let recovery = cx.recoveries.get_mut(&key).unwrap();
+        if let Err(e) = recovery.complete(event) {
+            // 待っている転送を終わらせられるように、失敗も`usbd`に知らせる
+            error!(
+                "slot {}: endpoint {} recovery failed: {:?}",
+                key.0, key.1, e
+            );
+            cx.recoveries.remove(&key);
+            if cx.recovered_endpoints.push((key, Err(e))).is_err() {
+                debug!("recovered endpoint queue is full. {:?}", key);
+            }
+            return Ok(());
+        }
+        if !recovery.is_done() {
+            return self.issue_recovery_command(key);
+        }
+
+        cx.recoveries.remove(&key);
+        if cx.recovered_endpoints.push((key, Ok(()))).is_err() {
+            debug!("recovered endpoint queue is full. {:?}", key);
+        }
+        info!("slot {}: endpoint {} recovered", key.0, key.1);
+        Ok(())
+    }
+
+    /// Disable Slotの完了。取り外しの後始末なので、失敗してもスロットは解放する。
+    /// デバイスコンテキストを割り当てる前に無効にしたスロットは、上に通知しない。
+    fn process_disable_slot_completion(&mut self, slot_id: SlotId, event: CommandCompletionEvent) {
+        if !event.is_success() {
+            error!(
+                "slot {}: disable slot failed: {:?}",
+                slot_id,
+                event.get_status_completion_code()
+            );
+        }
+
+        let cx = unsafe { self.cx.as_mut().get_unchecked_mut() };
+        cx.device_context_ptrs[slot_id as usize] = ptr::null_mut();
+        cx.recoveries.retain(|k, _| k.0 != slot_id);
+        if cx.device_manager.free_device(slot_id).is_none() {
+            info!("slot {}: disabled before use", slot_id);
+            return;
+        }
+        if cx.detached_slots.push(slot_id).is_err() {
+            debug!("detached slot queue is full. slot: {}", slot_id);
+        }
+        info!("slot {}: disabled", slot_id);
+    }
+
+    /// ルートポートから外れたデバイスと、その先のハブにつながっていたデバイスのスロットを無効にする。
+    /// スロットはDisable Slotコマンドの完了時に解放する。
+    fn process_port_disconnect(&mut self, port_num: u8) -> Result<()> {
+        info!("port {}: disconnected", port_num);
+        let phase = self.ports_config_phase.phase_mut(port_num);
+        let mut port = port(&mut self.operational_registers, port_num, phase);
+        // 次の接続でまたPort Status Change Eventが来るように消しておく
+        port.clear_connect_status_change();
+        port.set_phase(PortConfigPhase::NotConnected);
+        if self.ports_config_phase.processing_port() == Some(port_num) {
+            self.ports_config_phase.clear_processing_port()?;
+        }
+
+        let cx = unsafe { self.cx.as_mut().get_unchecked_mut() };
+        let mut slots = [None; DEV_MNGR_SIZE];
+        let devices = cx
+            .device_manager
+            .devices_mut()
+            .filter(|d| d.root_port_num() == port_num);
+        for (slot, device) in slots.iter_mut().zip(devices) {
+            *slot = Some(device.slot_id());
+        }
+
+        for slot_id in slots.iter().flatten() {
+            debug!("disable slot {}", slot_id);
+            cx.issue_command(DisableSlotCommand::new(*slot_id));
+        }
+        if slots.iter().any(Option::is_some) {
+            self.doorbell_registers
+                .host_controller_mut()
+                .notify_host_controller();
+        }
+        Ok(())
+    }
 }
 
 fn port<'a, 'b, 'c>(
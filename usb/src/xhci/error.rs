@@ -1,6 +1,6 @@
 use super::{
     port::PortConfigPhase,
-    trb::{CommandConpletionCode, Trb},
+    trb::{CompletionCode, TransferEvent, Trb},
 };
 
 pub type Result<T> = core::result::Result<T, Error>;
@@ -49,9 +49,30 @@ impl Error {
         Self(ErrorKind::InvalidPhase { expected, actual })
     }
 
-    pub fn command_not_success(code: CommandConpletionCode, issuer: Trb) -> Self {
+    pub fn command_not_success(code: CompletionCode, issuer: Trb) -> Self {
         Self(ErrorKind::CommandNotSuccess { code, issuer })
     }
+
+    pub fn transfer_not_success(event: TransferEvent) -> Self {
+        Self(ErrorKind::TransferNotSuccess {
+            code: event.get_status_completion_code(),
+            slot_id: event.get_control_slot_id(),
+            endpoint_id: event.get_control_endpoint_id(),
+        })
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.0
+    }
+
+    /// コマンドや転送の失敗なら完了コードを返す
+    pub fn completion_code(&self) -> Option<CompletionCode> {
+        match self.0 {
+            ErrorKind::CommandNotSuccess { code, .. }
+            | ErrorKind::TransferNotSuccess { code, .. } => Some(code),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -70,7 +91,12 @@ pub enum ErrorKind {
         actual: PortConfigPhase,
     },
     CommandNotSuccess {
-        code: CommandConpletionCode,
+        code: CompletionCode,
         issuer: Trb,
     },
+    TransferNotSuccess {
+        code: CompletionCode,
+        slot_id: u8,
+        endpoint_id: u8,
+    },
 }
@@ -0,0 +1,1306 @@
+//! テスト用の偽のxHC
+//!
+//! `mmio`に差し込んでレジスタへの書き込みに反応させる。ドアベルが鳴るとコマンドリングや転送リングを読んで、
+//! 結果をイベントリングに書き込む。xHCに渡すアドレスはホストのアドレスそのままなので、
+//! リングやコンテキスト、転送のバッファはポインタで直接読み書きする。
+//! Isoch TDは`advance_frames`で進めたMFINDEXが指定のフレームになったときに実行する。
+//! `FakeDevice::with_storage`でBulk-Only Transportのマスストレージとして、
+//! `FakeDevice::with_hub`で下流ポートを持つUSB 2.0ハブとして振る舞わせられる。
+//! ハブの先のデバイスはSlot ContextのRoute Stringで探す。ストリームは扱わない。
+
+extern crate std;
+
+use std::{boxed::Box, cell::RefCell, collections::VecDeque, rc::Rc, vec, vec::Vec};
+
+use common::Zeroed;
+
+use super::{
+    context::{DeviceContext, InputContext},
+    device::SlotId,
+    mmio::{self, Mmio},
+    register_map::{
+        CapabilityRegisters, InterrupterRegisterSet, OperationalRegisters, PortRegisterSet,
+        RuntimeRegisters,
+    },
+    ring::EventRingSegmentTableEntry,
+    trb::{
+        CommandCompletionEvent, CompletionCode, Link, Normal, PortStatusChangeEvent, TransferEvent,
+        Trb, TrbRaw, TrbType,
+    },
+};
+
+const REGS_SIZE: usize = 0x1000;
+const CAP_LENGTH: usize = 0x20;
+const RTS_OFFSET: usize = 0x600;
+const DB_OFFSET: usize = 0x800;
+const MAX_SLOTS: u8 = 8;
+const MAX_PORTS: u8 = 2;
+const MAX_ENDPOINTS: usize = 32;
+
+const USBCMD: usize = CAP_LENGTH + OperationalRegisters::USB_COMMAND_OFFSET;
+const USBSTS: usize = CAP_LENGTH + OperationalRegisters::USB_STATUS_OFFSET;
+const CRCR: usize = CAP_LENGTH + OperationalRegisters::COMMAND_RING_CONTROL_OFFSET;
+const DCBAAP: usize =
+    CAP_LENGTH + OperationalRegisters::DEVICE_CONTEXT_BASE_ADDRESS_ARRAY_POINTER_OFFSET;
+const PORTSC: usize = CAP_LENGTH
+    + OperationalRegisters::PORT_REGISTER_SET_OFFSET
+    + PortRegisterSet::PORT_STATUS_AND_CONTROL_OFFSET;
+const MFINDEX: usize = RTS_OFFSET + RuntimeRegisters::MICROFRAME_INDEX_OFFSET;
+const INTERRUPTER: usize = RTS_OFFSET + RuntimeRegisters::INTERRUPTER_REGISTER_OFFSET;
+const IMAN: usize = INTERRUPTER + InterrupterRegisterSet::INTERRUPT_MANAGEMENT_OFFSET;
+const ERSTBA: usize =
+    INTERRUPTER + InterrupterRegisterSet::EVENT_RING_SEGMENT_TABLE_BASE_ADDRESS_OFFSET;
+const ERDP: usize = INTERRUPTER + InterrupterRegisterSet::EVENT_RING_DEQUEUE_POINTER_OFFSET;
+
+const USBCMD_RUN_STOP: u64 = 1 << 0;
+const USBCMD_HCRST: u64 = 1 << 1;
+const USBSTS_HCH: u64 = 1 << 0;
+const USBSTS_EINT: u64 = 1 << 3;
+/// HSE, EINT, PCD, SRE
+const USBSTS_RW1C: u64 = (1 << 2) | (1 << 3) | (1 << 4) | (1 << 10);
+const PORTSC_CCS: u64 = 1 << 0;
+const PORTSC_PED: u64 = 1 << 1;
+const PORTSC_PR: u64 = 1 << 4;
+const PORTSC_PP: u64 = 1 << 9;
+const PORTSC_SPEED_SHIFT: u64 = 10;
+const PORTSC_CSC: u64 = 1 << 17;
+const PORTSC_PRC: u64 = 1 << 21;
+/// CSC, PEC, WRC, OCC, PRC, PLC, CEC
+const PORTSC_RW1C: u64 = 0x7f << 17;
+const IMAN_IP: u64 = 1 << 0;
+/// MFINDEXは14ビット
+const MFINDEX_MASK: u64 = 0x3fff;
+const FRAME_ID_MASK: u64 = 0x7ff;
+/// これより先のFrame IDはまだ来ていないフレーム、後ろは過ぎたフレームとみなす
+const FRAME_ID_HALF: u16 = 0x400;
+/// Isoch TDを積んでから実行できるまでのマイクロフレーム数
+const ISOCH_SCHEDULING_THRESHOLD: u64 = 4;
+
+/// xHCが読むメモリ。アドレスはホストのアドレスと同じ。
+unsafe fn dma_read<T: Copy>(addr: u64) -> T {
+    unsafe { (addr as *const T).read_unaligned() }
+}
+
+unsafe fn dma_write<T: Copy>(addr: u64, value: T) {
+    unsafe { (addr as *mut T).write_unaligned(value) }
+}
+
+/// Setup Stageで受け取ったリクエスト
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl Request {
+    fn is_in(&self) -> bool {
+        self.request_type & 0x80 != 0
+    }
+}
+
+const GET_STATUS: u8 = 0x00;
+const CLEAR_FEATURE: u8 = 0x01;
+const SET_FEATURE: u8 = 0x03;
+const GET_DESCRIPTOR: u8 = 0x06;
+/// ハブクラスの、ポートを宛先にするリクエストのbmRequestType
+const HUB_PORT_OUT: u8 = 0x23;
+const HUB_PORT_IN: u8 = 0xa3;
+const PORT_RESET: u16 = 4;
+const PORT_POWER: u16 = 8;
+const C_PORT_CONNECTION: u16 = 16;
+const C_PORT_RESET: u16 = 20;
+const PORT_CONNECTION: u16 = 1 << 0;
+const PORT_ENABLE: u16 = 1 << 1;
+const PORT_RESET_STATUS: u16 = 1 << 4;
+const PORT_POWER_STATUS: u16 = 1 << 8;
+const PORT_LOW_SPEED: u16 = 1 << 9;
+const PORT_HIGH_SPEED: u16 = 1 << 10;
+const SPEED_LOW: u8 = 2;
+const SPEED_HIGH: u8 = 3;
+const BULK_ONLY_RESET: u8 = 0xff;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_LEN: usize = 31;
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const UNIT_ATTENTION: u8 = 0x6;
+const ILLEGAL_REQUEST: u8 = 0x5;
+const CSW_PASSED: u8 = 0;
+const CSW_FAILED: u8 = 1;
+const CSW_PHASE_ERROR: u8 = 2;
+
+/// 偽のマスストレージが次のコマンドで起こす異常
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageFault {
+    /// CSWのタグをCBWと違うものにする
+    WrongTag,
+    /// CSWでPhase Errorを返す
+    PhaseError,
+    /// CSWを1回だけSTALLしてから返す
+    StallCsw,
+}
+
+/// Bulk-Only TransportでSCSIコマンドを受け付けるマスストレージ。LUNは0だけ。
+#[derive(Debug, Clone)]
+pub struct FakeStorage {
+    bulk_in: u8,
+    bulk_out: u8,
+    block_length: u32,
+    /// ディスクの中身。`block_length`の倍数。
+    pub data: Vec<u8>,
+    /// TEST UNIT READYをUNIT ATTENTIONで失敗させる残りの回数
+    not_ready: usize,
+    /// REQUEST SENSEで返すセンスキー
+    sense_key: u8,
+    /// WRITE(10)のデータを待っているときの、CBWのタグと書き込む位置、長さ
+    write: Option<(u32, usize, usize)>,
+    /// 書き込むデータのうち受け取った分
+    received: Vec<u8>,
+    fault: Option<StorageFault>,
+    /// 受け取ったSCSIコマンドのオペコード
+    pub commands: Vec<u8>,
+    /// Bulk-Only Mass Storage Resetを受け取った回数
+    pub resets: usize,
+}
+
+impl FakeStorage {
+    /// CBWを受け取って、INエンドポイントに返すデータとCSWを積む
+    fn command(&mut self, cbw: &[u8], inputs: &mut VecDeque<(u8, Option<Vec<u8>>)>) {
+        let u32_at = |i: usize| u32::from_le_bytes(cbw[i..i + 4].try_into().unwrap());
+        if cbw.len() != CBW_LEN || u32_at(0) != CBW_SIGNATURE {
+            return;
+        }
+        let tag = u32_at(4);
+        let length = u32_at(8) as usize;
+        let cb = &cbw[15..31];
+        self.commands.push(cb[0]);
+
+        let lba = u32::from_be_bytes(cb[2..6].try_into().unwrap()) as usize;
+        let blocks = u16::from_be_bytes(cb[7..9].try_into().unwrap()) as usize;
+        let block_length = self.block_length as usize;
+        let in_range = (lba + blocks) * block_length <= self.data.len();
+        let (data, status) = match cb[0] {
+            TEST_UNIT_READY if self.not_ready > 0 => {
+                self.not_ready -= 1;
+                self.sense_key = UNIT_ATTENTION;
+                (Vec::new(), CSW_FAILED)
+            }
+            TEST_UNIT_READY => (Vec::new(), CSW_PASSED),
+            REQUEST_SENSE => {
+                let mut sense = vec![0; 18];
+                sense[0] = 0x70;
+                sense[2] = core::mem::take(&mut self.sense_key);
+                sense[7] = 10;
+                (sense, CSW_PASSED)
+            }
+            INQUIRY => {
+                let mut inquiry = vec![0, 0x80, 5, 2, 31, 0, 0, 0];
+                inquiry.extend_from_slice(b"QEMU    QEMU HARDDISK   2.5+");
+                (inquiry, CSW_PASSED)
+            }
+            READ_CAPACITY_10 => {
+                let last_lba = (self.data.len() / block_length) as u32 - 1;
+                let mut capacity = last_lba.to_be_bytes().to_vec();
+                capacity.extend_from_slice(&self.block_length.to_be_bytes());
+                (capacity, CSW_PASSED)
+            }
+            READ_10 if in_range => {
+                let offset = lba * block_length;
+                let data = self.data[offset..offset + blocks * block_length].to_vec();
+                (data, CSW_PASSED)
+            }
+            WRITE_10 if in_range && blocks > 0 => {
+                self.write = Some((tag, lba * block_length, blocks * block_length));
+                return;
+            }
+            _ => {
+                self.sense_key = ILLEGAL_REQUEST;
+                (Vec::new(), CSW_FAILED)
+            }
+        };
+
+        let data = &data[..data.len().min(length)];
+        if !data.is_empty() {
+            inputs.push_back((self.bulk_in, Some(data.to_vec())));
+        }
+        self.status(tag, (length - data.len()) as u32, status, inputs);
+    }
+
+    /// WRITE(10)のデータを受け取る。全部そろったら書き込んでCSWを積む。
+    fn write_data(&mut self, data: &[u8], inputs: &mut VecDeque<(u8, Option<Vec<u8>>)>) {
+        let Some((tag, offset, length)) = self.write else {
+            return;
+        };
+        self.received.extend_from_slice(data);
+        if self.received.len() < length {
+            return;
+        }
+        self.data[offset..offset + length].copy_from_slice(&self.received[..length]);
+        self.received.clear();
+        self.write = None;
+        self.status(tag, 0, CSW_PASSED, inputs);
+    }
+
+    fn status(
+        &mut self,
+        mut tag: u32,
+        residue: u32,
+        mut status: u8,
+        inputs: &mut VecDeque<(u8, Option<Vec<u8>>)>,
+    ) {
+        match self.fault.take() {
+            Some(StorageFault::WrongTag) => tag = tag.wrapping_add(1),
+            Some(StorageFault::PhaseError) => status = CSW_PHASE_ERROR,
+            Some(StorageFault::StallCsw) => inputs.push_back((self.bulk_in, None)),
+            None => {}
+        }
+        let mut csw = CSW_SIGNATURE.to_le_bytes().to_vec();
+        csw.extend_from_slice(&tag.to_le_bytes());
+        csw.extend_from_slice(&residue.to_le_bytes());
+        csw.push(status);
+        inputs.push_back((self.bulk_in, Some(csw)));
+    }
+
+    /// Bulk-Only Mass Storage Reset。途中のコマンドを捨てて次のCBWを待つ。
+    fn reset(&mut self, inputs: &mut VecDeque<(u8, Option<Vec<u8>>)>) {
+        self.resets += 1;
+        self.write = None;
+        self.received.clear();
+        inputs.retain(|v| v.0 != self.bulk_in);
+    }
+}
+
+/// USB 2.0ハブの下流ポート。リセットはすぐに終わる。
+#[derive(Debug, Clone)]
+pub struct FakeHub {
+    /// 下流ポートにつながっているデバイス。ポート番号は1から。
+    pub ports: Vec<Option<FakeDevice>>,
+    /// 下流ポートごとのwPortStatusとwPortChange
+    status: Vec<(u16, u16)>,
+    /// 立っているポートはリセットしても終わらない
+    stuck_reset: Vec<bool>,
+}
+
+impl FakeHub {
+    /// ポートを宛先にするハブクラスのリクエスト。範囲外のポートならSTALLする。
+    fn control(&mut self, request: Request) -> Option<Vec<u8>> {
+        let idx = (request.index as usize).checked_sub(1)?;
+        let (status, change) = self.status.get_mut(idx)?;
+        match (request.request_type, request.request, request.value) {
+            (HUB_PORT_IN, GET_STATUS, _) => {
+                let mut data = status.to_le_bytes().to_vec();
+                data.extend_from_slice(&change.to_le_bytes());
+                return Some(data);
+            }
+            (HUB_PORT_OUT, SET_FEATURE, PORT_POWER) => {
+                *status |= PORT_POWER_STATUS;
+                if self.ports[idx].is_some() {
+                    *status |= PORT_CONNECTION;
+                    *change |= PORT_CONNECTION;
+                }
+            }
+            (HUB_PORT_OUT, SET_FEATURE, PORT_RESET) => {
+                let Some(device) = self.ports[idx].as_ref() else {
+                    return Some(Vec::new());
+                };
+                if self.stuck_reset[idx] {
+                    *status |= PORT_RESET_STATUS;
+                    return Some(Vec::new());
+                }
+                *status |= PORT_ENABLE;
+                *status |= match device.speed {
+                    SPEED_LOW => PORT_LOW_SPEED,
+                    SPEED_HIGH => PORT_HIGH_SPEED,
+                    _ => 0,
+                };
+                *change |= PORT_RESET_STATUS;
+            }
+            // wPortChangeのビットはwPortStatusの同じ位置のビットの変化
+            (HUB_PORT_OUT, CLEAR_FEATURE, C_PORT_CONNECTION) => *change &= !PORT_CONNECTION,
+            (HUB_PORT_OUT, CLEAR_FEATURE, C_PORT_RESET) => *change &= !PORT_RESET_STATUS,
+            (HUB_PORT_OUT, _, _) => {}
+            _ => return None,
+        }
+        Some(Vec::new())
+    }
+}
+
+/// ポートにつなぐ偽のデバイス。ディスクリプタを返し、それ以外のINリクエストはSTALLする。
+/// OUTリクエストはすべて受け付けて記録だけする。
+#[derive(Debug, Clone)]
+pub struct FakeDevice {
+    speed: u8,
+    /// ディスクリプタのタイプとインデックス、中身
+    descriptors: Vec<(u8, u8, Vec<u8>)>,
+    /// DCIごとのINエンドポイントに届いたデータ。`None`ならそこでSTALLする。
+    inputs: VecDeque<(u8, Option<Vec<u8>>)>,
+    /// OUTエンドポイントで受け取ったデータとそのDCI
+    pub outputs: Vec<(u8, Vec<u8>)>,
+    /// 受け取ったコントロールリクエスト
+    pub requests: Vec<Request>,
+    /// Isoch TDを実行したフレーム
+    pub isoch_frames: Vec<u16>,
+    /// このbRequestのSetup Stageを受け取ると、応答せずにポートから外れる
+    detach_on: Option<u8>,
+    pub storage: Option<FakeStorage>,
+    pub hub: Option<FakeHub>,
+}
+
+impl FakeDevice {
+    pub fn new(speed: u8) -> Self {
+        Self {
+            speed,
+            descriptors: Vec::new(),
+            inputs: VecDeque::new(),
+            outputs: Vec::new(),
+            requests: Vec::new(),
+            isoch_frames: Vec::new(),
+            detach_on: None,
+            storage: None,
+            hub: None,
+        }
+    }
+
+    /// GET_DESCRIPTORで返すディスクリプタ。wValueの上位がタイプ、下位がインデックス。
+    pub fn with_descriptor(mut self, ty: u8, index: u8, data: &[u8]) -> Self {
+        self.descriptors.push((ty, index, data.to_vec()));
+        self
+    }
+
+    /// 文字列ディスクリプタ。言語IDの一覧(0番)は`with_descriptor`で入れる。
+    pub fn with_string(self, index: u8, s: &str) -> Self {
+        let mut data = vec![0, 3];
+        data.extend(s.encode_utf16().flat_map(u16::to_le_bytes));
+        data[0] = data.len() as u8;
+        self.with_descriptor(3, index, &data)
+    }
+
+    /// 設定の途中で抜かれるデバイス。`request`のコントロール転送を始めたところでポートから外れる。
+    pub fn detach_on_request(mut self, request: u8) -> Self {
+        self.detach_on = Some(request);
+        self
+    }
+
+    /// Bulk IN `bulk_in`とBulk OUT `bulk_out`(DCI)でSCSIコマンドを受け付けるマスストレージ。
+    /// 最初の`not_ready`回のTEST UNIT READYはUNIT ATTENTIONで失敗する。
+    pub fn with_storage(
+        mut self,
+        bulk_in: u8,
+        bulk_out: u8,
+        block_length: u32,
+        data: Vec<u8>,
+        not_ready: usize,
+    ) -> Self {
+        self.storage = Some(FakeStorage {
+            bulk_in,
+            bulk_out,
+            block_length,
+            data,
+            not_ready,
+            sense_key: 0,
+            write: None,
+            received: Vec::new(),
+            fault: None,
+            commands: Vec::new(),
+            resets: 0,
+        });
+        self
+    }
+
+    /// `ports`を下流ポートにつないだハブ。ハブディスクリプタも返すようになる。
+    pub fn with_hub(self, ports: Vec<Option<FakeDevice>>) -> Self {
+        let num_ports = ports.len() as u8;
+        // bPwrOn2PwrGoodは0にして、電源を入れたらすぐに接続状態を読めるようにする
+        let descriptor = [9, 0x29, num_ports, 0, 0, 0, 0, 0, 0xff];
+        let mut device = self.with_descriptor(0x29, 0, &descriptor);
+        device.hub = Some(FakeHub {
+            status: vec![(0, 0); ports.len()],
+            stuck_reset: vec![false; ports.len()],
+            ports,
+        });
+        device
+    }
+
+    /// ハブの下流ポート`port`のリセットが終わらないようにする
+    pub fn with_stuck_reset(mut self, port: u8) -> Self {
+        self.hub.as_mut().unwrap().stuck_reset[port as usize - 1] = true;
+        self
+    }
+
+    /// 文字列ディスクリプタを取り除き、読もうとするとSTALLするようにする
+    pub fn without_strings(mut self) -> Self {
+        self.descriptors.retain(|d| d.0 != 3);
+        self
+    }
+
+    fn control(&mut self, request: Request) -> Option<Vec<u8>> {
+        self.requests.push(request);
+        if let Some(hub) = self.hub.as_mut() {
+            if matches!(request.request_type, HUB_PORT_OUT | HUB_PORT_IN) {
+                let mut data = hub.control(request)?;
+                data.truncate(request.length as usize);
+                return Some(data);
+            }
+        }
+        if !request.is_in() {
+            if let Some(storage) = self.storage.as_mut() {
+                if (request.request_type, request.request) == (0x21, BULK_ONLY_RESET) {
+                    storage.reset(&mut self.inputs);
+                }
+            }
+            return Some(Vec::new());
+        }
+
+        let mut data = match request.request {
+            GET_STATUS => vec![0, 0],
+            GET_DESCRIPTOR => {
+                let [index, ty] = request.value.to_le_bytes();
+                self.descriptors
+                    .iter()
+                    .find(|d| (d.0, d.1) == (ty, index))?
+                    .2
+                    .clone()
+            }
+            _ => return None,
+        };
+        data.truncate(request.length as usize);
+        Some(data)
+    }
+
+    /// INエンドポイントに次に届くデータ。STALLすることになっていれば`None`。
+    fn take_input(&mut self, dci: u8) -> Option<Vec<u8>> {
+        let idx = self.inputs.iter().position(|v| v.0 == dci)?;
+        self.inputs[idx].1.as_ref()?;
+        self.inputs.remove(idx).and_then(|v| v.1)
+    }
+
+    /// INエンドポイントが次にSTALLすることになっていれば、それを取り除いて`true`を返す
+    fn take_stall(&mut self, dci: u8) -> bool {
+        match self.inputs.iter().position(|v| v.0 == dci) {
+            Some(idx) if self.inputs[idx].1.is_none() => {
+                self.inputs.remove(idx);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// OUTエンドポイントでデータを受け取る。マスストレージならCBWか書き込むデータとして扱う。
+    fn receive(&mut self, dci: u8, data: Vec<u8>) {
+        if let Some(storage) = self.storage.as_mut().filter(|s| s.bulk_out == dci) {
+            if storage.write.is_some() {
+                storage.write_data(&data, &mut self.inputs);
+            } else {
+                storage.command(&data, &mut self.inputs);
+            }
+        }
+        self.outputs.push((dci, data));
+    }
+}
+
+/// 転送リングやコマンドリングの読み出し位置
+#[derive(Debug, Clone, Copy)]
+struct Cursor {
+    ptr: u64,
+    cycle_bit: bool,
+}
+
+impl Cursor {
+    /// Link TRBをたどって、次に実行するTRBとそのアドレスを返す
+    fn peek(&mut self) -> Option<(u64, TrbRaw)> {
+        loop {
+            let trb: TrbRaw = unsafe { dma_read(self.ptr) };
+            if trb.get_remain_cycle_bit() != self.cycle_bit {
+                return None;
+            }
+            let Ok(link) = Link::try_from(trb) else {
+                return Some((self.ptr, trb));
+            };
+            self.ptr = ((link.get_ring_segment_pointer_hi() as u64) << 32)
+                | (link.get_ring_segment_pointer_lo_data() as u64) << 4;
+            if link.get_remain_toggle_cycle() {
+                self.cycle_bit = !self.cycle_bit;
+            }
+        }
+    }
+
+    fn advance(&mut self) {
+        self.ptr += core::mem::size_of::<TrbRaw>() as u64;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Endpoint {
+    cursor: Cursor,
+    halted: bool,
+    /// 実行中のコントロール転送のリクエストと、Data Stageを終えたか
+    setup: Option<(Request, bool)>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Slot {
+    /// ルートハブのポート
+    port: u8,
+    /// ハブの先なら0以外。下位の4ビットから順に各段のハブのポート番号。
+    route: u32,
+    endpoints: [Option<Endpoint>; MAX_ENDPOINTS],
+}
+
+#[derive(Debug, Clone, Copy)]
+struct EventProducer {
+    base: u64,
+    size: usize,
+    idx: usize,
+    cycle_bit: bool,
+}
+
+struct Inner {
+    regs: Box<[u64; REGS_SIZE / 8]>,
+    ports: [Option<FakeDevice>; MAX_PORTS as usize],
+    slots: [Option<Slot>; MAX_SLOTS as usize + 1],
+    command_ring: Option<Cursor>,
+    event_ring: Option<EventProducer>,
+    /// 立っている間はドアベルが鳴ってもコマンドを実行しない
+    commands_held: bool,
+    /// 実行せずに失敗させるコマンドの種類と返す完了コード
+    command_failures: VecDeque<(TrbType, CompletionCode)>,
+    /// 立っている間はMFINDEXを読むたびに1マイクロフレーム進める
+    free_running: bool,
+}
+
+/// 偽のxHC。`install`でこのスレッドのレジスタアクセスを受け取るようになる。
+#[derive(Clone)]
+pub struct FakeXhc(Rc<RefCell<Inner>>);
+
+impl FakeXhc {
+    pub fn new() -> Self {
+        let mut inner = Inner {
+            regs: Box::new([0; REGS_SIZE / 8]),
+            ports: [None, None],
+            slots: [None; MAX_SLOTS as usize + 1],
+            command_ring: None,
+            event_ring: None,
+            commands_held: false,
+            command_failures: VecDeque::new(),
+            free_running: false,
+        };
+        inner.store(CapabilityRegisters::CAP_LENGTH_OFFSET, 1, CAP_LENGTH as u64);
+        inner.store(CapabilityRegisters::HCI_VERSION_OFFSET, 2, 0x0100);
+        // MaxSlots, MaxIntrs, MaxPorts
+        let params1 = MAX_SLOTS as u64 | (1 << 8) | (MAX_PORTS as u64) << 24;
+        inner.store(CapabilityRegisters::HCS_PARAMS1_OFFSET, 4, params1);
+        inner.store(
+            CapabilityRegisters::HCS_PARAMS2_OFFSET,
+            4,
+            ISOCH_SCHEDULING_THRESHOLD,
+        );
+        inner.store(CapabilityRegisters::DB_OFFSET_OFFSET, 4, DB_OFFSET as u64);
+        inner.store(CapabilityRegisters::RTS_OFFSET_OFFSET, 4, RTS_OFFSET as u64);
+        inner.store(USBSTS, 4, USBSTS_HCH);
+        for port in 0..MAX_PORTS {
+            inner.store(PORTSC + 0x10 * port as usize, 4, PORTSC_PP);
+        }
+        Self(Rc::new(RefCell::new(inner)))
+    }
+
+    /// このスレッドの`RegisterMap`の読み書きをこのxHCで受ける
+    pub fn install(&self) {
+        mmio::set_handler(Some(Box::new(self.clone())));
+    }
+
+    /// `Controller::new`に渡すアドレス
+    pub fn bar(&self) -> u64 {
+        self.0.borrow().regs.as_ptr() as u64
+    }
+
+    /// ルートハブのポートにデバイスをつなぐ。`Driver::new`より前なら最初から接続されていたことになる。
+    pub fn attach(&self, port: u8, device: FakeDevice) {
+        let mut inner = self.0.borrow_mut();
+        let speed = (device.speed as u64) << PORTSC_SPEED_SHIFT;
+        inner.ports[port as usize - 1] = Some(device);
+        let portsc = PORTSC_CCS | PORTSC_PP | PORTSC_CSC | speed;
+        inner.store(Inner::portsc(port), 4, portsc);
+        // 動いているxHCならイベントで知らせる。イベントリングはまだ何も書いていなければ作られていない。
+        if inner.load(USBCMD, 4) & USBCMD_RUN_STOP != 0 {
+            inner.post_port_status_change(port);
+        }
+    }
+
+    /// ルートハブのポートからデバイスを外す
+    pub fn detach(&self, port: u8) {
+        self.0.borrow_mut().detach(port);
+    }
+
+    /// `release_commands`を呼ぶまで、積まれたコマンドを実行せずに置いておく
+    pub fn hold_commands(&self) {
+        self.0.borrow_mut().commands_held = true;
+    }
+
+    /// 置いておいたコマンドを実行する
+    pub fn release_commands(&self) {
+        let mut inner = self.0.borrow_mut();
+        inner.commands_held = false;
+        inner.run_commands();
+    }
+
+    /// エンドポイントを止めて、積まれているTDをStall Errorで終わらせる
+    pub fn stall(&self, port: u8, dci: u8) {
+        let mut inner = self.0.borrow_mut();
+        let slot_id = inner.slot_id(port).unwrap();
+        let mut ep = *inner.endpoint_mut(slot_id, dci).unwrap();
+        let (ptr, _) = ep.cursor.peek().unwrap();
+        inner.stall(slot_id, dci, ptr, ep);
+    }
+
+    /// MFINDEXを読むたびに1マイクロフレーム進めて、時間が過ぎるのを待つ処理が終わるようにする
+    pub fn free_run_mfindex(&self) {
+        self.0.borrow_mut().free_running = true;
+    }
+
+    /// 次に来る`ty`のコマンドを実行せず、`code`で完了させる
+    pub fn fail_next_command(&self, ty: TrbType, code: CompletionCode) {
+        self.0.borrow_mut().command_failures.push_back((ty, code));
+    }
+
+    /// INエンドポイントにデータを届け、積まれているNormal TRBがあれば完了させる
+    pub fn send(&self, port: u8, dci: u8, data: &[u8]) {
+        let mut inner = self.0.borrow_mut();
+        let device = inner.ports[port as usize - 1].as_mut().unwrap();
+        device.inputs.push_back((dci, Some(data.to_vec())));
+        if let Some(slot_id) = inner.slot_id(port) {
+            inner.run_endpoint(slot_id, dci);
+        }
+    }
+
+    /// MFINDEXを1フレームずつ進めて、そのフレームに予定されているIsoch TDを実行する
+    pub fn advance_frames(&self, frames: usize) {
+        let mut inner = self.0.borrow_mut();
+        for _ in 0..frames {
+            let mfindex = (inner.load(MFINDEX, 4) + 8) & MFINDEX_MASK;
+            inner.store(MFINDEX, 4, mfindex);
+            for slot_id in 1..=MAX_SLOTS {
+                for dci in 1..MAX_ENDPOINTS as u8 {
+                    inner.run_endpoint(slot_id, dci);
+                }
+            }
+        }
+    }
+
+    /// ポートにつながっているマスストレージに、次のコマンドで`fault`を起こさせる
+    pub fn storage_fault(&self, port: u8, fault: StorageFault) {
+        let mut inner = self.0.borrow_mut();
+        let device = inner.ports[port as usize - 1].as_mut().unwrap();
+        device.storage.as_mut().unwrap().fault = Some(fault);
+    }
+
+    /// ポートにつながっているデバイス
+    pub fn device(&self, port: u8) -> FakeDevice {
+        self.0.borrow().ports[port as usize - 1].clone().unwrap()
+    }
+}
+
+impl Mmio for FakeXhc {
+    fn read(&mut self, addr: usize, size: usize) -> u64 {
+        let mut inner = self.0.borrow_mut();
+        let offset = inner.offset(addr);
+        let value = inner.load(offset, size);
+        if offset == MFINDEX && inner.free_running {
+            inner.store(MFINDEX, 4, (value + 1) & MFINDEX_MASK);
+        }
+        value
+    }
+
+    fn write(&mut self, addr: usize, size: usize, value: u64) {
+        let mut inner = self.0.borrow_mut();
+        let offset = inner.offset(addr);
+        inner.write(offset, size, value);
+    }
+}
+
+impl Inner {
+    fn offset(&self, addr: usize) -> usize {
+        let base = self.regs.as_ptr() as usize;
+        assert!(
+            (base..base + REGS_SIZE).contains(&addr),
+            "not a register: {:#x}",
+            addr
+        );
+        addr - base
+    }
+
+    fn load(&self, offset: usize, size: usize) -> u64 {
+        let ptr = unsafe { (self.regs.as_ptr() as *const u8).add(offset) };
+        let mut bytes = [0u8; 8];
+        unsafe { core::ptr::copy_nonoverlapping(ptr, bytes.as_mut_ptr(), size) };
+        u64::from_le_bytes(bytes)
+    }
+
+    fn store(&mut self, offset: usize, size: usize, value: u64) {
+        let ptr = unsafe { (self.regs.as_mut_ptr() as *mut u8).add(offset) };
+        let bytes = value.to_le_bytes();
+        unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, size) };
+    }
+
+    fn portsc(port: u8) -> usize {
+        PORTSC + 0x10 * (port as usize - 1)
+    }
+
+    /// ポートのデバイスに割り当てたスロット
+    /// ルートハブのポートに直接つながっているデバイスのスロット
+    fn slot_id(&self, port: u8) -> Option<SlotId> {
+        (1..=MAX_SLOTS)
+            .find(|&id| self.slots[id as usize].is_some_and(|s| s.port == port && s.route == 0))
+    }
+
+    /// ルートハブのポートからRoute Stringのとおりにハブをたどった先のデバイス
+    fn device_mut(&mut self, port: u8, mut route: u32) -> Option<&mut FakeDevice> {
+        let mut device = self.ports.get_mut(port as usize - 1)?.as_mut()?;
+        while route & 0xf != 0 {
+            let hub = device.hub.as_mut()?;
+            device = hub.ports.get_mut((route & 0xf) as usize - 1)?.as_mut()?;
+            route >>= 4;
+        }
+        Some(device)
+    }
+
+    fn is_connected(&self, port: u8) -> bool {
+        self.load(Self::portsc(port), 4) & PORTSC_CCS != 0
+    }
+
+    /// ルートハブのポートからデバイスを外す。デバイスは`FakeXhc::device`で読めるように残しておく。
+    fn detach(&mut self, port: u8) {
+        let speed = self.load(Self::portsc(port), 4) & (0xf << PORTSC_SPEED_SHIFT);
+        self.store(Self::portsc(port), 4, PORTSC_PP | PORTSC_CSC | speed);
+        self.post_port_status_change(port);
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) {
+        match offset {
+            USBCMD => {
+                if value & USBCMD_HCRST != 0 {
+                    self.command_ring = None;
+                    self.event_ring = None;
+                }
+                self.store(offset, size, value & !USBCMD_HCRST);
+                let status = self.load(USBSTS, 4);
+                let status = if value & USBCMD_RUN_STOP != 0 {
+                    status & !USBSTS_HCH
+                } else {
+                    status | USBSTS_HCH
+                };
+                self.store(USBSTS, 4, status);
+            }
+            USBSTS => {
+                let status = self.load(USBSTS, 4) & !(value & USBSTS_RW1C);
+                self.store(offset, size, status);
+            }
+            CRCR => {
+                self.command_ring = None;
+                self.store(offset, size, value);
+            }
+            ERSTBA => {
+                self.event_ring = None;
+                self.store(offset, size, value);
+            }
+            IMAN => {
+                let iman = (self.load(IMAN, 4) & IMAN_IP & !value) | (value & !IMAN_IP);
+                self.store(offset, size, iman);
+            }
+            DB_OFFSET => self.run_commands(),
+            o if (DB_OFFSET..DB_OFFSET + 4 * (MAX_SLOTS as usize + 1)).contains(&o) => {
+                let slot_id = ((o - DB_OFFSET) / 4) as SlotId;
+                self.run_endpoint(slot_id, value as u8);
+            }
+            o if (PORTSC..Self::portsc(MAX_PORTS + 1)).contains(&o)
+                && (o - PORTSC).is_multiple_of(0x10) =>
+            {
+                self.write_portsc((((o - PORTSC) / 0x10) + 1) as u8, value);
+            }
+            _ => self.store(offset, size, value),
+        }
+    }
+
+    /// QEMUと同じく、PRを書いたときはリセットだけしてRW1Cのビットは消さない
+    fn write_portsc(&mut self, port: u8, value: u64) {
+        let offset = Self::portsc(port);
+        let portsc = self.load(offset, 4);
+        if value & PORTSC_PR != 0 {
+            if portsc & PORTSC_CCS != 0 {
+                self.store(offset, 4, portsc | PORTSC_PED | PORTSC_PRC);
+                self.post_port_status_change(port);
+            }
+            return;
+        }
+        self.store(offset, 4, portsc & !(value & PORTSC_RW1C));
+    }
+
+    fn post_port_status_change(&mut self, port: u8) {
+        let trb = TrbRaw::zeroed()
+            .with_parameter0((port as u32) << 24)
+            .with_status((CompletionCode::Success.as_u8() as u32) << 24)
+            .with_remain_trb_type(PortStatusChangeEvent::TYPE);
+        self.post_event(trb);
+    }
+
+    fn post_transfer_event(
+        &mut self,
+        slot_id: SlotId,
+        dci: u8,
+        ptr: u64,
+        residual: u32,
+        code: CompletionCode,
+    ) {
+        let trb = TrbRaw::zeroed()
+            .with_parameter0(ptr as u32)
+            .with_parameter1((ptr >> 32) as u32)
+            .with_status(residual | (code.as_u8() as u32) << 24)
+            .with_control(((slot_id as u16) << 8) | dci as u16)
+            .with_remain_trb_type(TransferEvent::TYPE);
+        self.post_event(trb);
+    }
+
+    fn post_event(&mut self, trb: TrbRaw) {
+        let mut producer = match self.event_ring {
+            Some(producer) => producer,
+            None => {
+                let table = self.load(ERSTBA, 8) & !0x3f;
+                let entry: EventRingSegmentTableEntry = unsafe { dma_read(table) };
+                EventProducer {
+                    base: entry.get_ring_segment_base_address_data() << 6,
+                    size: entry.get_ring_segment_size_data() as usize,
+                    idx: 0,
+                    cycle_bit: true,
+                }
+            }
+        };
+
+        let dequeue = ((self.load(ERDP, 8) & !0xf) - producer.base) as usize / 16;
+        assert_ne!(
+            (producer.idx + 1) % producer.size,
+            dequeue,
+            "event ring full"
+        );
+
+        let addr = producer.base + 16 * producer.idx as u64;
+        unsafe { dma_write(addr, trb.with_remain_cycle_bit(producer.cycle_bit)) };
+        producer.idx += 1;
+        if producer.idx == producer.size {
+            producer.idx = 0;
+            producer.cycle_bit = !producer.cycle_bit;
+        }
+        self.event_ring = Some(producer);
+
+        let iman = self.load(IMAN, 4);
+        self.store(IMAN, 4, iman | IMAN_IP);
+        let status = self.load(USBSTS, 4);
+        self.store(USBSTS, 4, status | USBSTS_EINT);
+    }
+
+    fn run_commands(&mut self) {
+        if self.commands_held {
+            return;
+        }
+        let mut cursor = self.command_ring.unwrap_or_else(|| {
+            let crcr = self.load(CRCR, 8);
+            Cursor {
+                ptr: crcr & !0x3f,
+                cycle_bit: crcr & 1 != 0,
+            }
+        });
+        while let Some((ptr, trb)) = cursor.peek() {
+            cursor.advance();
+            self.command_ring = Some(cursor);
+            let failure = self
+                .command_failures
+                .iter()
+                .position(|f| f.0 == trb.get_remain_trb_type());
+            let (code, slot_id) = match failure {
+                // コマンドに入っているスロットIDをそのまま返す
+                Some(idx) => (
+                    self.command_failures.remove(idx).unwrap().1,
+                    (trb.get_control() >> 8) as SlotId,
+                ),
+                None => self.execute(Trb::from(trb)),
+            };
+            let event = CommandCompletionEvent::zeroed()
+                .with_params_ptr(ptr >> 4)
+                .with_status_completion_code(code)
+                .with_control_slot_id(slot_id);
+            self.post_event(TrbRaw::from(event).with_remain_trb_type(CommandCompletionEvent::TYPE));
+        }
+        self.command_ring = Some(cursor);
+    }
+
+    fn device_context(&self, slot_id: SlotId) -> *mut DeviceContext {
+        let dcbaa = self.load(DCBAAP, 8) & !0x3f;
+        unsafe { dma_read::<u64>(dcbaa + 8 * slot_id as u64) as *mut DeviceContext }
+    }
+
+    fn execute(&mut self, command: Trb) -> (CompletionCode, SlotId) {
+        match command {
+            Trb::EnableSlotCommand(_) => {
+                match (1..=MAX_SLOTS).find(|&id| self.slots[id as usize].is_none()) {
+                    Some(slot_id) => {
+                        self.slots[slot_id as usize] = Some(Slot::default());
+                        (CompletionCode::Success, slot_id)
+                    }
+                    None => (CompletionCode::NoSlotsAvailableError, 0),
+                }
+            }
+            Trb::DisableSlotCommand(c) => {
+                let slot_id = c.get_control_slot_id();
+                self.slots[slot_id as usize] = None;
+                (CompletionCode::Success, slot_id)
+            }
+            Trb::AddressDeviceCommand(c) => {
+                let slot_id = c.get_control_slot_id();
+                let input: InputContext =
+                    unsafe { dma_read(c.get_params_input_context_ptr() << 4) };
+                let output = unsafe { &mut *self.device_context(slot_id) };
+                output.slot_context = input.slot;
+                output.slot_context.set_data_3_usb_device_address(slot_id);
+                output.slot_context.set_data_3_slot_state(2);
+
+                let Some(slot) = self.slots[slot_id as usize].as_mut() else {
+                    return (CompletionCode::SlotNotEnabledError, slot_id);
+                };
+                slot.port = input.slot.get_data_1_root_hub_port_number();
+                slot.route = input.slot.get_data_0_route_string();
+                Self::enable_endpoint(slot, output, &input, 1);
+                (CompletionCode::Success, slot_id)
+            }
+            Trb::ConfigureEndpointCommand(c) => {
+                let slot_id = c.get_control_slot_id();
+                let ptr = ((c.get_input_context_ptr_hi() as u64) << 32)
+                    | (c.get_parameter0_input_context_ptr_lo() as u64) << 4;
+                let input: InputContext = unsafe { dma_read(ptr) };
+                let output = unsafe { &mut *self.device_context(slot_id) };
+                let Some(slot) = self.slots[slot_id as usize].as_mut() else {
+                    return (CompletionCode::SlotNotEnabledError, slot_id);
+                };
+                let control = input.input_control_context;
+                for dci in 2..MAX_ENDPOINTS as u8 {
+                    if control.get_drop_context_flags() & (1 << dci) != 0 {
+                        slot.endpoints[dci as usize] = None;
+                    }
+                    if control.get_add_context_flags() & (1 << dci) != 0 {
+                        Self::enable_endpoint(slot, output, &input, dci);
+                    }
+                }
+                output.slot_context.set_data_3_slot_state(3);
+                (CompletionCode::Success, slot_id)
+            }
+            Trb::ResetEndpointCommand(c) => {
+                let slot_id = c.get_control_slot_id();
+                let code = match self.endpoint_mut(slot_id, c.get_control_endpoint_id()) {
+                    Some(ep) if ep.halted => {
+                        ep.halted = false;
+                        CompletionCode::Success
+                    }
+                    _ => CompletionCode::ContextStateError,
+                };
+                (code, slot_id)
+            }
+            Trb::SetTrDequeuePointerCommand(c) => {
+                let slot_id = c.get_control_slot_id();
+                let code = match self.endpoint_mut(slot_id, c.get_control_endpoint_id()) {
+                    Some(ep) => {
+                        ep.cursor = Cursor {
+                            ptr: c.dequeue_pointer(),
+                            cycle_bit: c.get_parameter0_dequeue_cycle_state(),
+                        };
+                        ep.setup = None;
+                        CompletionCode::Success
+                    }
+                    None => CompletionCode::EndpointNotEnabledError,
+                };
+                (code, slot_id)
+            }
+            _ => (CompletionCode::Success, 0),
+        }
+    }
+
+    /// Input ContextのエンドポイントコンテキストをDevice Contextに写して、転送リングを読み始める
+    fn enable_endpoint(slot: &mut Slot, output: &mut DeviceContext, input: &InputContext, dci: u8) {
+        let mut cx = input.ep_contexts[dci as usize - 1];
+        // Running
+        cx.set_data_0_ep_state(1);
+        output.device_contexts[dci as usize - 1] = cx;
+        slot.endpoints[dci as usize] = Some(Endpoint {
+            cursor: Cursor {
+                ptr: ((cx.get_data_3_tr_dequeue_pointer_hi() as u64) << 32)
+                    | (cx.get_data_2_tr_dequeue_pointer_lo() as u64) << 4,
+                cycle_bit: cx.get_data_2_dequeue_cycle_state(),
+            },
+            halted: false,
+            setup: None,
+        });
+    }
+
+    fn endpoint_mut(&mut self, slot_id: SlotId, dci: u8) -> Option<&mut Endpoint> {
+        self.slots
+            .get_mut(slot_id as usize)?
+            .as_mut()?
+            .endpoints
+            .get_mut(dci as usize)?
+            .as_mut()
+    }
+
+    /// 転送リングに積まれたTDを、データが無いINエンドポイントで止まるか、リングが空になるまで実行する
+    fn run_endpoint(&mut self, slot_id: SlotId, dci: u8) {
+        loop {
+            let Some(Slot { port, route, .. }) = self.slots[slot_id as usize] else {
+                return;
+            };
+            // 外れたデバイスは何も返さない
+            if !self.is_connected(port) {
+                return;
+            }
+            let Some(mut ep) = self.endpoint_mut(slot_id, dci).copied() else {
+                return;
+            };
+            if ep.halted {
+                return;
+            }
+            let Some((ptr, trb)) = ep.cursor.peek() else {
+                return;
+            };
+            let frame = ((self.load(MFINDEX, 4) >> 3) & FRAME_ID_MASK) as u16;
+            let Some(device) = self.device_mut(port, route) else {
+                return;
+            };
+
+            // イベントを発生させるTRBのアドレスと残りのバイト数
+            let mut event = None;
+            // Normal TRBはTDの分だけ読み進める
+            let mut consumed = false;
+            // 予定のフレームを過ぎていたIsoch TD
+            let mut missed = false;
+            // Setup Stageを受け取ったところで抜かれた
+            let mut detached = false;
+            match Trb::from(trb) {
+                Trb::SetupStage(s) => {
+                    let request = Request {
+                        request_type: s.get_parameter0_bm_request_type(),
+                        request: s.get_parameter0_b_request(),
+                        value: s.get_parameter0_w_value(),
+                        index: s.get_parameter1_w_index(),
+                        length: s.get_parameter1_w_length(),
+                    };
+                    if device.detach_on == Some(request.request) {
+                        device.detach_on = None;
+                        detached = true;
+                    }
+                    ep.setup = Some((request, false));
+                }
+                Trb::DataStage(d) => {
+                    let (request, _) = ep.setup.unwrap();
+                    let buf = ((d.get_buf_ptr_hi() as u64) << 32) | d.get_buf_ptr_lo() as u64;
+                    let len = d.get_status_trb_transfer_length() as usize;
+                    let Some(data) = device.control(request) else {
+                        return self.stall(slot_id, dci, ptr, ep);
+                    };
+                    // OUTのData Stageの中身は見ない
+                    let n = if d.get_control_dir() {
+                        data.len().min(len)
+                    } else {
+                        len
+                    };
+                    let data = if d.get_control_dir() {
+                        data
+                    } else {
+                        Vec::new()
+                    };
+                    unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), buf as *mut u8, n) };
+                    ep.setup = Some((request, true));
+                    if d.get_remain_interrupt_on_completion() {
+                        event = Some((ptr, (len - n) as u32));
+                    }
+                }
+                Trb::StatusStage(s) => {
+                    let (request, done) = ep.setup.take().unwrap();
+                    if !done && device.control(request).is_none() {
+                        return self.stall(slot_id, dci, ptr, ep);
+                    }
+                    if s.get_remain_interrupt_on_completion() {
+                        event = Some((ptr, 0));
+                    }
+                }
+                Trb::Isoch(isoch) if !isoch.get_control_start_isoch_asap() => {
+                    let Some(td) = Self::normal_td(ep.cursor) else {
+                        return;
+                    };
+                    let target = isoch.get_control_frame_id();
+                    let ahead = target.wrapping_sub(frame) & FRAME_ID_MASK as u16;
+                    if ahead != 0 && ahead < FRAME_ID_HALF {
+                        // まだ予定のフレームになっていない
+                        return;
+                    }
+                    if ahead != 0 {
+                        // 転送せずに読み飛ばす
+                        for _ in 0..td.len() {
+                            ep.cursor.peek();
+                            ep.cursor.advance();
+                        }
+                        event = Some((td[td.len() - 1].0, 0));
+                        missed = true;
+                        consumed = true;
+                    } else {
+                        if !Self::run_td(device, &mut ep, dci, &td, &mut event) {
+                            return;
+                        }
+                        device.isoch_frames.push(frame);
+                        consumed = true;
+                    }
+                }
+                Trb::Isoch(_) => {
+                    let Some(td) = Self::normal_td(ep.cursor) else {
+                        return;
+                    };
+                    if !Self::run_td(device, &mut ep, dci, &td, &mut event) {
+                        return;
+                    }
+                    device.isoch_frames.push(frame);
+                    consumed = true;
+                }
+                Trb::Normal(_) => {
+                    let Some(td) = Self::normal_td(ep.cursor) else {
+                        return;
+                    };
+                    if device.take_stall(dci) {
+                        return self.stall(slot_id, dci, ptr, ep);
+                    }
+                    if !Self::run_td(device, &mut ep, dci, &td, &mut event) {
+                        return;
+                    }
+                    consumed = true;
+                }
+                _ => {}
+            }
+
+            if detached {
+                return self.detach(port);
+            }
+            if !consumed {
+                ep.cursor.advance();
+            }
+            *self.endpoint_mut(slot_id, dci).unwrap() = ep;
+            if let Some((ptr, residual)) = event {
+                let code = if missed {
+                    CompletionCode::MissedServiceError
+                } else if residual == 0 {
+                    CompletionCode::Success
+                } else {
+                    CompletionCode::ShortPacket
+                };
+                self.post_transfer_event(slot_id, dci, ptr, residual, code);
+            }
+        }
+    }
+
+    /// Normal TRBやIsoch TRBで始まるTDを実行する。INはデバイスに届いているデータを書き込み、
+    /// OUTはバッファの中身をデバイスに渡す。データがまだ届いていなければ何もせずに`false`を返すので、
+    /// 呼び出し側はTDを残したまま待つ。
+    fn run_td(
+        device: &mut FakeDevice,
+        ep: &mut Endpoint,
+        dci: u8,
+        td: &[(u64, Normal)],
+        event: &mut Option<(u64, u32)>,
+    ) -> bool {
+        let is_in = dci % 2 == 1;
+        let data = if is_in {
+            let Some(data) = device.take_input(dci) else {
+                return false;
+            };
+            data
+        } else {
+            Vec::new()
+        };
+
+        let mut offset = 0;
+        let mut out = Vec::new();
+        for (i, &(trb_ptr, n)) in td.iter().enumerate() {
+            let buf = n.data_buffer_pointer();
+            let len = n.get_status_trb_transfer_length() as usize;
+            let copied = if is_in {
+                let copied = data.len().saturating_sub(offset).min(len);
+                unsafe {
+                    core::ptr::copy_nonoverlapping(data[offset..].as_ptr(), buf as *mut u8, copied)
+                };
+                copied
+            } else {
+                out.extend_from_slice(unsafe {
+                    core::slice::from_raw_parts(buf as *const u8, len)
+                });
+                len
+            };
+            offset += copied;
+            ep.cursor.peek();
+            ep.cursor.advance();
+
+            let is_last = i == td.len() - 1;
+            let is_short = copied < len;
+            if is_short && n.get_remain_interrupt_on_short_packet()
+                || is_last && n.get_remain_interrupt_on_completion()
+            {
+                let residual = (len - copied) as u32;
+                *event = Some((trb_ptr, residual));
+            }
+            if is_short {
+                // 残りのTRBは読み飛ばす
+                for _ in i + 1..td.len() {
+                    ep.cursor.peek();
+                    ep.cursor.advance();
+                }
+                break;
+            }
+        }
+        if is_in && offset < data.len() {
+            // TDに入りきらなかったデータは次のTDで返す
+            device
+                .inputs
+                .push_front((dci, Some(data[offset..].to_vec())));
+        } else if !is_in {
+            device.receive(dci, out);
+        }
+        true
+    }
+
+    /// Chainでつながった一連のNormal TRB。最後まで積まれていなければ`None`。
+    /// 先頭のIsoch TRBはフレームの指定のほかはNormal TRBと同じなので、Normal TRBにして返す。
+    fn normal_td(mut cursor: Cursor) -> Option<Vec<(u64, Normal)>> {
+        let mut td = Vec::new();
+        loop {
+            let (ptr, trb) = cursor.peek()?;
+            let n = match Trb::from(trb) {
+                Trb::Normal(n) => n,
+                Trb::Isoch(_) if td.is_empty() => {
+                    Normal::try_from(trb.with_remain_trb_type(Normal::TYPE)).ok()?
+                }
+                _ => return None,
+            };
+            td.push((ptr, n));
+            if !n.get_remain_chain_bit() {
+                return Some(td);
+            }
+            cursor.advance();
+        }
+    }
+
+    /// エンドポイントを止めてStall Errorを返す。再開はReset Endpointコマンドで。
+    fn stall(&mut self, slot_id: SlotId, dci: u8, ptr: u64, mut ep: Endpoint) {
+        ep.halted = true;
+        ep.setup = None;
+        *self.endpoint_mut(slot_id, dci).unwrap() = ep;
+        self.post_transfer_event(slot_id, dci, ptr, 0, CompletionCode::StallError);
+    }
+}
@@ -0,0 +1,95 @@
+//! レジスタの読み書き
+//!
+//! `RegisterMap`はここを通してMMIOを読み書きする。実機ではvolatileアクセスそのもので、
+//! テストではスレッドごとに`Mmio`を差し込んで偽のxHCに書き込みへ反応させる。
+
+#[cfg(test)]
+extern crate std;
+
+#[cfg(test)]
+use std::{boxed::Box, cell::RefCell};
+
+/// 1回のアクセスで読み書きする値
+pub trait Word: Copy {
+    fn to_u64(self) -> u64;
+    fn from_u64(v: u64) -> Self;
+}
+
+macro_rules! impl_word {
+    ($($type:ty),*) => {
+        $(
+            impl Word for $type {
+                fn to_u64(self) -> u64 {
+                    self as u64
+                }
+
+                fn from_u64(v: u64) -> Self {
+                    v as $type
+                }
+            }
+        )*
+    };
+}
+
+impl_word!(u8, u16, u32, u64);
+
+/// MMIO領域へのアクセスを受け取るもの。`size`はバイト数。
+pub trait Mmio {
+    fn read(&mut self, addr: usize, size: usize) -> u64;
+    fn write(&mut self, addr: usize, size: usize, value: u64);
+}
+
+#[cfg(test)]
+std::thread_local! {
+    static HANDLER: RefCell<Option<Box<dyn Mmio>>> = const { RefCell::new(None) };
+}
+
+/// このスレッドのレジスタアクセスを`handler`に回す。`None`で元に戻す。
+#[cfg(test)]
+pub fn set_handler(handler: Option<Box<dyn Mmio>>) {
+    HANDLER.with(|h| *h.borrow_mut() = handler);
+}
+
+/// # Safety
+/// `ptr`はレジスタを指していなければならない
+#[cfg(not(test))]
+pub unsafe fn read<T: Word>(ptr: *const T) -> T {
+    unsafe { ptr.read_volatile() }
+}
+
+/// # Safety
+/// `ptr`はレジスタを指していなければならない
+#[cfg(not(test))]
+pub unsafe fn write<T: Word>(ptr: *mut T, value: T) {
+    unsafe { ptr.write_volatile(value) }
+}
+
+/// # Safety
+/// `ptr`はレジスタを指していなければならない
+#[cfg(test)]
+pub unsafe fn read<T: Word>(ptr: *const T) -> T {
+    let size = core::mem::size_of::<T>();
+    HANDLER
+        .with(|h| {
+            h.borrow_mut()
+                .as_mut()
+                .map(|h| T::from_u64(h.read(ptr as usize, size)))
+        })
+        .unwrap_or_else(|| unsafe { ptr.read_volatile() })
+}
+
+/// # Safety
+/// `ptr`はレジスタを指していなければならない
+#[cfg(test)]
+pub unsafe fn write<T: Word>(ptr: *mut T, value: T) {
+    let size = core::mem::size_of::<T>();
+    let handled = HANDLER.with(|h| {
+        h.borrow_mut()
+            .as_mut()
+            .map(|h| h.write(ptr as usize, size, value.to_u64()))
+            .is_some()
+    });
+    if !handled {
+        unsafe { ptr.write_volatile(value) }
+    }
+}
@@ -4,7 +4,12 @@ pub mod doorbell;
 pub mod driver;
 pub mod endian;
 pub mod error;
+#[cfg(test)]
+pub(crate) mod fake;
+pub mod mmio;
 pub mod port;
+pub mod recovery;
 pub mod register_map;
 pub mod ring;
+pub mod route;
 pub mod trb;
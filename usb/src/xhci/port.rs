@@ -67,6 +67,16 @@ impl<'a, 'b, 'c> PortWrapper<'a, 'b, 'c> {
             .get_data_port_reset_change()
     }
 
+    pub fn clear_connect_status_change(&mut self) {
+        let v = self
+            .set
+            .port_status_and_control()
+            .read()
+            .clear_rw1s()
+            .with_data_connect_status_change(true);
+        self.set.port_status_and_control_mut().write(v);
+    }
+
     pub fn clear_port_reset_change(&mut self) {
         let v = self
             .set
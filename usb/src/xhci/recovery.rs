@@ -0,0 +1,216 @@
+//! 停止したエンドポイントの復旧
+//!
+//! Stall Errorなどでエンドポイントが停止(Halted)したら、Reset Endpointコマンドで状態を戻し、
+//! Set TR Dequeue Pointerコマンドで転送リングの読み出し位置を次にTRBを積む場所まで進める。
+//! 止まったときに積まれていたTRBは実行されない。
+
+use super::{
+    device::SlotId,
+    error::{Error, Result},
+    ring::TCRing,
+    trb::{CommandCompletionEvent, ResetEndpointCommand, SetTrDequeuePointerCommand, Trb},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    ResettingEndpoint,
+    SettingDequeuePointer,
+    Done,
+}
+
+/// 1つのエンドポイントの復旧の進み具合
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointRecovery {
+    slot_id: SlotId,
+    dci: u8,
+    phase: Phase,
+    /// 完了を待っているコマンドのアドレスとTRB
+    command: Option<(u64, Trb)>,
+}
+
+impl EndpointRecovery {
+    pub fn new(slot_id: SlotId, dci: u8) -> Self {
+        Self {
+            slot_id,
+            dci,
+            phase: Phase::ResettingEndpoint,
+            command: None,
+        }
+    }
+
+    pub fn slot_id(&self) -> SlotId {
+        self.slot_id
+    }
+
+    pub fn dci(&self) -> u8 {
+        self.dci
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.phase == Phase::Done
+    }
+
+    /// 次のコマンドを`issue`でコマンドリングに積む。`issue`は積んだTRBのアドレスを返す。
+    /// `transfer_ring`は止まったエンドポイントの転送リング。
+    pub fn issue_next<const N: usize>(
+        &mut self,
+        transfer_ring: &TCRing<N>,
+        issue: impl FnOnce(Trb) -> u64,
+    ) {
+        let trb = match self.phase {
+            Phase::ResettingEndpoint => {
+                Trb::ResetEndpointCommand(ResetEndpointCommand::new(self.slot_id, self.dci))
+            }
+            Phase::SettingDequeuePointer => {
+                Trb::SetTrDequeuePointerCommand(SetTrDequeuePointerCommand::new(
+                    self.slot_id,
+                    self.dci,
+                    transfer_ring.enqueue_pointer(),
+                    transfer_ring.cycle_bit(),
+                ))
+            }
+            Phase::Done => return,
+        };
+        self.command = Some((issue(trb), trb));
+    }
+
+    pub fn is_completed_by(&self, event: &CommandCompletionEvent) -> bool {
+        self.command
+            .is_some_and(|(ptr, _)| ptr == event.command_trb_pointer())
+    }
+
+    /// 待っていたコマンドの完了で次に進む。失敗していたらエラーにして、それ以上は進めない。
+    pub fn complete(&mut self, event: CommandCompletionEvent) -> Result<()> {
+        let Some((_, issuer)) = self.command.take() else {
+            return Ok(());
+        };
+        if !event.is_success() {
+            return Err(Error::command_not_success(event.completion_code(), issuer));
+        }
+
+        self.phase = match self.phase {
+            Phase::ResettingEndpoint => Phase::SettingDequeuePointer,
+            Phase::SettingDequeuePointer | Phase::Done => Phase::Done,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::Zeroed;
+
+    use super::*;
+    use crate::xhci::trb::{CompletionCode, Normal, TransferEvent, TrbRaw};
+
+    const SLOT_ID: SlotId = 3;
+    const DCI: u8 = 3;
+
+    /// 積まれたコマンドをリングから読んで、台本どおりの完了コードを返すだけのxHC
+    struct FakeController<'s> {
+        command_ring: TCRing<8>,
+        transfer_ring: TCRing<8>,
+        script: &'s [CompletionCode],
+        issued: [Option<Trb>; 4],
+        count: usize,
+    }
+
+    impl<'s> FakeController<'s> {
+        fn new(script: &'s [CompletionCode]) -> Self {
+            Self {
+                command_ring: TCRing::new(),
+                transfer_ring: TCRing::new(),
+                script,
+                issued: [None; 4],
+                count: 0,
+            }
+        }
+
+        /// 転送リングの先頭のTDがSTALLしたことにする
+        fn stall(&mut self) -> TransferEvent {
+            let ptr = self.transfer_ring.push(Normal::default());
+            // 止まったTDの後ろに積まれていて、実行されないもの
+            self.transfer_ring.push(Normal::default());
+            let raw = TrbRaw::zeroed()
+                .with_parameter0(ptr as u32)
+                .with_parameter1((ptr >> 32) as u32)
+                .with_status((CompletionCode::StallError.as_u8() as u32) << 24)
+                .with_control(((SLOT_ID as u16) << 8) | DCI as u16);
+            TransferEvent::try_from(raw.with_remain_trb_type(TransferEvent::TYPE)).unwrap()
+        }
+
+        /// コマンドリングから読んだコマンドを記録して完了イベントを返す
+        fn run(&mut self, ptr: u64) -> CommandCompletionEvent {
+            let trb = Trb::from(unsafe { *(ptr as *const TrbRaw) });
+            self.issued[self.count] = Some(trb);
+            let code = self.script[self.count];
+            self.count += 1;
+
+            CommandCompletionEvent::zeroed()
+                .with_params_ptr(ptr >> 4)
+                .with_status_completion_code(code)
+                .with_control_slot_id(SLOT_ID)
+        }
+
+        fn recover(&mut self, recovery: &mut EndpointRecovery) -> Result<()> {
+            while !recovery.is_done() {
+                let mut issued = 0;
+                let command_ring = &mut self.command_ring;
+                recovery.issue_next(&self.transfer_ring, |trb| {
+                    issued = command_ring.push(trb);
+                    issued
+                });
+                let event = self.run(issued);
+                assert!(recovery.is_completed_by(&event));
+                recovery.complete(event)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_recover_stalled_endpoint() {
+        let mut xhc = FakeController::new(&[CompletionCode::Success, CompletionCode::Success]);
+        let event = xhc.stall();
+        assert!(event.completion_code().halts_endpoint());
+
+        let mut recovery =
+            EndpointRecovery::new(event.get_control_slot_id(), event.get_control_endpoint_id());
+        xhc.recover(&mut recovery).unwrap();
+
+        let Some(Trb::ResetEndpointCommand(reset)) = xhc.issued[0] else {
+            panic!("{:?}", xhc.issued[0]);
+        };
+        assert_eq!(reset.get_control_slot_id(), SLOT_ID);
+        assert_eq!(reset.get_control_endpoint_id(), DCI);
+
+        // 止まったTDも後ろのTDも読み飛ばして、次に積む場所から再開する
+        let Some(Trb::SetTrDequeuePointerCommand(set)) = xhc.issued[1] else {
+            panic!("{:?}", xhc.issued[1]);
+        };
+        assert_eq!(set.get_control_slot_id(), SLOT_ID);
+        assert_eq!(set.get_control_endpoint_id(), DCI);
+        assert_eq!(set.dequeue_pointer(), xhc.transfer_ring.enqueue_pointer());
+        assert_eq!(
+            set.get_parameter0_dequeue_cycle_state(),
+            xhc.transfer_ring.cycle_bit()
+        );
+        assert_eq!(xhc.issued[2], None);
+    }
+
+    #[test]
+    fn test_reset_endpoint_failed() {
+        let mut xhc = FakeController::new(&[CompletionCode::ContextStateError]);
+        let event = xhc.stall();
+
+        let mut recovery =
+            EndpointRecovery::new(event.get_control_slot_id(), event.get_control_endpoint_id());
+        let err = xhc.recover(&mut recovery).unwrap_err();
+        assert_eq!(
+            err.completion_code(),
+            Some(CompletionCode::ContextStateError)
+        );
+        assert!(!recovery.is_done());
+        assert_eq!(xhc.count, 1);
+    }
+}
@@ -2,6 +2,7 @@ use super::{
     device::SlotId,
     doorbell::{DCDoorbell, HCDoorbell},
     endian::{Endian, EndianInto},
+    mmio::{self, Word},
 };
 use common::Zeroed;
 use core::{
@@ -21,7 +22,7 @@ mod sealed {
 
     pub trait RawPtrBase<T>: Copy {
         unsafe fn add(self, count: usize) -> Self;
-        unsafe fn read_volatile(self) -> T;
+        fn as_const(self) -> *const T;
     }
 
     impl<T> RawPtrBase<T> for *const T {
@@ -29,8 +30,8 @@ mod sealed {
             self.add(count)
         }
 
-        unsafe fn read_volatile(self) -> T {
-            self.read_volatile()
+        fn as_const(self) -> *const T {
+            self
         }
     }
 
@@ -39,8 +40,8 @@ mod sealed {
             self.add(count)
         }
 
-        unsafe fn read_volatile(self) -> T {
-            self.read_volatile()
+        fn as_const(self) -> *const T {
+            self
         }
     }
 }
@@ -72,11 +73,11 @@ where
 impl<'a, const N: usize, U> RegisterMap<'a, N, U, ReadWrite>
 where
     U: Segment<N>,
-    <U as Segment<N>>::Element: Endian + Copy,
+    <U as Segment<N>>::Element: Endian + Word,
 {
     pub fn write(&mut self, val: U) {
         for (idx, v) in val.into_segment().into_iter().enumerate() {
-            unsafe { self.ptr.add(idx).write_volatile(v.to_le()) };
+            unsafe { mmio::write(self.ptr.add(idx), v.to_le()) };
         }
     }
 
@@ -117,14 +118,14 @@ where
 impl<'a, const N: usize, U, Mode> RegisterMap<'a, N, U, Mode>
 where
     U: Segment<N>,
-    <U as Segment<N>>::Element: Endian + Copy,
+    <U as Segment<N>>::Element: Endian + Word,
     Mode: AccessMode,
 {
     pub fn read(&self) -> U {
         let mut arr: [MaybeUninit<<U as Segment<N>>::Element>; N] =
             unsafe { MaybeUninit::uninit().assume_init() };
         for (i, arr) in arr.iter_mut().enumerate() {
-            arr.write(unsafe { self.ptr.add(i).read_volatile() });
+            arr.write(unsafe { mmio::read(self.ptr.add(i).as_const()) });
         }
 
         // could not compile
@@ -908,6 +909,20 @@ bitfield_struct! {
     }
 }
 
+bitfield_struct! {
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, IntoSegment, FromSegment)]
+    #[endian = "little"]
+    pub struct Mfindex {
+        data: u32 => {
+            #[bits(14)]
+            microframe_index: u16,
+            #[bits(18)]
+            _rsvdz: u32,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct InterrupterRegisterSet<'a> {
     interrupt_management: RegisterMap<'a, 1, Iman, ReadWrite>,
@@ -995,7 +1010,7 @@ impl<'a> InterrupterRegisterSet<'a> {
 pub const INTERRUPTER_REGISTER_SET_NUM: usize = 1;
 #[derive(Debug)]
 pub struct RuntimeRegisters<'a> {
-    _microframe_index: RegisterMap<'a, 1, RsvdZU32, ReadOnly>,
+    microframe_index: RegisterMap<'a, 1, Mfindex, ReadOnly>,
     interrupter_register_sets: [InterrupterRegisterSet<'a>; INTERRUPTER_REGISTER_SET_NUM],
 }
 
@@ -1018,13 +1033,15 @@ impl<'a> RuntimeRegisters<'a> {
         let interrupter_register_sets = unsafe { core::mem::transmute(arr) };
 
         Self {
-            _microframe_index: RegisterMap::from_raw(
-                base.add(Self::MICROFRAME_INDEX_OFFSET).cast(),
-            ),
+            microframe_index: RegisterMap::from_raw(base.add(Self::MICROFRAME_INDEX_OFFSET).cast()),
             interrupter_register_sets,
         }
     }
 
+    pub fn microframe_index(&self) -> &RegisterMap<'a, 1, Mfindex, ReadOnly> {
+        &self.microframe_index
+    }
+
     pub fn get_interrupter_register_sets(
         &self,
     ) -> &[InterrupterRegisterSet<'a>; INTERRUPTER_REGISTER_SET_NUM] {
@@ -6,6 +6,9 @@ use common::{debug, ring_buf::RingBuffer, Zeroed as _};
 use core::mem::MaybeUninit;
 use macros::bitfield_struct;
 
+/// 転送TRBのremainにあるChainビット
+const CHAIN_BIT: u16 = 1 << 4;
+
 /// Transfer or Communicate ring.
 #[repr(C, align(64))]
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -34,7 +37,8 @@ impl<const SIZE: usize> TCRing<SIZE> {
         }
     }
 
-    pub fn push<T>(&mut self, v: T)
+    /// 積んだTRBのアドレスを返す。Transfer Eventのポインタとの照合に使う。
+    pub fn push<T>(&mut self, v: T) -> u64
     where
         T: Into<TrbRaw> + Type + Copy,
     {
@@ -42,11 +46,15 @@ impl<const SIZE: usize> TCRing<SIZE> {
         let mut v: TrbRaw = v.into();
         v.set_remain_cycle_bit(self.cycle_bit);
         v.set_remain_trb_type(ty);
+        let addr = self.enqueue_pointer();
         self.ring_buf.push_overwrite(v);
 
         if self.ring_buf.tail() % SIZE == SIZE - 1 {
+            // TDの途中で折り返すときはLink TRBもChainでつなぐ
+            let chain = v.get_remain() & CHAIN_BIT != 0;
             let link = Link::new(self.ring_buf.as_ptr() as *const ())
                 .with_remain_toggle_cycle(true)
+                .with_remain_chain_bit(chain)
                 .with_remain_cycle_bit(self.cycle_bit)
                 .with_remain_interrupt_on_completion(true);
             let base = TrbRaw::from(link);
@@ -56,6 +64,13 @@ impl<const SIZE: usize> TCRing<SIZE> {
 
             debug_assert!(self.ring_buf.is_full());
         }
+
+        addr
+    }
+
+    /// 次にTRBを積むアドレス。Set TR Dequeue Pointerでここまで読み飛ばさせる。
+    pub fn enqueue_pointer(&self) -> u64 {
+        unsafe { self.ring_buf.as_ptr().add(self.ring_buf.tail() % SIZE) as u64 }
     }
 
     pub fn as_ptr(&self) -> *const MaybeUninit<TrbRaw> {
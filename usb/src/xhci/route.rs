@@ -0,0 +1,140 @@
+//! ハブの先にあるデバイスの位置
+//!
+//! Slot Contextに入れるRoute String、ルートハブのポート番号、速度、TTの情報をまとめたもの。
+
+use super::{context::SlotContext, device::SlotId};
+
+/// Route Stringに入るハブの段数
+pub const MAX_TIERS: u8 = 5;
+
+/// PORTSCやSlot Contextの速度(デフォルトのPSI)
+pub mod speed {
+    pub const FULL: u8 = 1;
+    pub const LOW: u8 = 2;
+    pub const HIGH: u8 = 3;
+    pub const SUPER: u8 = 4;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Route {
+    pub root_port: u8,
+    /// 4ビットごとに、ルートに近いハブから順に下流ポート番号が入る
+    pub route_string: u32,
+    /// ルートハブのポートにつながっていれば0
+    pub tier: u8,
+    pub speed: u8,
+    /// LS/FSデバイスが使うHSハブのスロットID。使わなければ0。
+    pub tt_hub_slot_id: SlotId,
+    pub tt_port_number: u8,
+}
+
+impl Route {
+    pub fn root(root_port: u8, speed: u8) -> Self {
+        Self {
+            root_port,
+            speed,
+            ..Default::default()
+        }
+    }
+
+    /// アドレスを割り当て済みのデバイスの位置
+    pub fn from_slot_context(slot: &SlotContext) -> Self {
+        let route_string = slot.get_data_0_route_string();
+        Self {
+            root_port: slot.get_data_1_root_hub_port_number(),
+            route_string,
+            tier: (0..MAX_TIERS)
+                .take_while(|i| (route_string >> (i * 4)) & 0xf != 0)
+                .count() as u8,
+            speed: slot.get_data_0_speed(),
+            tt_hub_slot_id: slot.get_data_2_tt_hub_slot_id(),
+            tt_port_number: slot.get_data_2_tt_port_number(),
+        }
+    }
+
+    /// このハブ(スロット`hub_slot_id`)の`port`番ポートにつながった`speed`のデバイスの位置。
+    /// 段数が多すぎれば`None`。
+    pub fn child(&self, hub_slot_id: SlotId, port: u8, speed: u8) -> Option<Self> {
+        if self.tier >= MAX_TIERS {
+            return None;
+        }
+
+        let is_slow = matches!(speed, speed::FULL | speed::LOW);
+        let (tt_hub_slot_id, tt_port_number) = if !is_slow {
+            (0, 0)
+        } else if self.speed == speed::HIGH {
+            // HSハブのTTを使う
+            (hub_slot_id, port)
+        } else {
+            // FSハブの先は上流のTTをそのまま使う
+            (self.tt_hub_slot_id, self.tt_port_number)
+        };
+
+        Some(Self {
+            root_port: self.root_port,
+            route_string: self.route_string | ((port.min(15) as u32) << (self.tier * 4)),
+            tier: self.tier + 1,
+            speed,
+            tt_hub_slot_id,
+            tt_port_number,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_string() {
+        let root = Route::root(3, speed::HIGH);
+        let hub = root.child(1, 2, speed::HIGH).unwrap();
+        assert_eq!((hub.root_port, hub.route_string, hub.tier), (3, 0x2, 1));
+
+        let device = hub.child(2, 4, speed::HIGH).unwrap();
+        assert_eq!((device.route_string, device.tier), (0x42, 2));
+        assert_eq!((device.tt_hub_slot_id, device.tt_port_number), (0, 0));
+
+        let mut deep = root;
+        for _ in 0..MAX_TIERS {
+            deep = deep.child(1, 1, speed::HIGH).unwrap();
+        }
+        assert_eq!(deep.route_string, 0x11111);
+        assert_eq!(deep.child(1, 1, speed::HIGH), None);
+    }
+
+    #[test]
+    fn test_transaction_translator() {
+        // HSハブの先のFSハブの先のLSデバイス
+        let hs_hub = Route::root(1, speed::HIGH)
+            .child(1, 1, speed::HIGH)
+            .unwrap();
+        let fs_hub = hs_hub.child(2, 3, speed::FULL).unwrap();
+        assert_eq!((fs_hub.tt_hub_slot_id, fs_hub.tt_port_number), (2, 3));
+
+        let device = fs_hub.child(5, 1, speed::LOW).unwrap();
+        assert_eq!((device.tt_hub_slot_id, device.tt_port_number), (2, 3));
+
+        // ルートのFSハブの先はTTを使わない
+        let device = Route::root(1, speed::FULL)
+            .child(1, 1, speed::FULL)
+            .unwrap();
+        assert_eq!((device.tt_hub_slot_id, device.tt_port_number), (0, 0));
+    }
+
+    #[test]
+    fn test_from_slot_context() {
+        let route = Route::root(2, speed::HIGH)
+            .child(1, 3, speed::HIGH)
+            .and_then(|r| r.child(4, 1, speed::FULL))
+            .unwrap();
+
+        let slot = SlotContext::default()
+            .with_data_0_route_string(route.route_string)
+            .with_data_0_speed(route.speed)
+            .with_data_1_root_hub_port_number(route.root_port)
+            .with_data_2_tt_hub_slot_id(route.tt_hub_slot_id)
+            .with_data_2_tt_port_number(route.tt_port_number);
+        assert_eq!(Route::from_slot_context(&slot), route);
+    }
+}
@@ -134,9 +134,25 @@ impl From<ConfigureEndpointCommand> for TrbRaw {
     }
 }
 
-impl From<Trb> for TrbRaw {
-    fn from(_value: Trb) -> Self {
-        todo!()
+impl From<CommandCompletionEvent> for TrbRaw {
+    fn from(value: CommandCompletionEvent) -> Self {
+        Self::zeroed()
+            .with_parameter0(value.params as u32)
+            .with_parameter1((value.params >> 32) as u32)
+            .with_status(value.status)
+            .with_remain(value.remain)
+            .with_control(value.control)
+    }
+}
+
+impl From<PortStatusChangeEvent> for TrbRaw {
+    fn from(value: PortStatusChangeEvent) -> Self {
+        Self::zeroed()
+            .with_parameter0(value.parameter0)
+            .with_parameter1(value.parameter1)
+            .with_status(value.status)
+            .with_remain(value.remain)
+            .with_control(value.control)
     }
 }
 
@@ -146,15 +162,28 @@ pub enum TrbType {
     SetupStage,
     DataStage,
     StatusStage,
+    Isoch,
     Link,
+    EventData,
     NoOp,
     EnableSlotCommand,
+    DisableSlotCommand,
     AddressDeviceCommand,
     ConfigureEndpoint,
+    EvaluateContextCommand,
+    ResetEndpointCommand,
+    StopEndpointCommand,
+    SetTrDequeuePointerCommand,
+    ResetDeviceCommand,
     NoOpCommand,
     TransferEvent,
     CommandConpletionEvent,
     PortStatusChangeEvent,
+    BandwidthRequestEvent,
+    DoorbellEvent,
+    HostControllerEvent,
+    DeviceNotificationEvent,
+    MfindexWrapEvent,
     Unknown(u8),
 }
 
@@ -166,15 +195,28 @@ impl TrbType {
             2 => SetupStage,
             3 => DataStage,
             4 => StatusStage,
+            5 => Isoch,
             6 => Link,
+            7 => EventData,
             8 => NoOp,
             9 => EnableSlotCommand,
+            10 => DisableSlotCommand,
             11 => AddressDeviceCommand,
             12 => ConfigureEndpoint,
+            13 => EvaluateContextCommand,
+            14 => ResetEndpointCommand,
+            15 => StopEndpointCommand,
+            16 => SetTrDequeuePointerCommand,
+            17 => ResetDeviceCommand,
             23 => NoOpCommand,
             32 => TransferEvent,
             33 => CommandConpletionEvent,
             34 => PortStatusChangeEvent,
+            35 => BandwidthRequestEvent,
+            36 => DoorbellEvent,
+            37 => HostControllerEvent,
+            38 => DeviceNotificationEvent,
+            39 => MfindexWrapEvent,
             x => Unknown(x),
         }
     }
@@ -186,15 +228,28 @@ impl TrbType {
             SetupStage => 2,
             DataStage => 3,
             StatusStage => 4,
+            Isoch => 5,
             Link => 6,
+            EventData => 7,
             NoOp => 8,
             EnableSlotCommand => 9,
+            DisableSlotCommand => 10,
             AddressDeviceCommand => 11,
             ConfigureEndpoint => 12,
+            EvaluateContextCommand => 13,
+            ResetEndpointCommand => 14,
+            StopEndpointCommand => 15,
+            SetTrDequeuePointerCommand => 16,
+            ResetDeviceCommand => 17,
             NoOpCommand => 23,
             TransferEvent => 32,
             CommandConpletionEvent => 33,
             PortStatusChangeEvent => 34,
+            BandwidthRequestEvent => 35,
+            DoorbellEvent => 36,
+            HostControllerEvent => 37,
+            DeviceNotificationEvent => 38,
+            MfindexWrapEvent => 39,
             Unknown(x) => x,
         }
     }
@@ -455,7 +510,7 @@ bitfield_struct! {
             #[bits(24)]
             trb_transfer_length: u32,
             #[bits(8)]
-            completion_code: CommandConpletionCode,
+            completion_code: CompletionCode,
         },
         remain: u16 => {
             #[bits(1)]
@@ -482,6 +537,24 @@ bitfield_struct! {
 
 impl TransferEvent {
     pub const TYPE: TrbType = TrbType::TransferEvent;
+
+    /// イベントを発生させたTRBのアドレス
+    pub fn trb_pointer(self) -> u64 {
+        ((self.get_trb_pointer_hi() as u64) << 32) | self.get_trb_pointer_lo() as u64
+    }
+
+    pub fn completion_code(self) -> CompletionCode {
+        self.get_status_completion_code()
+    }
+
+    /// 完了コードがエラーなら`Err`にする。Short Packetは成功として扱う。
+    pub fn check(self) -> crate::xhci::error::Result<Self> {
+        if self.completion_code().is_error() {
+            Err(crate::xhci::error::Error::transfer_not_success(self))
+        } else {
+            Ok(self)
+        }
+    }
 }
 
 impl Type for TransferEvent {
@@ -792,7 +865,7 @@ bitfield_struct! {
             #[bits(24)]
             command_completion_parameter: u32,
             #[bits(8)]
-            completion_code: CommandConpletionCode,
+            completion_code: CompletionCode,
         },
         remain: u16 => {
             #[bits(1)]
@@ -817,13 +890,22 @@ impl CommandCompletionEvent {
     /// # Safety
     /// issuer ptr must be valid
     pub unsafe fn issuer(self) -> Trb {
-        let ptr = (self.get_params_ptr() << 4) as *const TrbRaw;
+        let ptr = self.command_trb_pointer() as *const TrbRaw;
         Trb::from(unsafe { *ptr })
     }
 
+    /// 完了したコマンドTRBのアドレス。`issue_command`の返り値と比べられる。
+    pub fn command_trb_pointer(self) -> u64 {
+        self.get_params_ptr() << 4
+    }
+
     pub fn is_success(self) -> bool {
         self.get_status_completion_code().is_success()
     }
+
+    pub fn completion_code(self) -> CompletionCode {
+        self.get_status_completion_code()
+    }
 }
 
 impl Type for CommandCompletionEvent {
@@ -849,20 +931,179 @@ impl TryFrom<TrbRaw> for CommandCompletionEvent {
     }
 }
 
-#[repr(transparent)]
+/// Command Completion EventとTransfer Eventなどに入る完了コード
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct CommandConpletionCode(u8);
+pub enum CompletionCode {
+    Invalid,
+    Success,
+    DataBufferError,
+    BabbleDetectedError,
+    UsbTransactionError,
+    TrbError,
+    StallError,
+    ResourceError,
+    BandwidthError,
+    NoSlotsAvailableError,
+    InvalidStreamTypeError,
+    SlotNotEnabledError,
+    EndpointNotEnabledError,
+    ShortPacket,
+    RingUnderrun,
+    RingOverrun,
+    VfEventRingFullError,
+    ParameterError,
+    BandwidthOverrunError,
+    ContextStateError,
+    NoPingResponseError,
+    EventRingFullError,
+    IncompatibleDeviceError,
+    MissedServiceError,
+    CommandRingStopped,
+    CommandAborted,
+    Stopped,
+    StoppedLengthInvalid,
+    StoppedShortPacket,
+    MaxExitLatencyTooLargeError,
+    IsochBufferOverrun,
+    EventLostError,
+    UndefinedError,
+    InvalidStreamIdError,
+    SecondaryBandwidthError,
+    SplitTransactionError,
+    /// 192 ~ 223
+    VendorDefinedError(u8),
+    /// 224 ~ 255
+    VendorDefinedInfo(u8),
+    Reserved(u8),
+}
+
+impl CompletionCode {
+    pub fn from_u8(v: u8) -> Self {
+        use CompletionCode::*;
+        match v {
+            0 => Invalid,
+            1 => Success,
+            2 => DataBufferError,
+            3 => BabbleDetectedError,
+            4 => UsbTransactionError,
+            5 => TrbError,
+            6 => StallError,
+            7 => ResourceError,
+            8 => BandwidthError,
+            9 => NoSlotsAvailableError,
+            10 => InvalidStreamTypeError,
+            11 => SlotNotEnabledError,
+            12 => EndpointNotEnabledError,
+            13 => ShortPacket,
+            14 => RingUnderrun,
+            15 => RingOverrun,
+            16 => VfEventRingFullError,
+            17 => ParameterError,
+            18 => BandwidthOverrunError,
+            19 => ContextStateError,
+            20 => NoPingResponseError,
+            21 => EventRingFullError,
+            22 => IncompatibleDeviceError,
+            23 => MissedServiceError,
+            24 => CommandRingStopped,
+            25 => CommandAborted,
+            26 => Stopped,
+            27 => StoppedLengthInvalid,
+            28 => StoppedShortPacket,
+            29 => MaxExitLatencyTooLargeError,
+            31 => IsochBufferOverrun,
+            32 => EventLostError,
+            33 => UndefinedError,
+            34 => InvalidStreamIdError,
+            35 => SecondaryBandwidthError,
+            36 => SplitTransactionError,
+            192..=223 => VendorDefinedError(v),
+            224..=255 => VendorDefinedInfo(v),
+            x => Reserved(x),
+        }
+    }
 
-impl CommandConpletionCode {
-    pub const INVALID: u8 = 0;
-    pub const SUCCESS: u8 = 1;
+    pub fn as_u8(self) -> u8 {
+        use CompletionCode::*;
+        match self {
+            Invalid => 0,
+            Success => 1,
+            DataBufferError => 2,
+            BabbleDetectedError => 3,
+            UsbTransactionError => 4,
+            TrbError => 5,
+            StallError => 6,
+            ResourceError => 7,
+            BandwidthError => 8,
+            NoSlotsAvailableError => 9,
+            InvalidStreamTypeError => 10,
+            SlotNotEnabledError => 11,
+            EndpointNotEnabledError => 12,
+            ShortPacket => 13,
+            RingUnderrun => 14,
+            RingOverrun => 15,
+            VfEventRingFullError => 16,
+            ParameterError => 17,
+            BandwidthOverrunError => 18,
+            ContextStateError => 19,
+            NoPingResponseError => 20,
+            EventRingFullError => 21,
+            IncompatibleDeviceError => 22,
+            MissedServiceError => 23,
+            CommandRingStopped => 24,
+            CommandAborted => 25,
+            Stopped => 26,
+            StoppedLengthInvalid => 27,
+            StoppedShortPacket => 28,
+            MaxExitLatencyTooLargeError => 29,
+            IsochBufferOverrun => 31,
+            EventLostError => 32,
+            UndefinedError => 33,
+            InvalidStreamIdError => 34,
+            SecondaryBandwidthError => 35,
+            SplitTransactionError => 36,
+            VendorDefinedError(x) | VendorDefinedInfo(x) | Reserved(x) => x,
+        }
+    }
 
     pub fn is_success(self) -> bool {
-        self.0 == Self::SUCCESS
+        self == Self::Success
+    }
+
+    /// 要求より短いデータで転送が終わった。エラーではない。
+    pub fn is_short_packet(self) -> bool {
+        self == Self::ShortPacket
+    }
+
+    /// Stop Endpoint Commandで止めたときのコード
+    pub fn is_stopped(self) -> bool {
+        matches!(
+            self,
+            Self::Stopped | Self::StoppedLengthInvalid | Self::StoppedShortPacket
+        )
+    }
+
+    /// エンドポイントがHalted状態になり、Reset Endpoint Commandが必要になるもの
+    pub fn halts_endpoint(self) -> bool {
+        matches!(
+            self,
+            Self::StallError
+                | Self::BabbleDetectedError
+                | Self::UsbTransactionError
+                | Self::SplitTransactionError
+        )
+    }
+
+    /// 転送が失敗したもの。Success、Short Packet、Stoppedとベンダー定義の情報コードはエラーではない。
+    pub fn is_error(self) -> bool {
+        !(self.is_success()
+            || self.is_short_packet()
+            || self.is_stopped()
+            || matches!(self, Self::VendorDefinedInfo(_)))
     }
 }
 
-impl EndianFrom<u32> for CommandConpletionCode {
+impl EndianFrom<u32> for CompletionCode {
     fn from_le(v: u32) -> Self {
         Self::from_ne(u32::from_le(v))
     }
@@ -872,11 +1113,11 @@ impl EndianFrom<u32> for CommandConpletionCode {
     }
 
     fn from_ne(v: u32) -> Self {
-        Self(v as u8)
+        Self::from_u8(v as u8)
     }
 }
 
-impl EndianInto<u32> for CommandConpletionCode {
+impl EndianInto<u32> for CompletionCode {
     fn to_le(self) -> u32 {
         self.to_ne().to_le()
     }
@@ -886,7 +1127,7 @@ impl EndianInto<u32> for CommandConpletionCode {
     }
 
     fn to_ne(self) -> u32 {
-        self.0 as u32
+        self.as_u8() as u32
     }
 }
 
@@ -906,7 +1147,7 @@ bitfield_struct! {
             #[bits(24)]
             _rsvdz: u32,
             #[bits(8)]
-            completion_code: u8,
+            completion_code: CompletionCode,
         },
         remain: u16 => {
             #[bits(1)]
@@ -948,76 +1189,963 @@ impl TryFrom<TrbRaw> for PortStatusChangeEvent {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub enum Trb {
-    Normal,
-    SetupStage,
-    DataStage,
-    StatusStage,
-    Link(Link),
-    NoOp,
-    EnableSlotCommand(EnableSlotCommand),
-    AddressDeviceCommand(AddressDeviceCommand),
-    ConfigureEndpointCommand(ConfigureEndpointCommand),
-    NoOpCommand,
-    TransferEvent(TransferEvent),
-    CommandCompletionEvent(CommandCompletionEvent),
-    PortStatusChangeEvent(PortStatusChangeEvent),
-    Unknown(u8),
-}
+/// `parameter0`, `parameter1`, `status`, `remain`, `control`をそのまま持つTRBの変換を実装する
+macro_rules! impl_trb {
+    ($name:ident, $ty:expr) => {
+        impl $name {
+            pub const TYPE: TrbType = $ty;
+        }
 
-impl From<TrbRaw> for Trb {
-    fn from(value: TrbRaw) -> Self {
-        match value.get_remain_trb_type() {
-            TrbType::Normal => todo!(),
-            TrbType::SetupStage => todo!(),
-            TrbType::DataStage => todo!(),
-            TrbType::StatusStage => todo!(),
-            TrbType::Link => Self::Link(Link::try_from(value).unwrap()),
-            TrbType::NoOp => todo!(),
-            TrbType::EnableSlotCommand => {
-                Self::EnableSlotCommand(EnableSlotCommand::try_from(value).unwrap())
+        impl Type for $name {
+            fn get_type(self) -> TrbType {
+                Self::TYPE
             }
-            TrbType::AddressDeviceCommand => {
-                Self::AddressDeviceCommand(AddressDeviceCommand::try_from(value).unwrap())
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                Self::zeroed().with_remain_trb_type(Self::TYPE)
             }
-            TrbType::ConfigureEndpoint => {
-                Self::ConfigureEndpointCommand(ConfigureEndpointCommand::try_from(value).unwrap())
+        }
+
+        impl TryFrom<TrbRaw> for $name {
+            type Error = ();
+
+            fn try_from(value: TrbRaw) -> Result<Self, Self::Error> {
+                if matches!(value.get_remain_trb_type(), Self::TYPE) {
+                    Ok(Self {
+                        parameter0: value.parameter0,
+                        parameter1: value.parameter1,
+                        status: value.status,
+                        remain: value.remain,
+                        control: value.control,
+                    })
+                } else {
+                    Err(())
+                }
             }
-            TrbType::NoOpCommand => todo!(),
-            TrbType::TransferEvent => Self::TransferEvent(TransferEvent::try_from(value).unwrap()),
-            TrbType::CommandConpletionEvent => {
-                Self::CommandCompletionEvent(CommandCompletionEvent::try_from(value).unwrap())
+        }
+
+        impl From<$name> for TrbRaw {
+            fn from(value: $name) -> Self {
+                Self::zeroed()
+                    .with_parameter0(value.parameter0)
+                    .with_parameter1(value.parameter1)
+                    .with_status(value.status)
+                    .with_remain(value.remain)
+                    .with_control(value.control)
             }
-            TrbType::PortStatusChangeEvent => {
-                Self::PortStatusChangeEvent(PortStatusChangeEvent::try_from(value).unwrap())
+        }
+    };
+}
+
+/// `parameter0`と`parameter1`に64bitのアドレスを持つTRB
+macro_rules! impl_trb_pointer {
+    ($name:ident, $get:ident, $with:ident) => {
+        impl $name {
+            pub fn $get(&self) -> u64 {
+                ((self.parameter1 as u64) << 32) | self.parameter0 as u64
+            }
+
+            pub fn $with(self, ptr: u64) -> Self {
+                self.with_parameter0(ptr as u32)
+                    .with_parameter1((ptr >> 32) as u32)
             }
-            TrbType::Unknown(x) => Trb::Unknown(x),
         }
+    };
+}
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct Normal {
+        parameter0: u32,
+        parameter1: u32,
+        status: u32 => {
+            #[bits(17)]
+            trb_transfer_length: u32,
+            #[bits(5)]
+            td_size: u8,
+            #[bits(10)]
+            interrupter_target: u16,
+        },
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(1)]
+            evaluate_next_trb: bool,
+            #[bits(1)]
+            interrupt_on_short_packet: bool,
+            #[bits(1)]
+            no_snoop: bool,
+            #[bits(1)]
+            chain_bit: bool,
+            #[bits(1)]
+            interrupt_on_completion: bool,
+            #[bits(1)]
+            immediate_data: bool,
+            #[bits(2)]
+            _rsvdz: u8,
+            #[bits(1)]
+            block_event_interrupt: bool,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16,
     }
 }
 
-impl Type for Trb {
-    fn get_type(self) -> TrbType {
-        match self {
-            Trb::Normal => todo!(),
-            Trb::SetupStage => todo!(),
-            Trb::DataStage => todo!(),
-            Trb::StatusStage => todo!(),
-            Trb::Link(_) => Link::TYPE,
-            Trb::NoOp => todo!(),
-            Trb::EnableSlotCommand(_) => EnableSlotCommand::TYPE,
-            Trb::AddressDeviceCommand(_) => AddressDeviceCommand::TYPE,
-            Trb::ConfigureEndpointCommand(_) => ConfigureEndpointCommand::TYPE,
-            Trb::NoOpCommand => todo!(),
-            Trb::TransferEvent(_) => TransferEvent::TYPE,
-            Trb::CommandCompletionEvent(_) => CommandCompletionEvent::TYPE,
-            Trb::PortStatusChangeEvent(_) => PortStatusChangeEvent::TYPE,
-            Trb::Unknown(x) => TrbType::Unknown(x),
+impl_trb!(Normal, TrbType::Normal);
+impl_trb_pointer!(Normal, data_buffer_pointer, with_data_buffer_pointer);
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct Isoch {
+        parameter0: u32,
+        parameter1: u32,
+        status: u32 => {
+            #[bits(17)]
+            trb_transfer_length: u32,
+            #[bits(5)]
+            td_size: u8,
+            #[bits(10)]
+            interrupter_target: u16,
+        },
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(1)]
+            evaluate_next_trb: bool,
+            #[bits(1)]
+            interrupt_on_short_packet: bool,
+            #[bits(1)]
+            no_snoop: bool,
+            #[bits(1)]
+            chain_bit: bool,
+            #[bits(1)]
+            interrupt_on_completion: bool,
+            #[bits(1)]
+            immediate_data: bool,
+            #[bits(2)]
+            transfer_burst_count: u8,
+            #[bits(1)]
+            block_event_interrupt: bool,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16 => {
+            #[bits(4)]
+            transfer_last_burst_packet_count: u8,
+            #[bits(11)]
+            frame_id: u16,
+            #[bits(1)]
+            start_isoch_asap: bool,
         }
     }
 }
 
-pub trait Type {
-    fn get_type(self) -> TrbType;
+impl_trb!(Isoch, TrbType::Isoch);
+impl_trb_pointer!(Isoch, data_buffer_pointer, with_data_buffer_pointer);
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct EventData {
+        parameter0: u32,
+        parameter1: u32,
+        status: u32 => {
+            #[bits(22)]
+            _rsvdz: u32,
+            #[bits(10)]
+            interrupter_target: u16,
+        },
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(1)]
+            evaluate_next_trb: bool,
+            #[bits(2)]
+            _rsvdz1: u8,
+            #[bits(1)]
+            chain_bit: bool,
+            #[bits(1)]
+            interrupt_on_completion: bool,
+            #[bits(3)]
+            _rsvdz2: u8,
+            #[bits(1)]
+            block_event_interrupt: bool,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16,
+    }
+}
+
+impl_trb!(EventData, TrbType::EventData);
+impl_trb_pointer!(EventData, event_data, with_event_data);
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct NoOp {
+        parameter0: u32,
+        parameter1: u32,
+        status: u32 => {
+            #[bits(22)]
+            _rsvdz: u32,
+            #[bits(10)]
+            interrupter_target: u16,
+        },
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(1)]
+            evaluate_next_trb: bool,
+            #[bits(2)]
+            _rsvdz1: u8,
+            #[bits(1)]
+            chain_bit: bool,
+            #[bits(1)]
+            interrupt_on_completion: bool,
+            #[bits(4)]
+            _rsvdz2: u8,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16,
+    }
+}
+
+impl_trb!(NoOp, TrbType::NoOp);
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct DisableSlotCommand {
+        parameter0: u32,
+        parameter1: u32,
+        status: u32,
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(9)]
+            _rsvdz: u16,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16 => {
+            #[bits(8)]
+            _rsvdz: u8,
+            #[bits(8)]
+            slot_id: u8,
+        }
+    }
+}
+
+impl_trb!(DisableSlotCommand, TrbType::DisableSlotCommand);
+
+impl DisableSlotCommand {
+    pub fn new(slot_id: u8) -> Self {
+        Self::default().with_control_slot_id(slot_id)
+    }
+}
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct EvaluateContextCommand {
+        parameter0: u32,
+        parameter1: u32,
+        status: u32,
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(9)]
+            _rsvdz: u16,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16 => {
+            #[bits(8)]
+            _rsvdz: u8,
+            #[bits(8)]
+            slot_id: u8,
+        }
+    }
+}
+
+impl_trb!(EvaluateContextCommand, TrbType::EvaluateContextCommand);
+impl_trb_pointer!(
+    EvaluateContextCommand,
+    input_context_pointer,
+    with_input_context_pointer
+);
+
+impl EvaluateContextCommand {
+    pub fn new(input_cx_ptr: *mut u8, slot_id: u8) -> Self {
+        Self::default()
+            .with_input_context_pointer(input_cx_ptr as u64)
+            .with_control_slot_id(slot_id)
+    }
+}
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct ResetEndpointCommand {
+        parameter0: u32,
+        parameter1: u32,
+        status: u32,
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(8)]
+            _rsvdz: u8,
+            #[bits(1)]
+            transfer_state_preserve: bool,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16 => {
+            #[bits(5)]
+            endpoint_id: u8,
+            #[bits(3)]
+            _rsvdz: u8,
+            #[bits(8)]
+            slot_id: u8,
+        }
+    }
+}
+
+impl_trb!(ResetEndpointCommand, TrbType::ResetEndpointCommand);
+
+impl ResetEndpointCommand {
+    pub fn new(slot_id: u8, endpoint_id: u8) -> Self {
+        Self::default()
+            .with_control_slot_id(slot_id)
+            .with_control_endpoint_id(endpoint_id)
+    }
+}
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct StopEndpointCommand {
+        parameter0: u32,
+        parameter1: u32,
+        status: u32,
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(9)]
+            _rsvdz: u16,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16 => {
+            #[bits(5)]
+            endpoint_id: u8,
+            #[bits(2)]
+            _rsvdz: u8,
+            #[bits(1)]
+            suspend: bool,
+            #[bits(8)]
+            slot_id: u8,
+        }
+    }
+}
+
+impl_trb!(StopEndpointCommand, TrbType::StopEndpointCommand);
+
+impl StopEndpointCommand {
+    pub fn new(slot_id: u8, endpoint_id: u8) -> Self {
+        Self::default()
+            .with_control_slot_id(slot_id)
+            .with_control_endpoint_id(endpoint_id)
+    }
+}
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct SetTrDequeuePointerCommand {
+        parameter0: u32 => {
+            #[bits(1)]
+            dequeue_cycle_state: bool,
+            #[bits(3)]
+            stream_context_type: u8,
+            #[bits(28)]
+            dequeue_pointer_lo: u32,
+        },
+        parameter1: u32,
+        status: u32 => {
+            #[bits(16)]
+            _rsvdz: u16,
+            #[bits(16)]
+            stream_id: u16,
+        },
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(9)]
+            _rsvdz: u16,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16 => {
+            #[bits(5)]
+            endpoint_id: u8,
+            #[bits(3)]
+            _rsvdz: u8,
+            #[bits(8)]
+            slot_id: u8,
+        }
+    }
+}
+
+impl_trb!(
+    SetTrDequeuePointerCommand,
+    TrbType::SetTrDequeuePointerCommand
+);
+
+impl SetTrDequeuePointerCommand {
+    pub fn new(slot_id: u8, endpoint_id: u8, dequeue_ptr: u64, cycle_state: bool) -> Self {
+        Self::default()
+            .with_control_slot_id(slot_id)
+            .with_control_endpoint_id(endpoint_id)
+            .with_dequeue_pointer(dequeue_ptr)
+            .with_parameter0_dequeue_cycle_state(cycle_state)
+    }
+
+    /// 16バイト境界のアドレス
+    pub fn dequeue_pointer(&self) -> u64 {
+        ((self.parameter1 as u64) << 32) | (self.get_parameter0_dequeue_pointer_lo() as u64) << 4
+    }
+
+    pub fn with_dequeue_pointer(self, ptr: u64) -> Self {
+        self.with_parameter0_dequeue_pointer_lo((ptr as u32) >> 4)
+            .with_parameter1((ptr >> 32) as u32)
+    }
+}
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct ResetDeviceCommand {
+        parameter0: u32,
+        parameter1: u32,
+        status: u32,
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(9)]
+            _rsvdz: u16,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16 => {
+            #[bits(8)]
+            _rsvdz: u8,
+            #[bits(8)]
+            slot_id: u8,
+        }
+    }
+}
+
+impl_trb!(ResetDeviceCommand, TrbType::ResetDeviceCommand);
+
+impl ResetDeviceCommand {
+    pub fn new(slot_id: u8) -> Self {
+        Self::default().with_control_slot_id(slot_id)
+    }
+}
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct NoOpCommand {
+        parameter0: u32,
+        parameter1: u32,
+        status: u32,
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(9)]
+            _rsvdz: u16,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16,
+    }
+}
+
+impl_trb!(NoOpCommand, TrbType::NoOpCommand);
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct BandwidthRequestEvent {
+        parameter0: u32,
+        parameter1: u32,
+        status: u32 => {
+            #[bits(24)]
+            _rsvdz: u32,
+            #[bits(8)]
+            completion_code: CompletionCode,
+        },
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(9)]
+            _rsvdz: u16,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16 => {
+            #[bits(8)]
+            _rsvdz: u8,
+            #[bits(8)]
+            slot_id: u8,
+        }
+    }
+}
+
+impl_trb!(BandwidthRequestEvent, TrbType::BandwidthRequestEvent);
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct DoorbellEvent {
+        parameter0: u32 => {
+            #[bits(5)]
+            reason: u8,
+            #[bits(27)]
+            _rsvdz: u32,
+        },
+        parameter1: u32,
+        status: u32 => {
+            #[bits(24)]
+            _rsvdz: u32,
+            #[bits(8)]
+            completion_code: CompletionCode,
+        },
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(9)]
+            _rsvdz: u16,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16 => {
+            #[bits(8)]
+            vf_id: u8,
+            #[bits(8)]
+            slot_id: u8,
+        }
+    }
+}
+
+impl_trb!(DoorbellEvent, TrbType::DoorbellEvent);
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct HostControllerEvent {
+        parameter0: u32,
+        parameter1: u32,
+        status: u32 => {
+            #[bits(24)]
+            _rsvdz: u32,
+            #[bits(8)]
+            completion_code: CompletionCode,
+        },
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(9)]
+            _rsvdz: u16,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16,
+    }
+}
+
+impl_trb!(HostControllerEvent, TrbType::HostControllerEvent);
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct DeviceNotificationEvent {
+        parameter0: u32 => {
+            #[bits(4)]
+            _rsvdz: u8,
+            #[bits(4)]
+            notification_type: u8,
+            #[bits(24)]
+            notification_data_lo: u32,
+        },
+        parameter1: u32,
+        status: u32 => {
+            #[bits(24)]
+            _rsvdz: u32,
+            #[bits(8)]
+            completion_code: CompletionCode,
+        },
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(9)]
+            _rsvdz: u16,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16 => {
+            #[bits(8)]
+            _rsvdz: u8,
+            #[bits(8)]
+            slot_id: u8,
+        }
+    }
+}
+
+impl_trb!(DeviceNotificationEvent, TrbType::DeviceNotificationEvent);
+
+impl DeviceNotificationEvent {
+    /// Device Notification Data (56bit)
+    pub fn notification_data(&self) -> u64 {
+        ((self.parameter1 as u64) << 24) | self.get_parameter0_notification_data_lo() as u64
+    }
+}
+
+bitfield_struct! {
+    #[repr(C, packed)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Zeroed)]
+    #[endian = "little"]
+    pub struct MfindexWrapEvent {
+        parameter0: u32,
+        parameter1: u32,
+        status: u32 => {
+            #[bits(24)]
+            _rsvdz: u32,
+            #[bits(8)]
+            completion_code: CompletionCode,
+        },
+        remain: u16 => {
+            #[bits(1)]
+            cycle_bit: bool,
+            #[bits(9)]
+            _rsvdz: u16,
+            #[bits(6)]
+            trb_type: TrbType,
+        },
+        control: u16,
+    }
+}
+
+impl_trb!(MfindexWrapEvent, TrbType::MfindexWrapEvent);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Trb {
+    Normal(Normal),
+    SetupStage(SetupStage),
+    DataStage(DataStage),
+    StatusStage(StatusStage),
+    Isoch(Isoch),
+    Link(Link),
+    EventData(EventData),
+    NoOp(NoOp),
+    EnableSlotCommand(EnableSlotCommand),
+    DisableSlotCommand(DisableSlotCommand),
+    AddressDeviceCommand(AddressDeviceCommand),
+    ConfigureEndpointCommand(ConfigureEndpointCommand),
+    EvaluateContextCommand(EvaluateContextCommand),
+    ResetEndpointCommand(ResetEndpointCommand),
+    StopEndpointCommand(StopEndpointCommand),
+    SetTrDequeuePointerCommand(SetTrDequeuePointerCommand),
+    ResetDeviceCommand(ResetDeviceCommand),
+    NoOpCommand(NoOpCommand),
+    TransferEvent(TransferEvent),
+    CommandCompletionEvent(CommandCompletionEvent),
+    PortStatusChangeEvent(PortStatusChangeEvent),
+    BandwidthRequestEvent(BandwidthRequestEvent),
+    DoorbellEvent(DoorbellEvent),
+    HostControllerEvent(HostControllerEvent),
+    DeviceNotificationEvent(DeviceNotificationEvent),
+    MfindexWrapEvent(MfindexWrapEvent),
+    Unknown(u8),
+}
+
+impl From<TrbRaw> for Trb {
+    fn from(value: TrbRaw) -> Self {
+        // 種類を見て変換するのでtry_fromは失敗しない
+        match value.get_remain_trb_type() {
+            TrbType::Normal => Self::Normal(Normal::try_from(value).unwrap()),
+            TrbType::SetupStage => Self::SetupStage(SetupStage::try_from(value).unwrap()),
+            TrbType::DataStage => Self::DataStage(DataStage::try_from(value).unwrap()),
+            TrbType::StatusStage => Self::StatusStage(StatusStage::try_from(value).unwrap()),
+            TrbType::Isoch => Self::Isoch(Isoch::try_from(value).unwrap()),
+            TrbType::Link => Self::Link(Link::try_from(value).unwrap()),
+            TrbType::EventData => Self::EventData(EventData::try_from(value).unwrap()),
+            TrbType::NoOp => Self::NoOp(NoOp::try_from(value).unwrap()),
+            TrbType::EnableSlotCommand => {
+                Self::EnableSlotCommand(EnableSlotCommand::try_from(value).unwrap())
+            }
+            TrbType::DisableSlotCommand => {
+                Self::DisableSlotCommand(DisableSlotCommand::try_from(value).unwrap())
+            }
+            TrbType::AddressDeviceCommand => {
+                Self::AddressDeviceCommand(AddressDeviceCommand::try_from(value).unwrap())
+            }
+            TrbType::ConfigureEndpoint => {
+                Self::ConfigureEndpointCommand(ConfigureEndpointCommand::try_from(value).unwrap())
+            }
+            TrbType::EvaluateContextCommand => {
+                Self::EvaluateContextCommand(EvaluateContextCommand::try_from(value).unwrap())
+            }
+            TrbType::ResetEndpointCommand => {
+                Self::ResetEndpointCommand(ResetEndpointCommand::try_from(value).unwrap())
+            }
+            TrbType::StopEndpointCommand => {
+                Self::StopEndpointCommand(StopEndpointCommand::try_from(value).unwrap())
+            }
+            TrbType::SetTrDequeuePointerCommand => Self::SetTrDequeuePointerCommand(
+                SetTrDequeuePointerCommand::try_from(value).unwrap(),
+            ),
+            TrbType::ResetDeviceCommand => {
+                Self::ResetDeviceCommand(ResetDeviceCommand::try_from(value).unwrap())
+            }
+            TrbType::NoOpCommand => Self::NoOpCommand(NoOpCommand::try_from(value).unwrap()),
+            TrbType::TransferEvent => Self::TransferEvent(TransferEvent::try_from(value).unwrap()),
+            TrbType::CommandConpletionEvent => {
+                Self::CommandCompletionEvent(CommandCompletionEvent::try_from(value).unwrap())
+            }
+            TrbType::PortStatusChangeEvent => {
+                Self::PortStatusChangeEvent(PortStatusChangeEvent::try_from(value).unwrap())
+            }
+            TrbType::BandwidthRequestEvent => {
+                Self::BandwidthRequestEvent(BandwidthRequestEvent::try_from(value).unwrap())
+            }
+            TrbType::DoorbellEvent => Self::DoorbellEvent(DoorbellEvent::try_from(value).unwrap()),
+            TrbType::HostControllerEvent => {
+                Self::HostControllerEvent(HostControllerEvent::try_from(value).unwrap())
+            }
+            TrbType::DeviceNotificationEvent => {
+                Self::DeviceNotificationEvent(DeviceNotificationEvent::try_from(value).unwrap())
+            }
+            TrbType::MfindexWrapEvent => {
+                Self::MfindexWrapEvent(MfindexWrapEvent::try_from(value).unwrap())
+            }
+            TrbType::Unknown(x) => Trb::Unknown(x),
+        }
+    }
+}
+
+impl From<Trb> for TrbRaw {
+    fn from(value: Trb) -> Self {
+        match value {
+            Trb::Normal(trb) => trb.into(),
+            Trb::SetupStage(trb) => trb.into(),
+            Trb::DataStage(trb) => trb.into(),
+            Trb::StatusStage(trb) => trb.into(),
+            Trb::Isoch(trb) => trb.into(),
+            Trb::Link(trb) => trb.into(),
+            Trb::EventData(trb) => trb.into(),
+            Trb::NoOp(trb) => trb.into(),
+            Trb::EnableSlotCommand(trb) => trb.into(),
+            Trb::DisableSlotCommand(trb) => trb.into(),
+            Trb::AddressDeviceCommand(trb) => trb.into(),
+            Trb::ConfigureEndpointCommand(trb) => trb.into(),
+            Trb::EvaluateContextCommand(trb) => trb.into(),
+            Trb::ResetEndpointCommand(trb) => trb.into(),
+            Trb::StopEndpointCommand(trb) => trb.into(),
+            Trb::SetTrDequeuePointerCommand(trb) => trb.into(),
+            Trb::ResetDeviceCommand(trb) => trb.into(),
+            Trb::NoOpCommand(trb) => trb.into(),
+            Trb::TransferEvent(trb) => trb.into(),
+            Trb::CommandCompletionEvent(trb) => trb.into(),
+            Trb::PortStatusChangeEvent(trb) => trb.into(),
+            Trb::BandwidthRequestEvent(trb) => trb.into(),
+            Trb::DoorbellEvent(trb) => trb.into(),
+            Trb::HostControllerEvent(trb) => trb.into(),
+            Trb::DeviceNotificationEvent(trb) => trb.into(),
+            Trb::MfindexWrapEvent(trb) => trb.into(),
+            Trb::Unknown(x) => TrbRaw::zeroed().with_remain_trb_type(TrbType::Unknown(x)),
+        }
+    }
+}
+
+impl Type for Trb {
+    fn get_type(self) -> TrbType {
+        match self {
+            Trb::Normal(_) => Normal::TYPE,
+            Trb::SetupStage(_) => SetupStage::TYPE,
+            Trb::DataStage(_) => DataStage::TYPE,
+            Trb::StatusStage(_) => StatusStage::TYPE,
+            Trb::Isoch(_) => Isoch::TYPE,
+            Trb::Link(_) => Link::TYPE,
+            Trb::EventData(_) => EventData::TYPE,
+            Trb::NoOp(_) => NoOp::TYPE,
+            Trb::EnableSlotCommand(_) => EnableSlotCommand::TYPE,
+            Trb::DisableSlotCommand(_) => DisableSlotCommand::TYPE,
+            Trb::AddressDeviceCommand(_) => AddressDeviceCommand::TYPE,
+            Trb::ConfigureEndpointCommand(_) => ConfigureEndpointCommand::TYPE,
+            Trb::EvaluateContextCommand(_) => EvaluateContextCommand::TYPE,
+            Trb::ResetEndpointCommand(_) => ResetEndpointCommand::TYPE,
+            Trb::StopEndpointCommand(_) => StopEndpointCommand::TYPE,
+            Trb::SetTrDequeuePointerCommand(_) => SetTrDequeuePointerCommand::TYPE,
+            Trb::ResetDeviceCommand(_) => ResetDeviceCommand::TYPE,
+            Trb::NoOpCommand(_) => NoOpCommand::TYPE,
+            Trb::TransferEvent(_) => TransferEvent::TYPE,
+            Trb::CommandCompletionEvent(_) => CommandCompletionEvent::TYPE,
+            Trb::PortStatusChangeEvent(_) => PortStatusChangeEvent::TYPE,
+            Trb::BandwidthRequestEvent(_) => BandwidthRequestEvent::TYPE,
+            Trb::DoorbellEvent(_) => DoorbellEvent::TYPE,
+            Trb::HostControllerEvent(_) => HostControllerEvent::TYPE,
+            Trb::DeviceNotificationEvent(_) => DeviceNotificationEvent::TYPE,
+            Trb::MfindexWrapEvent(_) => MfindexWrapEvent::TYPE,
+            Trb::Unknown(x) => TrbType::Unknown(x),
+        }
+    }
+}
+
+pub trait Type {
+    fn get_type(self) -> TrbType;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(trb_type: u8) -> TrbRaw {
+        let remain = TrbRaw::zeroed()
+            .with_remain_cycle_bit(true)
+            .with_remain_remain(0x5a)
+            .with_remain_trb_type(TrbType::from_u8(trb_type))
+            .get_remain();
+        TrbRaw::new(0x1234_5670, 0x9abc_def0, 0x0102_0304, 0x0506, remain)
+    }
+
+    #[test]
+    fn test_trb_type_round_trip() {
+        for v in 0..64 {
+            assert_eq!(TrbType::from_u8(v).as_u8(), v);
+        }
+    }
+
+    #[test]
+    fn test_raw_round_trip() {
+        let known = [
+            1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 23, 32, 33, 34, 35, 36, 37,
+            38, 39,
+        ];
+        for trb_type in known {
+            let raw = raw(trb_type);
+            let trb = Trb::from(raw);
+            assert!(
+                !matches!(trb, Trb::Unknown(_)),
+                "type {} is not decoded",
+                trb_type
+            );
+            assert_eq!(trb.get_type().as_u8(), trb_type);
+            assert_eq!(TrbRaw::from(trb), raw, "type {}", trb_type);
+        }
+
+        let trb = Trb::from(raw(30));
+        assert_eq!(trb, Trb::Unknown(30));
+        assert_eq!(
+            TrbRaw::from(trb).get_remain_trb_type(),
+            TrbType::Unknown(30)
+        );
+    }
+
+    #[test]
+    fn test_completion_code() {
+        for v in 0..=255 {
+            assert_eq!(CompletionCode::from_u8(v).as_u8(), v);
+        }
+        assert_eq!(CompletionCode::from_u8(6), CompletionCode::StallError);
+        assert_eq!(CompletionCode::from_u8(13), CompletionCode::ShortPacket);
+        assert_eq!(CompletionCode::from_u8(30), CompletionCode::Reserved(30));
+        assert_eq!(
+            CompletionCode::from_u8(200),
+            CompletionCode::VendorDefinedError(200)
+        );
+
+        assert!(!CompletionCode::ShortPacket.is_error());
+        assert!(CompletionCode::StallError.is_error());
+        assert!(CompletionCode::StallError.halts_endpoint());
+        assert!(!CompletionCode::RingUnderrun.halts_endpoint());
+
+        let event = TransferEvent::try_from(raw(32).with_status(6 << 24 | 8)).unwrap();
+        assert_eq!(event.completion_code(), CompletionCode::StallError);
+        assert_eq!(event.get_status_trb_transfer_length(), 8);
+        assert_eq!(
+            event.check().unwrap_err().completion_code(),
+            Some(CompletionCode::StallError)
+        );
+        let event = TransferEvent::try_from(raw(32).with_status(13 << 24)).unwrap();
+        assert!(event.check().is_ok());
+    }
+
+    #[test]
+    fn test_try_from_rejects_other_type() {
+        assert!(Normal::try_from(raw(2)).is_err());
+        assert!(Normal::try_from(raw(1)).is_ok());
+        assert!(MfindexWrapEvent::try_from(raw(37)).is_err());
+    }
+
+    #[test]
+    fn test_fields() {
+        let normal = Normal::default()
+            .with_data_buffer_pointer(0x1_2345_6780)
+            .with_status_trb_transfer_length(512)
+            .with_remain_interrupt_on_completion(true);
+        let raw = TrbRaw::from(normal);
+        assert_eq!(raw.get_parameter0(), 0x2345_6780);
+        assert_eq!(raw.get_parameter1(), 0x1);
+        assert_eq!(raw.get_status(), 512);
+        assert_eq!(raw.get_remain(), (1 << 10) | (1 << 5));
+
+        let isoch = Isoch::default()
+            .with_control_frame_id(0x7ff)
+            .with_control_start_isoch_asap(true);
+        assert_eq!(TrbRaw::from(isoch).get_control(), 0xfff0);
+
+        let cmd = SetTrDequeuePointerCommand::new(3, 5, 0xdead_beef_0000_1230, true);
+        let raw = TrbRaw::from(cmd);
+        assert_eq!(raw.get_parameter0(), 0x0000_1231);
+        assert_eq!(raw.get_parameter1(), 0xdead_beef);
+        assert_eq!(raw.get_control(), (3 << 8) | 5);
+        assert_eq!(cmd.dequeue_pointer(), 0xdead_beef_0000_1230);
+
+        let cmd = StopEndpointCommand::new(2, 3).with_control_suspend(true);
+        assert_eq!(TrbRaw::from(cmd).get_control(), (2 << 8) | (1 << 7) | 3);
+
+        let event = DeviceNotificationEvent::try_from(raw_with(38, 0xabcd_ef50, 0x12)).unwrap();
+        assert_eq!(event.get_parameter0_notification_type(), 5);
+        assert_eq!(event.notification_data(), 0x12ab_cdef);
+    }
+
+    fn raw_with(trb_type: u8, parameter0: u32, parameter1: u32) -> TrbRaw {
+        let raw = raw(trb_type);
+        raw.with_parameter0(parameter0).with_parameter1(parameter1)
+    }
 }